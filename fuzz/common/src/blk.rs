@@ -18,7 +18,7 @@ pub mod tests {
     use std::mem;
     use virtio_bindings::bindings::virtio_blk::VIRTIO_BLK_T_IN;
     use virtio_bindings::bindings::virtio_ring::{VRING_DESC_F_NEXT, VRING_DESC_F_WRITE};
-    use virtio_blk::request::{Request, RequestType};
+    use virtio_blk::request::{DataDescriptor, Request, RequestType};
     use virtio_queue::{mock::MockSplitQueue, Descriptor};
     use vm_memory::{ByteValued, Bytes, GuestAddress, GuestMemoryMmap};
 
@@ -100,11 +100,17 @@ pub mod tests {
         assert_eq!(data.len(), 2);
         assert_eq!(
             data.get(0),
-            Some(&(GuestAddress(REQ_DATA_ADDRS[0]), DESC_LEN as u32))
+            Some(&DataDescriptor::new(
+                GuestAddress(REQ_DATA_ADDRS[0]),
+                DESC_LEN as u32
+            ))
         );
         assert_eq!(
             data.get(1),
-            Some(&(GuestAddress(REQ_DATA_ADDRS[1]), DESC_LEN as u32))
+            Some(&DataDescriptor::new(
+                GuestAddress(REQ_DATA_ADDRS[1]),
+                DESC_LEN as u32
+            ))
         );
         assert_eq!(req.sector(), REQ_SECTOR);
         assert_eq!(req.status_addr(), GuestAddress(REQ_STATUS_ADDR));
@@ -0,0 +1,159 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Asynchronous request execution on top of the tokio runtime.
+//!
+//! The [`StdIoBackend`](../stdio_executor/struct.StdIoBackend.html) executes the requests with
+//! blocking I/O, so this module runs them on the blocking thread pool of tokio with
+//! `tokio::task::spawn_blocking`, instead of blocking the asynchronous tasks.
+
+use std::future::Future;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use vm_memory::GuestMemory;
+
+use crate::request::Request;
+use crate::stdio_executor::{Backend, Result, StdIoBackend};
+
+/// Executes requests with a [`StdIoBackend`] on the blocking thread pool of tokio.
+///
+/// The executor is shared by the blocking tasks through an `Arc<Mutex<_>>`, so the requests are
+/// executed one at a time, on the same backend. Overlapping writes are therefore never
+/// interleaved, but requests whose futures are pending at the same time may be executed in any
+/// order, as for a device that completes requests out of order. Requests that have to be ordered
+/// must be submitted after the previous ones completed.
+///
+/// Requests that should run in parallel can be executed by
+/// [`StdIoBackend::concurrent_execute`](../stdio_executor/struct.StdIoBackend.html#method.concurrent_execute)
+/// instead.
+#[derive(Debug)]
+pub struct AsyncExecutor<B: Backend> {
+    backend: Arc<Mutex<StdIoBackend<B>>>,
+}
+
+impl<B: Backend> Clone for AsyncExecutor<B> {
+    fn clone(&self) -> Self {
+        AsyncExecutor {
+            backend: self.backend.clone(),
+        }
+    }
+}
+
+impl<B: Backend + Send + 'static> AsyncExecutor<B> {
+    /// Creates a new `AsyncExecutor` executing the requests with `backend`.
+    ///
+    /// # Arguments
+    /// * `backend` - The executor of the requests.
+    pub fn new(backend: StdIoBackend<B>) -> Self {
+        AsyncExecutor {
+            backend: Arc::new(Mutex::new(backend)),
+        }
+    }
+
+    /// Obtains the executor of the requests, waiting for the request being executed, if any.
+    pub fn backend(&self) -> MutexGuard<'_, StdIoBackend<B>> {
+        self.backend
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Executes `request` like
+    /// [`StdIoBackend::execute`](../stdio_executor/struct.StdIoBackend.html#method.execute)
+    /// does, on the blocking thread pool of the current tokio runtime, and returns a future
+    /// resolving to its result.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called outside of a tokio runtime, and the future panics if the execution of
+    /// the request panics.
+    ///
+    /// # Arguments
+    /// * `mem` - The guest memory.
+    /// * `request` - The request to execute.
+    pub fn execute_async<M: GuestMemory + Send + Sync + 'static>(
+        &self,
+        mem: Arc<M>,
+        request: Request,
+    ) -> impl Future<Output = Result<u32>> {
+        let backend = self.backend.clone();
+        let task = tokio::task::spawn_blocking(move || {
+            backend
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .execute(mem.as_ref(), &request)
+        });
+        async move { task.await.expect("request execution task panicked") }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use vm_memory::{Bytes, GuestAddress, GuestMemoryMmap};
+    use vmm_sys_util::tempfile::TempFile;
+
+    use crate::request::{DataDescriptor, RequestType};
+    use crate::stdio_executor::Error;
+
+    #[tokio::test]
+    async fn test_execute_async() {
+        let mem =
+            Arc::new(GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap());
+        mem.write_slice(&[0xAB; 0x400], GuestAddress(0x1000))
+            .unwrap();
+        let f = TempFile::new().unwrap().into_file();
+        f.set_len(0x1000).unwrap();
+        let req_exec = AsyncExecutor::new(StdIoBackend::new(f, 0).unwrap());
+
+        let out_req = Request::new(
+            RequestType::Out,
+            vec![DataDescriptor::new(GuestAddress(0x1000), 0x400)],
+            1,
+            GuestAddress(0x100),
+        );
+        assert_eq!(req_exec.execute_async(mem.clone(), out_req).await, Ok(0));
+
+        let in_req = Request::new(
+            RequestType::In,
+            vec![DataDescriptor::new(GuestAddress(0x2000), 0x400)],
+            1,
+            GuestAddress(0x100),
+        );
+        assert_eq!(req_exec.execute_async(mem.clone(), in_req).await, Ok(0x400));
+        let mut data = [0u8; 0x400];
+        mem.read_slice(&mut data, GuestAddress(0x2000)).unwrap();
+        assert_eq!(data, [0xAB; 0x400]);
+
+        // The errors are returned by the future as well.
+        let in_req = Request::new(
+            RequestType::In,
+            vec![DataDescriptor::new(GuestAddress(0x2000), 0x400)],
+            8,
+            GuestAddress(0x100),
+        );
+        assert_eq!(
+            req_exec.execute_async(mem.clone(), in_req).await,
+            Err(Error::InvalidAccess)
+        );
+        assert_eq!(req_exec.backend().stats().snapshot().reads, 2);
+
+        // The requests are executed with the same backend, one at a time.
+        let out_reqs: Vec<_> = (0..4)
+            .map(|sector| {
+                let out_req = Request::new(
+                    RequestType::Out,
+                    vec![DataDescriptor::new(GuestAddress(0x1000), 0x400)],
+                    sector,
+                    GuestAddress(0x100),
+                );
+                req_exec.execute_async(mem.clone(), out_req)
+            })
+            .collect();
+        for out_req in out_reqs {
+            assert_eq!(out_req.await, Ok(0));
+        }
+        assert_eq!(req_exec.backend().stats().snapshot().writes, 5);
+    }
+}
@@ -0,0 +1,1209 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Composable block device backends.
+//!
+//! This module provides the following abstractions for building stacks of backends, where every
+//! layer adds some functionality (e.g. throttling, encryption or checksums) on top of the next
+//! one:
+//!
+//! - [`BackendLayer`](trait.BackendLayer.html) which is implemented by the layers. Every operation
+//! is forwarded to the next layer by default, so a layer only has to implement the operations it
+//! cares about.
+//! - [`DelegatingBackend`](struct.DelegatingBackend.html) which combines a layer with the backend
+//! below it into a new [`Backend`](../stdio_executor/trait.Backend.html).
+//!
+//! The capabilities of a backend can be queried at runtime with
+//! [`BackendCapabilities::info`](trait.BackendCapabilities.html#method.info).
+//!
+//! It also provides [`RotatingBackend`](struct.RotatingBackend.html), which archives the backing
+//! file and continues with a fresh one according to a [`RotationPolicy`](enum.RotationPolicy.html),
+//! [`VecBackend`](struct.VecBackend.html), which keeps the data in memory, and
+//! [`VerifyingBackend`](struct.VerifyingBackend.html), which mirrors the data to a second backend.
+
+use std::cmp;
+use std::fmt::{self, Display};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Cursor, Seek, SeekFrom};
+#[cfg(target_os = "linux")]
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::result;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use vm_memory::bitmap::BitmapSlice;
+use vm_memory::{ReadVolatile, VolatileMemoryError, VolatileSlice, WriteVolatile};
+use vmm_sys_util::file_traits::FileSync;
+#[cfg(target_os = "linux")]
+use vmm_sys_util::ioctl::ioctl_with_mut_ref;
+use vmm_sys_util::write_zeroes::{PunchHole, WriteZeroesAt};
+
+use crate::defs::SECTOR_SIZE;
+use crate::stdio_executor::Backend;
+
+/// A layer of a backend stack, which receives every operation together with the backend below
+/// it.
+///
+/// All the methods forward the operation to `inner` by default.
+pub trait BackendLayer<B: Backend> {
+    /// Reads data from `inner` into `buf`, like [`ReadVolatile::read_volatile`] does.
+    ///
+    /// # Arguments
+    /// * `inner` - The backend below the layer.
+    /// * `buf` - The buffer to fill.
+    fn read_volatile<S: BitmapSlice>(
+        &mut self,
+        inner: &mut B,
+        buf: &mut VolatileSlice<S>,
+    ) -> result::Result<usize, VolatileMemoryError> {
+        inner.read_volatile(buf)
+    }
+
+    /// Writes the data from `buf` to `inner`, like [`WriteVolatile::write_volatile`] does.
+    ///
+    /// # Arguments
+    /// * `inner` - The backend below the layer.
+    /// * `buf` - The buffer to write.
+    fn write_volatile<S: BitmapSlice>(
+        &mut self,
+        inner: &mut B,
+        buf: &VolatileSlice<S>,
+    ) -> result::Result<usize, VolatileMemoryError> {
+        inner.write_volatile(buf)
+    }
+
+    /// Changes the position of `inner`, like [`Seek::seek`] does.
+    ///
+    /// # Arguments
+    /// * `inner` - The backend below the layer.
+    /// * `pos` - The position to seek to.
+    fn seek(&mut self, inner: &mut B, pos: SeekFrom) -> io::Result<u64> {
+        inner.seek(pos)
+    }
+
+    /// Syncs the data of `inner`, like [`FileSync::fsync`] does.
+    ///
+    /// # Arguments
+    /// * `inner` - The backend below the layer.
+    fn fsync(&mut self, inner: &mut B) -> io::Result<()> {
+        inner.fsync()
+    }
+
+    /// Deallocates a range of `inner`, like [`PunchHole::punch_hole`] does.
+    ///
+    /// # Arguments
+    /// * `inner` - The backend below the layer.
+    /// * `offset` - The offset of the range.
+    /// * `length` - The length of the range.
+    fn punch_hole(&mut self, inner: &mut B, offset: u64, length: u64) -> io::Result<()> {
+        inner.punch_hole(offset, length)
+    }
+
+    /// Zeroes out a range of `inner`, like [`WriteZeroesAt::write_zeroes_at`] does.
+    ///
+    /// # Arguments
+    /// * `inner` - The backend below the layer.
+    /// * `offset` - The offset of the range.
+    /// * `length` - The length of the range.
+    fn write_zeroes_at(&mut self, inner: &mut B, offset: u64, length: usize) -> io::Result<usize> {
+        inner.write_zeroes_at(offset, length)
+    }
+}
+
+/// A backend made of a [`BackendLayer`] on top of another backend.
+///
+/// Since a `DelegatingBackend` is a [`Backend`] itself, stacks with any number of layers can be
+/// built by nesting them.
+///
+/// # Example
+///
+/// ```rust
+/// # use std::io;
+/// # use virtio_blk::backend::{BackendLayer, DelegatingBackend};
+/// # use virtio_blk::stdio_executor::{Backend, StdIoBackend};
+/// # use vmm_sys_util::tempfile::TempFile;
+/// // A layer that ignores the flushes.
+/// struct NoFlush;
+///
+/// impl<B: Backend> BackendLayer<B> for NoFlush {
+///     fn fsync(&mut self, _inner: &mut B) -> io::Result<()> {
+///         Ok(())
+///     }
+/// }
+///
+/// let backend = DelegatingBackend::new(TempFile::new().unwrap().into_file(), NoFlush);
+/// let request_exec = StdIoBackend::new(backend, 0).unwrap();
+/// ```
+#[derive(Debug)]
+pub struct DelegatingBackend<B, L> {
+    inner: B,
+    layer: L,
+}
+
+impl<B: Backend, L: BackendLayer<B>> DelegatingBackend<B, L> {
+    /// Creates a new `DelegatingBackend` with `layer` on top of `inner`.
+    ///
+    /// # Arguments
+    /// * `inner` - The backend below the layer.
+    /// * `layer` - The layer that receives the operations first.
+    pub fn new(inner: B, layer: L) -> Self {
+        DelegatingBackend { inner, layer }
+    }
+
+    /// Obtains an immutable reference to the backend below the layer.
+    pub fn inner(&self) -> &B {
+        &self.inner
+    }
+
+    /// Obtains a mutable reference to the backend below the layer.
+    pub fn inner_mut(&mut self) -> &mut B {
+        &mut self.inner
+    }
+
+    /// Obtains an immutable reference to the layer.
+    pub fn layer(&self) -> &L {
+        &self.layer
+    }
+
+    /// Obtains a mutable reference to the layer.
+    pub fn layer_mut(&mut self) -> &mut L {
+        &mut self.layer
+    }
+
+    /// Consumes the `DelegatingBackend`, returning the backend below the layer and the layer.
+    pub fn into_parts(self) -> (B, L) {
+        (self.inner, self.layer)
+    }
+}
+
+impl<B: Backend, L: BackendLayer<B>> ReadVolatile for DelegatingBackend<B, L> {
+    fn read_volatile<S: BitmapSlice>(
+        &mut self,
+        buf: &mut VolatileSlice<S>,
+    ) -> result::Result<usize, VolatileMemoryError> {
+        self.layer.read_volatile(&mut self.inner, buf)
+    }
+}
+
+impl<B: Backend, L: BackendLayer<B>> WriteVolatile for DelegatingBackend<B, L> {
+    fn write_volatile<S: BitmapSlice>(
+        &mut self,
+        buf: &VolatileSlice<S>,
+    ) -> result::Result<usize, VolatileMemoryError> {
+        self.layer.write_volatile(&mut self.inner, buf)
+    }
+}
+
+impl<B: Backend, L: BackendLayer<B>> Seek for DelegatingBackend<B, L> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.layer.seek(&mut self.inner, pos)
+    }
+}
+
+impl<B: Backend, L: BackendLayer<B>> FileSync for DelegatingBackend<B, L> {
+    fn fsync(&mut self) -> io::Result<()> {
+        self.layer.fsync(&mut self.inner)
+    }
+}
+
+impl<B: Backend, L: BackendLayer<B>> PunchHole for DelegatingBackend<B, L> {
+    fn punch_hole(&mut self, offset: u64, length: u64) -> io::Result<()> {
+        self.layer.punch_hole(&mut self.inner, offset, length)
+    }
+}
+
+impl<B: Backend, L: BackendLayer<B>> WriteZeroesAt for DelegatingBackend<B, L> {
+    fn write_zeroes_at(&mut self, offset: u64, length: usize) -> io::Result<usize> {
+        self.layer.write_zeroes_at(&mut self.inner, offset, length)
+    }
+}
+
+/// The capabilities of a backend.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BackendInfo {
+    /// Whether punching holes deallocates the storage, instead of only zeroing it.
+    pub supports_punch_hole: bool,
+    /// Whether ranges can be zeroed without writing buffers of zeroes.
+    pub supports_write_zeroes: bool,
+    /// Whether syncing makes the written data persistent.
+    pub supports_fsync: bool,
+    /// Whether the backend implements
+    /// [`VectoredBackend`](../stdio_executor/trait.VectoredBackend.html).
+    pub supports_vectored_io: bool,
+    /// The size in bytes of the smallest unit the storage can write atomically.
+    pub physical_block_size: u32,
+    /// The size in bytes of the smallest unit the storage can address.
+    pub logical_block_size: u32,
+}
+
+impl Default for BackendInfo {
+    /// Returns the capabilities that can be assumed for any backend: none of the optional
+    /// operations, and blocks of one sector.
+    fn default() -> Self {
+        BackendInfo {
+            supports_punch_hole: false,
+            supports_write_zeroes: false,
+            supports_fsync: false,
+            supports_vectored_io: false,
+            physical_block_size: SECTOR_SIZE as u32,
+            logical_block_size: SECTOR_SIZE as u32,
+        }
+    }
+}
+
+/// A backend that reports its capabilities at runtime.
+pub trait BackendCapabilities {
+    /// Returns the capabilities of the backend.
+    ///
+    /// The default implementation returns the conservative
+    /// [`BackendInfo::default`](struct.BackendInfo.html#method.default).
+    fn info(&self) -> BackendInfo {
+        BackendInfo::default()
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod ioctls {
+    use vmm_sys_util::{ioctl_io_nr, ioctl_ioc_nr};
+
+    ioctl_io_nr!(BLKSSZGET, 0x12, 104);
+    ioctl_io_nr!(BLKPBSZGET, 0x12, 123);
+}
+
+impl BackendCapabilities for File {
+    /// Returns the capabilities of the file.
+    ///
+    /// On Linux, the block sizes of block devices are obtained with the `BLKSSZGET` and
+    /// `BLKPBSZGET` ioctls. For regular files, the logical block size is one sector and the
+    /// physical block size is the preferred I/O size of the filesystem.
+    fn info(&self) -> BackendInfo {
+        #[cfg(target_os = "linux")]
+        {
+            let mut info = BackendInfo {
+                supports_punch_hole: true,
+                supports_write_zeroes: true,
+                supports_fsync: true,
+                supports_vectored_io: true,
+                ..Default::default()
+            };
+            let metadata = match self.metadata() {
+                Ok(metadata) => metadata,
+                Err(_) => return info,
+            };
+            if metadata.file_type().is_block_device() {
+                let mut logical_block_size: libc::c_int = 0;
+                let mut physical_block_size: libc::c_uint = 0;
+                // SAFETY: Safe because the ioctls only write an integer to the given reference,
+                // and the return values are checked.
+                unsafe {
+                    if ioctl_with_mut_ref(self, ioctls::BLKSSZGET(), &mut logical_block_size) == 0 {
+                        info.logical_block_size = logical_block_size as u32;
+                    }
+                    if ioctl_with_mut_ref(self, ioctls::BLKPBSZGET(), &mut physical_block_size) == 0
+                    {
+                        info.physical_block_size = physical_block_size;
+                    }
+                }
+            } else if let Ok(blksize) = u32::try_from(metadata.blksize()) {
+                info.physical_block_size = blksize.max(info.logical_block_size);
+            }
+            info
+        }
+        #[cfg(not(target_os = "linux"))]
+        BackendInfo {
+            supports_fsync: true,
+            ..Default::default()
+        }
+    }
+}
+
+impl<B: Backend + BackendCapabilities, L: BackendLayer<B>> BackendCapabilities
+    for DelegatingBackend<B, L>
+{
+    fn info(&self) -> BackendInfo {
+        self.inner.info()
+    }
+}
+
+/// A backend that can be created from the path of its backing file.
+pub trait NewFromPath: Sized {
+    /// Opens the backing file at `path` for reading and writing, creating it if it doesn't exist.
+    ///
+    /// # Arguments
+    /// * `path` - The path of the backing file.
+    fn new_from_path(path: &Path) -> io::Result<Self>;
+
+    /// Truncates or extends the backing file to `len` bytes.
+    ///
+    /// # Arguments
+    /// * `len` - The new length of the backing file.
+    fn set_len(&mut self, len: u64) -> io::Result<()>;
+}
+
+impl NewFromPath for File {
+    fn new_from_path(path: &Path) -> io::Result<Self> {
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        File::set_len(self, len)
+    }
+}
+
+/// The condition that triggers the rotation of a [`RotatingBackend`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RotationPolicy {
+    /// Rotate once the given number of bytes was written to the current backing file.
+    MaxSize(u64),
+    /// Rotate once a day, at the given time since midnight (UTC).
+    TimeOfDay(Duration),
+}
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// A backend that moves its backing file to a timestamped archive, and continues with a fresh
+/// one, when its [`RotationPolicy`] is met.
+///
+/// The policy is checked before every write. The archive is named after the backing file, with
+/// the number of seconds since the Unix epoch appended (e.g. `disk.img.1700000000`). The fresh
+/// file has the same length as the archived one, with no data allocated, and is positioned at
+/// the same offset.
+#[derive(Debug)]
+pub struct RotatingBackend<B> {
+    inner: B,
+    path: PathBuf,
+    policy: RotationPolicy,
+    // The number of bytes written to the current backing file.
+    written: u64,
+    // The time of the next rotation, for `RotationPolicy::TimeOfDay`.
+    next_rotation: Option<SystemTime>,
+}
+
+impl<B: Backend + NewFromPath> RotatingBackend<B> {
+    /// Opens the backing file at `path` and creates a new `RotatingBackend` based on it.
+    ///
+    /// # Arguments
+    /// * `path` - The path of the backing file.
+    /// * `policy` - The condition that triggers the rotation.
+    pub fn new(path: &Path, policy: RotationPolicy) -> io::Result<Self> {
+        let next_rotation = match policy {
+            RotationPolicy::TimeOfDay(time) => {
+                Some(Self::next_time_of_day(SystemTime::now(), time))
+            }
+            RotationPolicy::MaxSize(_) => None,
+        };
+        Ok(RotatingBackend {
+            inner: B::new_from_path(path)?,
+            path: path.to_path_buf(),
+            policy,
+            written: 0,
+            next_rotation,
+        })
+    }
+
+    /// Obtains an immutable reference to the current backend.
+    pub fn inner(&self) -> &B {
+        &self.inner
+    }
+
+    /// Archives the current backing file and continues with a fresh one, regardless of the
+    /// rotation policy.
+    pub fn force_rotate(&mut self) -> io::Result<()> {
+        let position = self.inner.stream_position()?;
+        let len = self.inner.seek(SeekFrom::End(0))?;
+        self.inner.seek(SeekFrom::Start(position))?;
+        self.inner.fsync()?;
+
+        // The fresh file is prepared next to the current one, so a failure leaves the current
+        // file in place. It is truncated first, since it may be left over from a rotation that
+        // was interrupted.
+        let fresh_path = PathBuf::from(format!("{}.rotating", self.path.display()));
+        let mut fresh = B::new_from_path(&fresh_path)?;
+        if let Err(e) = fresh
+            .set_len(0)
+            .and_then(|_| fresh.set_len(len))
+            .and_then(|_| fresh.seek(SeekFrom::Start(position)).map(|_| ()))
+        {
+            let _ = fs::remove_file(&fresh_path);
+            return Err(e);
+        }
+
+        let now = SystemTime::now();
+        let secs = now
+            .duration_since(UNIX_EPOCH)
+            .map_err(io::Error::other)?
+            .as_secs();
+        let mut archive = PathBuf::from(format!("{}.{}", self.path.display(), secs));
+        // More than one rotation can happen in the same second.
+        let mut index = 1;
+        while archive.exists() {
+            archive = PathBuf::from(format!("{}.{}-{}", self.path.display(), secs, index));
+            index += 1;
+        }
+        if let Err(e) = fs::rename(&self.path, &archive) {
+            let _ = fs::remove_file(&fresh_path);
+            return Err(e);
+        }
+        if let Err(e) = fs::rename(&fresh_path, &self.path) {
+            // Put the current file back in place.
+            let _ = fs::rename(&archive, &self.path);
+            let _ = fs::remove_file(&fresh_path);
+            return Err(e);
+        }
+
+        self.inner = fresh;
+        self.written = 0;
+        if let RotationPolicy::TimeOfDay(time) = self.policy {
+            self.next_rotation = Some(Self::next_time_of_day(now, time));
+        }
+        Ok(())
+    }
+
+    // Rotates the backing file if the policy is met.
+    fn rotate_if_needed(&mut self) -> io::Result<()> {
+        let rotate = match self.policy {
+            RotationPolicy::MaxSize(max_size) => self.written >= max_size,
+            RotationPolicy::TimeOfDay(_) => self
+                .next_rotation
+                .is_some_and(|next_rotation| SystemTime::now() >= next_rotation),
+        };
+        if rotate {
+            self.force_rotate()?;
+        }
+        Ok(())
+    }
+
+    // Returns the first time after `now` that is `time` past a midnight (UTC).
+    fn next_time_of_day(now: SystemTime, time: Duration) -> SystemTime {
+        let secs = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let midnight = UNIX_EPOCH + Duration::from_secs(secs - secs % SECS_PER_DAY);
+        let today = midnight + Duration::from_secs(time.as_secs() % SECS_PER_DAY);
+        if today > now {
+            today
+        } else {
+            today + Duration::from_secs(SECS_PER_DAY)
+        }
+    }
+}
+
+impl<B: Backend + NewFromPath> ReadVolatile for RotatingBackend<B> {
+    fn read_volatile<S: BitmapSlice>(
+        &mut self,
+        buf: &mut VolatileSlice<S>,
+    ) -> result::Result<usize, VolatileMemoryError> {
+        self.inner.read_volatile(buf)
+    }
+}
+
+impl<B: Backend + NewFromPath> WriteVolatile for RotatingBackend<B> {
+    fn write_volatile<S: BitmapSlice>(
+        &mut self,
+        buf: &VolatileSlice<S>,
+    ) -> result::Result<usize, VolatileMemoryError> {
+        self.rotate_if_needed()
+            .map_err(VolatileMemoryError::IOError)?;
+        let count = self.inner.write_volatile(buf)?;
+        self.written += count as u64;
+        Ok(count)
+    }
+}
+
+impl<B: Backend + NewFromPath> Seek for RotatingBackend<B> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+impl<B: Backend + NewFromPath> FileSync for RotatingBackend<B> {
+    fn fsync(&mut self) -> io::Result<()> {
+        self.inner.fsync()
+    }
+}
+
+impl<B: Backend + NewFromPath> PunchHole for RotatingBackend<B> {
+    fn punch_hole(&mut self, offset: u64, length: u64) -> io::Result<()> {
+        self.inner.punch_hole(offset, length)
+    }
+}
+
+impl<B: Backend + NewFromPath + BackendCapabilities> BackendCapabilities for RotatingBackend<B> {
+    fn info(&self) -> BackendInfo {
+        self.inner.info()
+    }
+}
+
+impl<B: Backend + NewFromPath> WriteZeroesAt for RotatingBackend<B> {
+    fn write_zeroes_at(&mut self, offset: u64, length: usize) -> io::Result<usize> {
+        self.inner.write_zeroes_at(offset, length)
+    }
+}
+
+/// A backend whose data is kept in a `Vec<u8>`, e.g. for tests that don't need a backing file.
+///
+/// Writes past the end of the data extend it with zeroes, like they extend a file. Syncing does
+/// nothing, and punching holes fills them with zeroes.
+#[derive(Clone, Debug, Default)]
+pub struct VecBackend(Cursor<Vec<u8>>);
+
+impl VecBackend {
+    /// Creates a new `VecBackend` with the given initial data.
+    ///
+    /// # Arguments
+    /// * `data` - The initial data of the backend.
+    pub fn new(data: Vec<u8>) -> Self {
+        VecBackend(Cursor::new(data))
+    }
+
+    /// Returns the data of the backend.
+    pub fn data(&self) -> &[u8] {
+        self.0.get_ref()
+    }
+
+    /// Consumes the `VecBackend`, returning its data.
+    pub fn into_inner(self) -> Vec<u8> {
+        self.0.into_inner()
+    }
+
+    // Returns the `length` bytes of data at `offset`, extending the data with zeroes if they
+    // are past its end.
+    fn range_mut(&mut self, offset: u64, length: usize) -> io::Result<&mut [u8]> {
+        let invalid_input = || io::Error::from(io::ErrorKind::InvalidInput);
+        let start = usize::try_from(offset).map_err(|_| invalid_input())?;
+        let end = start.checked_add(length).ok_or_else(invalid_input)?;
+        let data = self.0.get_mut();
+        if data.len() < end {
+            data.resize(end, 0);
+        }
+        Ok(&mut data[start..end])
+    }
+}
+
+impl ReadVolatile for VecBackend {
+    fn read_volatile<S: BitmapSlice>(
+        &mut self,
+        buf: &mut VolatileSlice<S>,
+    ) -> result::Result<usize, VolatileMemoryError> {
+        self.0.read_volatile(buf)
+    }
+}
+
+impl WriteVolatile for VecBackend {
+    fn write_volatile<S: BitmapSlice>(
+        &mut self,
+        buf: &VolatileSlice<S>,
+    ) -> result::Result<usize, VolatileMemoryError> {
+        let position = self.0.position();
+        let count = buf.copy_to(
+            self.range_mut(position, buf.len())
+                .map_err(VolatileMemoryError::IOError)?,
+        );
+        self.0.set_position(position + count as u64);
+        Ok(count)
+    }
+}
+
+impl Seek for VecBackend {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.0.seek(pos)
+    }
+}
+
+impl FileSync for VecBackend {
+    fn fsync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl PunchHole for VecBackend {
+    fn punch_hole(&mut self, offset: u64, length: u64) -> io::Result<()> {
+        // Like on a file, punching a hole doesn't change the size of the data.
+        let len = self.data().len() as u64;
+        let start = cmp::min(offset, len);
+        let end = cmp::min(offset.saturating_add(length), len);
+        // The conversions are safe since both values are at most the length of the data.
+        self.0.get_mut()[start as usize..end as usize].fill(0);
+        Ok(())
+    }
+}
+
+impl WriteZeroesAt for VecBackend {
+    fn write_zeroes_at(&mut self, offset: u64, length: usize) -> io::Result<usize> {
+        self.range_mut(offset, length)?.fill(0);
+        Ok(length)
+    }
+}
+
+impl BackendCapabilities for VecBackend {
+    /// Returns the capabilities of the data in memory, which can be zeroed directly, but is
+    /// neither deallocated by punching holes nor made persistent by syncing.
+    fn info(&self) -> BackendInfo {
+        BackendInfo {
+            supports_write_zeroes: true,
+            ..Default::default()
+        }
+    }
+}
+
+/// The errors returned by a [`VerifyingBackend`] when its two backends are no longer consistent.
+///
+/// They are wrapped in an `io::Error` of kind `Other`, like every other backend error.
+#[derive(Debug, PartialEq, Eq)]
+pub enum MirrorError {
+    /// The operation succeeded on the primary backend but failed on the secondary one.
+    MirrorMismatch {
+        /// The first sector of the operation.
+        sector: u64,
+    },
+    /// The data read from the two backends differs.
+    MirrorDiverge {
+        /// The first sector that differs.
+        sector: u64,
+    },
+}
+
+impl Display for MirrorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::MirrorError::*;
+
+        match self {
+            MirrorMismatch { sector } => write!(
+                f,
+                "the secondary backend failed an operation at sector {}",
+                sector
+            ),
+            MirrorDiverge { sector } => write!(
+                f,
+                "the data of the two backends differs at sector {}",
+                sector
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MirrorError {}
+
+/// Whether a [`VerifyingBackend`] compares the data of its two backends when reading.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum VerifyMode {
+    /// Read from the primary backend only.
+    #[default]
+    Off,
+    /// Read from both backends, and fail with [`MirrorError::MirrorDiverge`] if the data differs.
+    VerifyOnRead,
+}
+
+/// A backend that mirrors the data of a primary backend to a secondary one, like a RAID-1 array
+/// with two disks.
+///
+/// Every modification is applied to the primary backend first, and then to the same range of the
+/// secondary backend. If the latter fails, the operation fails with
+/// [`MirrorError::MirrorMismatch`]. The data is read from the primary backend, and with
+/// [`VerifyMode::VerifyOnRead`] it is compared with the data of the secondary backend as well.
+///
+/// The position of the secondary backend is only meaningful during an operation, since it is set
+/// to the position of the primary backend before accessing it.
+#[derive(Debug)]
+pub struct VerifyingBackend<P, M> {
+    primary: P,
+    secondary: M,
+    mode: VerifyMode,
+}
+
+impl<P: Backend, M: Backend> VerifyingBackend<P, M> {
+    /// Creates a new `VerifyingBackend` that doesn't verify reads.
+    ///
+    /// # Arguments
+    /// * `primary` - The backend the data is read from.
+    /// * `secondary` - The backend the data is mirrored to.
+    pub fn new(primary: P, secondary: M) -> Self {
+        VerifyingBackend {
+            primary,
+            secondary,
+            mode: VerifyMode::Off,
+        }
+    }
+
+    /// Sets whether the reads are verified against the secondary backend.
+    ///
+    /// # Arguments
+    /// * `mode` - The verification mode.
+    pub fn with_verify_mode(mut self, mode: VerifyMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Obtains an immutable reference to the primary backend.
+    pub fn primary(&self) -> &P {
+        &self.primary
+    }
+
+    /// Obtains an immutable reference to the secondary backend.
+    pub fn secondary(&self) -> &M {
+        &self.secondary
+    }
+
+    /// Obtains a mutable reference to the secondary backend.
+    pub fn secondary_mut(&mut self) -> &mut M {
+        &mut self.secondary
+    }
+
+    /// Consumes the `VerifyingBackend`, returning the primary and the secondary backends.
+    pub fn into_parts(self) -> (P, M) {
+        (self.primary, self.secondary)
+    }
+
+    fn mismatch(offset: u64) -> io::Error {
+        io::Error::other(MirrorError::MirrorMismatch {
+            sector: offset / SECTOR_SIZE,
+        })
+    }
+}
+
+impl<P: Backend, M: Backend> ReadVolatile for VerifyingBackend<P, M> {
+    fn read_volatile<S: BitmapSlice>(
+        &mut self,
+        buf: &mut VolatileSlice<S>,
+    ) -> result::Result<usize, VolatileMemoryError> {
+        if self.mode == VerifyMode::Off {
+            return self.primary.read_volatile(buf);
+        }
+
+        let position = self
+            .primary
+            .stream_position()
+            .map_err(VolatileMemoryError::IOError)?;
+        let count = self.primary.read_volatile(buf)?;
+        let mut primary_data = vec![0u8; count];
+        buf.copy_to(&mut primary_data);
+        let mut secondary_data = vec![0u8; count];
+        self.secondary
+            .seek(SeekFrom::Start(position))
+            .map_err(VolatileMemoryError::IOError)?;
+        self.secondary
+            .read_exact_volatile(&mut VolatileSlice::from(secondary_data.as_mut_slice()))?;
+
+        if let Some(index) = primary_data
+            .iter()
+            .zip(&secondary_data)
+            .position(|(a, b)| a != b)
+        {
+            return Err(VolatileMemoryError::IOError(io::Error::other(
+                MirrorError::MirrorDiverge {
+                    sector: (position + index as u64) / SECTOR_SIZE,
+                },
+            )));
+        }
+        Ok(count)
+    }
+}
+
+impl<P: Backend, M: Backend> WriteVolatile for VerifyingBackend<P, M> {
+    fn write_volatile<S: BitmapSlice>(
+        &mut self,
+        buf: &VolatileSlice<S>,
+    ) -> result::Result<usize, VolatileMemoryError> {
+        let position = self
+            .primary
+            .stream_position()
+            .map_err(VolatileMemoryError::IOError)?;
+        let count = self.primary.write_volatile(buf)?;
+        let mirrored = self.secondary.seek(SeekFrom::Start(position)).is_ok()
+            && buf
+                .subslice(0, count)
+                .and_then(|data| self.secondary.write_all_volatile(&data))
+                .is_ok();
+        if !mirrored {
+            return Err(VolatileMemoryError::IOError(Self::mismatch(position)));
+        }
+        Ok(count)
+    }
+}
+
+impl<P: Backend, M: Backend> Seek for VerifyingBackend<P, M> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.primary.seek(pos)
+    }
+}
+
+impl<P: Backend, M: Backend> FileSync for VerifyingBackend<P, M> {
+    fn fsync(&mut self) -> io::Result<()> {
+        self.primary.fsync()?;
+        self.secondary.fsync()
+    }
+}
+
+impl<P: Backend, M: Backend> PunchHole for VerifyingBackend<P, M> {
+    fn punch_hole(&mut self, offset: u64, length: u64) -> io::Result<()> {
+        self.primary.punch_hole(offset, length)?;
+        self.secondary
+            .punch_hole(offset, length)
+            .map_err(|_| Self::mismatch(offset))
+    }
+}
+
+impl<P: Backend, M: Backend> WriteZeroesAt for VerifyingBackend<P, M> {
+    fn write_zeroes_at(&mut self, offset: u64, length: usize) -> io::Result<usize> {
+        let count = self.primary.write_zeroes_at(offset, length)?;
+        self.secondary
+            .write_all_zeroes_at(offset, count)
+            .map_err(|_| Self::mismatch(offset))?;
+        Ok(count)
+    }
+}
+
+impl<P: Backend + BackendCapabilities, M: Backend> BackendCapabilities for VerifyingBackend<P, M> {
+    fn info(&self) -> BackendInfo {
+        self.primary.info()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::fs::{FileExt, MetadataExt};
+
+    use virtio_bindings::bindings::virtio_blk::VIRTIO_BLK_F_FLUSH;
+    use vm_memory::{Bytes, GuestAddress, GuestMemoryError, GuestMemoryMmap};
+    use vmm_sys_util::tempdir::TempDir;
+    use vmm_sys_util::tempfile::TempFile;
+
+    use crate::request::{DataDescriptor, Request, RequestType};
+    use crate::stdio_executor::StdIoBackend;
+
+    // A layer that inverts the bits of the data.
+    struct Invert;
+
+    impl<B: Backend> BackendLayer<B> for Invert {
+        fn read_volatile<S: BitmapSlice>(
+            &mut self,
+            inner: &mut B,
+            buf: &mut VolatileSlice<S>,
+        ) -> result::Result<usize, VolatileMemoryError> {
+            let mut data = vec![0u8; buf.len()];
+            let count = inner.read_volatile(&mut VolatileSlice::from(data.as_mut_slice()))?;
+            data.iter_mut().for_each(|b| *b = !*b);
+            buf.copy_from(&data[..count]);
+            Ok(count)
+        }
+
+        fn write_volatile<S: BitmapSlice>(
+            &mut self,
+            inner: &mut B,
+            buf: &VolatileSlice<S>,
+        ) -> result::Result<usize, VolatileMemoryError> {
+            let mut data = vec![0u8; buf.len()];
+            buf.copy_to(&mut data);
+            data.iter_mut().for_each(|b| *b = !*b);
+            inner.write_volatile(&VolatileSlice::from(data.as_mut_slice()))
+        }
+    }
+
+    // A layer that counts the flushes.
+    #[derive(Default)]
+    struct CountFlushes(usize);
+
+    impl<B: Backend> BackendLayer<B> for CountFlushes {
+        fn fsync(&mut self, inner: &mut B) -> io::Result<()> {
+            self.0 += 1;
+            inner.fsync()
+        }
+    }
+
+    // A layer that doesn't change any operation.
+    struct PassThrough;
+
+    impl<B: Backend> BackendLayer<B> for PassThrough {}
+
+    #[test]
+    fn test_delegating_backend() {
+        const NON_ZERO_VALUE: u8 = 0x55;
+
+        let f = TempFile::new().unwrap().into_file();
+        f.set_len(0x1000).unwrap();
+        let mem = GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        mem.write_slice(&[NON_ZERO_VALUE; 0x200], GuestAddress(0x1000))
+            .unwrap();
+
+        let backend = DelegatingBackend::new(
+            DelegatingBackend::new(DelegatingBackend::new(f, Invert), CountFlushes::default()),
+            PassThrough,
+        );
+        let mut req_exec = StdIoBackend::new(backend, 1 << VIRTIO_BLK_F_FLUSH).unwrap();
+        assert_eq!(req_exec.num_sectors(), 8);
+
+        let out_req = Request::new(
+            RequestType::Out,
+            vec![DataDescriptor::new(GuestAddress(0x1000), 0x200)],
+            1,
+            GuestAddress(0x100),
+        );
+        let in_req = Request::new(
+            RequestType::In,
+            vec![DataDescriptor::new(GuestAddress(0x2000), 0x200)],
+            1,
+            GuestAddress(0x100),
+        );
+        let flush_req = Request::new(RequestType::Flush, vec![], 0, GuestAddress(0x100));
+        req_exec.execute(&mem, &out_req).unwrap();
+        assert_eq!(req_exec.execute(&mem, &in_req).unwrap(), 0x200);
+        req_exec.execute(&mem, &flush_req).unwrap();
+
+        // The data is read back through the inverting layer.
+        let mut v = vec![0u8; 0x200];
+        mem.read_slice(&mut v, GuestAddress(0x2000)).unwrap();
+        assert_eq!(v, vec![NON_ZERO_VALUE; 0x200]);
+
+        // The flush only implemented by the middle layer went through it.
+        let (middle, _) = req_exec.into_inner().into_parts();
+        assert_eq!(middle.layer().0, 1);
+
+        // The data is inverted in the file.
+        let (f, _): (File, _) = middle.into_parts().0.into_parts();
+        let mut v = vec![0u8; 0x200];
+        f.read_exact_at(&mut v, 0x200).unwrap();
+        assert_eq!(v, vec![!NON_ZERO_VALUE; 0x200]);
+    }
+
+    #[test]
+    fn test_rotating_backend() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.as_path().join("disk.img");
+        File::create(&path).unwrap().set_len(0x1000).unwrap();
+        let mem = GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        mem.write_slice(&[0x55; 0x400], GuestAddress(0x1000))
+            .unwrap();
+
+        let backend = RotatingBackend::<File>::new(&path, RotationPolicy::MaxSize(0x400)).unwrap();
+        let mut req_exec = StdIoBackend::new(backend, 0).unwrap();
+        let out_req = Request::new(
+            RequestType::Out,
+            vec![DataDescriptor::new(GuestAddress(0x1000), 0x400)],
+            0,
+            GuestAddress(0x100),
+        );
+
+        // The first write fills the current file up to the limit.
+        req_exec.execute(&mem, &out_req).unwrap();
+        let archives = |dir: &TempDir| {
+            fs::read_dir(dir.as_path())
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+                .filter(|name| name.starts_with("disk.img.") && !name.ends_with(".rotating"))
+                .collect::<Vec<_>>()
+        };
+        assert!(archives(&dir).is_empty());
+
+        // The second one triggers the rotation.
+        req_exec.execute(&mem, &out_req).unwrap();
+        let archived = archives(&dir);
+        assert_eq!(archived.len(), 1);
+        let mut v = vec![0u8; 0x400];
+        File::open(dir.as_path().join(&archived[0]))
+            .unwrap()
+            .read_exact_at(&mut v, 0)
+            .unwrap();
+        assert_eq!(v, vec![0x55; 0x400]);
+        assert_eq!(fs::metadata(&path).unwrap().len(), 0x1000);
+
+        // The fresh file has the size of the device, so the sectors that weren't written yet
+        // can be read back as zeroes, even if a previous rotation left data in it.
+        fs::write(dir.as_path().join("disk.img.rotating"), [0xAA; 0x1000]).unwrap();
+        req_exec.inner_mut().force_rotate().unwrap();
+        assert_eq!(archives(&dir).len(), 2);
+        assert_eq!(fs::metadata(&path).unwrap().len(), 0x1000);
+        let in_req = Request::new(
+            RequestType::In,
+            vec![DataDescriptor::new(GuestAddress(0x2000), 0x400)],
+            4,
+            GuestAddress(0x100),
+        );
+        assert_eq!(req_exec.execute(&mem, &in_req).unwrap(), 0x400);
+        mem.read_slice(&mut v, GuestAddress(0x2000)).unwrap();
+        assert_eq!(v, vec![0; 0x400]);
+
+        // A failed rotation leaves the current file in place.
+        fs::create_dir(dir.as_path().join("disk.img.rotating")).unwrap();
+        req_exec.inner_mut().force_rotate().unwrap_err();
+        assert_eq!(archives(&dir).len(), 2);
+        assert!(path.exists());
+        assert_eq!(req_exec.execute(&mem, &in_req).unwrap(), 0x400);
+    }
+
+    #[test]
+    fn test_next_time_of_day() {
+        let now = UNIX_EPOCH + Duration::from_secs(10 * SECS_PER_DAY + 3600);
+        let next = RotatingBackend::<File>::next_time_of_day(now, Duration::from_secs(7200));
+        assert_eq!(next, now + Duration::from_secs(3600));
+        let next = RotatingBackend::<File>::next_time_of_day(now, Duration::ZERO);
+        assert_eq!(next, now + Duration::from_secs(SECS_PER_DAY - 3600));
+    }
+
+    #[test]
+    fn test_backend_info() {
+        assert_eq!(BackendInfo::default().physical_block_size, 512);
+        assert!(!BackendInfo::default().supports_punch_hole);
+
+        let f = TempFile::new().unwrap().into_file();
+        assert_eq!(
+            f.info(),
+            BackendInfo {
+                supports_punch_hole: true,
+                supports_write_zeroes: true,
+                supports_fsync: true,
+                supports_vectored_io: true,
+                physical_block_size: (f.metadata().unwrap().blksize() as u32).max(512),
+                logical_block_size: 512,
+            }
+        );
+        assert_eq!(
+            VecBackend::new(vec![0; 0x200]).info(),
+            BackendInfo {
+                supports_write_zeroes: true,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    #[ignore = "probes the block devices of the host"]
+    fn test_block_device_info() {
+        // The block sizes of a block device match the ones reported by sysfs.
+        let block_devices = fs::read_dir("/sys/class/block").into_iter().flatten();
+        for entry in block_devices.flatten() {
+            // Partitions don't have their own queue attributes.
+            let queue = entry.path().join("queue");
+            if !queue.is_dir() {
+                continue;
+            }
+            let device = match File::open(Path::new("/dev").join(entry.file_name())) {
+                Ok(device) => device,
+                Err(_) => continue,
+            };
+            let queue_attr = |attr: &str| -> u32 {
+                fs::read_to_string(queue.join(attr))
+                    .unwrap()
+                    .trim()
+                    .parse()
+                    .unwrap()
+            };
+            let info = device.info();
+            assert_eq!(info.physical_block_size, queue_attr("physical_block_size"));
+            assert_eq!(info.logical_block_size, queue_attr("logical_block_size"));
+            break;
+        }
+    }
+
+    #[test]
+    fn test_vec_backend() {
+        let mut backend = VecBackend::new(vec![0xFF; 0x100]);
+        backend.punch_hole(0x80, 0x100).unwrap();
+        assert_eq!(backend.data().len(), 0x100);
+        assert_eq!(backend.data()[..0x80], [0xFF; 0x80]);
+        assert_eq!(backend.data()[0x80..], [0; 0x80]);
+
+        // Zeroes and writes past the end extend the data.
+        let mut backend = VecBackend::new(vec![0xFF; 0x100]);
+        assert_eq!(backend.write_zeroes_at(0xF0, 0x20).unwrap(), 0x20);
+        assert_eq!(backend.data().len(), 0x110);
+        assert_eq!(backend.data()[0xF0..], [0; 0x20]);
+        let mut data = [0xAB; 0x10];
+        backend.seek(SeekFrom::Start(0x118)).unwrap();
+        backend
+            .write_volatile(&VolatileSlice::from(&mut data[..]))
+            .unwrap();
+        assert_eq!(backend.data().len(), 0x128);
+        assert_eq!(backend.data()[0x110..0x118], [0; 8]);
+        assert_eq!(backend.into_inner()[0x118..], [0xAB; 0x10]);
+    }
+
+    // A layer that fails all the writes.
+    struct FailWrites;
+
+    impl<B: Backend> BackendLayer<B> for FailWrites {
+        fn write_volatile<S: BitmapSlice>(
+            &mut self,
+            _inner: &mut B,
+            _buf: &VolatileSlice<S>,
+        ) -> result::Result<usize, VolatileMemoryError> {
+            Err(VolatileMemoryError::IOError(io::Error::other(
+                "write failed",
+            )))
+        }
+    }
+
+    fn mirror_error(err: &io::Error) -> Option<&MirrorError> {
+        err.get_ref()?.downcast_ref::<MirrorError>()
+    }
+
+    #[test]
+    fn test_verifying_backend() {
+        let mem = GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        mem.write_slice(&[0x55; 0x400], GuestAddress(0x1000))
+            .unwrap();
+        let out_req = Request::new(
+            RequestType::Out,
+            vec![DataDescriptor::new(GuestAddress(0x1000), 0x400)],
+            2,
+            GuestAddress(0x100),
+        );
+        let in_req = Request::new(
+            RequestType::In,
+            vec![DataDescriptor::new(GuestAddress(0x2000), 0x400)],
+            2,
+            GuestAddress(0x100),
+        );
+
+        let backend = VerifyingBackend::new(
+            VecBackend::new(vec![0; 0x1000]),
+            VecBackend::new(vec![0; 0x1000]),
+        )
+        .with_verify_mode(VerifyMode::VerifyOnRead);
+        let mut req_exec = StdIoBackend::new(backend, 0).unwrap();
+        req_exec.execute(&mem, &out_req).unwrap();
+        assert_eq!(req_exec.execute(&mem, &in_req).unwrap(), 0x400);
+        let (primary, secondary) = req_exec.into_inner().into_parts();
+        assert_eq!(primary.data()[0x400..0x800], [0x55; 0x400]);
+        assert_eq!(primary.data(), secondary.data());
+
+        // Corrupt the fourth sector of the secondary backend.
+        let mut backend = VerifyingBackend::new(primary, secondary);
+        backend.secondary_mut().write_zeroes_at(0x600, 1).unwrap();
+        let mut buf = [0u8; 0x400];
+        backend.seek(SeekFrom::Start(0x400)).unwrap();
+        backend
+            .read_exact_volatile(&mut VolatileSlice::from(&mut buf[..]))
+            .unwrap();
+        assert_eq!(buf, [0x55; 0x400]);
+
+        let mut backend = backend.with_verify_mode(VerifyMode::VerifyOnRead);
+        backend.seek(SeekFrom::Start(0x400)).unwrap();
+        match backend.read_volatile(&mut VolatileSlice::from(&mut buf[..])) {
+            Err(VolatileMemoryError::IOError(err)) => assert_eq!(
+                mirror_error(&err),
+                Some(&MirrorError::MirrorDiverge { sector: 3 })
+            ),
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        // The writes fail if the secondary backend can't be written.
+        let backend = VerifyingBackend::new(
+            VecBackend::new(vec![0; 0x1000]),
+            DelegatingBackend::new(VecBackend::new(vec![0; 0x1000]), FailWrites),
+        );
+        let mut req_exec = StdIoBackend::new(backend, 0).unwrap();
+        match req_exec.execute(&mem, &out_req).unwrap_err() {
+            crate::stdio_executor::Error::Write(GuestMemoryError::IOError(err)) => assert_eq!(
+                mirror_error(&err),
+                Some(&MirrorError::MirrorMismatch { sector: 2 })
+            ),
+            err => panic!("unexpected error: {:?}", err),
+        }
+    }
+}
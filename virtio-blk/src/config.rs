@@ -0,0 +1,413 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Virtio block device configuration space.
+//!
+//! This module provides the following abstractions for constructing the configuration space of
+//! a block device:
+//!
+//! - [`VirtioBlkConfigBuilder`](struct.VirtioBlkConfigBuilder.html) which validates the
+//! configuration and computes the fields that derive from other parameters.
+//! - [`BlockTopology`](struct.BlockTopology.html) which describes the optimal I/O alignment and
+//! sizes of the device (the `VIRTIO_BLK_F_TOPOLOGY` fields).
+//! - [`ConfigSpaceAccess`](trait.ConfigSpaceAccess.html) which handles the byte-granular accesses
+//! of the driver to the configuration space.
+
+use std::fmt::{self, Display};
+use std::{mem, result, slice};
+
+use virtio_bindings::bindings::virtio_blk::virtio_blk_config;
+
+use crate::defs::SECTOR_SIZE;
+
+/// The configuration space of a block device, with all fields in little-endian byte order.
+pub type VirtioBlkConfig = virtio_blk_config;
+
+/// Configuration space construction errors.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The capacity of the device is 0.
+    InvalidCapacity,
+    /// The logical block size is not a power of two of at least `SECTOR_SIZE` bytes.
+    InvalidBlockSize(u32),
+    /// The queue size is not a power of two larger than 2.
+    InvalidQueueSize(u16),
+    /// The access is not within the configuration space.
+    InvalidAccess {
+        /// Offset of the access.
+        offset: u64,
+        /// Length of the access.
+        len: usize,
+    },
+    /// The driver can't write the configuration space byte at the given offset.
+    ReadOnly(u64),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            InvalidCapacity => write!(f, "the capacity of the device must not be 0"),
+            InvalidBlockSize(size) => write!(
+                f,
+                "invalid logical block size: {}, it should be a power of two of at least {}",
+                size, SECTOR_SIZE
+            ),
+            InvalidQueueSize(size) => write!(
+                f,
+                "invalid queue size: {}, it should be a power of two larger than 2",
+                size
+            ),
+            InvalidAccess { offset, len } => write!(
+                f,
+                "invalid configuration space access of {} bytes at offset {}",
+                len, offset
+            ),
+            ReadOnly(offset) => write!(
+                f,
+                "the configuration space byte at offset {} is read-only",
+                offset
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Dedicated [`Result`](https://doc.rust-lang.org/std/result/) type.
+pub type Result<T> = result::Result<T, Error>;
+
+/// The optimal I/O alignment and sizes of a block device.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BlockTopology {
+    /// The number of logical blocks per physical block, as a power of two.
+    pub physical_block_exp: u8,
+    /// The offset of the first aligned logical block.
+    pub alignment_offset: u8,
+    /// The suggested minimum I/O size, in logical blocks.
+    pub min_io_size: u16,
+    /// The optimal (and maximum) I/O size, in logical blocks.
+    pub opt_io_size: u32,
+}
+
+/// Builder for the configuration space of a block device.
+///
+/// # Example
+///
+/// ```rust
+/// # use virtio_blk::config::VirtioBlkConfigBuilder;
+/// let config = VirtioBlkConfigBuilder::new()
+///     .capacity(0x1000)
+///     .queue_size(256)
+///     .build()
+///     .unwrap();
+/// assert_eq!(u32::from_le(config.seg_max), 254);
+/// ```
+#[derive(Clone, Debug)]
+pub struct VirtioBlkConfigBuilder {
+    capacity: u64,
+    queue_size: u16,
+    logical_block_size: u32,
+    topology: BlockTopology,
+}
+
+impl Default for VirtioBlkConfigBuilder {
+    fn default() -> Self {
+        VirtioBlkConfigBuilder {
+            capacity: 0,
+            queue_size: 0,
+            logical_block_size: SECTOR_SIZE as u32,
+            topology: BlockTopology::default(),
+        }
+    }
+}
+
+impl VirtioBlkConfigBuilder {
+    /// Creates a new `VirtioBlkConfigBuilder`. The capacity and the queue size have to be set
+    /// before building the configuration space.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the capacity of the device.
+    ///
+    /// # Arguments
+    /// * `capacity` - The size of the device, in 512-byte sectors.
+    pub fn capacity(mut self, capacity: u64) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    /// Sets the size of the device queues, from which `seg_max` is computed.
+    ///
+    /// # Arguments
+    /// * `queue_size` - The maximum number of descriptors in a queue.
+    pub fn queue_size(mut self, queue_size: u16) -> Self {
+        self.queue_size = queue_size;
+        self
+    }
+
+    /// Sets the logical block size (`blk_size`), which is `SECTOR_SIZE` by default.
+    ///
+    /// # Arguments
+    /// * `logical_block_size` - The logical block size of the device, in bytes.
+    pub fn logical_block_size(mut self, logical_block_size: u32) -> Self {
+        self.logical_block_size = logical_block_size;
+        self
+    }
+
+    /// Sets the topology of the device.
+    ///
+    /// # Arguments
+    /// * `topology` - The optimal I/O alignment and sizes of the device.
+    pub fn topology(mut self, topology: BlockTopology) -> Self {
+        self.topology = topology;
+        self
+    }
+
+    /// Validates the configuration and builds the configuration space.
+    ///
+    /// `seg_max` is set to the queue size minus the descriptors of the request header and
+    /// status, so any request fits in a queue.
+    pub fn build(self) -> Result<VirtioBlkConfig> {
+        if self.capacity == 0 {
+            return Err(Error::InvalidCapacity);
+        }
+        if !self.queue_size.is_power_of_two() || self.queue_size <= 2 {
+            return Err(Error::InvalidQueueSize(self.queue_size));
+        }
+        if !self.logical_block_size.is_power_of_two()
+            || u64::from(self.logical_block_size) < SECTOR_SIZE
+        {
+            return Err(Error::InvalidBlockSize(self.logical_block_size));
+        }
+
+        Ok(VirtioBlkConfig {
+            capacity: self.capacity.to_le(),
+            seg_max: (u32::from(self.queue_size) - 2).to_le(),
+            blk_size: self.logical_block_size.to_le(),
+            physical_block_exp: self.topology.physical_block_exp,
+            alignment_offset: self.topology.alignment_offset,
+            min_io_size: self.topology.min_io_size.to_le(),
+            opt_io_size: self.topology.opt_io_size.to_le(),
+            ..Default::default()
+        })
+    }
+}
+
+/// Byte-granular accesses to a configuration space, e.g. for handling the MMIO or PCI
+/// accesses of the driver.
+pub trait ConfigSpaceAccess {
+    /// Reads the bytes of the configuration space at `offset` into `data`.
+    ///
+    /// # Arguments
+    /// * `offset` - The offset of the first byte to read.
+    /// * `data` - The buffer to fill.
+    fn read_config(&self, offset: u64, data: &mut [u8]) -> Result<()>;
+
+    /// Writes `data` to the configuration space at `offset`.
+    ///
+    /// Nothing is written if any of the bytes is read-only.
+    ///
+    /// # Arguments
+    /// * `offset` - The offset of the first byte to write.
+    /// * `data` - The bytes to write.
+    fn write_config(&mut self, offset: u64, data: &[u8]) -> Result<()>;
+}
+
+impl ConfigSpaceAccess for VirtioBlkConfig {
+    fn read_config(&self, offset: u64, data: &mut [u8]) -> Result<()> {
+        let start = config_range(offset, data.len())?;
+        // SAFETY: `VirtioBlkConfig` is a packed struct that contains only plain data, so all its
+        // bytes are initialized.
+        let bytes = unsafe {
+            slice::from_raw_parts(
+                self as *const VirtioBlkConfig as *const u8,
+                mem::size_of::<VirtioBlkConfig>(),
+            )
+        };
+        data.copy_from_slice(&bytes[start..start + data.len()]);
+        Ok(())
+    }
+
+    fn write_config(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+        // Only the writeback mode (`wce`) can be changed by the driver.
+        const WCE_OFFSET: usize = mem::offset_of!(VirtioBlkConfig, wce);
+
+        let start = config_range(offset, data.len())?;
+        if let Some(pos) = (start..start + data.len()).find(|pos| *pos != WCE_OFFSET) {
+            return Err(Error::ReadOnly(pos as u64));
+        }
+        if let Some(wce) = data.first() {
+            self.wce = *wce;
+        }
+        Ok(())
+    }
+}
+
+// Returns the offset of an access of `len` bytes at `offset` if it is within the configuration
+// space.
+fn config_range(offset: u64, len: usize) -> Result<usize> {
+    usize::try_from(offset)
+        .ok()
+        .filter(|start| {
+            start
+                .checked_add(len)
+                .is_some_and(|end| end <= mem::size_of::<VirtioBlkConfig>())
+        })
+        .ok_or(Error::InvalidAccess { offset, len })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build() {
+        let topology = BlockTopology {
+            physical_block_exp: 3,
+            alignment_offset: 1,
+            min_io_size: 8,
+            opt_io_size: 256,
+        };
+        let config = VirtioBlkConfigBuilder::new()
+            .capacity(0x10_0000)
+            .queue_size(256)
+            .logical_block_size(4096)
+            .topology(topology)
+            .build()
+            .unwrap();
+        assert_eq!(u64::from_le(config.capacity), 0x10_0000);
+        assert_eq!(u32::from_le(config.seg_max), 254);
+        assert_eq!(u32::from_le(config.blk_size), 4096);
+        assert_eq!(config.physical_block_exp, 3);
+        assert_eq!(config.alignment_offset, 1);
+        assert_eq!(u16::from_le(config.min_io_size), 8);
+        assert_eq!(u32::from_le(config.opt_io_size), 256);
+        assert_eq!(u32::from_le(config.size_max), 0);
+
+        let builder = VirtioBlkConfigBuilder::new().capacity(1).queue_size(4);
+        assert_eq!(u32::from_le(builder.clone().build().unwrap().seg_max), 2);
+        assert_eq!(
+            u32::from_le(builder.clone().build().unwrap().blk_size),
+            SECTOR_SIZE as u32
+        );
+        assert_eq!(
+            builder.clone().capacity(0).build().unwrap_err(),
+            Error::InvalidCapacity
+        );
+        assert_eq!(
+            VirtioBlkConfigBuilder::new().build().unwrap_err(),
+            Error::InvalidCapacity
+        );
+        for queue_size in [0, 2, 100] {
+            assert_eq!(
+                builder.clone().queue_size(queue_size).build().unwrap_err(),
+                Error::InvalidQueueSize(queue_size)
+            );
+        }
+        for block_size in [256, 1000] {
+            assert_eq!(
+                builder
+                    .clone()
+                    .logical_block_size(block_size)
+                    .build()
+                    .unwrap_err(),
+                Error::InvalidBlockSize(block_size)
+            );
+        }
+    }
+
+    #[test]
+    fn test_config_access() {
+        let mut config = VirtioBlkConfigBuilder::new()
+            .capacity(0x1122_3344_5566_7788)
+            .queue_size(128)
+            .logical_block_size(4096)
+            .topology(BlockTopology {
+                physical_block_exp: 3,
+                alignment_offset: 1,
+                min_io_size: 0x1234,
+                opt_io_size: 0x5678_9ABC,
+            })
+            .build()
+            .unwrap();
+
+        let read = |config: &VirtioBlkConfig, offset: usize, len: usize| {
+            let mut data = vec![0u8; len];
+            config.read_config(offset as u64, &mut data).unwrap();
+            data
+        };
+        assert_eq!(
+            read(&config, mem::offset_of!(VirtioBlkConfig, capacity), 8),
+            0x1122_3344_5566_7788u64.to_le_bytes()
+        );
+        assert_eq!(
+            read(&config, mem::offset_of!(VirtioBlkConfig, seg_max), 4),
+            126u32.to_le_bytes()
+        );
+        assert_eq!(
+            read(&config, mem::offset_of!(VirtioBlkConfig, blk_size), 4),
+            4096u32.to_le_bytes()
+        );
+        assert_eq!(
+            read(
+                &config,
+                mem::offset_of!(VirtioBlkConfig, physical_block_exp),
+                2
+            ),
+            [3, 1]
+        );
+        assert_eq!(
+            read(&config, mem::offset_of!(VirtioBlkConfig, min_io_size), 2),
+            0x1234u16.to_le_bytes()
+        );
+        assert_eq!(
+            read(&config, mem::offset_of!(VirtioBlkConfig, opt_io_size), 4),
+            0x5678_9ABCu32.to_le_bytes()
+        );
+        // Partial accesses return the bytes at their offset.
+        assert_eq!(
+            read(&config, mem::offset_of!(VirtioBlkConfig, capacity) + 2, 2),
+            [0x66, 0x55]
+        );
+        assert_eq!(read(&config, mem::offset_of!(VirtioBlkConfig, wce), 1), [0]);
+
+        // Only the writeback mode can be written.
+        let wce_offset = mem::offset_of!(VirtioBlkConfig, wce) as u64;
+        config.write_config(wce_offset, &[1]).unwrap();
+        assert_eq!(config.wce, 1);
+        assert_eq!(read(&config, wce_offset as usize, 1), [1]);
+        assert_eq!(
+            config.write_config(wce_offset, &[0, 0]).unwrap_err(),
+            Error::ReadOnly(wce_offset + 1)
+        );
+        assert_eq!(
+            config.write_config(0, &[0; 4]).unwrap_err(),
+            Error::ReadOnly(0)
+        );
+        assert_eq!(config.wce, 1);
+        assert_eq!(u64::from_le(config.capacity), 0x1122_3344_5566_7788);
+
+        // Accesses past the end of the configuration space fail.
+        let size = mem::size_of::<VirtioBlkConfig>() as u64;
+        let mut data = [0u8; 4];
+        config.read_config(size - 4, &mut data).unwrap();
+        assert_eq!(
+            config.read_config(size - 2, &mut data).unwrap_err(),
+            Error::InvalidAccess {
+                offset: size - 2,
+                len: 4
+            }
+        );
+        assert_eq!(
+            config.write_config(u64::MAX, &[0]).unwrap_err(),
+            Error::InvalidAccess {
+                offset: u64::MAX,
+                len: 1
+            }
+        );
+    }
+}
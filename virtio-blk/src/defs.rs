@@ -2,7 +2,89 @@
 //
 // SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
 
+use virtio_bindings::bindings::virtio_blk::{
+    VIRTIO_BLK_S_IOERR, VIRTIO_BLK_S_OK, VIRTIO_BLK_S_UNSUPP, VIRTIO_BLK_S_ZONE_ACTIVE_RESOURCE,
+    VIRTIO_BLK_S_ZONE_INVALID_CMD, VIRTIO_BLK_S_ZONE_OPEN_RESOURCE, VIRTIO_BLK_S_ZONE_UNALIGNED_WP,
+};
+
 /// Sector shift.
 pub const SECTOR_SHIFT: u8 = 9;
 /// Sector size of a block device.
 pub const SECTOR_SIZE: u64 = (0x01_u64) << SECTOR_SHIFT;
+
+/// Offset of the reserved field in the request header (`struct virtio_blk_outhdr`).
+pub const VIRTIO_BLK_REQ_HEADER_RESERVED_OFFSET: usize = 4;
+/// Size of the reserved field in the request header (`struct virtio_blk_outhdr`).
+pub const VIRTIO_BLK_REQ_HEADER_RESERVED_SIZE: usize = 4;
+
+/// Size of a discard or write zeroes segment (`struct virtio_blk_discard_write_zeroes`).
+pub const VIRTIO_BLK_DISCARD_SEGMENT_LEN: u64 = 16;
+
+/// Default maximum number of sectors in a discard segment (`max_discard_sectors`).
+pub const DEFAULT_MAX_DISCARD_SECTORS: u32 = 0xFFFF_FFFF;
+/// Default maximum number of sectors in a write zeroes segment (`max_write_zeroes_sectors`).
+pub const DEFAULT_MAX_WRITE_ZEROES_SECTORS: u32 = 0xFFFF_FFFF;
+
+/// Status of a request, written by the device in the last byte of the request.
+// The conversions from u32 to u8 are all safe because the status constants are <= 6.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum VirtioBlkReqStatus {
+    /// The request completed successfully (`VIRTIO_BLK_S_OK`).
+    Ok = VIRTIO_BLK_S_OK as u8,
+    /// The request failed (`VIRTIO_BLK_S_IOERR`).
+    IoError = VIRTIO_BLK_S_IOERR as u8,
+    /// The request is not supported by the device (`VIRTIO_BLK_S_UNSUPP`).
+    Unsupported = VIRTIO_BLK_S_UNSUPP as u8,
+    /// The zoned request is invalid, e.g. it writes to a conventional zone
+    /// (`VIRTIO_BLK_S_ZONE_INVALID_CMD`).
+    ZoneInvalidCmd = VIRTIO_BLK_S_ZONE_INVALID_CMD as u8,
+    /// The write doesn't start at the write pointer of a sequential write required zone
+    /// (`VIRTIO_BLK_S_ZONE_UNALIGNED_WP`).
+    ZoneUnalignedWp = VIRTIO_BLK_S_ZONE_UNALIGNED_WP as u8,
+    /// Too many zones would be open (`VIRTIO_BLK_S_ZONE_OPEN_RESOURCE`).
+    ZoneOpenResource = VIRTIO_BLK_S_ZONE_OPEN_RESOURCE as u8,
+    /// Too many zones would be active (`VIRTIO_BLK_S_ZONE_ACTIVE_RESOURCE`).
+    ZoneActiveResource = VIRTIO_BLK_S_ZONE_ACTIVE_RESOURCE as u8,
+}
+
+impl TryFrom<u8> for VirtioBlkReqStatus {
+    /// The invalid status value.
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, u8> {
+        let status = match u32::from(value) {
+            VIRTIO_BLK_S_OK => VirtioBlkReqStatus::Ok,
+            VIRTIO_BLK_S_IOERR => VirtioBlkReqStatus::IoError,
+            VIRTIO_BLK_S_UNSUPP => VirtioBlkReqStatus::Unsupported,
+            VIRTIO_BLK_S_ZONE_INVALID_CMD => VirtioBlkReqStatus::ZoneInvalidCmd,
+            VIRTIO_BLK_S_ZONE_UNALIGNED_WP => VirtioBlkReqStatus::ZoneUnalignedWp,
+            VIRTIO_BLK_S_ZONE_OPEN_RESOURCE => VirtioBlkReqStatus::ZoneOpenResource,
+            VIRTIO_BLK_S_ZONE_ACTIVE_RESOURCE => VirtioBlkReqStatus::ZoneActiveResource,
+            _ => return Err(value),
+        };
+        Ok(status)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_req_status() {
+        for value in 0..=u8::MAX {
+            match VirtioBlkReqStatus::try_from(value) {
+                Ok(status) => assert_eq!(status as u8, value),
+                Err(err) => {
+                    assert!(value > 6);
+                    assert_eq!(err, value);
+                }
+            }
+        }
+        assert_eq!(
+            VirtioBlkReqStatus::try_from(4),
+            Ok(VirtioBlkReqStatus::ZoneUnalignedWp)
+        );
+    }
+}
@@ -0,0 +1,764 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Virtio block device integration helpers.
+//!
+//! This module provides the following abstraction for integrating a virtio block device in a VMM:
+//!
+//! - [`BlockDeviceEventHandler`](struct.BlockDeviceEventHandler.html) which ties together the
+//! queue kick event, the virtqueue and the [`StdIoBackend`] executing the requests. VMMs based on
+//! `epoll` register [`BlockDeviceEventHandler::event_fd`](struct.BlockDeviceEventHandler.html#method.event_fd)
+//! and call [`BlockDeviceEventHandler::handle_event`](struct.BlockDeviceEventHandler.html#method.handle_event)
+//! when it becomes readable.
+//! - [`BlockDeviceSnapshot`](struct.BlockDeviceSnapshot.html) which holds the state of a
+//! [`StdIoBackend`] that needs to be saved on suspend and restored on resume. It can be
+//! serialized with `serde` when the `serde` feature is enabled. The handler saves and restores it
+//! with [`BlockDeviceEventHandler::pause`](struct.BlockDeviceEventHandler.html#method.pause) and
+//! [`BlockDeviceEventHandler::resume`](struct.BlockDeviceEventHandler.html#method.resume).
+//! - [`BlockDeviceMetrics`](trait.BlockDeviceMetrics.html) which is implemented by the metric
+//! backends of the VMM, e.g. [`StdoutMetrics`](struct.StdoutMetrics.html), so
+//! [`StdIoBackend`] can report the executed requests to them.
+
+use std::fmt::{self, Display};
+use std::io::{self, Write};
+use std::ops::Deref;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::result;
+
+use log::error;
+use virtio_queue::QueueT;
+use vm_memory::GuestMemory;
+use vmm_sys_util::eventfd::EventFd;
+
+use crate::request::{Request, RequestType};
+use crate::stdio_executor::{self, Backend, ProcessReqError, StdIoBackend};
+
+/// Errors encountered while handling the block device events.
+#[derive(Debug)]
+pub enum Error {
+    /// The device is paused, so the requests can't be processed.
+    DevicePaused,
+    /// Failed to read from the queue kick event.
+    EventFd(io::Error),
+    /// Failed to sync the backing object.
+    Flush(io::Error),
+    /// Failed to process the execution result of a request.
+    ProcessRequest(ProcessReqError),
+    /// Failed to access the virtqueue.
+    Queue(virtio_queue::Error),
+    /// Failed to restore the state of the device.
+    Restore(stdio_executor::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            DevicePaused => write!(f, "the device is paused"),
+            EventFd(ref err) => write!(f, "failed to read the queue kick event: {}", err),
+            Flush(ref err) => write!(f, "failed to sync the backing object: {}", err),
+            ProcessRequest(ref err) => write!(f, "failed to process block request: {}", err),
+            Queue(ref err) => write!(f, "failed to access the virtqueue: {}", err),
+            Restore(ref err) => write!(f, "failed to restore the device state: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Dedicated [`Result`](https://doc.rust-lang.org/std/result/) type.
+pub type Result<T> = result::Result<T, Error>;
+
+/// A metric backend that the requests executed by a [`StdIoBackend`] are reported to.
+///
+/// VMMs implement it for forwarding the metrics to their own metric systems (e.g. Prometheus,
+/// StatsD or InfluxDB), and set it with
+/// [`StdIoBackend::with_metrics`](../stdio_executor/struct.StdIoBackend.html#method.with_metrics).
+pub trait BlockDeviceMetrics: Send + fmt::Debug {
+    /// Records a successfully executed request.
+    ///
+    /// # Arguments
+    /// * `request_type` - The type of the request.
+    /// * `latency_ns` - The execution time of the request, in nanoseconds.
+    /// * `bytes` - The data length of the request.
+    fn record_io(&mut self, request_type: RequestType, latency_ns: u64, bytes: u64);
+
+    /// Records a request that failed.
+    ///
+    /// # Arguments
+    /// * `error_kind` - The kind of the I/O error that caused the failure, or
+    ///                  `io::ErrorKind::Other` if it wasn't caused by an I/O error.
+    fn record_error(&mut self, error_kind: io::ErrorKind);
+
+    /// Sends the recorded metrics to their destination, for metric backends that buffer them.
+    ///
+    /// [`BlockDeviceEventHandler::process_queue`] calls it after processing the available
+    /// requests.
+    fn flush_metrics(&mut self) {}
+}
+
+/// A metric backend that discards the metrics.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NullMetrics;
+
+impl BlockDeviceMetrics for NullMetrics {
+    fn record_io(&mut self, _request_type: RequestType, _latency_ns: u64, _bytes: u64) {}
+
+    fn record_error(&mut self, _error_kind: io::ErrorKind) {}
+}
+
+/// A metric backend that prints a line on the standard output for every request.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StdoutMetrics;
+
+impl BlockDeviceMetrics for StdoutMetrics {
+    fn record_io(&mut self, request_type: RequestType, latency_ns: u64, bytes: u64) {
+        println!(
+            "virtio_blk io type={:?} latency_ns={} bytes={}",
+            request_type, latency_ns, bytes
+        );
+    }
+
+    fn record_error(&mut self, error_kind: io::ErrorKind) {
+        println!("virtio_blk error kind={:?}", error_kind);
+    }
+
+    fn flush_metrics(&mut self) {
+        let _ = io::stdout().flush();
+    }
+}
+
+/// The state of a [`StdIoBackend`], which is saved when the guest is suspended.
+///
+/// The backing object is not part of the snapshot, so it has to be provided again by the VMM
+/// when the device is restored with
+/// [`StdIoBackend::restore_from_snapshot`](../stdio_executor/struct.StdIoBackend.html#method.restore_from_snapshot).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BlockDeviceSnapshot {
+    /// The number of sectors of the device.
+    pub num_sectors: u64,
+    /// The features that were negotiated between driver and device.
+    pub features: u64,
+    /// The logical block size reported to the driver.
+    pub logical_block_size: u32,
+}
+
+impl<B: Backend> From<&StdIoBackend<B>> for BlockDeviceSnapshot {
+    fn from(backend: &StdIoBackend<B>) -> Self {
+        BlockDeviceSnapshot {
+            num_sectors: backend.num_sectors(),
+            features: backend.features(),
+            logical_block_size: backend.logical_block_size(),
+        }
+    }
+}
+
+/// Handles the queue kick events of a virtio block device.
+///
+/// # Example
+///
+/// ```rust
+/// # use virtio_blk::device::BlockDeviceEventHandler;
+/// # use virtio_blk::stdio_executor::StdIoBackend;
+/// # use virtio_queue::{Queue, QueueT};
+/// # use vm_memory::{GuestAddress, GuestMemoryMmap};
+/// # use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
+/// # use vmm_sys_util::tempfile::TempFile;
+/// let mem = GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+/// let backend = StdIoBackend::new(TempFile::new().unwrap().into_file(), 0).unwrap();
+/// let kick_evt = EventFd::new(EFD_NONBLOCK).unwrap();
+/// let handler = BlockDeviceEventHandler::new(kick_evt, Queue::new(256).unwrap(), backend, &mem);
+/// // The VMM registers this file descriptor with `epoll_ctl` and calls
+/// // `handler.handle_event()` when it becomes readable.
+/// let fd = handler.event_fd();
+/// ```
+#[derive(Debug)]
+pub struct BlockDeviceEventHandler<B: Backend, Q: QueueT, M> {
+    /// The event signaled by the driver when new requests are available.
+    kick_evt: EventFd,
+    /// The virtqueue of the device.
+    queue: Q,
+    /// The executor of the requests.
+    backend: StdIoBackend<B>,
+    /// The guest memory.
+    mem: M,
+    /// Whether the device is paused, e.g. because the guest is suspended.
+    paused: bool,
+}
+
+impl<B, Q, M> BlockDeviceEventHandler<B, Q, M>
+where
+    B: Backend,
+    Q: QueueT,
+    M: Deref,
+    M::Target: GuestMemory + Sized,
+{
+    /// Creates a new `BlockDeviceEventHandler`.
+    ///
+    /// # Arguments
+    /// * `kick_evt` - The event signaled by the driver when new requests are available.
+    /// * `queue` - The virtqueue of the device.
+    /// * `backend` - The executor of the requests.
+    /// * `mem` - The guest memory.
+    pub fn new(kick_evt: EventFd, queue: Q, backend: StdIoBackend<B>, mem: M) -> Self {
+        BlockDeviceEventHandler {
+            kick_evt,
+            queue,
+            backend,
+            mem,
+            paused: false,
+        }
+    }
+
+    /// Returns the file descriptor of the queue kick event, which can be registered with
+    /// `epoll_ctl`.
+    pub fn event_fd(&self) -> RawFd {
+        self.kick_evt.as_raw_fd()
+    }
+
+    /// Consumes the queue kick event and processes the available requests. Returns the number of
+    /// processed requests.
+    pub fn handle_event(&mut self) -> Result<usize> {
+        self.kick_evt.read().map_err(Error::EventFd)?;
+        self.process_queue()
+    }
+
+    /// Executes all the available requests and adds them to the used ring. Returns the number of
+    /// processed requests.
+    ///
+    /// Requests that can not be parsed are added to the used ring without writing anything in
+    /// the guest memory. If processing a request fails, it is added to the used ring with a
+    /// length of 0 before the error is returned. Signaling the used queue to the driver is up to
+    /// the VMM, which can check whether it is needed with `QueueT::needs_notification`.
+    ///
+    /// Requests whose execution fails with a retryable error, e.g. because the maximum queue
+    /// depth was reached, are left in the available ring and the error is returned, so they are
+    /// executed again the next time the queue is processed.
+    pub fn process_queue(&mut self) -> Result<usize> {
+        if self.paused {
+            return Err(Error::DevicePaused);
+        }
+        let mem = self.mem.deref();
+        let mut count = 0;
+        while let Some(mut desc_chain) = self.queue.pop_descriptor_chain(mem) {
+            let head_index = desc_chain.head_index();
+            let result = match Request::parse(&mut desc_chain) {
+                Ok(request) => self
+                    .backend
+                    .process_request(mem, &request)
+                    .map_err(Error::ProcessRequest),
+                Err(e) => {
+                    error!("failed parsing block request: {}", e);
+                    Ok(0)
+                }
+            };
+            if let Err(e @ Error::ProcessRequest(ProcessReqError::Retry(_))) = result {
+                // The chain is made available again, like `QueueOwnedT::go_to_previous_position`
+                // does.
+                let next_avail = self.queue.next_avail();
+                self.queue.set_next_avail(next_avail.wrapping_sub(1));
+                return Err(e);
+            }
+            // The chain was already popped, so it is returned to the driver even if the request
+            // failed, instead of leaving the driver waiting for it.
+            self.queue
+                .add_used(mem, head_index, *result.as_ref().unwrap_or(&0))
+                .map_err(Error::Queue)?;
+            result?;
+            count += 1;
+        }
+        self.backend.flush_metrics();
+        Ok(count)
+    }
+
+    /// Pauses the device, e.g. when the guest is suspended, and returns its state.
+    ///
+    /// The available requests are processed and the backing object is synced first, so all the
+    /// requests submitted before pausing are completed and persisted. Processing requests fails
+    /// with `Error::DevicePaused` until the device is resumed.
+    pub fn pause(&mut self) -> Result<BlockDeviceSnapshot> {
+        self.process_queue()?;
+        self.backend.sync_backing().map_err(Error::Flush)?;
+        self.paused = true;
+        Ok(BlockDeviceSnapshot::from(&self.backend))
+    }
+
+    /// Restores the state saved in `snapshot` and resumes processing requests.
+    ///
+    /// # Arguments
+    /// * `snapshot` - The state returned by [`pause`](#method.pause), possibly by another handler.
+    pub fn resume(&mut self, snapshot: &BlockDeviceSnapshot) -> Result<()> {
+        self.backend
+            .restore_from_snapshot(snapshot)
+            .map_err(Error::Restore)?;
+        self.paused = false;
+        Ok(())
+    }
+
+    /// Returns whether the device is paused.
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Obtains an immutable reference to the virtqueue.
+    pub fn queue(&self) -> &Q {
+        &self.queue
+    }
+
+    /// Obtains a mutable reference to the virtqueue.
+    pub fn queue_mut(&mut self) -> &mut Q {
+        &mut self.queue
+    }
+
+    /// Obtains an immutable reference to the request executor.
+    pub fn backend(&self) -> &StdIoBackend<B> {
+        &self.backend
+    }
+
+    /// Obtains a mutable reference to the request executor.
+    pub fn backend_mut(&mut self) -> &mut StdIoBackend<B> {
+        &mut self.backend
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::cell::Cell;
+    use std::fs::File;
+    use std::sync::atomic::Ordering;
+
+    use virtio_bindings::bindings::virtio_blk::{
+        VIRTIO_BLK_F_FLUSH, VIRTIO_BLK_F_RO, VIRTIO_BLK_F_ZONED, VIRTIO_BLK_S_OK, VIRTIO_BLK_T_IN,
+    };
+    use virtio_bindings::bindings::virtio_ring::{VRING_DESC_F_NEXT, VRING_DESC_F_WRITE};
+    use virtio_queue::mock::MockSplitQueue;
+    use virtio_queue::{Descriptor, Queue};
+    use vm_memory::guest_memory::GuestMemoryIterator;
+    use vm_memory::{Bytes, GuestAddress, GuestMemoryMmap, GuestRegionMmap};
+    use vmm_sys_util::eventfd::EFD_NONBLOCK;
+    use vmm_sys_util::tempfile::TempFile;
+
+    use crate::journal;
+    use crate::request::DataDescriptor;
+    use crate::stdio_executor::{self, StdIoBackendBuilder};
+
+    // Guest memory that fails the accesses to `addr` once `allowed` of them were done.
+    struct FailingMemory {
+        mem: GuestMemoryMmap,
+        addr: GuestAddress,
+        allowed: Cell<usize>,
+    }
+
+    impl GuestMemory for FailingMemory {
+        type R = GuestRegionMmap;
+        type I = GuestMemoryMmap;
+
+        fn num_regions(&self) -> usize {
+            self.mem.num_regions()
+        }
+
+        fn find_region(&self, addr: GuestAddress) -> Option<&GuestRegionMmap> {
+            if addr == self.addr {
+                let allowed = self.allowed.get();
+                if allowed == 0 {
+                    return None;
+                }
+                self.allowed.set(allowed - 1);
+            }
+            self.mem.find_region(addr)
+        }
+
+        fn iter(&self) -> <GuestMemoryMmap as GuestMemoryIterator<'_, GuestRegionMmap>>::Iter {
+            self.mem.iter()
+        }
+    }
+
+    #[test]
+    fn test_handle_event() {
+        let mem = GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x100_0000)]).unwrap();
+        let vq = MockSplitQueue::new(&mem, 16);
+
+        let f = TempFile::new().unwrap().into_file();
+        f.set_len(0x1000).unwrap();
+        let backend = StdIoBackend::new(f, 0).unwrap();
+        let kick_evt = EventFd::new(EFD_NONBLOCK).unwrap();
+        let kick_fd = kick_evt.as_raw_fd();
+        // The driver side of the queue kick event.
+        let driver_evt = kick_evt.try_clone().unwrap();
+        let mut handler = BlockDeviceEventHandler::new(
+            kick_evt,
+            vq.create_queue::<Queue>().unwrap(),
+            backend,
+            &mem,
+        );
+        assert_eq!(handler.event_fd(), kick_fd);
+
+        // The event was not signaled.
+        assert!(matches!(
+            handler.handle_event().unwrap_err(),
+            Error::EventFd(e) if e.kind() == io::ErrorKind::WouldBlock
+        ));
+
+        // An `In` request of one sector, followed by a request with a write-only header.
+        let v = [
+            Descriptor::new(0x10_0000, 0x10, VRING_DESC_F_NEXT as u16, 1),
+            Descriptor::new(
+                0x20_0000,
+                0x200,
+                (VRING_DESC_F_WRITE | VRING_DESC_F_NEXT) as u16,
+                2,
+            ),
+            Descriptor::new(0x30_0000, 0x1, VRING_DESC_F_WRITE as u16, 0),
+            Descriptor::new(0x40_0000, 0x10, VRING_DESC_F_WRITE as u16, 0),
+        ];
+        mem.write_obj::<u32>(VIRTIO_BLK_T_IN, GuestAddress(0x10_0000))
+            .unwrap();
+        mem.write_obj::<u8>(0xFF, GuestAddress(0x30_0000)).unwrap();
+        vq.add_desc_chains(&v, 0).unwrap();
+
+        driver_evt.write(1).unwrap();
+        assert_eq!(handler.handle_event().unwrap(), 2);
+        assert_eq!(vq.used().idx().load(), 2);
+        assert_eq!(
+            mem.read_obj::<u8>(GuestAddress(0x30_0000)).unwrap(),
+            VIRTIO_BLK_S_OK as u8
+        );
+        let used_elem = vq.used().ring().ref_at(0).unwrap().load();
+        assert_eq!(used_elem.id(), 0);
+        assert_eq!(used_elem.len(), 0x201);
+        let used_elem = vq.used().ring().ref_at(1).unwrap().load();
+        assert_eq!(used_elem.id(), 3);
+        assert_eq!(used_elem.len(), 0);
+
+        // Nothing else is available.
+        driver_evt.write(1).unwrap();
+        assert_eq!(handler.handle_event().unwrap(), 0);
+        assert_eq!(handler.backend().stats().snapshot().reads, 1);
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let mem = GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x100_0000)]).unwrap();
+        let f = TempFile::new().unwrap().into_file();
+        f.set_len(0x2000).unwrap();
+        let mut backend = StdIoBackendBuilder::new()
+            .inner(f)
+            .features(1 << VIRTIO_BLK_F_FLUSH)
+            .logical_block_size(4096)
+            .build()
+            .unwrap();
+        assert_eq!(backend.shrink_sectors(8).unwrap(), 8);
+
+        let snapshot = BlockDeviceSnapshot::from(&backend);
+        assert_eq!(
+            snapshot,
+            BlockDeviceSnapshot {
+                num_sectors: 8,
+                features: 1 << VIRTIO_BLK_F_FLUSH,
+                logical_block_size: 4096,
+            }
+        );
+        #[cfg(feature = "serde")]
+        let snapshot = {
+            let serialized = serde_json::to_string(&snapshot).unwrap();
+            serde_json::from_str::<BlockDeviceSnapshot>(&serialized).unwrap()
+        };
+
+        // Restore the state on a backend created from the same file.
+        let mut restored = StdIoBackend::new(backend.into_inner(), 0).unwrap();
+        restored.restore_from_snapshot(&snapshot).unwrap();
+        assert_eq!(BlockDeviceSnapshot::from(&restored), snapshot);
+
+        let flush_req = Request::new(RequestType::Flush, vec![], 0, GuestAddress(0x100));
+        restored.execute(&mem, &flush_req).unwrap();
+        // The removed sectors are not visible after restoring.
+        let in_req = Request::new(
+            RequestType::In,
+            vec![DataDescriptor::new(GuestAddress(0x1000), 0x200)],
+            8,
+            GuestAddress(0x100),
+        );
+        assert!(restored.execute(&mem, &in_req).is_err());
+
+        // The device can not grow past its backing object.
+        let invalid = BlockDeviceSnapshot {
+            num_sectors: 17,
+            ..snapshot
+        };
+        assert!(restored.restore_from_snapshot(&invalid).is_err());
+        let invalid = BlockDeviceSnapshot {
+            logical_block_size: 1000,
+            ..snapshot
+        };
+        assert!(restored.restore_from_snapshot(&invalid).is_err());
+        // Conflicting features are rejected, and leave the state unchanged.
+        let invalid = BlockDeviceSnapshot {
+            features: (1 << VIRTIO_BLK_F_ZONED) | (1 << VIRTIO_BLK_F_RO),
+            ..snapshot
+        };
+        assert!(matches!(
+            restored.restore_from_snapshot(&invalid),
+            Err(stdio_executor::Error::Features(_))
+        ));
+        assert_eq!(BlockDeviceSnapshot::from(&restored), snapshot);
+    }
+
+    #[test]
+    fn test_pause_resume() {
+        let mem = GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x100_0000)]).unwrap();
+        let vq = MockSplitQueue::new(&mem, 16);
+        let f = TempFile::new().unwrap().into_file();
+        f.set_len(0x1000).unwrap();
+        let journal = TempFile::new().unwrap();
+        let mut backend = StdIoBackend::new(f, 1 << VIRTIO_BLK_F_FLUSH)
+            .unwrap()
+            .with_journal(journal.as_file().try_clone().unwrap());
+        // The journal entry of the write is committed once the backing object is synced.
+        backend.write_at(1, &[0xAB; 0x200]).unwrap();
+        let uncommitted_entries =
+            || journal::uncommitted_entries(File::open(journal.as_path()).unwrap()).unwrap();
+        assert_eq!(uncommitted_entries().len(), 1);
+        let mut handler = BlockDeviceEventHandler::new(
+            EventFd::new(EFD_NONBLOCK).unwrap(),
+            vq.create_queue::<Queue>().unwrap(),
+            backend,
+            &mem,
+        );
+
+        // An `In` request of one sector.
+        let v = [
+            Descriptor::new(0x10_0000, 0x10, VRING_DESC_F_NEXT as u16, 1),
+            Descriptor::new(
+                0x20_0000,
+                0x200,
+                (VRING_DESC_F_WRITE | VRING_DESC_F_NEXT) as u16,
+                2,
+            ),
+            Descriptor::new(0x30_0000, 0x1, VRING_DESC_F_WRITE as u16, 0),
+        ];
+        mem.write_obj::<u32>(VIRTIO_BLK_T_IN, GuestAddress(0x10_0000))
+            .unwrap();
+        vq.add_desc_chains(&v, 0).unwrap();
+
+        // The available requests are completed and the writes are synced before pausing.
+        let snapshot = handler.pause().unwrap();
+        assert!(handler.is_paused());
+        assert_eq!(vq.used().idx().load(), 1);
+        assert!(uncommitted_entries().is_empty());
+        assert_eq!(snapshot, BlockDeviceSnapshot::from(handler.backend()));
+
+        vq.add_desc_chains(&v, 1).unwrap();
+        assert!(matches!(
+            handler.process_queue().unwrap_err(),
+            Error::DevicePaused
+        ));
+        assert_eq!(vq.used().idx().load(), 1);
+
+        let invalid_snapshot = BlockDeviceSnapshot {
+            num_sectors: 9,
+            ..snapshot
+        };
+        assert!(matches!(
+            handler.resume(&invalid_snapshot).unwrap_err(),
+            Error::Restore(_)
+        ));
+        assert!(handler.is_paused());
+
+        handler.resume(&snapshot).unwrap();
+        assert!(!handler.is_paused());
+        assert_eq!(handler.process_queue().unwrap(), 1);
+        assert_eq!(vq.used().idx().load(), 2);
+    }
+
+    #[test]
+    fn test_process_queue_error() {
+        let mem = FailingMemory {
+            mem: GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x100_0000)]).unwrap(),
+            addr: GuestAddress(0x30_0000),
+            // Only the check of the status address by `Request::parse` succeeds, so writing the
+            // status fails.
+            allowed: Cell::new(1),
+        };
+        let vq = MockSplitQueue::new(&mem.mem, 16);
+        let f = TempFile::new().unwrap().into_file();
+        f.set_len(0x1000).unwrap();
+        let backend = StdIoBackend::new(f, 0).unwrap();
+        let mut handler = BlockDeviceEventHandler::new(
+            EventFd::new(EFD_NONBLOCK).unwrap(),
+            vq.create_queue::<Queue>().unwrap(),
+            backend,
+            &mem,
+        );
+
+        // An `In` request of one sector.
+        let v = [
+            Descriptor::new(0x10_0000, 0x10, VRING_DESC_F_NEXT as u16, 1),
+            Descriptor::new(
+                0x20_0000,
+                0x200,
+                (VRING_DESC_F_WRITE | VRING_DESC_F_NEXT) as u16,
+                2,
+            ),
+            Descriptor::new(0x30_0000, 0x1, VRING_DESC_F_WRITE as u16, 0),
+        ];
+        mem.mem
+            .write_obj::<u32>(VIRTIO_BLK_T_IN, GuestAddress(0x10_0000))
+            .unwrap();
+        vq.add_desc_chains(&v, 0).unwrap();
+
+        assert!(matches!(
+            handler.process_queue().unwrap_err(),
+            Error::ProcessRequest(ProcessReqError::GuestMemory(_))
+        ));
+        // The request is returned to the driver anyway, with nothing written.
+        assert_eq!(handler.queue().next_used(), 1);
+        assert_eq!(vq.used().idx().load(), 1);
+        let used_elem = vq.used().ring().ref_at(0).unwrap().load();
+        assert_eq!(used_elem.id(), 0);
+        assert_eq!(used_elem.len(), 0);
+    }
+
+    #[test]
+    fn test_process_queue_retry() {
+        let mem = GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x100_0000)]).unwrap();
+        let vq = MockSplitQueue::new(&mem, 16);
+        let f = TempFile::new().unwrap().into_file();
+        f.set_len(0x1000).unwrap();
+        let backend = StdIoBackend::new(f, 0).unwrap().with_max_queue_depth(1);
+        let mut handler = BlockDeviceEventHandler::new(
+            EventFd::new(EFD_NONBLOCK).unwrap(),
+            vq.create_queue::<Queue>().unwrap(),
+            backend,
+            &mem,
+        );
+
+        // An `In` request of one sector.
+        let v = [
+            Descriptor::new(0x10_0000, 0x10, VRING_DESC_F_NEXT as u16, 1),
+            Descriptor::new(
+                0x20_0000,
+                0x200,
+                (VRING_DESC_F_WRITE | VRING_DESC_F_NEXT) as u16,
+                2,
+            ),
+            Descriptor::new(0x30_0000, 0x1, VRING_DESC_F_WRITE as u16, 0),
+        ];
+        mem.write_obj::<u32>(VIRTIO_BLK_T_IN, GuestAddress(0x10_0000))
+            .unwrap();
+        mem.write_obj::<u8>(0xFF, GuestAddress(0x30_0000)).unwrap();
+        vq.add_desc_chains(&v, 0).unwrap();
+
+        // Another executor of the device saturates the queue depth.
+        handler.backend().current_depth.store(1, Ordering::SeqCst);
+        assert!(matches!(
+            handler.process_queue().unwrap_err(),
+            Error::ProcessRequest(ProcessReqError::Retry(stdio_executor::Error::QueueFull {
+                depth: 1,
+                max: 1
+            }))
+        ));
+        // The request is neither completed nor consumed.
+        assert_eq!(vq.used().idx().load(), 0);
+        assert_eq!(handler.queue().next_avail(), 0);
+        assert_eq!(mem.read_obj::<u8>(GuestAddress(0x30_0000)).unwrap(), 0xFF);
+
+        handler.backend().current_depth.store(0, Ordering::SeqCst);
+        assert_eq!(handler.process_queue().unwrap(), 1);
+        assert_eq!(vq.used().idx().load(), 1);
+        assert_eq!(
+            mem.read_obj::<u8>(GuestAddress(0x30_0000)).unwrap(),
+            VIRTIO_BLK_S_OK as u8
+        );
+    }
+
+    #[test]
+    fn test_metrics() {
+        use std::sync::{Arc, Mutex};
+
+        // The records are shared with the test, since the executor owns the metric backend.
+        #[derive(Debug, Default)]
+        struct CollectingMetrics {
+            io: Arc<Mutex<Vec<(RequestType, u64)>>>,
+            errors: Arc<Mutex<Vec<io::ErrorKind>>>,
+            flushes: Arc<Mutex<usize>>,
+        }
+
+        impl BlockDeviceMetrics for CollectingMetrics {
+            fn record_io(&mut self, request_type: RequestType, _latency_ns: u64, bytes: u64) {
+                self.io.lock().unwrap().push((request_type, bytes));
+            }
+
+            fn record_error(&mut self, error_kind: io::ErrorKind) {
+                self.errors.lock().unwrap().push(error_kind);
+            }
+
+            fn flush_metrics(&mut self) {
+                *self.flushes.lock().unwrap() += 1;
+            }
+        }
+
+        let metrics = CollectingMetrics::default();
+        let (io_records, errors, flushes) = (
+            metrics.io.clone(),
+            metrics.errors.clone(),
+            metrics.flushes.clone(),
+        );
+        let mem = GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let f = TempFile::new().unwrap().into_file();
+        f.set_len(0x1000).unwrap();
+        let mut backend = StdIoBackend::new(f, 0)
+            .unwrap()
+            .with_metrics(Some(Box::new(metrics)));
+
+        let in_req = Request::new(
+            RequestType::In,
+            vec![DataDescriptor::new(GuestAddress(0x1000), 0x200)],
+            0,
+            GuestAddress(0x100),
+        );
+        let out_req = Request::new(
+            RequestType::Out,
+            vec![DataDescriptor::new(GuestAddress(0x1000), 0x400)],
+            1,
+            GuestAddress(0x100),
+        );
+        let invalid_req = Request::new(
+            RequestType::In,
+            vec![DataDescriptor::new(GuestAddress(0x1000), 0x200)],
+            8,
+            GuestAddress(0x100),
+        );
+        backend.execute(&mem, &in_req).unwrap();
+        backend.execute(&mem, &out_req).unwrap();
+        // The clones report to the same metric backend.
+        let mut clone = backend.clone_for_queue(1).unwrap();
+        clone.execute(&mem, &in_req).unwrap();
+        backend.execute(&mem, &invalid_req).unwrap_err();
+        backend.flush_metrics();
+
+        assert_eq!(
+            *io_records.lock().unwrap(),
+            vec![
+                (RequestType::In, 0x200),
+                (RequestType::Out, 0x400),
+                (RequestType::In, 0x200)
+            ]
+        );
+        assert_eq!(*errors.lock().unwrap(), vec![io::ErrorKind::Other]);
+        assert_eq!(*flushes.lock().unwrap(), 1);
+
+        // The built-in metric backends accept the records as well.
+        let mut backend = backend.with_metrics(Some(Box::new(NullMetrics)));
+        backend.execute(&mem, &in_req).unwrap();
+        let mut backend = backend.with_metrics(Some(Box::new(StdoutMetrics)));
+        backend.execute(&mem, &in_req).unwrap();
+        backend.execute(&mem, &invalid_req).unwrap_err();
+        backend.flush_metrics();
+        assert_eq!(io_records.lock().unwrap().len(), 3);
+    }
+}
@@ -0,0 +1,406 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Write-ahead journal of the write requests.
+//!
+//! This module provides the following abstractions for logging the data of the write requests
+//! before it reaches the backing object, so the writes that were interrupted by a crash can be
+//! completed on recovery:
+//!
+//! - [`JournalRecord`](enum.JournalRecord.html) which is a record of the journal, either a
+//! [`JournalEntry`](struct.JournalEntry.html) with the [`JournalData`](enum.JournalData.html) of
+//! a write, the commit of an entry once the write completed, or a reset that commits all the
+//! previous entries.
+//! - [`JournalWriter`](trait.JournalWriter.html) which is implemented by the objects the journal
+//! can be written to.
+//! - [`uncommitted_entries`](fn.uncommitted_entries.html) which returns the writes that have to
+//! be replayed on recovery.
+//!
+//! Every record starts with a tag byte (`1` for entries with data, `2` for commits, `3` for
+//! entries that zero sectors out and `4` for resets) followed by the little endian LSN. Entries
+//! with data then contain the little endian first sector and data length, and the data itself.
+//! Entries that zero sectors out contain the little endian first sector and number of sectors.
+
+use std::collections::BTreeMap;
+#[cfg(feature = "backend-stdio")]
+use std::fmt;
+use std::io::{self, Read, Write};
+
+use vmm_sys_util::file_traits::FileSync;
+
+const ENTRY_TAG: u8 = 1;
+const COMMIT_TAG: u8 = 2;
+const ZEROES_TAG: u8 = 3;
+const RESET_TAG: u8 = 4;
+
+/// What a logged write stores to the sectors.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum JournalData {
+    /// The written data.
+    Write(Vec<u8>),
+    /// The given number of sectors are zeroed out, e.g. by a write zeroes or discard request.
+    Zeroes(u64),
+}
+
+/// The data of a write, logged before the write is performed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct JournalEntry {
+    /// The log sequence number of the entry.
+    pub lsn: u64,
+    /// The first sector of the write.
+    pub sector: u64,
+    /// What the write stores to the sectors.
+    pub data: JournalData,
+}
+
+/// A record of the journal.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum JournalRecord {
+    /// A write that is about to be performed.
+    Entry(JournalEntry),
+    /// The write logged by the entry with the given LSN completed.
+    Commit {
+        /// The log sequence number of the completed entry.
+        lsn: u64,
+    },
+    /// All the entries logged before the record are committed, e.g. because the whole device was
+    /// overwritten after them.
+    Reset {
+        /// The log sequence number of the reset.
+        lsn: u64,
+    },
+}
+
+impl JournalRecord {
+    /// Writes the record to `writer`.
+    ///
+    /// # Arguments
+    /// * `writer` - The journal to append the record to.
+    pub fn write_to<W: Write + ?Sized>(&self, writer: &mut W) -> io::Result<()> {
+        match self {
+            JournalRecord::Entry(JournalEntry {
+                lsn,
+                sector,
+                data: JournalData::Write(data),
+            }) => {
+                let len = u32::try_from(data.len())
+                    .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+                let mut buf = Vec::with_capacity(21 + data.len());
+                buf.push(ENTRY_TAG);
+                buf.extend_from_slice(&lsn.to_le_bytes());
+                buf.extend_from_slice(&sector.to_le_bytes());
+                buf.extend_from_slice(&len.to_le_bytes());
+                buf.extend_from_slice(data);
+                writer.write_all(&buf)
+            }
+            JournalRecord::Entry(JournalEntry {
+                lsn,
+                sector,
+                data: JournalData::Zeroes(num_sectors),
+            }) => {
+                let mut buf = [0u8; 25];
+                buf[0] = ZEROES_TAG;
+                buf[1..9].copy_from_slice(&lsn.to_le_bytes());
+                buf[9..17].copy_from_slice(&sector.to_le_bytes());
+                buf[17..].copy_from_slice(&num_sectors.to_le_bytes());
+                writer.write_all(&buf)
+            }
+            JournalRecord::Commit { lsn } => {
+                let mut buf = [0u8; 9];
+                buf[0] = COMMIT_TAG;
+                buf[1..].copy_from_slice(&lsn.to_le_bytes());
+                writer.write_all(&buf)
+            }
+            JournalRecord::Reset { lsn } => {
+                let mut buf = [0u8; 9];
+                buf[0] = RESET_TAG;
+                buf[1..].copy_from_slice(&lsn.to_le_bytes());
+                writer.write_all(&buf)
+            }
+        }
+    }
+
+    /// Reads the next record from `reader`.
+    ///
+    /// Returns `None` at the end of the journal, including when the last record is incomplete,
+    /// which happens when a crash interrupted its write.
+    ///
+    /// # Arguments
+    /// * `reader` - The journal to read the record from.
+    pub fn read_from<R: Read + ?Sized>(reader: &mut R) -> io::Result<Option<Self>> {
+        match Self::read_record(reader) {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+            result => result,
+        }
+    }
+
+    fn read_record<R: Read + ?Sized>(reader: &mut R) -> io::Result<Option<Self>> {
+        let mut tag = [0u8; 1];
+        if reader.read(&mut tag)? == 0 {
+            return Ok(None);
+        }
+        let lsn = read_u64(reader)?;
+        match tag[0] {
+            ENTRY_TAG => {
+                let sector = read_u64(reader)?;
+                let mut len = [0u8; 4];
+                reader.read_exact(&mut len)?;
+                let mut data = vec![0u8; u32::from_le_bytes(len) as usize];
+                reader.read_exact(&mut data)?;
+                Ok(Some(JournalRecord::Entry(JournalEntry {
+                    lsn,
+                    sector,
+                    data: JournalData::Write(data),
+                })))
+            }
+            COMMIT_TAG => Ok(Some(JournalRecord::Commit { lsn })),
+            RESET_TAG => Ok(Some(JournalRecord::Reset { lsn })),
+            ZEROES_TAG => {
+                let sector = read_u64(reader)?;
+                let num_sectors = read_u64(reader)?;
+                Ok(Some(JournalRecord::Entry(JournalEntry {
+                    lsn,
+                    sector,
+                    data: JournalData::Zeroes(num_sectors),
+                })))
+            }
+            tag => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid journal record tag {}", tag),
+            )),
+        }
+    }
+}
+
+fn read_u64<R: Read + ?Sized>(reader: &mut R) -> io::Result<u64> {
+    let mut buf = [0u8; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+/// An object the journal can be written to and synced.
+pub trait JournalWriter: Write + FileSync + Send {}
+
+impl<W: Write + FileSync + Send> JournalWriter for W {}
+
+/// Returns the entries of `journal` that were not committed, in the order in which they were
+/// logged.
+///
+/// These are the writes that may have been interrupted, so they have to be performed again on
+/// recovery, in the returned order: a later entry may overwrite the sectors of an earlier one.
+/// An incomplete last record is ignored, since its write was never started.
+///
+/// # Arguments
+/// * `journal` - The journal to read.
+pub fn uncommitted_entries<R: Read>(journal: R) -> io::Result<Vec<JournalEntry>> {
+    read_journal(journal).map(|(entries, _)| entries)
+}
+
+// Returns the entries of `journal` that were not committed, and the highest LSN of its records,
+// if any.
+pub(crate) fn read_journal<R: Read>(
+    mut journal: R,
+) -> io::Result<(Vec<JournalEntry>, Option<u64>)> {
+    let mut entries = BTreeMap::new();
+    let mut last_lsn = None;
+    while let Some(record) = JournalRecord::read_from(&mut journal)? {
+        let lsn = match record {
+            JournalRecord::Entry(entry) => {
+                let lsn = entry.lsn;
+                entries.insert(lsn, entry);
+                lsn
+            }
+            JournalRecord::Commit { lsn } => {
+                entries.remove(&lsn);
+                lsn
+            }
+            JournalRecord::Reset { lsn } => {
+                entries.clear();
+                lsn
+            }
+        };
+        last_lsn = last_lsn.max(Some(lsn));
+    }
+    Ok((entries.into_values().collect(), last_lsn))
+}
+
+// The journal of a `StdIoBackend`.
+#[cfg(feature = "backend-stdio")]
+pub(crate) struct Journal {
+    writer: Box<dyn JournalWriter>,
+    next_lsn: u64,
+    // The LSNs of the writes that completed, but may not have reached the backing object yet.
+    completed: Vec<u64>,
+}
+
+#[cfg(feature = "backend-stdio")]
+impl Journal {
+    pub(crate) fn new(writer: Box<dyn JournalWriter>) -> Self {
+        Journal {
+            writer,
+            next_lsn: 0,
+            completed: Vec::new(),
+        }
+    }
+
+    // Makes the following entries start after `lsn`, e.g. the last one of a replayed journal
+    // that is appended to.
+    pub(crate) fn continue_after(&mut self, lsn: u64) {
+        self.next_lsn = self.next_lsn.max(lsn + 1);
+    }
+
+    // Logs the data of a write and syncs the journal, returning the LSN of the entry.
+    pub(crate) fn log_write(&mut self, sector: u64, data: JournalData) -> io::Result<u64> {
+        let lsn = self.next_lsn;
+        JournalRecord::Entry(JournalEntry { lsn, sector, data }).write_to(&mut self.writer)?;
+        self.writer.flush()?;
+        self.writer.fsync()?;
+        self.next_lsn += 1;
+        Ok(lsn)
+    }
+
+    // Records that the write with the given LSN completed. It is committed by `log_commits`
+    // once the backing object is synced.
+    pub(crate) fn complete(&mut self, lsn: u64) {
+        self.completed.push(lsn);
+    }
+
+    // Returns the LSNs of the completed writes that were not committed yet.
+    pub(crate) fn take_completed(&mut self) -> Vec<u64> {
+        std::mem::take(&mut self.completed)
+    }
+
+    // Logs a reset, so that none of the previous entries is replayed, and syncs the journal. The
+    // writes of the previous entries must not be replayed anymore, e.g. because the whole backing
+    // object was overwritten and synced after them.
+    pub(crate) fn reset(&mut self) -> io::Result<()> {
+        JournalRecord::Reset { lsn: self.next_lsn }.write_to(&mut self.writer)?;
+        self.writer.flush()?;
+        self.writer.fsync()?;
+        self.next_lsn += 1;
+        self.completed.clear();
+        Ok(())
+    }
+
+    // Logs the commit of the writes with the given LSNs, which must have been synced to the
+    // backing object. If this fails, the writes are replayed on recovery, which is harmless.
+    pub(crate) fn log_commits(&mut self, lsns: &[u64]) -> io::Result<()> {
+        for &lsn in lsns {
+            JournalRecord::Commit { lsn }.write_to(&mut self.writer)?;
+        }
+        self.writer.flush()
+    }
+}
+
+#[cfg(feature = "backend-stdio")]
+impl fmt::Debug for Journal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Journal")
+            .field("next_lsn", &self.next_lsn)
+            .field("completed", &self.completed)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_records() {
+        let entry = JournalEntry {
+            lsn: 3,
+            sector: 8,
+            data: JournalData::Write(vec![0xAB; 0x200]),
+        };
+        let mut journal = Vec::new();
+        JournalRecord::Entry(entry.clone())
+            .write_to(&mut journal)
+            .unwrap();
+        JournalRecord::Commit { lsn: 3 }
+            .write_to(&mut journal)
+            .unwrap();
+        assert_eq!(journal.len(), 21 + 0x200 + 9);
+
+        let mut reader = journal.as_slice();
+        assert_eq!(
+            JournalRecord::read_from(&mut reader).unwrap(),
+            Some(JournalRecord::Entry(entry.clone()))
+        );
+        assert_eq!(
+            JournalRecord::read_from(&mut reader).unwrap(),
+            Some(JournalRecord::Commit { lsn: 3 })
+        );
+        assert_eq!(JournalRecord::read_from(&mut reader).unwrap(), None);
+
+        // An incomplete record is ignored.
+        let mut reader = &journal[..0x100];
+        assert_eq!(JournalRecord::read_from(&mut reader).unwrap(), None);
+
+        // Only the entries without a commit are returned.
+        let second = JournalEntry { lsn: 4, ..entry };
+        JournalRecord::Entry(second.clone())
+            .write_to(&mut journal)
+            .unwrap();
+        assert_eq!(
+            uncommitted_entries(journal.as_slice()).unwrap(),
+            vec![second.clone()]
+        );
+        // The highest LSN is returned even if its entry was committed.
+        JournalRecord::Commit { lsn: 5 }
+            .write_to(&mut journal)
+            .unwrap();
+        assert_eq!(
+            read_journal(journal.as_slice()).unwrap(),
+            (vec![second.clone()], Some(5))
+        );
+        assert_eq!(read_journal([].as_slice()).unwrap(), (vec![], None));
+
+        // Entries that zero sectors out are returned in order with the other ones.
+        let zeroes = JournalEntry {
+            lsn: 6,
+            sector: 8,
+            data: JournalData::Zeroes(16),
+        };
+        let mut zeroes_record = Vec::new();
+        JournalRecord::Entry(zeroes.clone())
+            .write_to(&mut zeroes_record)
+            .unwrap();
+        assert_eq!(zeroes_record.len(), 25);
+        journal.extend_from_slice(&zeroes_record);
+        assert_eq!(
+            read_journal(journal.as_slice()).unwrap(),
+            (vec![second, zeroes.clone()], Some(6))
+        );
+        assert_eq!(
+            JournalRecord::read_from(&mut &zeroes_record[..]).unwrap(),
+            Some(JournalRecord::Entry(zeroes))
+        );
+        assert_eq!(
+            JournalRecord::read_from(&mut &zeroes_record[..24]).unwrap(),
+            None
+        );
+
+        // A reset drops the entries logged before it, but not the following ones.
+        JournalRecord::Reset { lsn: 7 }
+            .write_to(&mut journal)
+            .unwrap();
+        assert_eq!(read_journal(journal.as_slice()).unwrap(), (vec![], Some(7)));
+        let third = JournalEntry {
+            lsn: 8,
+            sector: 0,
+            data: JournalData::Zeroes(1),
+        };
+        JournalRecord::Entry(third.clone())
+            .write_to(&mut journal)
+            .unwrap();
+        assert_eq!(
+            read_journal(journal.as_slice()).unwrap(),
+            (vec![third], Some(8))
+        );
+
+        let err = uncommitted_entries([7u8; 9].as_slice()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -7,12 +7,47 @@
 
 #![deny(missing_docs)]
 
+/// Contains asynchronous request execution based on the tokio runtime.
+#[cfg(feature = "tokio")]
+pub mod async_executor;
+
+/// Contains composable block device backends.
+#[cfg(feature = "backend-stdio")]
+pub mod backend;
+
+/// Contains the virtio block device configuration space.
+pub mod config;
+
 /// Contains virtio block constant definitions.
 pub mod defs;
 
+/// Contains helpers for integrating a block device in a VMM.
+#[cfg(feature = "backend-stdio")]
+pub mod device;
+
+/// Contains the write-ahead journal of the write requests.
+pub mod journal;
+
+/// Contains the locking of sector ranges for requests executed concurrently.
+pub mod locking;
+
+// Contains a seeded pseudo-random number generator.
+#[cfg(any(test, feature = "backend-stdio", feature = "test-utils"))]
+mod rand;
+
 /// Contains block request parsing abstraction.
 pub mod request;
 
+/// Contains block request statistics.
+pub mod stats;
+
+/// Contains zoned block device definitions.
+pub mod zoned;
+
+/// Contains utilities for testing block device backends.
+#[cfg(any(test, feature = "test-utils"))]
+pub mod testing;
+
 /// Contains a block request execution abstraction that is based on
 /// [`std::io::Read`](https://doc.rust-lang.org/std/io/trait.Read.html)
 /// and [`std::io::Write`](https://doc.rust-lang.org/std/io/trait.Write.html).
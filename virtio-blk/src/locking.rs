@@ -0,0 +1,216 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Sector range locking.
+//!
+//! This module provides the following abstractions for serializing the requests that access
+//! overlapping sectors, when they are executed concurrently (e.g. by the threads servicing
+//! different virtqueues of the same device):
+//!
+//! - [`SectorRangeLock`](struct.SectorRangeLock.html) which keeps track of the locked ranges of
+//! sectors.
+//! - [`LockGuard`](struct.LockGuard.html) which releases a locked range when dropped.
+
+use std::cmp;
+use std::collections::BTreeMap;
+use std::ops::Range;
+use std::sync::{Condvar, Mutex, MutexGuard};
+
+// A range of sectors that is currently locked.
+#[derive(Debug)]
+struct LockedRange {
+    sectors: Range<u64>,
+    exclusive: bool,
+}
+
+impl LockedRange {
+    fn conflicts_with(&self, sectors: &Range<u64>, exclusive: bool) -> bool {
+        (self.exclusive || exclusive)
+            && cmp::max(self.sectors.start, sectors.start) < cmp::min(self.sectors.end, sectors.end)
+    }
+}
+
+#[derive(Debug, Default)]
+struct LockState {
+    // The locked ranges, by the identifier of their lock guard.
+    ranges: BTreeMap<u64, LockedRange>,
+    // The identifier of the next lock guard.
+    next_id: u64,
+}
+
+/// Locks ranges of sectors, either exclusively (e.g. for writing them) or shared (e.g. for
+/// reading them).
+///
+/// A range can be locked by any number of shared locks at the same time, but an exclusive lock
+/// can't overlap with any other lock. The same `SectorRangeLock` has to be used (e.g. via an
+/// `Arc`) by everyone accessing the same disk.
+///
+/// # Example
+///
+/// ```rust
+/// # use virtio_blk::locking::SectorRangeLock;
+/// let lock = SectorRangeLock::new();
+/// {
+///     let _guard = lock.lock_exclusive(0, 8);
+///     // Sectors 0 to 7 can be written here.
+/// }
+/// let _guard = lock.lock_shared(0, 8);
+/// let _other_guard = lock.lock_shared(4, 16);
+/// ```
+#[derive(Debug, Default)]
+pub struct SectorRangeLock {
+    state: Mutex<LockState>,
+    // Notified every time a range is unlocked.
+    unlocked: Condvar,
+}
+
+impl SectorRangeLock {
+    /// Creates a new `SectorRangeLock` without any locked range.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Locks the sectors from `start` to `end` (exclusive) for exclusive access, blocking until
+    /// no other lock overlaps with them.
+    ///
+    /// # Arguments
+    /// * `start` - The first sector of the range.
+    /// * `end` - The sector right after the end of the range.
+    pub fn lock_exclusive(&self, start: u64, end: u64) -> LockGuard<'_> {
+        self.lock(start..end, true)
+    }
+
+    /// Locks the sectors from `start` to `end` (exclusive) for shared access, blocking until no
+    /// exclusive lock overlaps with them.
+    ///
+    /// # Arguments
+    /// * `start` - The first sector of the range.
+    /// * `end` - The sector right after the end of the range.
+    pub fn lock_shared(&self, start: u64, end: u64) -> LockGuard<'_> {
+        self.lock(start..end, false)
+    }
+
+    fn lock(&self, sectors: Range<u64>, exclusive: bool) -> LockGuard<'_> {
+        let mut state = self.lock_state();
+        while state
+            .ranges
+            .values()
+            .any(|range| range.conflicts_with(&sectors, exclusive))
+        {
+            state = self
+                .unlocked
+                .wait(state)
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+        }
+        let id = state.next_id;
+        state.next_id += 1;
+        state.ranges.insert(id, LockedRange { sectors, exclusive });
+        LockGuard { lock: self, id }
+    }
+
+    // The state is consistent even if a thread panicked while holding the mutex, since it is
+    // only modified by single `BTreeMap` operations.
+    fn lock_state(&self) -> MutexGuard<'_, LockState> {
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// A locked range of sectors, which is unlocked when the `LockGuard` is dropped.
+#[derive(Debug)]
+pub struct LockGuard<'a> {
+    lock: &'a SectorRangeLock,
+    id: u64,
+}
+
+impl Drop for LockGuard<'_> {
+    fn drop(&mut self) {
+        self.lock.lock_state().ranges.remove(&self.id);
+        self.lock.unlocked.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_conflicts() {
+        let exclusive = LockedRange {
+            sectors: 4..8,
+            exclusive: true,
+        };
+        let shared = LockedRange {
+            sectors: 4..8,
+            exclusive: false,
+        };
+        assert!(exclusive.conflicts_with(&(0..5), false));
+        assert!(exclusive.conflicts_with(&(7..9), true));
+        assert!(!exclusive.conflicts_with(&(0..4), true));
+        assert!(!exclusive.conflicts_with(&(8..16), true));
+        // Empty ranges don't conflict with anything.
+        assert!(!exclusive.conflicts_with(&(5..5), true));
+
+        assert!(shared.conflicts_with(&(0..5), true));
+        assert!(!shared.conflicts_with(&(0..5), false));
+    }
+
+    #[test]
+    fn test_lock() {
+        let lock = Arc::new(SectorRangeLock::new());
+        let writing = Arc::new(AtomicBool::new(false));
+
+        // Two threads write overlapping ranges, so they have to be serialized.
+        let handles: Vec<_> = [(0, 8), (4, 12)]
+            .iter()
+            .map(|&(start, end)| {
+                let lock = lock.clone();
+                let writing = writing.clone();
+                thread::spawn(move || {
+                    for _ in 0..10 {
+                        let _guard = lock.lock_exclusive(start, end);
+                        assert!(!writing.swap(true, Ordering::SeqCst));
+                        thread::sleep(Duration::from_millis(1));
+                        writing.store(false, Ordering::SeqCst);
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        // Shared locks, and exclusive locks of other ranges, don't block each other.
+        let shared = lock.lock_shared(0, 8);
+        let other_shared = lock.lock_shared(4, 12);
+        let _exclusive = lock.lock_exclusive(12, 16);
+        assert_eq!(lock.lock_state().ranges.len(), 3);
+
+        // An exclusive lock waits until the overlapping shared locks are released.
+        let locked = Arc::new(AtomicBool::new(false));
+        let handle = {
+            let lock = lock.clone();
+            let locked = locked.clone();
+            thread::spawn(move || {
+                let _guard = lock.lock_exclusive(6, 7);
+                locked.store(true, Ordering::SeqCst);
+            })
+        };
+        thread::sleep(Duration::from_millis(10));
+        assert!(!locked.load(Ordering::SeqCst));
+        drop(shared);
+        thread::sleep(Duration::from_millis(10));
+        assert!(!locked.load(Ordering::SeqCst));
+        drop(other_shared);
+        handle.join().unwrap();
+        assert!(locked.load(Ordering::SeqCst));
+        assert_eq!(lock.lock_state().ranges.len(), 1);
+    }
+}
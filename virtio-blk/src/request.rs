@@ -22,18 +22,35 @@
 //! [this](https://docs.oasis-open.org/virtio/virtio/v1.1/csprd01/virtio-v1.1-csprd01.html#x1-280004)).
 //! However, we expect the `Request` interface won't change even if we switch to the general
 //! approach.
+//!
+//! - [`FrozenRequest`](struct.FrozenRequest.html) which is a request that can't be modified
+//! anymore, obtained via [`Request::freeze`](struct.Request.html#method.freeze).
+//!
+//! - [`RequestQueue`](struct.RequestQueue.html) which keeps track of the in-flight requests by a
+//! unique [`RequestId`](type.RequestId.html), for VMMs that dispatch and complete the requests
+//! separately.
 
+#[cfg(feature = "backend-stdio")]
+use std::cmp;
+use std::collections::HashMap;
 use std::fmt::{self, Display};
-use std::ops::Deref;
+#[cfg(feature = "backend-stdio")]
+use std::mem;
+use std::ops::{BitOr, Deref, RangeInclusive};
 use std::result;
+use std::sync::atomic::{AtomicU64, Ordering};
 
 use virtio_bindings::bindings::virtio_blk::{
-    VIRTIO_BLK_T_DISCARD, VIRTIO_BLK_T_FLUSH, VIRTIO_BLK_T_GET_ID, VIRTIO_BLK_T_IN,
-    VIRTIO_BLK_T_OUT, VIRTIO_BLK_T_WRITE_ZEROES,
+    virtio_blk_outhdr, VIRTIO_BLK_T_BARRIER, VIRTIO_BLK_T_DISCARD, VIRTIO_BLK_T_FLUSH,
+    VIRTIO_BLK_T_GET_ID, VIRTIO_BLK_T_IN, VIRTIO_BLK_T_OUT, VIRTIO_BLK_T_WRITE_ZEROES,
+    VIRTIO_BLK_T_ZONE_REPORT,
 };
 
 use virtio_queue::{Descriptor, DescriptorChain};
-use vm_memory::{ByteValued, Bytes, GuestAddress, GuestMemory, GuestMemoryError};
+use vm_memory::bitmap::MS;
+use vm_memory::{ByteValued, Bytes, GuestAddress, GuestMemory, GuestMemoryError, VolatileSlice};
+
+use crate::defs::{SECTOR_SHIFT, VIRTIO_BLK_DISCARD_SEGMENT_LEN};
 
 /// Block request parsing errors.
 #[derive(Debug)]
@@ -46,6 +63,8 @@ pub enum Error {
     GuestMemory(GuestMemoryError),
     /// Invalid sector value for a flush request.
     InvalidFlushSector,
+    /// The reserved field of the request header is not zero.
+    NonZeroReserved(u32),
     /// Read only descriptor that protocol says to write to.
     UnexpectedReadOnlyDescriptor,
     /// Write only descriptor that protocol says to read from.
@@ -61,6 +80,11 @@ impl Display for Error {
             DescriptorLengthTooSmall => write!(f, "descriptor length too small"),
             GuestMemory(ref err) => write!(f, "error accessing guest memory: {}", err),
             InvalidFlushSector => write!(f, "invalid sector in flush request, it should be 0"),
+            NonZeroReserved(reserved) => write!(
+                f,
+                "invalid reserved field in request header: {}, it should be 0",
+                reserved
+            ),
             UnexpectedReadOnlyDescriptor => write!(f, "unexpected read only descriptor"),
             UnexpectedWriteOnlyDescriptor => write!(f, "unexpected write only descriptor"),
         }
@@ -85,6 +109,8 @@ pub enum RequestType {
     Discard,
     /// Write zeroes request.
     WriteZeroes,
+    /// Zone report request.
+    ZoneReport,
     /// Unknown request.
     Unsupported(u32),
 }
@@ -98,11 +124,109 @@ impl From<u32> for RequestType {
             VIRTIO_BLK_T_GET_ID => RequestType::GetDeviceID,
             VIRTIO_BLK_T_DISCARD => RequestType::Discard,
             VIRTIO_BLK_T_WRITE_ZEROES => RequestType::WriteZeroes,
+            VIRTIO_BLK_T_ZONE_REPORT => RequestType::ZoneReport,
             t => RequestType::Unsupported(t),
         }
     }
 }
 
+impl From<RequestType> for u32 {
+    fn from(request_type: RequestType) -> Self {
+        match request_type {
+            RequestType::In => VIRTIO_BLK_T_IN,
+            RequestType::Out => VIRTIO_BLK_T_OUT,
+            RequestType::Flush => VIRTIO_BLK_T_FLUSH,
+            RequestType::GetDeviceID => VIRTIO_BLK_T_GET_ID,
+            RequestType::Discard => VIRTIO_BLK_T_DISCARD,
+            RequestType::WriteZeroes => VIRTIO_BLK_T_WRITE_ZEROES,
+            RequestType::ZoneReport => VIRTIO_BLK_T_ZONE_REPORT,
+            RequestType::Unsupported(t) => t,
+        }
+    }
+}
+
+impl From<virtio_blk_outhdr> for RequestType {
+    fn from(header: virtio_blk_outhdr) -> Self {
+        RequestType::from(header.type_ & !RequestTypeFlags::MASK)
+    }
+}
+
+/// Flags stored in the high bits of the request type of the header by legacy drivers.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RequestTypeFlags(u32);
+
+impl RequestTypeFlags {
+    /// The request has to be completed after the preceding requests, and before the following
+    /// ones (`VIRTIO_BLK_T_BARRIER`).
+    pub const BARRIER: RequestTypeFlags = RequestTypeFlags(VIRTIO_BLK_T_BARRIER);
+
+    /// The bits of the request type that contain flags.
+    pub const MASK: u32 = VIRTIO_BLK_T_BARRIER;
+
+    /// Returns a set with no flags.
+    pub const fn empty() -> Self {
+        RequestTypeFlags(0)
+    }
+
+    /// Returns the flags of the raw request type `request_type`.
+    ///
+    /// # Arguments
+    /// * `request_type` - The `type` field of the request header.
+    pub const fn from_request_type(request_type: u32) -> Self {
+        RequestTypeFlags(request_type & Self::MASK)
+    }
+
+    /// Returns the raw value of the flags.
+    pub const fn bits(&self) -> u32 {
+        self.0
+    }
+
+    /// Checks whether all the flags in `other` are also set in `self`.
+    pub const fn contains(&self, other: RequestTypeFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for RequestTypeFlags {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        RequestTypeFlags(self.0 | rhs.0)
+    }
+}
+
+/// Flags that control how a request is parsed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ParseFlags(u32);
+
+impl ParseFlags {
+    /// Rejects the requests with a non-zero reserved field in the header.
+    pub const STRICT: ParseFlags = ParseFlags(1);
+
+    /// Returns a set with no flags.
+    pub const fn empty() -> Self {
+        ParseFlags(0)
+    }
+
+    /// Returns the raw value of the flags.
+    pub const fn bits(&self) -> u32 {
+        self.0
+    }
+
+    /// Checks whether all the flags in `other` are also set in `self`.
+    pub const fn contains(&self, other: ParseFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for ParseFlags {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        ParseFlags(self.0 | rhs.0)
+    }
+}
+
 /// Block request header.
 #[derive(Copy, Clone, Debug, Default)]
 #[repr(C)]
@@ -112,18 +236,51 @@ struct RequestHeader {
     sector: u64,
 }
 
+impl RequestHeader {
+    // Returns the flags in the high bits of the request type.
+    fn flags(&self) -> RequestTypeFlags {
+        RequestTypeFlags::from_request_type(self.request_type)
+    }
+
+    // Returns the request type without the flags.
+    fn request_type(&self) -> RequestType {
+        RequestType::from(self.request_type & !RequestTypeFlags::MASK)
+    }
+}
+
+/// A data descriptor of a request, i.e. a buffer in the guest memory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DataDescriptor {
+    /// The guest address of the buffer.
+    pub addr: GuestAddress,
+    /// The length of the buffer, in bytes.
+    pub len_bytes: u32,
+}
+
+impl DataDescriptor {
+    /// Creates a new `DataDescriptor`.
+    ///
+    /// # Arguments
+    /// * `addr` - The guest address of the buffer.
+    /// * `len_bytes` - The length of the buffer, in bytes.
+    pub fn new(addr: GuestAddress, len_bytes: u32) -> Self {
+        DataDescriptor { addr, len_bytes }
+    }
+}
+
 /// Stores the necessary information for further execution of a block request.
 #[derive(Debug, PartialEq, Eq)]
 pub struct Request {
     /// The type of the request.
     request_type: RequestType,
-    /// Stores the (address, data length) pairs where the data descriptors
-    /// point to.
-    data: Vec<(GuestAddress, u32)>,
+    /// Stores the buffers the data descriptors point to.
+    data: Vec<DataDescriptor>,
     /// The offset (multiplied by 512) where the read or write is to occur.
     sector: u64,
     /// The address where the device should write the request status.
     status_addr: GuestAddress,
+    /// The flags of the request type.
+    type_flags: RequestTypeFlags,
 }
 
 // SAFETY: Safe because RequestHeader contains only plain data.
@@ -135,16 +292,51 @@ impl Request {
         self.request_type
     }
 
-    /// Returns the (address, len) pairs where the request data is in the guest memory.
-    pub fn data(&self) -> &[(GuestAddress, u32)] {
+    /// Returns the flags that were set in the request type of the header, such as
+    /// [`RequestTypeFlags::BARRIER`].
+    pub fn type_flags(&self) -> RequestTypeFlags {
+        self.type_flags
+    }
+
+    /// Returns the buffers where the request data is in the guest memory.
+    pub fn data(&self) -> &[DataDescriptor] {
         &self.data
     }
 
+    /// Returns the guest memory slices of the request data.
+    ///
+    /// Each data descriptor has to be contained by a single guest memory region, otherwise its
+    /// slice can't be obtained and an error is returned instead.
+    ///
+    /// # Arguments
+    /// * `mem` - A reference to the guest memory.
+    pub fn data_slices<'a, M: GuestMemory>(
+        &'a self,
+        mem: &'a M,
+    ) -> impl Iterator<Item = result::Result<VolatileSlice<'a, MS<'a, M>>, GuestMemoryError>> + 'a
+    {
+        self.data
+            .iter()
+            .map(move |desc| mem.get_slice(desc.addr, desc.len_bytes as usize))
+    }
+
     /// Returns the sector.
     pub fn sector(&self) -> u64 {
         self.sector
     }
 
+    /// Returns the request with its sector replaced by `host_sector`.
+    ///
+    /// This is useful for VMMs that remap the guest sectors to different host sectors (e.g. for
+    /// thin provisioning) before executing the request.
+    ///
+    /// # Arguments
+    /// * `host_sector` - The sector where the request should be executed.
+    pub fn with_sector_offset(mut self, host_sector: u64) -> Self {
+        self.sector = host_sector;
+        self
+    }
+
     /// Returns the status address.
     pub fn status_addr(&self) -> GuestAddress {
         self.status_addr
@@ -154,7 +346,122 @@ impl Request {
     pub fn total_data_len(&self) -> u64 {
         // The maximum queue size is 32768 (2^15), which is the maximum  possible descriptor chain
         // length and since data length is an u32, this sum can not overflow an u64.
-        self.data.iter().map(|x| x.1 as u64).sum()
+        self.data.iter().map(|x| x.len_bytes as u64).sum()
+    }
+
+    /// Returns whether the request modifies the contents of the disk, which is the case for
+    /// `Out`, `Discard` and `WriteZeroes` requests.
+    pub fn is_write_modifying(&self) -> bool {
+        matches!(
+            self.request_type,
+            RequestType::Out | RequestType::Discard | RequestType::WriteZeroes
+        )
+    }
+
+    /// Returns the size of the data that the request transfers, in bytes.
+    ///
+    /// This is the data length for most request types. For `Discard` and `WriteZeroes` requests
+    /// it is the length of the complete segments only, and for `Flush` and unsupported requests
+    /// it is 0.
+    pub fn request_size_bytes(&self) -> u64 {
+        match self.request_type {
+            RequestType::Discard | RequestType::WriteZeroes => {
+                self.total_data_len() / VIRTIO_BLK_DISCARD_SEGMENT_LEN
+                    * VIRTIO_BLK_DISCARD_SEGMENT_LEN
+            }
+            RequestType::Flush | RequestType::Unsupported(_) => 0,
+            _ => self.total_data_len(),
+        }
+    }
+
+    /// Returns the range of sectors accessed by an `In` or `Out` request.
+    ///
+    /// Returns `None` for the other request types, including `Discard` and `WriteZeroes`, whose
+    /// sectors are given by their segments instead of the request header. It also returns `None`
+    /// for requests without data, and for ranges past the last representable sector.
+    pub fn sector_range(&self) -> Option<RangeInclusive<u64>> {
+        match self.request_type {
+            RequestType::In | RequestType::Out => {
+                let num_sectors = self.total_data_len() >> SECTOR_SHIFT;
+                let last = self.sector.checked_add(num_sectors.checked_sub(1)?)?;
+                Some(self.sector..=last)
+            }
+            _ => None,
+        }
+    }
+
+    // Splits the request into requests for consecutive ranges of at most `max_sectors` sectors
+    // each, with the same type, flags and status address. The data length has to be a multiple
+    // of the sector size, and `max_sectors` can't be 0.
+    #[cfg(feature = "backend-stdio")]
+    pub(crate) fn split(&self, max_sectors: u64) -> Vec<Request> {
+        let max_len = max_sectors << SECTOR_SHIFT;
+        let mut requests = Vec::new();
+        let mut data = Vec::new();
+        let mut sector = self.sector;
+        let mut len = 0;
+        for desc in self.data.iter() {
+            let mut addr = desc.addr;
+            let mut remaining = u64::from(desc.len_bytes);
+            while remaining > 0 {
+                let chunk_len = cmp::min(remaining, max_len - len);
+                // The cast is safe since `chunk_len` is at most `desc.len_bytes`.
+                data.push(DataDescriptor::new(addr, chunk_len as u32));
+                // Invalid addresses are reported when the memory is accessed.
+                addr = GuestAddress(addr.0.wrapping_add(chunk_len));
+                remaining -= chunk_len;
+                len += chunk_len;
+                if len == max_len {
+                    requests.push(Request {
+                        request_type: self.request_type,
+                        data: mem::take(&mut data),
+                        sector,
+                        status_addr: self.status_addr,
+                        type_flags: self.type_flags,
+                    });
+                    sector += max_sectors;
+                    len = 0;
+                }
+            }
+        }
+        if !data.is_empty() {
+            requests.push(Request {
+                request_type: self.request_type,
+                data,
+                sector,
+                status_addr: self.status_addr,
+                type_flags: self.type_flags,
+            });
+        }
+        requests
+    }
+
+    // Returns the request for the data that follows the first `num_sectors` sectors of the
+    // request, with the same type, flags and status address. The request has to have at least
+    // `num_sectors` sectors of data.
+    #[cfg(feature = "backend-stdio")]
+    pub(crate) fn skip_sectors(&self, num_sectors: u64) -> Request {
+        let mut skip = num_sectors << SECTOR_SHIFT;
+        let mut data = Vec::new();
+        for desc in self.data.iter() {
+            let len = u64::from(desc.len_bytes);
+            if skip >= len {
+                skip -= len;
+                continue;
+            }
+            // Invalid addresses are reported when the memory is accessed.
+            let addr = GuestAddress(desc.addr.0.wrapping_add(skip));
+            // The cast is safe since the result is at most `desc.len_bytes`.
+            data.push(DataDescriptor::new(addr, (len - skip) as u32));
+            skip = 0;
+        }
+        Request {
+            request_type: self.request_type,
+            data,
+            sector: self.sector + num_sectors,
+            status_addr: self.status_addr,
+            type_flags: self.type_flags,
+        }
     }
 
     // Checks that a descriptor meets the minimal requirements for a valid status descriptor.
@@ -187,7 +494,9 @@ impl Request {
         // also check that the device doesn't want to read a device-writable buffer
         // because this one is not a MUST (the device MAY do that for debugging or
         // diagnostic purposes).
-        if !desc.is_write_only() && request_type == RequestType::In {
+        if !desc.is_write_only()
+            && (request_type == RequestType::In || request_type == RequestType::ZoneReport)
+        {
             return Err(Error::UnexpectedReadOnlyDescriptor);
         }
         Ok(())
@@ -205,6 +514,28 @@ impl Request {
     /// * `desc_chain` - A mutable reference to the descriptor chain that should point to the
     ///                  buffers of a virtio block request.
     pub fn parse<M>(desc_chain: &mut DescriptorChain<M>) -> Result<Request>
+    where
+        M: Deref,
+        M::Target: GuestMemory,
+    {
+        Request::parse_with_flags(desc_chain, ParseFlags::empty())
+    }
+
+    /// Parses a `desc_chain` as [`Request::parse`] does, with additional checks selected by
+    /// `flags`.
+    ///
+    /// The reserved field of the request header is only validated when `ParseFlags::STRICT` is
+    /// set. The specification requires it to be zero, but legacy drivers use it to pass the I/O
+    /// priority (`ioprio`), so rejecting such requests by default would break them.
+    ///
+    /// # Arguments
+    /// * `desc_chain` - A mutable reference to the descriptor chain that should point to the
+    ///                  buffers of a virtio block request.
+    /// * `flags` - The additional checks to perform.
+    pub fn parse_with_flags<M>(
+        desc_chain: &mut DescriptorChain<M>,
+        flags: ParseFlags,
+    ) -> Result<Request>
     where
         M: Deref,
         M::Target: GuestMemory,
@@ -220,15 +551,22 @@ impl Request {
             .read_obj::<RequestHeader>(chain_head.addr())
             .map_err(Error::GuestMemory)?;
 
-        if request_header.request_type == VIRTIO_BLK_T_FLUSH && request_header.sector != 0 {
+        if flags.contains(ParseFlags::STRICT) && request_header._reserved != 0 {
+            return Err(Error::NonZeroReserved(request_header._reserved));
+        }
+
+        // Legacy drivers may set flags in the high bits of the request type, e.g. for barriers.
+        let request_type = request_header.request_type();
+        if request_type == RequestType::Flush && request_header.sector != 0 {
             return Err(Error::InvalidFlushSector);
         }
 
         let mut request = Request {
-            request_type: RequestType::from(request_header.request_type),
+            request_type,
             data: Vec::new(),
             sector: request_header.sector,
             status_addr: GuestAddress(0),
+            type_flags: request_header.flags(),
         };
 
         let mut desc = desc_chain.next().ok_or(Error::DescriptorChainTooShort)?;
@@ -236,7 +574,9 @@ impl Request {
         while desc.has_next() {
             Request::check_data_desc(desc, request.request_type)?;
 
-            request.data.push((desc.addr(), desc.len()));
+            request
+                .data
+                .push(DataDescriptor::new(desc.addr(), desc.len()));
             desc = desc_chain.next().ok_or(Error::DescriptorChainTooShort)?;
         }
         let status_desc = desc;
@@ -246,6 +586,189 @@ impl Request {
         request.status_addr = status_desc.addr();
         Ok(request)
     }
+
+    /// Creates a request from a request header and the descriptors of its buffers, e.g. when the
+    /// descriptor chain was walked by C code.
+    ///
+    /// The header is checked like [`Request::parse`] does. The access direction of the data
+    /// buffers can't be checked, since `DataDescriptor` doesn't contain the descriptor flags.
+    ///
+    /// # Arguments
+    /// * `header` - The request header.
+    /// * `data` - The data descriptors.
+    /// * `status_addr` - The guest address of the status byte.
+    pub fn from_header_and_descriptors(
+        header: virtio_blk_outhdr,
+        data: Vec<DataDescriptor>,
+        status_addr: GuestAddress,
+    ) -> Result<Request> {
+        let request_type = RequestType::from(header);
+        if request_type == RequestType::Flush && header.sector != 0 {
+            return Err(Error::InvalidFlushSector);
+        }
+        Ok(Request {
+            request_type,
+            data,
+            sector: header.sector,
+            status_addr,
+            type_flags: RequestTypeFlags::from_request_type(header.type_),
+        })
+    }
+
+    /// Returns the header of the request.
+    ///
+    /// The `ioprio` field is always zero, since it is not kept by the request.
+    pub fn header(&self) -> virtio_blk_outhdr {
+        virtio_blk_outhdr {
+            type_: u32::from(self.request_type) | self.type_flags.bits(),
+            ioprio: 0,
+            sector: self.sector,
+        }
+    }
+
+    /// Returns a compact description of the request for diagnostics, such as
+    /// `Out(sector=5, len=4096, descriptors=2, status_addr=0x1000)`.
+    ///
+    /// Unlike the `Debug` output, it contains the total data length instead of the individual
+    /// data descriptors.
+    pub fn to_debug_string(&self) -> String {
+        format!(
+            "{:?}(sector={}, len={}, descriptors={}, status_addr={:#x})",
+            self.request_type,
+            self.sector,
+            self.total_data_len(),
+            self.data.len(),
+            self.status_addr.0
+        )
+    }
+
+    /// Freezes the request, so it can't be modified anymore, e.g. before sharing it with the
+    /// threads that execute it.
+    pub fn freeze(self) -> FrozenRequest {
+        FrozenRequest(self)
+    }
+}
+
+/// A [`Request`] that can't be modified.
+///
+/// It dereferences to the wrapped `Request`, so it can be passed to the functions that execute
+/// requests, and it can be shared between threads via `Arc<FrozenRequest>`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct FrozenRequest(Request);
+
+impl FrozenRequest {
+    /// Consumes the `FrozenRequest`, returning the request, which can be modified again.
+    pub fn thaw(self) -> Request {
+        self.0
+    }
+}
+
+impl Deref for FrozenRequest {
+    type Target = Request;
+
+    fn deref(&self) -> &Request {
+        &self.0
+    }
+}
+
+impl AsRef<Request> for FrozenRequest {
+    fn as_ref(&self) -> &Request {
+        &self.0
+    }
+}
+
+#[cfg(any(test, feature = "test-utils"))]
+impl Request {
+    /// Creates a new `Request` from its parts, without parsing a descriptor chain.
+    ///
+    /// # Arguments
+    /// * `request_type` - The type of the request.
+    /// * `data` - The data descriptors.
+    /// * `sector` - The first sector accessed by the request.
+    /// * `status_addr` - The guest address of the status byte.
+    pub fn new(
+        request_type: RequestType,
+        data: Vec<DataDescriptor>,
+        sector: u64,
+        status_addr: GuestAddress,
+    ) -> Self {
+        Request {
+            request_type,
+            data,
+            sector,
+            status_addr,
+            type_flags: RequestTypeFlags::empty(),
+        }
+    }
+}
+
+/// The unique identifier of a request submitted to a [`RequestQueue`].
+pub type RequestId = u64;
+
+/// Keeps track of the in-flight requests.
+///
+/// # Example
+///
+/// ```rust
+/// # use virtio_blk::request::{Request, RequestQueue};
+/// # use virtio_queue::DescriptorChain;
+/// # use vm_memory::GuestMemory;
+/// # use std::ops::Deref;
+/// fn dispatch<M>(queue: &mut RequestQueue, desc_chain: &mut DescriptorChain<M>)
+/// where
+///     M: Deref,
+///     M::Target: GuestMemory,
+/// {
+///     let id = queue.submit(Request::parse(desc_chain).unwrap());
+///     // Once the request is executed:
+///     let request = queue.complete(id).unwrap();
+/// }
+/// ```
+#[derive(Debug, Default)]
+pub struct RequestQueue {
+    /// The in-flight requests.
+    requests: HashMap<RequestId, Request>,
+    /// The identifier of the next submitted request.
+    next_id: AtomicU64,
+}
+
+impl RequestQueue {
+    /// Creates a new empty `RequestQueue`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `request` to the in-flight requests and returns its identifier.
+    ///
+    /// # Arguments
+    /// * `request` - The submitted request.
+    pub fn submit(&mut self, request: Request) -> RequestId {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.requests.insert(id, request);
+        id
+    }
+
+    /// Returns the in-flight request identified by `id`, if any.
+    ///
+    /// # Arguments
+    /// * `id` - The identifier returned on submission.
+    pub fn get(&self, id: RequestId) -> Option<&Request> {
+        self.requests.get(&id)
+    }
+
+    /// Removes the request identified by `id` from the in-flight requests and returns it, or
+    /// `None` if there is no such request.
+    ///
+    /// # Arguments
+    /// * `id` - The identifier returned on submission.
+    pub fn complete(&mut self, id: RequestId) -> Option<Request> {
+        self.requests.remove(&id)
+    }
+
+    /// Returns the number of in-flight requests.
+    pub fn in_flight(&self) -> usize {
+        self.requests.len()
+    }
 }
 
 #[cfg(test)]
@@ -254,6 +777,8 @@ mod tests {
 
     use vm_memory::GuestMemoryMmap;
 
+    use crate::defs::{VIRTIO_BLK_REQ_HEADER_RESERVED_OFFSET, VIRTIO_BLK_REQ_HEADER_RESERVED_SIZE};
+
     use virtio_bindings::bindings::virtio_ring::VRING_DESC_F_WRITE;
     use virtio_queue::mock::MockSplitQueue;
 
@@ -267,6 +792,9 @@ mod tests {
                     format!("{}", e).eq(&format!("{}", other_e))
                 }
                 (InvalidFlushSector, InvalidFlushSector) => true,
+                (NonZeroReserved(reserved), NonZeroReserved(other_reserved)) => {
+                    reserved == other_reserved
+                }
                 (UnexpectedReadOnlyDescriptor, UnexpectedReadOnlyDescriptor) => true,
                 (UnexpectedWriteOnlyDescriptor, UnexpectedWriteOnlyDescriptor) => true,
                 _ => false,
@@ -274,22 +802,6 @@ mod tests {
         }
     }
 
-    impl Request {
-        pub fn new(
-            request_type: RequestType,
-            data: Vec<(GuestAddress, u32)>,
-            sector: u64,
-            status_addr: GuestAddress,
-        ) -> Self {
-            Request {
-                request_type,
-                data,
-                sector,
-                status_addr,
-            }
-        }
-    }
-
     #[test]
     fn test_parse_request() {
         let mem: GuestMemoryMmap =
@@ -421,11 +933,12 @@ mod tests {
         let expected_request = Request {
             request_type: RequestType::Out,
             data: vec![
-                (GuestAddress(0x20_0000), 0x100),
-                (GuestAddress(0x30_0000), 0x200),
+                DataDescriptor::new(GuestAddress(0x20_0000), 0x100),
+                DataDescriptor::new(GuestAddress(0x30_0000), 0x200),
             ],
             sector: 2,
             status_addr: GuestAddress(0x40_0000),
+            type_flags: RequestTypeFlags::empty(),
         };
         assert_eq!(request, expected_request);
         assert_eq!(request.status_addr(), GuestAddress(0x40_0000));
@@ -461,4 +974,369 @@ mod tests {
         let mut chain = queue.build_desc_chain(&v[..2]).unwrap();
         assert!(Request::parse(&mut chain).is_ok());
     }
+
+    #[test]
+    fn test_parse_strict() {
+        let mem: GuestMemoryMmap =
+            GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x1000_0000)]).unwrap();
+        let v = [
+            Descriptor::new(0x10_0000, 0x100, 0, 0),
+            Descriptor::new(0x20_0000, 0x100, VRING_DESC_F_WRITE as u16, 0),
+            Descriptor::new(0x30_0000, 0x100, VRING_DESC_F_WRITE as u16, 0),
+        ];
+        let queue = MockSplitQueue::new(&mem, 16);
+
+        let req_header = RequestHeader {
+            request_type: VIRTIO_BLK_T_IN,
+            _reserved: 0x1234,
+            sector: 2,
+        };
+        mem.write_obj::<RequestHeader>(req_header, GuestAddress(0x10_0000))
+            .unwrap();
+        let mut reserved = [0u8; VIRTIO_BLK_REQ_HEADER_RESERVED_SIZE];
+        mem.read_slice(
+            &mut reserved,
+            GuestAddress(0x10_0000 + VIRTIO_BLK_REQ_HEADER_RESERVED_OFFSET as u64),
+        )
+        .unwrap();
+        assert_eq!(u32::from_le_bytes(reserved), 0x1234);
+
+        // The reserved field is ignored by default.
+        let mut chain = queue.build_desc_chain(&v[..3]).unwrap();
+        assert!(Request::parse(&mut chain).is_ok());
+
+        let mut chain = queue.build_desc_chain(&v[..3]).unwrap();
+        assert_eq!(
+            Request::parse_with_flags(&mut chain, ParseFlags::STRICT).unwrap_err(),
+            Error::NonZeroReserved(0x1234)
+        );
+
+        let req_header = RequestHeader {
+            request_type: VIRTIO_BLK_T_IN,
+            _reserved: 0,
+            sector: 2,
+        };
+        mem.write_obj::<RequestHeader>(req_header, GuestAddress(0x10_0000))
+            .unwrap();
+        let mut chain = queue.build_desc_chain(&v[..3]).unwrap();
+        let request = Request::parse_with_flags(&mut chain, ParseFlags::STRICT).unwrap();
+        assert_eq!(request.sector(), 2);
+
+        assert!((ParseFlags::empty() | ParseFlags::STRICT).contains(ParseFlags::STRICT));
+        assert!(!ParseFlags::empty().contains(ParseFlags::STRICT));
+    }
+
+    #[test]
+    fn test_request_queue() {
+        let mut queue = RequestQueue::new();
+        assert_eq!(queue.in_flight(), 0);
+
+        let ids: Vec<RequestId> = (0..3)
+            .map(|sector| {
+                queue.submit(Request::new(
+                    RequestType::In,
+                    vec![DataDescriptor::new(GuestAddress(0x1000), 0x200)],
+                    sector,
+                    GuestAddress(0x100),
+                ))
+            })
+            .collect();
+        assert_eq!(queue.in_flight(), 3);
+        assert_ne!(ids[0], ids[1]);
+        assert_ne!(ids[1], ids[2]);
+        assert_ne!(ids[0], ids[2]);
+
+        assert_eq!(queue.get(ids[2]).unwrap().sector(), 2);
+        assert_eq!(queue.complete(ids[1]).unwrap().sector(), 1);
+        assert_eq!(queue.in_flight(), 2);
+        assert!(queue.get(ids[1]).is_none());
+        assert!(queue.complete(ids[1]).is_none());
+        assert_eq!(queue.in_flight(), 2);
+
+        assert_eq!(queue.complete(ids[0]).unwrap().sector(), 0);
+        assert_eq!(queue.complete(ids[2]).unwrap().sector(), 2);
+        assert_eq!(queue.in_flight(), 0);
+
+        // Identifiers are not reused.
+        let id = queue.submit(Request::new(
+            RequestType::Flush,
+            vec![],
+            0,
+            GuestAddress(0x100),
+        ));
+        assert!(!ids.contains(&id));
+    }
+
+    #[test]
+    fn test_freeze() {
+        let request = Request::new(
+            RequestType::Out,
+            vec![DataDescriptor::new(GuestAddress(0x1000), 0x200)],
+            1,
+            GuestAddress(0x100),
+        );
+        let frozen = request.freeze();
+        assert_eq!(frozen.request_type(), RequestType::Out);
+        assert_eq!(frozen.sector(), 1);
+        assert_eq!(frozen.total_data_len(), 0x200);
+
+        let request = frozen.thaw().with_sector_offset(8);
+        assert_eq!(request.sector(), 8);
+
+        fn _assert_send_sync<T: Send + Sync>() {}
+        _assert_send_sync::<FrozenRequest>();
+    }
+
+    #[test]
+    #[cfg(feature = "backend-stdio")]
+    fn test_freeze_shared() {
+        use std::os::unix::fs::FileExt;
+        use std::sync::Arc;
+
+        use vmm_sys_util::tempfile::TempFile;
+
+        use crate::stdio_executor::StdIoBackend;
+
+        let f = TempFile::new().unwrap().into_file();
+        f.set_len(0x1000).unwrap();
+        let mem = GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        mem.write_slice(&[0xAB; 0x200], GuestAddress(0x1000))
+            .unwrap();
+        let req_exec = StdIoBackend::new(f, 0).unwrap();
+
+        // The same request is executed by the queues of two threads.
+        let frozen = Arc::new(
+            Request::new(
+                RequestType::Out,
+                vec![DataDescriptor::new(GuestAddress(0x1000), 0x200)],
+                1,
+                GuestAddress(0x100),
+            )
+            .freeze(),
+        );
+        let handles: Vec<_> = (1..=2)
+            .map(|queue_id| {
+                let mut queue_exec = req_exec.clone_for_queue(queue_id).unwrap();
+                let frozen = frozen.clone();
+                let mem = mem.clone();
+                std::thread::spawn(move || queue_exec.execute(&mem, &frozen))
+            })
+            .collect();
+        for handle in handles {
+            assert_eq!(handle.join().unwrap().unwrap(), 0);
+        }
+
+        let mut v = [0u8; 0x200];
+        req_exec.inner().read_exact_at(&mut v, 0x200).unwrap();
+        assert_eq!(v, [0xAB; 0x200]);
+    }
+
+    #[test]
+    fn test_request_size_bytes() {
+        let data = vec![
+            DataDescriptor::new(GuestAddress(0x1000), 0x200),
+            DataDescriptor::new(GuestAddress(0x2000), 0x28),
+        ];
+        let request = |request_type| Request::new(request_type, data.clone(), 0, GuestAddress(0));
+
+        assert_eq!(request(RequestType::In).request_size_bytes(), 0x228);
+        assert_eq!(request(RequestType::Out).request_size_bytes(), 0x228);
+        assert_eq!(
+            request(RequestType::GetDeviceID).request_size_bytes(),
+            0x228
+        );
+        // 0x228 bytes of data hold 34 complete segments of 16 bytes each.
+        assert_eq!(request(RequestType::Discard).request_size_bytes(), 34 * 16);
+        assert_eq!(
+            request(RequestType::WriteZeroes).request_size_bytes(),
+            34 * 16
+        );
+        assert_eq!(request(RequestType::Flush).request_size_bytes(), 0);
+        assert_eq!(
+            request(RequestType::Unsupported(0xFF)).request_size_bytes(),
+            0
+        );
+    }
+
+    #[test]
+    fn test_data_descriptor() {
+        let desc = DataDescriptor::new(GuestAddress(0x100), 0x200);
+        assert_eq!(desc.addr, GuestAddress(0x100));
+        assert_eq!(desc.len_bytes, 0x200);
+
+        let request = Request::new(RequestType::In, vec![desc], 0, GuestAddress(0));
+        assert_eq!(request.data(), [desc]);
+    }
+
+    #[test]
+    fn test_sector_range() {
+        let data = vec![
+            DataDescriptor::new(GuestAddress(0x1000), 0x200),
+            DataDescriptor::new(GuestAddress(0x2000), 0x200),
+        ];
+        let request = |request_type, sector| {
+            Request::new(request_type, data.clone(), sector, GuestAddress(0))
+        };
+
+        assert_eq!(request(RequestType::In, 3).sector_range(), Some(3..=4));
+        assert_eq!(request(RequestType::Out, 3).sector_range(), Some(3..=4));
+        assert_eq!(request(RequestType::Discard, 3).sector_range(), None);
+        assert_eq!(request(RequestType::Flush, 3).sector_range(), None);
+        assert_eq!(request(RequestType::In, u64::MAX).sector_range(), None);
+
+        let empty = Request::new(RequestType::In, vec![], 3, GuestAddress(0));
+        assert_eq!(empty.sector_range(), None);
+    }
+
+    #[test]
+    fn test_discard_segment_len() {
+        use std::mem;
+        use virtio_bindings::bindings::virtio_blk::virtio_blk_discard_write_zeroes;
+
+        assert_eq!(VIRTIO_BLK_DISCARD_SEGMENT_LEN, 16);
+        assert_eq!(
+            mem::size_of::<virtio_blk_discard_write_zeroes>() as u64,
+            VIRTIO_BLK_DISCARD_SEGMENT_LEN
+        );
+    }
+
+    #[test]
+    fn test_from_header_and_descriptors() {
+        let data = vec![
+            DataDescriptor::new(GuestAddress(0x1000), 0x200),
+            DataDescriptor::new(GuestAddress(0x2000), 0x400),
+        ];
+        let request_types = [
+            RequestType::In,
+            RequestType::Out,
+            RequestType::GetDeviceID,
+            RequestType::Discard,
+            RequestType::WriteZeroes,
+            RequestType::ZoneReport,
+            RequestType::Unsupported(0xFF),
+        ];
+        for request_type in request_types {
+            let header = virtio_blk_outhdr {
+                type_: request_type.into(),
+                ioprio: 0,
+                sector: 7,
+            };
+            assert_eq!(RequestType::from(header), request_type);
+            let request =
+                Request::from_header_and_descriptors(header, data.clone(), GuestAddress(0x3000))
+                    .unwrap();
+            assert_eq!(
+                request,
+                Request::new(request_type, data.clone(), 7, GuestAddress(0x3000))
+            );
+            assert_eq!(request.header(), header);
+        }
+
+        let mut header = virtio_blk_outhdr {
+            type_: VIRTIO_BLK_T_FLUSH,
+            ioprio: 0,
+            sector: 0,
+        };
+        let request =
+            Request::from_header_and_descriptors(header, vec![], GuestAddress(0x3000)).unwrap();
+        assert_eq!(request.request_type(), RequestType::Flush);
+        assert_eq!(request.header(), header);
+        header.sector = 1;
+        assert!(matches!(
+            Request::from_header_and_descriptors(header, vec![], GuestAddress(0x3000)),
+            Err(Error::InvalidFlushSector)
+        ));
+    }
+
+    #[test]
+    fn test_to_debug_string() {
+        let data = vec![
+            DataDescriptor::new(GuestAddress(0x2000), 0x800),
+            DataDescriptor::new(GuestAddress(0x3000), 0x800),
+        ];
+        let request = Request::new(RequestType::Out, data.clone(), 5, GuestAddress(0x1000));
+        assert_eq!(
+            request.to_debug_string(),
+            "Out(sector=5, len=4096, descriptors=2, status_addr=0x1000)"
+        );
+
+        for request_type in [
+            RequestType::In,
+            RequestType::Flush,
+            RequestType::GetDeviceID,
+            RequestType::Discard,
+            RequestType::WriteZeroes,
+            RequestType::ZoneReport,
+            RequestType::Unsupported(42),
+        ] {
+            let s =
+                Request::new(request_type, data.clone(), 7, GuestAddress(0x100)).to_debug_string();
+            assert!(s.starts_with(&format!("{:?}(", request_type)));
+            for field in ["sector=7", "len=4096", "descriptors=2", "status_addr=0x100"] {
+                assert!(s.contains(field), "{} doesn't contain {}", s, field);
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_barrier() {
+        let mem: GuestMemoryMmap =
+            GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x1000_0000)]).unwrap();
+        let v = [
+            Descriptor::new(0x10_0000, 0x100, 0, 0),
+            Descriptor::new(0x20_0000, 0x200, 0, 0),
+            Descriptor::new(0x30_0000, 0x100, VRING_DESC_F_WRITE as u16, 0),
+        ];
+        let queue = MockSplitQueue::new(&mem, 16);
+
+        let req_header = RequestHeader {
+            request_type: VIRTIO_BLK_T_OUT | VIRTIO_BLK_T_BARRIER,
+            _reserved: 0,
+            sector: 2,
+        };
+        mem.write_obj::<RequestHeader>(req_header, GuestAddress(0x10_0000))
+            .unwrap();
+        let mut chain = queue.build_desc_chain(&v[..3]).unwrap();
+        let request = Request::parse(&mut chain).unwrap();
+        assert_eq!(request.request_type(), RequestType::Out);
+        assert!(request.type_flags().contains(RequestTypeFlags::BARRIER));
+        assert_eq!(
+            request.header().type_,
+            VIRTIO_BLK_T_OUT | VIRTIO_BLK_T_BARRIER
+        );
+        // The flags are kept by the split requests.
+        #[cfg(feature = "backend-stdio")]
+        assert!(request
+            .split(1)
+            .iter()
+            .all(|r| r.type_flags() == RequestTypeFlags::BARRIER));
+
+        let req_header = RequestHeader {
+            request_type: VIRTIO_BLK_T_OUT,
+            ..req_header
+        };
+        mem.write_obj::<RequestHeader>(req_header, GuestAddress(0x10_0000))
+            .unwrap();
+        let mut chain = queue.build_desc_chain(&v[..3]).unwrap();
+        let request = Request::parse(&mut chain).unwrap();
+        assert_eq!(request.type_flags(), RequestTypeFlags::empty());
+
+        // A flush barrier still has to target sector 0.
+        let header = virtio_blk_outhdr {
+            type_: VIRTIO_BLK_T_FLUSH | VIRTIO_BLK_T_BARRIER,
+            ioprio: 0,
+            sector: 1,
+        };
+        assert_eq!(RequestType::from(header), RequestType::Flush);
+        assert!(matches!(
+            Request::from_header_and_descriptors(header, vec![], GuestAddress(0x3000)),
+            Err(Error::InvalidFlushSector)
+        ));
+
+        assert_eq!(
+            RequestTypeFlags::from_request_type(VIRTIO_BLK_T_IN | VIRTIO_BLK_T_BARRIER),
+            RequestTypeFlags::empty() | RequestTypeFlags::BARRIER
+        );
+        assert!(!RequestTypeFlags::empty().contains(RequestTypeFlags::BARRIER));
+    }
 }
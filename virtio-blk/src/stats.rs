@@ -0,0 +1,437 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Virtio block request statistics.
+//!
+//! This module provides the following abstractions for keeping track of the block requests that
+//! were executed by a device:
+//!
+//! - [`BlockStats`](struct.BlockStats.html) which holds the counters and can be shared between
+//! the threads executing requests and the ones reporting the statistics.
+//! - [`BlockStatsSnapshot`](struct.BlockStatsSnapshot.html) which is a consistent copy of all the
+//! counters, obtained via [`BlockStats::snapshot`](struct.BlockStats.html#method.snapshot).
+//! - [`BlockStatsPrometheus`](struct.BlockStatsPrometheus.html) which formats the counters in the
+//! Prometheus text exposition format (requires the `prometheus` feature).
+
+#[cfg(feature = "prometheus")]
+use std::fmt::{self, Display};
+use std::hint;
+use std::sync::atomic::{fence, AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::request::RequestType;
+
+/// A consistent copy of the [`BlockStats`] counters.
+///
+/// It can be serialized with `serde` when the `serde` feature is enabled.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BlockStatsSnapshot {
+    /// The index of the queue whose requests are counted, if the statistics are per queue.
+    pub queue_id: Option<u16>,
+    /// Number of read requests.
+    pub reads: u64,
+    /// Number of bytes requested by read requests.
+    pub read_bytes: u64,
+    /// Number of write requests.
+    pub writes: u64,
+    /// Number of bytes requested by write requests.
+    pub write_bytes: u64,
+    /// Number of flush requests.
+    pub flushes: u64,
+    /// Number of discard requests.
+    pub discards: u64,
+    /// Number of write zeroes requests.
+    pub write_zeroes: u64,
+    /// Number of requests that failed.
+    pub errors: u64,
+    /// Total execution time of the read requests, in nanoseconds.
+    pub read_latency_ns_sum: u64,
+    /// Number of read requests whose execution time was recorded.
+    pub read_latency_count: u64,
+    /// Total execution time of the write requests, in nanoseconds.
+    pub write_latency_ns_sum: u64,
+    /// Number of write requests whose execution time was recorded.
+    pub write_latency_count: u64,
+    /// Total execution time of the flush requests, in nanoseconds.
+    pub flush_latency_ns_sum: u64,
+    /// Number of flush requests whose execution time was recorded.
+    pub flush_latency_count: u64,
+}
+
+/// Block request counters.
+///
+/// Every counter is an `AtomicU64`, but the counters that are affected by the same request are
+/// updated together under a sequence lock, so [`BlockStats::snapshot`] never observes a
+/// partially recorded request.
+#[derive(Debug, Default)]
+pub struct BlockStats {
+    // Sequence number of the lock. It is odd while a writer updates the counters.
+    seq: AtomicU64,
+    queue_id: Option<u16>,
+    reads: AtomicU64,
+    read_bytes: AtomicU64,
+    writes: AtomicU64,
+    write_bytes: AtomicU64,
+    flushes: AtomicU64,
+    discards: AtomicU64,
+    write_zeroes: AtomicU64,
+    errors: AtomicU64,
+    read_latency_ns_sum: AtomicU64,
+    read_latency_count: AtomicU64,
+    write_latency_ns_sum: AtomicU64,
+    write_latency_count: AtomicU64,
+    flush_latency_ns_sum: AtomicU64,
+    flush_latency_count: AtomicU64,
+}
+
+impl BlockStats {
+    /// Creates a new `BlockStats` object with all the counters set to 0.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a new `BlockStats` object for the requests of a single queue, with all the
+    /// counters set to 0.
+    ///
+    /// # Arguments
+    /// * `queue_id` - The index of the queue whose requests are counted.
+    pub fn for_queue(queue_id: u16) -> Self {
+        BlockStats {
+            queue_id: Some(queue_id),
+            ..Default::default()
+        }
+    }
+
+    /// Returns the index of the queue whose requests are counted, if the statistics are per
+    /// queue.
+    pub fn queue_id(&self) -> Option<u16> {
+        self.queue_id
+    }
+
+    // Runs `f` with exclusive write access to the counters.
+    fn write_locked<F: FnOnce(&Self)>(&self, f: F) {
+        let mut seq = self.seq.load(Ordering::Relaxed);
+        loop {
+            if seq & 1 == 1 {
+                hint::spin_loop();
+                seq = self.seq.load(Ordering::Relaxed);
+                continue;
+            }
+            match self.seq.compare_exchange_weak(
+                seq,
+                seq.wrapping_add(1),
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => break,
+                Err(crt_seq) => seq = crt_seq,
+            }
+        }
+        // Make sure the odd sequence number is visible before any of the counter updates.
+        fence(Ordering::Release);
+        f(self);
+        self.seq.store(seq.wrapping_add(2), Ordering::Release);
+    }
+
+    /// Records the execution of a request.
+    ///
+    /// # Arguments
+    /// * `request_type` - The type of the executed request.
+    /// * `bytes` - The data length of the request. It is only accounted for reads and writes.
+    /// * `success` - Whether the request was executed successfully.
+    pub fn record(&self, request_type: RequestType, bytes: u64, success: bool) {
+        self.write_locked(|stats| {
+            match request_type {
+                RequestType::In => {
+                    stats.reads.fetch_add(1, Ordering::Relaxed);
+                    stats.read_bytes.fetch_add(bytes, Ordering::Relaxed);
+                }
+                RequestType::Out => {
+                    stats.writes.fetch_add(1, Ordering::Relaxed);
+                    stats.write_bytes.fetch_add(bytes, Ordering::Relaxed);
+                }
+                RequestType::Flush => {
+                    stats.flushes.fetch_add(1, Ordering::Relaxed);
+                }
+                RequestType::Discard => {
+                    stats.discards.fetch_add(1, Ordering::Relaxed);
+                }
+                RequestType::WriteZeroes => {
+                    stats.write_zeroes.fetch_add(1, Ordering::Relaxed);
+                }
+                RequestType::GetDeviceID
+                | RequestType::ZoneReport
+                | RequestType::Unsupported(_) => {}
+            }
+            if !success {
+                stats.errors.fetch_add(1, Ordering::Relaxed);
+            }
+        });
+    }
+
+    /// Records the execution time of a request.
+    ///
+    /// Only the execution times of reads, writes and flushes are accounted. Dividing the sum of
+    /// the execution times by their count gives the mean latency of each request type.
+    ///
+    /// # Arguments
+    /// * `request_type` - The type of the executed request.
+    /// * `latency` - The execution time of the request.
+    pub fn record_latency(&self, request_type: RequestType, latency: Duration) {
+        let (sum, count) = match request_type {
+            RequestType::In => (&self.read_latency_ns_sum, &self.read_latency_count),
+            RequestType::Out => (&self.write_latency_ns_sum, &self.write_latency_count),
+            RequestType::Flush => (&self.flush_latency_ns_sum, &self.flush_latency_count),
+            _ => return,
+        };
+        let latency_ns = u64::try_from(latency.as_nanos()).unwrap_or(u64::MAX);
+        self.write_locked(|_| {
+            sum.fetch_add(latency_ns, Ordering::Relaxed);
+            count.fetch_add(1, Ordering::Relaxed);
+        });
+    }
+
+    /// Returns a consistent copy of all the counters.
+    ///
+    /// The counters are read optimistically and the read is retried if a request was recorded in
+    /// the meantime.
+    pub fn snapshot(&self) -> BlockStatsSnapshot {
+        loop {
+            let seq = self.seq.load(Ordering::Acquire);
+            if seq & 1 == 1 {
+                hint::spin_loop();
+                continue;
+            }
+            let snapshot = BlockStatsSnapshot {
+                queue_id: self.queue_id,
+                reads: self.reads.load(Ordering::Relaxed),
+                read_bytes: self.read_bytes.load(Ordering::Relaxed),
+                writes: self.writes.load(Ordering::Relaxed),
+                write_bytes: self.write_bytes.load(Ordering::Relaxed),
+                flushes: self.flushes.load(Ordering::Relaxed),
+                discards: self.discards.load(Ordering::Relaxed),
+                write_zeroes: self.write_zeroes.load(Ordering::Relaxed),
+                errors: self.errors.load(Ordering::Relaxed),
+                read_latency_ns_sum: self.read_latency_ns_sum.load(Ordering::Relaxed),
+                read_latency_count: self.read_latency_count.load(Ordering::Relaxed),
+                write_latency_ns_sum: self.write_latency_ns_sum.load(Ordering::Relaxed),
+                write_latency_count: self.write_latency_count.load(Ordering::Relaxed),
+                flush_latency_ns_sum: self.flush_latency_ns_sum.load(Ordering::Relaxed),
+                flush_latency_count: self.flush_latency_count.load(Ordering::Relaxed),
+            };
+            // Make sure the counters are read before checking the sequence number again.
+            fence(Ordering::Acquire);
+            if self.seq.load(Ordering::Relaxed) == seq {
+                return snapshot;
+            }
+        }
+    }
+}
+
+/// Formats the [`BlockStats`] counters of a queue in the Prometheus text exposition format.
+///
+/// Every counter is emitted on its own line, named `virtio_blk_<counter>_total` and labeled with
+/// the index of the queue. There are no `# HELP` or `# TYPE` lines, so the output for several
+/// queues can be concatenated.
+///
+/// # Example
+///
+/// ```rust
+/// # use virtio_blk::request::RequestType;
+/// # use virtio_blk::stats::{BlockStats, BlockStatsPrometheus};
+/// let stats = BlockStats::new();
+/// stats.record(RequestType::In, 0x200, true);
+/// let text = BlockStatsPrometheus::new(&stats, 0).to_string();
+/// assert!(text.starts_with("virtio_blk_reads_total{queue=\"0\"} 1\n"));
+/// ```
+#[cfg(feature = "prometheus")]
+#[derive(Clone, Copy, Debug)]
+pub struct BlockStatsPrometheus<'a> {
+    stats: &'a BlockStats,
+    queue: u16,
+}
+
+#[cfg(feature = "prometheus")]
+impl<'a> BlockStatsPrometheus<'a> {
+    /// Creates a new `BlockStatsPrometheus` object.
+    ///
+    /// # Arguments
+    /// * `stats` - The counters to format.
+    /// * `queue` - The index of the queue whose requests are counted by `stats`.
+    pub fn new(stats: &'a BlockStats, queue: u16) -> Self {
+        BlockStatsPrometheus { stats, queue }
+    }
+}
+
+#[cfg(feature = "prometheus")]
+impl Display for BlockStatsPrometheus<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let snapshot = self.stats.snapshot();
+        for (name, value) in [
+            ("reads", snapshot.reads),
+            ("read_bytes", snapshot.read_bytes),
+            ("writes", snapshot.writes),
+            ("write_bytes", snapshot.write_bytes),
+            ("flushes", snapshot.flushes),
+            ("discards", snapshot.discards),
+            ("write_zeroes", snapshot.write_zeroes),
+            ("errors", snapshot.errors),
+        ] {
+            writeln!(
+                f,
+                "virtio_blk_{}_total{{queue=\"{}\"}} {}",
+                name, self.queue, value
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn test_record() {
+        let stats = BlockStats::new();
+        assert_eq!(stats.snapshot(), BlockStatsSnapshot::default());
+
+        stats.record(RequestType::In, 0x400, true);
+        stats.record(RequestType::In, 0x200, false);
+        stats.record(RequestType::Out, 0x200, true);
+        stats.record(RequestType::Flush, 0, true);
+        stats.record(RequestType::Discard, 0, true);
+        stats.record(RequestType::WriteZeroes, 0, false);
+        stats.record(RequestType::GetDeviceID, 20, true);
+        stats.record(RequestType::Unsupported(8), 0, false);
+
+        assert_eq!(
+            stats.snapshot(),
+            BlockStatsSnapshot {
+                reads: 2,
+                read_bytes: 0x600,
+                writes: 1,
+                write_bytes: 0x200,
+                flushes: 1,
+                discards: 1,
+                write_zeroes: 1,
+                errors: 3,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_record_latency() {
+        let stats = BlockStats::new();
+        stats.record_latency(RequestType::In, Duration::from_micros(3));
+        stats.record_latency(RequestType::In, Duration::from_micros(5));
+        stats.record_latency(RequestType::Out, Duration::from_nanos(700));
+        stats.record_latency(RequestType::Flush, Duration::from_millis(2));
+        // The latency of the other request types is not recorded.
+        stats.record_latency(RequestType::Discard, Duration::from_millis(1));
+        stats.record_latency(RequestType::GetDeviceID, Duration::from_millis(1));
+
+        assert_eq!(
+            stats.snapshot(),
+            BlockStatsSnapshot {
+                read_latency_ns_sum: 8000,
+                read_latency_count: 2,
+                write_latency_ns_sum: 700,
+                write_latency_count: 1,
+                flush_latency_ns_sum: 2_000_000,
+                flush_latency_count: 1,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_queue_id() {
+        let stats = BlockStats::for_queue(2);
+        stats.record(RequestType::Out, 0x200, true);
+        assert_eq!(stats.queue_id(), Some(2));
+        assert_eq!(
+            stats.snapshot(),
+            BlockStatsSnapshot {
+                queue_id: Some(2),
+                writes: 1,
+                write_bytes: 0x200,
+                ..Default::default()
+            }
+        );
+        assert_eq!(BlockStats::new().snapshot().queue_id, None);
+
+        #[cfg(feature = "serde")]
+        {
+            let serialized = serde_json::to_string(&stats.snapshot()).unwrap();
+            assert!(serialized.starts_with("{\"queue_id\":2,"));
+        }
+    }
+
+    #[cfg(feature = "prometheus")]
+    #[test]
+    fn test_prometheus() {
+        let stats = BlockStats::new();
+        stats.record(RequestType::In, 0x400, true);
+        stats.record(RequestType::In, 0x200, false);
+        stats.record(RequestType::Out, 0x200, true);
+        stats.record(RequestType::Flush, 0, true);
+
+        assert_eq!(
+            BlockStatsPrometheus::new(&stats, 3).to_string(),
+            "virtio_blk_reads_total{queue=\"3\"} 2\n\
+             virtio_blk_read_bytes_total{queue=\"3\"} 1536\n\
+             virtio_blk_writes_total{queue=\"3\"} 1\n\
+             virtio_blk_write_bytes_total{queue=\"3\"} 512\n\
+             virtio_blk_flushes_total{queue=\"3\"} 1\n\
+             virtio_blk_discards_total{queue=\"3\"} 0\n\
+             virtio_blk_write_zeroes_total{queue=\"3\"} 0\n\
+             virtio_blk_errors_total{queue=\"3\"} 1\n"
+        );
+    }
+
+    #[test]
+    fn test_snapshot_consistency() {
+        const ITERATIONS: u64 = 100_000;
+
+        let stats = Arc::new(BlockStats::new());
+        let done = Arc::new(AtomicBool::new(false));
+
+        let writer = {
+            let stats = stats.clone();
+            let done = done.clone();
+            thread::spawn(move || {
+                // Every recorded request is a failed flush, so `flushes` and `errors` always have
+                // to be equal in a consistent snapshot.
+                for _ in 0..ITERATIONS {
+                    stats.record(RequestType::Flush, 0, false);
+                }
+                done.store(true, Ordering::Release);
+            })
+        };
+
+        let reader = {
+            let stats = stats.clone();
+            thread::spawn(move || loop {
+                let finished = done.load(Ordering::Acquire);
+                let snapshot = stats.snapshot();
+                assert_eq!(snapshot.flushes, snapshot.errors);
+                if finished {
+                    break snapshot;
+                }
+            })
+        };
+
+        writer.join().unwrap();
+        let snapshot = reader.join().unwrap();
+        assert_eq!(snapshot.flushes, ITERATIONS);
+        assert_eq!(snapshot.errors, ITERATIONS);
+    }
+}
@@ -23,26 +23,65 @@
 //! For more complex executors, that need asynchronous dispatch of requests for example, we can
 //! add separate modules for those abstractions as well.
 
+use std::cmp;
+use std::collections::HashMap;
 use std::fmt::{self, Display};
-use std::io::{Seek, SeekFrom};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::{Deref, DerefMut, Range, RangeInclusive};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::Path;
+use std::slice;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::{io, mem, result};
 
-use log::{error, warn};
+use log::{error, trace, warn};
 
+use vm_memory::bitmap::{BitmapSlice, MS};
 use vm_memory::{
-    Address, ByteValued, Bytes, GuestMemory, GuestMemoryError, ReadVolatile, WriteVolatile,
+    Address, ByteValued, Bytes, GuestAddress, GuestMemory, GuestMemoryError, ReadVolatile,
+    VolatileMemoryError, VolatileSlice, WriteVolatile,
 };
 use vmm_sys_util::file_traits::FileSync;
+#[cfg(target_os = "linux")]
+use vmm_sys_util::ioctl::ioctl_with_mut_ptr;
 use vmm_sys_util::write_zeroes::{PunchHole, WriteZeroesAt};
 
-use crate::defs::{SECTOR_SHIFT, SECTOR_SIZE};
-use crate::request::{Request, RequestType};
+use crate::backend::{BackendCapabilities, VecBackend};
+use crate::defs::{
+    VirtioBlkReqStatus, DEFAULT_MAX_DISCARD_SECTORS, DEFAULT_MAX_WRITE_ZEROES_SECTORS,
+    SECTOR_SHIFT, SECTOR_SIZE, VIRTIO_BLK_DISCARD_SEGMENT_LEN,
+};
+use crate::device::{BlockDeviceMetrics, BlockDeviceSnapshot};
+use crate::journal::{self, Journal, JournalData, JournalWriter};
+use crate::locking::SectorRangeLock;
+use crate::rand::SplitMix64;
+use crate::request::{
+    DataDescriptor, Request, RequestId, RequestQueue, RequestType, RequestTypeFlags,
+};
+use crate::stats::BlockStats;
+use crate::zoned::{ZoneDescriptor, ZoneReportHeader};
 use virtio_bindings::bindings::virtio_blk::{
-    VIRTIO_BLK_F_DISCARD, VIRTIO_BLK_F_FLUSH, VIRTIO_BLK_F_RO, VIRTIO_BLK_F_WRITE_ZEROES,
-    VIRTIO_BLK_ID_BYTES, VIRTIO_BLK_S_IOERR, VIRTIO_BLK_S_OK, VIRTIO_BLK_S_UNSUPP,
-    VIRTIO_BLK_T_DISCARD, VIRTIO_BLK_T_FLUSH, VIRTIO_BLK_T_GET_ID, VIRTIO_BLK_T_WRITE_ZEROES,
+    VIRTIO_BLK_F_CONFIG_WCE, VIRTIO_BLK_F_DISCARD, VIRTIO_BLK_F_FLUSH, VIRTIO_BLK_F_RO,
+    VIRTIO_BLK_F_WRITE_ZEROES, VIRTIO_BLK_F_ZONED, VIRTIO_BLK_ID_BYTES, VIRTIO_BLK_T_DISCARD,
+    VIRTIO_BLK_T_FLUSH, VIRTIO_BLK_T_GET_ID, VIRTIO_BLK_T_WRITE_ZEROES, VIRTIO_BLK_T_ZONE_REPORT,
 };
 
+/// The number of sectors that are copied at once between or inside backends.
+const COPY_CHUNK_SECTORS: u64 = 128;
+
+/// The number of chunks each device can be read ahead of the comparison in
+/// [`StdIoBackend::diff_from`].
+const DIFF_QUEUED_CHUNKS: usize = 4;
+
+/// The maximum number of sectors accessed by an operation of [`StdIoBackend::stress_test`].
+const STRESS_TEST_MAX_SECTORS: u64 = 64;
+
 /// Trait that keeps as supertraits the ones that are necessary for the `StdIoBackend` abstraction
 /// used for the virtio block request execution.
 pub trait Backend:
@@ -52,6 +91,308 @@ pub trait Backend:
 
 impl<B: ReadVolatile + WriteVolatile + Seek + FileSync + PunchHole + WriteZeroesAt> Backend for B {}
 
+// Returns the file descriptor of a backend.
+type RawFdGetter<B> = fn(&B) -> RawFd;
+
+// Waits until `fd` is ready for one of the poll `events`, for at most `timeout`.
+fn poll_ready(fd: RawFd, events: libc::c_short, timeout: Duration) -> Result<()> {
+    let mut pollfd = libc::pollfd {
+        fd,
+        events,
+        revents: 0,
+    };
+    let timeout_ms = libc::c_int::try_from(timeout.as_millis()).unwrap_or(libc::c_int::MAX);
+    loop {
+        // SAFETY: Safe because `pollfd` is a valid `pollfd` structure, and the return value is
+        // checked.
+        match unsafe { libc::poll(&mut pollfd, 1, timeout_ms) } {
+            0 => return Err(Error::PollTimeout { fd }),
+            -1 if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted => continue,
+            // Other errors are reported by the I/O that follows.
+            _ => return Ok(()),
+        }
+    }
+}
+
+/// Extension of [`Backend`] for the backends that can transfer data from or to several buffers
+/// with a single operation.
+///
+/// [`StdIoBackend::execute_vectored`] uses it for reducing the number of operations needed by
+/// requests with multiple data descriptors. It is implemented for `std::fs::File` on Linux, where
+/// it relies on the `preadv` and `pwritev` syscalls.
+pub trait VectoredBackend {
+    /// Reads data starting with `offset` into `bufs`, in order, and returns the number of bytes
+    /// that were read. Like `preadv`, fewer bytes than requested may be read, and the position of
+    /// the backend is not changed.
+    ///
+    /// # Arguments
+    /// * `bufs` - The buffers to fill.
+    /// * `offset` - The offset in bytes where the read starts.
+    fn preadv<S: BitmapSlice>(
+        &mut self,
+        bufs: &[VolatileSlice<S>],
+        offset: u64,
+    ) -> io::Result<usize>;
+
+    /// Writes the data from `bufs`, in order, starting with `offset`, and returns the number of
+    /// bytes that were written. Like `pwritev`, fewer bytes than requested may be written, and
+    /// the position of the backend is not changed.
+    ///
+    /// # Arguments
+    /// * `bufs` - The buffers to write.
+    /// * `offset` - The offset in bytes where the write starts.
+    fn pwritev<S: BitmapSlice>(
+        &mut self,
+        bufs: &[VolatileSlice<S>],
+        offset: u64,
+    ) -> io::Result<usize>;
+}
+
+#[cfg(target_os = "linux")]
+impl VectoredBackend for File {
+    fn preadv<S: BitmapSlice>(
+        &mut self,
+        bufs: &[VolatileSlice<S>],
+        offset: u64,
+    ) -> io::Result<usize> {
+        let offset = libc::off_t::try_from(offset)
+            .map_err(|_| io::Error::from_raw_os_error(libc::EINVAL))?;
+        // Any buffers over the limit are left for a subsequent call.
+        let bufs = &bufs[..cmp::min(bufs.len(), libc::UIO_MAXIOV as usize)];
+        // The guards have to be kept alive while the memory is accessed.
+        let guards: Vec<_> = bufs.iter().map(|buf| buf.ptr_guard_mut()).collect();
+        let iovecs: Vec<libc::iovec> = guards
+            .iter()
+            .zip(bufs)
+            .map(|(guard, buf)| libc::iovec {
+                iov_base: guard.as_ptr().cast(),
+                iov_len: buf.len(),
+            })
+            .collect();
+        // SAFETY: Safe because the file descriptor is valid, and the `iovecs` describe the
+        // `bufs` memory areas, which are valid for writes by the invariants of `VolatileSlice`.
+        // The number of `iovecs` is at most UIO_MAXIOV, so the cast to `c_int` is safe too.
+        let bytes_read = unsafe {
+            libc::preadv(
+                self.as_raw_fd(),
+                iovecs.as_ptr(),
+                iovecs.len() as libc::c_int,
+                offset,
+            )
+        };
+        if bytes_read < 0 {
+            // We don't know if a partial read happened, so mark everything as dirty.
+            for buf in bufs {
+                buf.bitmap().mark_dirty(0, buf.len());
+            }
+            return Err(io::Error::last_os_error());
+        }
+        // The cast is safe since `bytes_read` is not negative.
+        let bytes_read = bytes_read as usize;
+        let mut remaining = bytes_read;
+        for buf in bufs {
+            let len = cmp::min(remaining, buf.len());
+            buf.bitmap().mark_dirty(0, len);
+            remaining -= len;
+        }
+        Ok(bytes_read)
+    }
+
+    fn pwritev<S: BitmapSlice>(
+        &mut self,
+        bufs: &[VolatileSlice<S>],
+        offset: u64,
+    ) -> io::Result<usize> {
+        let offset = libc::off_t::try_from(offset)
+            .map_err(|_| io::Error::from_raw_os_error(libc::EINVAL))?;
+        // Any buffers over the limit are left for a subsequent call.
+        let bufs = &bufs[..cmp::min(bufs.len(), libc::UIO_MAXIOV as usize)];
+        // The guards have to be kept alive while the memory is accessed.
+        let guards: Vec<_> = bufs.iter().map(|buf| buf.ptr_guard()).collect();
+        let iovecs: Vec<libc::iovec> = guards
+            .iter()
+            .zip(bufs)
+            .map(|(guard, buf)| libc::iovec {
+                iov_base: guard.as_ptr() as *mut libc::c_void,
+                iov_len: buf.len(),
+            })
+            .collect();
+        // SAFETY: Safe because the file descriptor is valid, and the `iovecs` describe the
+        // `bufs` memory areas, which are valid for reads by the invariants of `VolatileSlice`.
+        // The number of `iovecs` is at most UIO_MAXIOV, so the cast to `c_int` is safe too.
+        let bytes_written = unsafe {
+            libc::pwritev(
+                self.as_raw_fd(),
+                iovecs.as_ptr(),
+                iovecs.len() as libc::c_int,
+                offset,
+            )
+        };
+        if bytes_written < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // The cast is safe since `bytes_written` is not negative.
+        Ok(bytes_written as usize)
+    }
+}
+
+/// Extension of [`Backend`] for the backends that can be duplicated.
+///
+/// [`StdIoBackend::clone_for_queue`] and [`StdIoBackend::concurrent_execute`] use it for
+/// executing requests on the same data from several executors. The duplicate has to access the
+/// same data, but it must have its own position, since the executors seek before every transfer.
+/// It is implemented for `std::fs::File` on Linux, where the file is reopened through
+/// `/proc/self/fd`, unlike with `File::try_clone`, which shares the position.
+pub trait TryClone: Sized {
+    /// Returns a new handle for the same data, with its own position.
+    fn try_clone(&self) -> io::Result<Self>;
+}
+
+#[cfg(target_os = "linux")]
+impl TryClone for File {
+    fn try_clone(&self) -> io::Result<Self> {
+        let fd = self.as_raw_fd();
+        // SAFETY: Safe because `fd` is a valid file descriptor, and F_GETFL doesn't change it.
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        if flags < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let access_mode = flags & libc::O_ACCMODE;
+        OpenOptions::new()
+            .read(access_mode != libc::O_WRONLY)
+            .write(access_mode != libc::O_RDONLY)
+            .custom_flags(
+                flags & (libc::O_DIRECT | libc::O_DSYNC | libc::O_SYNC | libc::O_NONBLOCK),
+            )
+            .open(format!("/proc/self/fd/{}", fd))
+    }
+}
+
+/// Extension of [`Backend`] for the zoned block devices.
+///
+/// [`StdIoBackend::execute_zoned`] uses it for executing `VIRTIO_BLK_T_ZONE_REPORT` requests. It
+/// is implemented for `std::fs::File` on Linux, where it relies on the `BLKREPORTZONE` ioctl, so
+/// the file has to be a zoned block device.
+pub trait ZonedBackend {
+    /// Returns the descriptions of at most `nr_zones` zones, starting with the zone that
+    /// contains `sector`.
+    ///
+    /// # Arguments
+    /// * `sector` - A sector of the first reported zone.
+    /// * `nr_zones` - The maximum number of zones to report.
+    fn report_zones(&mut self, sector: u64, nr_zones: usize) -> io::Result<Vec<ZoneDescriptor>>;
+}
+
+// `struct blk_zone` from the Linux UAPI.
+#[cfg(target_os = "linux")]
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct BlkZone {
+    start: u64,
+    len: u64,
+    wp: u64,
+    zone_type: u8,
+    cond: u8,
+    non_seq: u8,
+    reset: u8,
+    resv: [u8; 4],
+    capacity: u64,
+    reserved: [u8; 24],
+}
+
+// `struct blk_zone_report` from the Linux UAPI, without the `zones` flexible array member.
+#[cfg(target_os = "linux")]
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct BlkZoneReport {
+    sector: u64,
+    nr_zones: u32,
+    flags: u32,
+}
+
+#[cfg(target_os = "linux")]
+impl BlkZoneReport {
+    // The `capacity` field of the zones is valid.
+    const REP_CAPACITY: u32 = 1;
+}
+
+#[cfg(target_os = "linux")]
+mod ioctls {
+    use vmm_sys_util::{ioctl_ioc_nr, ioctl_iowr_nr};
+
+    ioctl_iowr_nr!(BLKREPORTZONE, 0x12, 130, super::BlkZoneReport);
+}
+
+#[cfg(target_os = "linux")]
+impl ZonedBackend for File {
+    fn report_zones(&mut self, sector: u64, nr_zones: usize) -> io::Result<Vec<ZoneDescriptor>> {
+        const HEADER_LEN: usize = mem::size_of::<BlkZoneReport>();
+        const ZONE_LEN: usize = mem::size_of::<BlkZone>();
+
+        let nr_zones = cmp::min(nr_zones, u32::MAX as usize);
+        if nr_zones == 0 {
+            return Ok(Vec::new());
+        }
+        // The zones follow the header, so the buffer is made of u64s to keep them aligned.
+        let mut buf = vec![0u64; (HEADER_LEN + nr_zones * ZONE_LEN) / mem::size_of::<u64>()];
+        let report = buf.as_mut_ptr() as *mut BlkZoneReport;
+        // SAFETY: Safe because the buffer is large enough and aligned for a `BlkZoneReport`. The
+        // ioctl writes at most `nr_zones` zones after the header, which fit in the buffer too.
+        let ret = unsafe {
+            report.write(BlkZoneReport {
+                sector,
+                // The cast is safe since `nr_zones` was capped to u32::MAX.
+                nr_zones: nr_zones as u32,
+                flags: 0,
+            });
+            ioctl_with_mut_ptr(self, ioctls::BLKREPORTZONE(), report)
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: Safe because the ioctl succeeded, so the header is valid.
+        let header = unsafe { report.read() };
+        let reported = cmp::min(header.nr_zones as usize, nr_zones);
+        Ok((0..reported)
+            .map(|i| {
+                // SAFETY: Safe because the zone is in the buffer, at an offset that is a multiple
+                // of 8, and was initialized by the ioctl.
+                let zone = unsafe {
+                    (buf.as_ptr() as *const u8)
+                        .add(HEADER_LEN + i * ZONE_LEN)
+                        .cast::<BlkZone>()
+                        .read()
+                };
+                let capacity = if header.flags & BlkZoneReport::REP_CAPACITY != 0 {
+                    zone.capacity
+                } else {
+                    zone.len
+                };
+                // The Linux zone types and conditions have the same values as the virtio zone
+                // types and states.
+                ZoneDescriptor::new(zone.zone_type, zone.cond, zone.start, capacity, zone.wp)
+            })
+            .collect())
+    }
+}
+
+// Returns the buffers that remain after skipping the first `count` bytes of `bufs`.
+fn advance_slices<'a, S: BitmapSlice>(
+    bufs: &[VolatileSlice<'a, S>],
+    mut count: usize,
+) -> Result<Vec<VolatileSlice<'a, S>>> {
+    let mut remaining = Vec::with_capacity(bufs.len());
+    for buf in bufs {
+        if count >= buf.len() {
+            count -= buf.len();
+        } else {
+            remaining.push(buf.offset(count).map_err(Error::Backend)?);
+            count = 0;
+        }
+    }
+    Ok(remaining)
+}
+
 /// One or more `DiscardWriteZeroes` structs are used to describe the data for
 /// discard or write zeroes command.
 #[derive(Copy, Clone, Debug, Default)]
@@ -66,61 +407,228 @@ impl DiscardWriteZeroes {
     // The least significant bit from `flags` set -> the targeted range should be unmapped
     // (only valid for write zeroes command).
     const UNMAP: u32 = 1;
-    // Size of DiscardWriteZeroes struct.
-    const LEN: u64 = mem::size_of::<DiscardWriteZeroes>() as u64;
 }
 
+// The segments are parsed as `DiscardWriteZeroes` structs.
+const _: () =
+    assert!(mem::size_of::<DiscardWriteZeroes>() as u64 == VIRTIO_BLK_DISCARD_SEGMENT_LEN);
+
 // SAFETY: Safe because DiscardWriteZeroes contains only plain data.
 unsafe impl ByteValued for DiscardWriteZeroes {}
 
 /// Errors encountered during request execution.
 #[derive(Debug)]
 pub enum Error {
+    /// Error reading or writing the partial physical blocks of an unaligned write request.
+    AlignmentPaddingFailed(io::Error),
+    /// Error accessing the block device backend.
+    Backend(VolatileMemoryError),
+    /// Error cloning the handle of the backing file.
+    Clone(io::Error),
+    /// The deadline of the request passed before its execution completed.
+    DeadlineExceeded,
+    /// A data descriptor is larger than the device accepts.
+    DescriptorTooLarge {
+        /// Length of the data descriptor.
+        len: u32,
+        /// Maximum length accepted by the device.
+        max: u32,
+    },
     ///  Error during write zeroes request execution.
     DiscardWriteZeroes(io::Error),
+    /// A data descriptor crosses a DMA page boundary, and splitting the transfers is disabled.
+    DmaPageCrossing {
+        /// Guest address of the data descriptor.
+        addr: u64,
+        /// Size of the DMA pages.
+        page_size: u64,
+    },
+    /// The features violate the constraints of the specification.
+    Features(FeatureError),
     /// Error during flush request execution.
     Flush(io::Error),
     /// Invalid memory address.
     GuestMemory(GuestMemoryError),
     /// Invalid file access.
     InvalidAccess,
+    /// The logical or physical block size is not a power of two of at least `SECTOR_SIZE`
+    /// bytes.
+    InvalidBlockSize(u32),
     /// Discard/Write Zeroes command has invalid flags.
     InvalidFlags,
     /// Invalid data length of request.
     InvalidDataLength,
+    /// The number of sectors exceeds the size of the backing object.
+    InvalidNumSectors(u64),
+    /// There is no in-flight request with the given identifier.
+    InvalidRequestId(RequestId),
+    /// The serial is not an ASCII string of at most `VIRTIO_BLK_ID_BYTES` bytes.
+    InvalidSerial,
+    /// Error writing or reading the write-ahead journal.
+    Journal(io::Error),
+    /// The block device backend was not provided to the builder.
+    MissingBackend,
+    /// The sector at the given index contains non-zero data.
+    NonZeroSector(u64),
     /// Overflow when computing memory address.
     Overflow,
+    /// Error writing the data of the device to the destination of
+    /// [`pipe_to`](struct.StdIoBackend.html#method.pipe_to).
+    Pipe(io::Error),
+    /// The backing file descriptor was not ready for the I/O before the poll timeout expired.
+    PollTimeout {
+        /// The file descriptor that was polled.
+        fd: RawFd,
+    },
     /// Error during read request execution.
     // The `u32` represents the number of bytes written to memory until the error occurred.
     Read(GuestMemoryError, u32),
+    /// Error opening the block device backing file.
+    Open(io::Error),
+    /// The request modifies a protected sector, whose index is given.
+    ProtectedRange {
+        /// The first protected sector modified by the request.
+        sector: u64,
+    },
+    /// Too many requests are being executed by the executors of the device.
+    QueueFull {
+        /// Number of requests being executed.
+        depth: usize,
+        /// Maximum number of requests that can be executed at the same time.
+        max: usize,
+    },
     /// Can't execute an operation other than `read` on a read-only device.
     ReadOnly,
+    /// The request, or its discard/write zeroes segment, has more sectors than the device
+    /// accepts.
+    RequestTooLarge {
+        /// Number of sectors of the request or segment.
+        request_sectors: u64,
+        /// Maximum number of sectors accepted by the device.
+        max_sectors: u32,
+    },
     /// Error during write request execution.
     Write(GuestMemoryError),
     /// Error during file seek execution.
     Seek(io::Error),
+    /// The request has more data descriptors, or discard/write zeroes segments, than the device
+    /// accepts.
+    TooManySegments {
+        /// Number of segments of the request.
+        count: usize,
+        /// Maximum number of segments accepted by the device.
+        max: u32,
+    },
     /// Can't execute an unsupported request.
     Unsupported(u32),
+    /// The backend would block before the request data was completely transferred.
+    WouldBlock(PendingRequest),
+    /// Error during zone report request execution.
+    ZoneReport(io::Error),
 }
 
 impl Error {
-    fn status(&self) -> u8 {
+    /// Returns the status that is reported to the driver for the error.
+    pub fn to_virtio_status(&self) -> VirtioBlkReqStatus {
+        match self {
+            Error::AlignmentPaddingFailed(_) => VirtioBlkReqStatus::IoError,
+            Error::Backend(_) => VirtioBlkReqStatus::IoError,
+            Error::Clone(_) => VirtioBlkReqStatus::IoError,
+            Error::DeadlineExceeded => VirtioBlkReqStatus::IoError,
+            Error::DescriptorTooLarge { .. } => VirtioBlkReqStatus::IoError,
+            Error::DiscardWriteZeroes(_) => VirtioBlkReqStatus::IoError,
+            Error::DmaPageCrossing { .. } => VirtioBlkReqStatus::IoError,
+            Error::Features(_) => VirtioBlkReqStatus::IoError,
+            Error::Flush(_) => VirtioBlkReqStatus::IoError,
+            Error::GuestMemory(_) => VirtioBlkReqStatus::IoError,
+            Error::InvalidAccess => VirtioBlkReqStatus::IoError,
+            Error::InvalidBlockSize(_) => VirtioBlkReqStatus::IoError,
+            Error::InvalidFlags => VirtioBlkReqStatus::Unsupported,
+            Error::InvalidDataLength => VirtioBlkReqStatus::IoError,
+            Error::InvalidNumSectors(_) => VirtioBlkReqStatus::IoError,
+            Error::InvalidRequestId(_) => VirtioBlkReqStatus::IoError,
+            Error::InvalidSerial => VirtioBlkReqStatus::IoError,
+            Error::Journal(_) => VirtioBlkReqStatus::IoError,
+            Error::MissingBackend => VirtioBlkReqStatus::IoError,
+            Error::NonZeroSector(_) => VirtioBlkReqStatus::IoError,
+            Error::Open(_) => VirtioBlkReqStatus::IoError,
+            Error::Overflow => VirtioBlkReqStatus::IoError,
+            Error::Pipe(_) => VirtioBlkReqStatus::IoError,
+            Error::PollTimeout { .. } => VirtioBlkReqStatus::IoError,
+            Error::ProtectedRange { .. } => VirtioBlkReqStatus::IoError,
+            Error::QueueFull { .. } => VirtioBlkReqStatus::IoError,
+            Error::Read(_, _) => VirtioBlkReqStatus::IoError,
+            Error::ReadOnly => VirtioBlkReqStatus::IoError,
+            Error::RequestTooLarge { .. } => VirtioBlkReqStatus::IoError,
+            Error::Write(_) => VirtioBlkReqStatus::IoError,
+            Error::Seek(_) => VirtioBlkReqStatus::IoError,
+            Error::TooManySegments { .. } => VirtioBlkReqStatus::IoError,
+            Error::Unsupported(_) => VirtioBlkReqStatus::Unsupported,
+            Error::WouldBlock(_) => VirtioBlkReqStatus::IoError,
+            Error::ZoneReport(_) => VirtioBlkReqStatus::IoError,
+        }
+    }
+
+    // Returns the kind of the I/O error that caused the error, or `io::ErrorKind::Other` if it
+    // wasn't caused by an I/O error.
+    fn io_error_kind(&self) -> io::ErrorKind {
+        match self {
+            Error::WouldBlock(_) => io::ErrorKind::WouldBlock,
+            Error::PollTimeout { .. } => io::ErrorKind::TimedOut,
+            Error::AlignmentPaddingFailed(err)
+            | Error::Clone(err)
+            | Error::DiscardWriteZeroes(err)
+            | Error::Flush(err)
+            | Error::Journal(err)
+            | Error::Open(err)
+            | Error::Pipe(err)
+            | Error::Seek(err)
+            | Error::ZoneReport(err) => err.kind(),
+            Error::Backend(VolatileMemoryError::IOError(err))
+            | Error::Read(GuestMemoryError::IOError(err), _)
+            | Error::Write(GuestMemoryError::IOError(err)) => err.kind(),
+            _ => io::ErrorKind::Other,
+        }
+    }
+
+    /// Returns the I/O error that caused the error, or `None` if it wasn't caused by an I/O
+    /// error.
+    pub fn into_io_error(self) -> Option<io::Error> {
+        self.split_io_error().ok()
+    }
+
+    // Returns the I/O error that caused the error, or the error itself if it wasn't caused by an
+    // I/O error.
+    fn split_io_error(self) -> result::Result<io::Error, Self> {
         match self {
-            // The conversions from u32 to u8 are all safe because the status constants are <= 2.
-            Error::DiscardWriteZeroes(_) => VIRTIO_BLK_S_IOERR as u8,
-            Error::Flush(_) => VIRTIO_BLK_S_IOERR as u8,
-            Error::GuestMemory(_) => VIRTIO_BLK_S_IOERR as u8,
-            Error::InvalidAccess => VIRTIO_BLK_S_IOERR as u8,
-            Error::InvalidFlags => VIRTIO_BLK_S_UNSUPP as u8,
-            Error::InvalidDataLength => VIRTIO_BLK_S_IOERR as u8,
-            Error::Overflow => VIRTIO_BLK_S_IOERR as u8,
-            Error::Read(_, _) => VIRTIO_BLK_S_IOERR as u8,
-            Error::ReadOnly => VIRTIO_BLK_S_IOERR as u8,
-            Error::Write(_) => VIRTIO_BLK_S_IOERR as u8,
-            Error::Seek(_) => VIRTIO_BLK_S_IOERR as u8,
-            Error::Unsupported(_) => VIRTIO_BLK_S_UNSUPP as u8,
+            Error::AlignmentPaddingFailed(err)
+            | Error::Clone(err)
+            | Error::DiscardWriteZeroes(err)
+            | Error::Flush(err)
+            | Error::Journal(err)
+            | Error::Open(err)
+            | Error::Pipe(err)
+            | Error::Seek(err)
+            | Error::ZoneReport(err) => Ok(err),
+            Error::Backend(VolatileMemoryError::IOError(err))
+            | Error::Read(GuestMemoryError::IOError(err), _)
+            | Error::Write(GuestMemoryError::IOError(err)) => Ok(err),
+            err => Err(err),
         }
     }
+
+    /// Returns whether the error is transient, so executing the request again may succeed.
+    ///
+    /// This is the case for the errors returned by the backend operations that would block, were
+    /// interrupted or timed out, for poll timeouts, and for full queues. All the other errors are
+    /// permanent.
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, Error::QueueFull { .. })
+            || matches!(
+                self.io_error_kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted | io::ErrorKind::TimedOut
+            )
+    }
 }
 
 impl Display for Error {
@@ -128,27 +636,280 @@ impl Display for Error {
         use self::Error::*;
 
         match self {
+            AlignmentPaddingFailed(ref err) => {
+                write!(f, "failed to pad the unaligned write request: {}", err)
+            }
+            Backend(ref err) => write!(f, "error accessing the block device backend: {}", err),
+            Clone(ref err) => write!(f, "failed to clone the backing file: {}", err),
+            DeadlineExceeded => write!(f, "the request deadline was exceeded"),
+            DescriptorTooLarge { len, max } => write!(
+                f,
+                "data descriptor too large: {} bytes, the maximum is {}",
+                len, max
+            ),
             DiscardWriteZeroes(ref err) => {
                 write!(f, "discard/write zeroes execution failed: {}", err)
             }
+            DmaPageCrossing { addr, page_size } => write!(
+                f,
+                "data descriptor at {:#x} crosses a {} bytes DMA page boundary",
+                addr, page_size
+            ),
+            Features(ref err) => write!(f, "invalid features: {}", err),
             Flush(ref err) => write!(f, "flush execution failed: {}", err),
             GuestMemory(ref err) => write!(f, "error accessing guest memory: {}", err),
             InvalidAccess => write!(f, "invalid file access"),
+            InvalidBlockSize(size) => write!(f, "invalid block size: {}", size),
             InvalidDataLength => write!(f, "invalid data length of request"),
             InvalidFlags => write!(f, "invalid flags for discard/write zeroes request"),
+            InvalidNumSectors(num_sectors) => write!(
+                f,
+                "{} sectors exceed the size of the backing object",
+                num_sectors
+            ),
+            InvalidRequestId(id) => write!(f, "no in-flight request with id {}", id),
+            InvalidSerial => write!(
+                f,
+                "the serial must be an ASCII string of at most {} bytes",
+                VIRTIO_BLK_ID_BYTES
+            ),
+            Journal(ref err) => write!(f, "error accessing the write-ahead journal: {}", err),
+            MissingBackend => write!(f, "the block device backend was not provided"),
+            NonZeroSector(sector) => write!(f, "sector {} contains non-zero data", sector),
+            Open(ref err) => write!(f, "failed to open the backing file: {}", err),
             Overflow => write!(f, "overflow when computing memory address"),
+            Pipe(ref err) => write!(f, "failed to write the device data: {}", err),
+            PollTimeout { fd } => write!(f, "file descriptor {} is not ready for I/O", fd),
+            ProtectedRange { sector } => {
+                write!(f, "the request modifies the protected sector {}", sector)
+            }
+            QueueFull { depth, max } => write!(
+                f,
+                "too many requests in execution: {}, the maximum is {}",
+                depth, max
+            ),
             Read(ref err, _) => write!(f, "error during read request execution: {}", err),
             ReadOnly => write!(
                 f,
                 "can't execute an operation other than `read` on a read-only device"
             ),
+            RequestTooLarge {
+                request_sectors,
+                max_sectors,
+            } => write!(
+                f,
+                "too many sectors in request or segment: {}, the maximum is {}",
+                request_sectors, max_sectors
+            ),
             Write(ref err) => write!(f, "error during write request execution: {}", err),
             Seek(ref err) => write!(f, "file seek execution failed: {}", err),
+            TooManySegments { count, max } => write!(
+                f,
+                "too many segments in request: {}, the maximum is {}",
+                count, max
+            ),
             Unsupported(t) => write!(f, "can't execute unsupported request {}", t),
+            WouldBlock(pending) => write!(
+                f,
+                "the backend would block with {} bytes of request data left to transfer",
+                pending.remaining_bytes
+            ),
+            ZoneReport(ref err) => write!(f, "error during zone report request execution: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<Error> for io::Error {
+    /// Returns the I/O error that caused `err`, or a new I/O error wrapping `err` if it wasn't
+    /// caused by an I/O error. The new error is of kind `io::ErrorKind::WouldBlock` for
+    /// [`Error::WouldBlock`](enum.Error.html#variant.WouldBlock), and `io::ErrorKind::Other`
+    /// otherwise.
+    fn from(err: Error) -> Self {
+        err.split_io_error()
+            .unwrap_or_else(|err| io::Error::new(err.io_error_kind(), err))
+    }
+}
+
+/// A failed operation of [`StdIoBackend::stress_test`].
+#[derive(Debug)]
+pub enum StressError {
+    /// The operation returned an error.
+    Operation(Error),
+    /// A read returned different data than the one last written to the given sector.
+    Mismatch(u64),
+}
+
+/// The results of [`StdIoBackend::stress_test`].
+#[derive(Debug, Default)]
+pub struct StressTestReport {
+    /// The number of operations that succeeded.
+    pub passed: usize,
+    /// The number of operations that failed.
+    pub failed: usize,
+    /// The failures, in the order in which they happened.
+    pub errors: Vec<StressError>,
+}
+
+/// The geometry of the disk, as returned by [`StdIoBackend::disk_metadata`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DiskMetadata {
+    /// The capacity of the disk in bytes.
+    pub capacity_bytes: u64,
+    /// The number of 512-byte sectors of the disk.
+    pub num_sectors: u64,
+    /// The logical block size reported to the driver.
+    pub logical_block_size: u32,
+}
+
+/// The results of [`StdIoBackend::integrity_scan`].
+#[derive(Debug, Default)]
+pub struct IntegrityScanReport {
+    /// The sectors that couldn't be read, in ascending order.
+    pub bad_sectors: Vec<u64>,
+    /// The errors returned when reading the bad sectors, together with their index.
+    pub io_errors: Vec<(u64, io::Error)>,
+}
+
+impl IntegrityScanReport {
+    /// Returns whether all the sectors could be read.
+    pub fn is_clean(&self) -> bool {
+        self.bad_sectors.is_empty()
+    }
+}
+
+/// The sectors that contain non-zero data, as returned by [`StdIoBackend::sector_map`].
+///
+/// The bits use the same layout as the `vm-memory` dirty bitmaps: bit `i % 64` of word `i / 64`
+/// is set if sector `i` contains non-zero data.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SectorMap {
+    words: Vec<u64>,
+    num_sectors: u64,
+}
+
+impl SectorMap {
+    // Creates a map of `num_sectors` sectors that contain only zeroes.
+    fn new(num_sectors: u64) -> Self {
+        SectorMap {
+            words: vec![0; num_sectors.div_ceil(64) as usize],
+            num_sectors,
         }
     }
+
+    // Marks `sector` as containing non-zero data.
+    fn set(&mut self, sector: u64) {
+        self.words[(sector / 64) as usize] |= 1 << (sector % 64);
+    }
+
+    /// Returns whether `sector` contains non-zero data. The sectors past the end of the device
+    /// don't.
+    ///
+    /// # Arguments
+    /// * `sector` - The sector to look up.
+    pub fn is_set(&self, sector: u64) -> bool {
+        sector < self.num_sectors && self.words[(sector / 64) as usize] & (1 << (sector % 64)) != 0
+    }
+
+    /// Returns the number of sectors of the device.
+    pub fn len(&self) -> u64 {
+        self.num_sectors
+    }
+
+    /// Returns whether the device has no sectors.
+    pub fn is_empty(&self) -> bool {
+        self.num_sectors == 0
+    }
+
+    /// Returns the sectors that contain non-zero data, in ascending order.
+    pub fn set_sectors(&self) -> impl Iterator<Item = u64> + '_ {
+        (0..self.num_sectors).filter(move |&sector| self.is_set(sector))
+    }
+
+    /// Returns the words of the bitmap, e.g. for merging it with a dirty bitmap.
+    pub fn as_words(&self) -> &[u64] {
+        &self.words
+    }
+}
+
+/// The progress of an `In` or `Out` request whose execution stopped because the backend would
+/// block, e.g. when it is a block device opened with `O_NONBLOCK`.
+///
+/// The execution can be resumed with [`StdIoBackend::resume`] once the backend is ready again.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PendingRequest {
+    /// The number of request data bytes that were transferred, i.e. the offset in the request
+    /// data where the execution has to be resumed.
+    pub transferred_bytes: u64,
+    /// The number of request data bytes that were not transferred yet.
+    pub remaining_bytes: u64,
 }
 
+/// What the VMM has to do after a request completes, as decided by the interrupt coalescing of
+/// a [`StdIoBackend`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CompletionAction {
+    /// Whether the guest has to be interrupted for the completions since the last interrupt.
+    pub fire_interrupt: bool,
+}
+
+/// When a [`StdIoBackend`] syncs its backing object.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FlushPolicy {
+    /// Sync after every `Out` request, in addition to the flush requests and barriers.
+    Always,
+    /// Sync for the flush requests and barriers, when `VIRTIO_BLK_F_FLUSH` was negotiated.
+    #[default]
+    OnFeature,
+    /// Never sync, e.g. for backing objects in memory. Flush requests and barriers succeed
+    /// without doing anything.
+    Never,
+}
+
+/// How [`StdIoBackend::wipe_for_decommission`] erases the device.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WipeStandard {
+    /// A single pass writing zeroes.
+    Zeros,
+    /// Three passes writing `0x00`, `0xFF` and pseudo-random data respectively, as described by
+    /// DoD 5220.22-M.
+    DoD5220_22M,
+    /// A single pass writing zeroes, as the Clear method of NIST SP 800-88 does.
+    NIST800_88,
+}
+
+/// Marker trait for VMM error types that can absorb an execution [`Error`].
+///
+/// It is implemented for every error type that provides a `From<Error>` conversion, so VMMs can
+/// use `?` on the result of [`StdIoBackend::execute`] directly. With `thiserror`, that conversion
+/// is usually obtained with a transparent `#[from]` variant.
+///
+/// # Example
+///
+/// ```rust
+/// # use virtio_blk::stdio_executor::{self, VmmBlockError};
+/// #[derive(Debug, thiserror::Error)]
+/// enum MyVmmError {
+///     #[error(transparent)]
+///     Block(#[from] stdio_executor::Error),
+/// }
+///
+/// fn assert_vmm_block_error<E: VmmBlockError>() {}
+/// assert_vmm_block_error::<MyVmmError>();
+///
+/// fn execute() -> Result<(), MyVmmError> {
+///     Err(stdio_executor::Error::InvalidAccess)?
+/// }
+/// assert!(matches!(
+///     execute(),
+///     Err(MyVmmError::Block(stdio_executor::Error::InvalidAccess))
+/// ));
+/// ```
+pub trait VmmBlockError: std::error::Error + From<Error> {}
+
+impl<E: std::error::Error + From<Error>> VmmBlockError for E {}
+
 /// Errors encountered while processing a request execution result.
 #[derive(Debug)]
 pub enum ProcessReqError {
@@ -156,14 +917,69 @@ pub enum ProcessReqError {
     GuestMemory(GuestMemoryError),
     /// Overflow occurred when computing number of bytes written to memory.
     Overflow,
+    /// The execution failed with a retryable error, so the status was not written and the
+    /// request can be processed again later.
+    Retry(Error),
+}
+
+impl Display for ProcessReqError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::ProcessReqError::*;
+
+        match self {
+            GuestMemory(ref err) => write!(f, "error accessing guest memory: {}", err),
+            Overflow => write!(
+                f,
+                "overflow when computing number of bytes written to memory"
+            ),
+            Retry(ref err) => write!(f, "request can be retried: {}", err),
+        }
+    }
 }
 
+impl std::error::Error for ProcessReqError {}
+
 impl From<vm_memory::GuestMemoryError> for ProcessReqError {
     fn from(e: vm_memory::GuestMemoryError) -> Self {
         ProcessReqError::GuestMemory(e)
     }
 }
 
+/// Errors of [`StdIoBackend::validate_features`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FeatureError {
+    /// The two features can't be negotiated together.
+    ConflictingFeatures {
+        /// The bit of the first feature.
+        a: u32,
+        /// The bit of the second feature.
+        b: u32,
+    },
+    /// A feature was negotiated without another feature it requires.
+    MissingDependency {
+        /// The bit of the required feature, which was not negotiated.
+        required: u32,
+        /// The bit of the negotiated feature that requires it.
+        depends_on: u32,
+    },
+}
+
+impl Display for FeatureError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FeatureError::ConflictingFeatures { a, b } => {
+                write!(f, "features {} and {} can't be negotiated together", a, b)
+            }
+            FeatureError::MissingDependency {
+                required,
+                depends_on,
+            } => write!(f, "feature {} requires feature {}", depends_on, required),
+        }
+    }
+}
+
+impl std::error::Error for FeatureError {}
+
 /// Dedicated [`Result`](https://doc.rust-lang.org/std/result/) type.
 pub type Result<T> = result::Result<T, Error>;
 
@@ -182,15 +998,211 @@ pub type Result<T> = result::Result<T, Error>;
 /// ```
 #[derive(Debug)]
 pub struct StdIoBackend<B: Backend> {
-    /// The block device backing file.
-    inner: B,
+    /// The block device backing file, synced on drop if enabled.
+    inner: FlushOnDrop<B>,
     /// The number of sectors of `inner`.
     num_sectors: u64,
     /// The disk features.
     features: u64,
+    /// The logical block size reported to the driver (`blk_size`).
+    logical_block_size: u32,
+    /// The block size the writes to the backing object have to be aligned to, if any.
+    physical_block_size: Option<u32>,
     /// The device id string, which is a NUL-padded ASCII string up to 20 bytes long.
     /// If the string is 20 bytes long, then there is no NUL terminator.
     device_id: Option<[u8; VIRTIO_BLK_ID_BYTES as usize]>,
+    /// The maximum length of a data descriptor (`size_max`).
+    size_max: u32,
+    /// The maximum number of data descriptors in a request (`seg_max`).
+    seg_max: u32,
+    /// The maximum number of segments in a discard request (`max_discard_seg`).
+    max_discard_seg: u32,
+    /// The maximum number of segments in a write zeroes request (`max_write_zeroes_seg`).
+    max_write_zeroes_seg: u32,
+    /// The maximum number of sectors in a discard segment (`max_discard_sectors`).
+    max_discard_sectors: u32,
+    /// The maximum number of sectors in a write zeroes segment (`max_write_zeroes_sectors`).
+    max_write_zeroes_sectors: u32,
+    /// The statistics of the executed requests.
+    stats: Arc<BlockStats>,
+    /// The deadline of the request that is being executed, if any.
+    deadline: Option<Instant>,
+    /// The ranges of sectors that can't be modified, sorted by their first sector.
+    protected_ranges: Vec<RangeInclusive<u64>>,
+    /// The lock shared with the other executors of requests for the same disk, if any.
+    range_lock: Option<Arc<SectorRangeLock>>,
+    /// The write and flush counters, shared with the clones of the executor.
+    flush_state: Arc<FlushState>,
+    /// The minimum number of sectors of the `In` requests whose guest memory is prefetched, or 0
+    /// if prefetching is disabled.
+    prefetch_threshold: u64,
+    /// The maximum number of sectors of an `In` or `Out` request, or 0 if there is no limit.
+    max_io_sectors: u32,
+    /// Whether the `In` and `Out` requests over `max_io_sectors` are split instead of rejected.
+    split_io: bool,
+    /// The size of the pages the guest memory transfers must not cross, or 0 if there is none.
+    dma_page_size: u64,
+    /// Whether the transfers crossing `dma_page_size` pages are split instead of rejected.
+    split_dma: bool,
+    /// The maximum number of completions, and the maximum time since the first one, before an
+    /// interrupt is fired, if interrupt coalescing is enabled.
+    interrupt_coalescing: Option<(usize, Duration)>,
+    /// The number of completions since the last interrupt.
+    pending_completions: usize,
+    /// The moment of the first completion since the last interrupt, if any.
+    first_pending_completion: Option<Instant>,
+    /// The write-ahead journal of the `Out` requests, if any, shared with the clones of the
+    /// executor.
+    journal: Option<Arc<Mutex<Journal>>>,
+    /// The metric backend the executed requests are reported to, if any, shared with the clones
+    /// of the executor.
+    metrics: Option<Arc<Mutex<Box<dyn BlockDeviceMetrics>>>>,
+    /// The number of sectors read ahead of the `In` requests, or 0 if read-ahead is disabled.
+    read_ahead_sectors: u64,
+    /// The sectors that were read ahead, shared with the clones of the `StdIoBackend`.
+    read_ahead_cache: ReadAheadCache,
+    /// The sector following the last `In` request executed by this `StdIoBackend`, if any, used
+    /// for detecting sequential reads.
+    last_read_end: Option<u64>,
+    /// The maximum time to wait for the backing file descriptor to be ready for the I/O of `In`
+    /// and `Out` requests, and the function returning the descriptor, if polling is enabled.
+    poll_timeout: Option<(Duration, RawFdGetter<B>)>,
+    /// The hook called before the execution of the requests that modify the disk, if any.
+    write_audit_hook: Option<WriteAuditHook>,
+    /// The description of the error returned by the last failed request, if any, shared with the
+    /// clones of the executor.
+    last_error: Arc<Mutex<Option<String>>>,
+    /// When the backing object is synced.
+    flush_policy: FlushPolicy,
+    /// The maximum number of requests executed at the same time by this backend and its
+    /// clones, or 0 if there is no limit.
+    max_queue_depth: usize,
+    /// The number of requests being executed by this backend and its clones.
+    pub(crate) current_depth: Arc<AtomicUsize>,
+    /// The buffer reused for the sectors accessed one at a time, or `None` while it is in use.
+    sector_buf: Option<Box<AlignedSector>>,
+}
+
+// The write and flush counters shared by an executor and its clones, since syncing any of their
+// handles syncs all the writes to the backing object.
+#[derive(Debug, Default)]
+struct FlushState {
+    // Counter of the operations that may have modified the backing object.
+    write_lsn: AtomicU64,
+    // One more than the value of `write_lsn` when the backing object was last synced, or 0 if it
+    // was never synced.
+    flushed_lsn: AtomicU64,
+}
+
+impl FlushState {
+    // Returns whether there may be writes that weren't synced yet.
+    fn needs_flush(&self) -> bool {
+        self.flushed_lsn.load(Ordering::SeqCst) != self.write_lsn.load(Ordering::SeqCst) + 1
+    }
+}
+
+// The backing object of a `StdIoBackend`, which is synced when dropped if `enabled` is set.
+#[derive(Debug)]
+struct FlushOnDrop<B: Backend> {
+    // Only `None` while the backing object is moved out by `into_inner`.
+    inner: Option<B>,
+    enabled: bool,
+}
+
+impl<B: Backend> FlushOnDrop<B> {
+    fn new(inner: B) -> Self {
+        FlushOnDrop {
+            inner: Some(inner),
+            enabled: false,
+        }
+    }
+
+    // Returns the backing object without syncing it.
+    fn into_inner(mut self) -> B {
+        self.inner
+            .take()
+            .expect("the backing object was already moved out")
+    }
+}
+
+impl<B: Backend> Deref for FlushOnDrop<B> {
+    type Target = B;
+
+    fn deref(&self) -> &B {
+        self.inner
+            .as_ref()
+            .expect("the backing object was moved out")
+    }
+}
+
+impl<B: Backend> DerefMut for FlushOnDrop<B> {
+    fn deref_mut(&mut self) -> &mut B {
+        self.inner
+            .as_mut()
+            .expect("the backing object was moved out")
+    }
+}
+
+impl<B: Backend> Drop for FlushOnDrop<B> {
+    fn drop(&mut self) {
+        if let (true, Some(inner)) = (self.enabled, self.inner.as_mut()) {
+            if let Err(e) = inner.fsync() {
+                error!("Failed to flush the block device backend on drop: {}", e);
+            }
+        }
+    }
+}
+
+// A sector sized buffer aligned to the largest page size commonly used for direct I/O, so it
+// can be transferred to backing objects opened with `O_DIRECT`.
+#[derive(Debug)]
+#[repr(C, align(4096))]
+struct AlignedSector([u8; SECTOR_SIZE as usize]);
+
+// The sectors read ahead of the `In` requests, shared by an executor and its clones.
+type ReadAheadCache = Arc<Mutex<HashMap<u64, [u8; SECTOR_SIZE as usize]>>>;
+
+// Invalidates the read-ahead cache when dropped, i.e. once a write to the backing object is
+// over, even if it failed. A read-ahead that raced with the write may have cached the old data
+// of the modified sectors after the write started.
+#[must_use]
+struct WriteGuard(Option<ReadAheadCache>);
+
+impl Drop for WriteGuard {
+    fn drop(&mut self) {
+        if let Some(cache) = self.0.take() {
+            cache.lock().unwrap().clear();
+        }
+    }
+}
+
+// Decrements the number of requests being executed when dropped, even if the execution panics.
+struct DepthGuard(Arc<AtomicUsize>);
+
+impl Drop for DepthGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// What `validate_request` prepares for executing a request.
+#[derive(Default)]
+struct ValidatedRequest {
+    // The sub-requests that an `In` or `Out` request has to be split into, if any.
+    sub_requests: Option<Vec<Request>>,
+    // The segments of a `Discard` or `WriteZeroes` request, which were all checked.
+    segments: Vec<DiscardWriteZeroes>,
+}
+
+// A hook called with the requests that modify the disk, shared with the clones of the
+// `StdIoBackend`.
+#[derive(Clone)]
+struct WriteAuditHook(Arc<dyn Fn(&Request) + Send + Sync>);
+
+impl fmt::Debug for WriteAuditHook {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("WriteAuditHook")
+    }
 }
 
 impl<B: Backend> StdIoBackend<B> {
@@ -199,7 +1211,37 @@ impl<B: Backend> StdIoBackend<B> {
     /// # Arguments
     /// * `inner` - The block device backend.
     /// * `features` - The features that were negotiated between driver and device.
-    pub fn new(mut inner: B, features: u64) -> Result<Self> {
+    pub fn new(inner: B, features: u64) -> Result<Self> {
+        StdIoBackendBuilder::new()
+            .inner(inner)
+            .features(features)
+            .build()
+    }
+
+    /// Creates a new `StdIoBackend` with the configuration of `builder`.
+    ///
+    /// # Arguments
+    /// * `builder` - The configuration of the `StdIoBackend`.
+    pub fn from_builder(builder: StdIoBackendBuilder<B>) -> Result<Self> {
+        let mut inner = builder.inner.ok_or(Error::MissingBackend)?;
+        Self::validate_features(builder.features).map_err(Error::Features)?;
+        let logical_block_size = builder.logical_block_size;
+        Self::check_logical_block_size(logical_block_size)?;
+        if let Some(physical_block_size) = builder.physical_block_size {
+            Self::check_logical_block_size(physical_block_size)?;
+        }
+        let device_id = match builder.serial {
+            Some(serial) => {
+                if !serial.is_ascii() || serial.len() > VIRTIO_BLK_ID_BYTES as usize {
+                    return Err(Error::InvalidSerial);
+                }
+                let mut device_id = [0u8; VIRTIO_BLK_ID_BYTES as usize];
+                device_id[..serial.len()].copy_from_slice(serial.as_bytes());
+                Some(device_id)
+            }
+            None => None,
+        };
+
         let disk_size = inner.seek(SeekFrom::End(0)).map_err(Error::Seek)?;
         // This check makes sense only if VIRTIO_BLK_F_BLK_SIZE feature is
         // unsupported, which might be okay to assume for now.
@@ -214,10 +1256,43 @@ impl<B: Backend> StdIoBackend<B> {
         }
 
         Ok(Self {
-            inner,
+            inner: FlushOnDrop::new(inner),
             num_sectors: disk_size >> SECTOR_SHIFT,
-            features,
-            device_id: None,
+            features: builder.features,
+            logical_block_size,
+            physical_block_size: builder.physical_block_size,
+            device_id,
+            size_max: u32::MAX,
+            seg_max: u32::MAX,
+            max_discard_seg: builder.max_discard_seg,
+            max_write_zeroes_seg: builder.max_write_zeroes_seg,
+            max_discard_sectors: DEFAULT_MAX_DISCARD_SECTORS,
+            max_write_zeroes_sectors: DEFAULT_MAX_WRITE_ZEROES_SECTORS,
+            stats: Arc::new(BlockStats::new()),
+            deadline: None,
+            protected_ranges: Vec::new(),
+            range_lock: None,
+            flush_state: Arc::new(FlushState::default()),
+            prefetch_threshold: 0,
+            max_io_sectors: 0,
+            split_io: false,
+            dma_page_size: 0,
+            split_dma: false,
+            interrupt_coalescing: None,
+            pending_completions: 0,
+            first_pending_completion: None,
+            journal: None,
+            metrics: None,
+            read_ahead_sectors: 0,
+            read_ahead_cache: Arc::new(Mutex::new(HashMap::new())),
+            last_read_end: None,
+            poll_timeout: None,
+            write_audit_hook: None,
+            last_error: Arc::new(Mutex::new(None)),
+            flush_policy: FlushPolicy::default(),
+            max_queue_depth: 0,
+            current_depth: Arc::new(AtomicUsize::new(0)),
+            sector_buf: Some(Box::new(AlignedSector([0u8; SECTOR_SIZE as usize]))),
         })
     }
 
@@ -231,37 +1306,546 @@ impl<B: Backend> StdIoBackend<B> {
         self
     }
 
-    fn has_feature(&self, feature_pos: u64) -> bool {
-        (self.features & (1u64 << feature_pos)) != 0
+    /// Returns the `device_id`, if it was set.
+    pub fn device_id(&self) -> Option<&[u8; VIRTIO_BLK_ID_BYTES as usize]> {
+        self.device_id.as_ref()
     }
 
-    fn num_sectors(&self) -> u64 {
-        self.num_sectors
+    /// Replaces the `device_id`, e.g. after a new disk image was attached to the device.
+    ///
+    /// # Arguments
+    /// * `device_id` - The new block device id.
+    pub fn set_device_id(&mut self, device_id: [u8; VIRTIO_BLK_ID_BYTES as usize]) {
+        self.device_id = Some(device_id);
     }
 
-    /// Processes the `request` execution result, writes its status in memory and returns the used
-    /// length (i.e. the total number of bytes written into the memory buffer, including the status
-    /// byte).
+    /// Sets the maximum length of a data descriptor.
     ///
     /// # Arguments
-    /// * `mem` - A reference to the guest memory.
-    /// * `request` - The request to execute.
-    pub fn process_request<M: GuestMemory>(
-        &mut self,
+    /// * `size_max` - The `size_max` value from the device configuration space.
+    pub fn with_size_max(mut self, size_max: u32) -> Self {
+        self.size_max = size_max;
+        self
+    }
+
+    /// Sets the maximum number of data descriptors in a request.
+    ///
+    /// # Arguments
+    /// * `seg_max` - The `seg_max` value from the device configuration space.
+    pub fn with_seg_max(mut self, seg_max: u32) -> Self {
+        self.seg_max = seg_max;
+        self
+    }
+
+    /// Sets the maximum number of segments in a discard request.
+    ///
+    /// # Arguments
+    /// * `max_discard_seg` - The `max_discard_seg` value from the device configuration space.
+    pub fn with_max_discard_seg(mut self, max_discard_seg: u32) -> Self {
+        self.max_discard_seg = max_discard_seg;
+        self
+    }
+
+    /// Sets the maximum number of segments in a write zeroes request.
+    ///
+    /// # Arguments
+    /// * `max_write_zeroes_seg` - The `max_write_zeroes_seg` value from the device configuration
+    ///                            space.
+    pub fn with_max_write_zeroes_seg(mut self, max_write_zeroes_seg: u32) -> Self {
+        self.max_write_zeroes_seg = max_write_zeroes_seg;
+        self
+    }
+
+    /// Sets the maximum number of sectors in a discard segment.
+    ///
+    /// # Arguments
+    /// * `max_discard_sectors` - The `max_discard_sectors` value from the device configuration
+    ///                           space.
+    pub fn with_max_discard_sectors(mut self, max_discard_sectors: u32) -> Self {
+        self.max_discard_sectors = max_discard_sectors;
+        self
+    }
+
+    /// Sets the maximum number of sectors in a write zeroes segment.
+    ///
+    /// # Arguments
+    /// * `max_write_zeroes_sectors` - The `max_write_zeroes_sectors` value from the device
+    ///                                configuration space.
+    pub fn with_max_write_zeroes_sectors(mut self, max_write_zeroes_sectors: u32) -> Self {
+        self.max_write_zeroes_sectors = max_write_zeroes_sectors;
+        self
+    }
+
+    /// Sets the lock used for serializing the accesses to overlapping sectors.
+    ///
+    /// When several `StdIoBackend` objects execute requests for the same disk concurrently (e.g.
+    /// one for each virtqueue), they have to share the same lock. `Out` requests lock their
+    /// sectors exclusively and `In` requests lock them shared, for the duration of the data
+    /// transfer.
+    ///
+    /// # Arguments
+    /// * `range_lock` - The lock shared by all the executors of requests for the disk.
+    pub fn with_range_lock(mut self, range_lock: Arc<SectorRangeLock>) -> Self {
+        self.range_lock = Some(range_lock);
+        self
+    }
+
+    /// Sets whether the backing object is synced when the `StdIoBackend` is dropped.
+    ///
+    /// This is disabled by default. Errors can not be returned from `drop`, so they are only
+    /// logged. [`into_inner`](#method.into_inner) never syncs the backing object.
+    ///
+    /// # Arguments
+    /// * `flush_on_drop` - Whether to call `fsync` on the backing object when dropped.
+    pub fn with_flush_on_drop(mut self, flush_on_drop: bool) -> Self {
+        self.inner.enabled = flush_on_drop;
+        self
+    }
+
+    /// Sets the minimum size of the `In` requests whose guest memory buffers are prefetched.
+    ///
+    /// The host pages backing the buffers of large reads are advised with `MADV_WILLNEED`
+    /// before the data is read, so they are faulted in ahead of the copy. Prefetching is
+    /// disabled by default.
+    ///
+    /// # Arguments
+    /// * `sectors` - The minimum number of sectors of the prefetched requests, or 0 to disable
+    ///               prefetching.
+    pub fn with_prefetch_threshold(mut self, sectors: u64) -> Self {
+        self.prefetch_threshold = sectors;
+        self
+    }
+
+    /// Sets the number of sectors that are read ahead of the `In` requests.
+    ///
+    /// When an `In` request starts at the sector that follows the previous `In` request executed
+    /// by the same `StdIoBackend` and is not served from the read-ahead cache, the `sectors`
+    /// sectors that follow it are read into the cache after its data, so that subsequent
+    /// sequential reads can be served without accessing the backend. A request served from the
+    /// cache that reaches the end of the cached sectors starts the next read-ahead window. Random
+    /// reads don't trigger read-ahead. The cache only holds the sectors of the last read-ahead,
+    /// and is invalidated by every write executed by the `StdIoBackend` or its clones.
+    /// Read-ahead is disabled by default.
+    ///
+    /// The sectors are read synchronously, after the data of the request that triggered the
+    /// read-ahead. Reading them on a background thread would require the backend to be
+    /// `TryClone + Send`, which isn't needed by any other part of the executor.
+    ///
+    /// # Arguments
+    /// * `sectors` - The number of sectors to read ahead, or 0 to disable read-ahead.
+    pub fn with_read_ahead_sectors(mut self, sectors: u64) -> Self {
+        self.read_ahead_sectors = sectors;
+        self
+    }
+
+    /// Sets the hook that is called with every request that modifies the disk, as reported by
+    /// [`Request::is_write_modifying`], before the request is executed.
+    ///
+    /// The hook is called even if the request fails afterwards, e.g. because it targets an
+    /// invalid range, so every attempted modification can be audited. It is shared with the
+    /// clones of the `StdIoBackend`. Resumed requests are reported again, with the part of the
+    /// request that is left.
+    ///
+    /// # Arguments
+    /// * `hook` - The function to call with the requests.
+    pub fn with_write_audit_hook(mut self, hook: Box<dyn Fn(&Request) + Send + Sync>) -> Self {
+        self.write_audit_hook = Some(WriteAuditHook(Arc::from(hook)));
+        self
+    }
+
+    /// Sets when the backing object is synced.
+    ///
+    /// By default, it is synced for flush requests and barriers when `VIRTIO_BLK_F_FLUSH` was
+    /// negotiated. Flush requests are still rejected when the feature wasn't negotiated,
+    /// regardless of the policy.
+    ///
+    /// # Arguments
+    /// * `flush_policy` - When to sync the backing object.
+    pub fn with_flush_policy(mut self, flush_policy: FlushPolicy) -> Self {
+        self.flush_policy = flush_policy;
+        self
+    }
+
+    /// Sets the maximum number of requests that [`execute`](#method.execute) runs at the same
+    /// time, across this `StdIoBackend` and its clones.
+    ///
+    /// When the limit is reached, the requests fail with [`Error::QueueFull`] without being
+    /// executed, so the VMM can stop processing the queue until the pending requests complete.
+    /// There is no limit by default.
+    ///
+    /// # Arguments
+    /// * `max_queue_depth` - The maximum number of requests, or 0 for no limit.
+    pub fn with_max_queue_depth(mut self, max_queue_depth: usize) -> Self {
+        self.max_queue_depth = max_queue_depth;
+        self
+    }
+
+    /// Sets the maximum number of sectors of the `In` and `Out` requests.
+    ///
+    /// Larger requests are either split into consecutive sub-requests of at most `max_sectors`
+    /// sectors, which are executed one after the other, or fail with
+    /// [`Error::RequestTooLarge`]. There is no limit by default.
+    ///
+    /// # Arguments
+    /// * `max_sectors` - The maximum number of sectors of a request, or 0 for no limit.
+    /// * `split` - Whether the larger requests are split instead of rejected.
+    pub fn with_max_io_sectors(mut self, max_sectors: u32, split: bool) -> Self {
+        self.max_io_sectors = max_sectors;
+        self.split_io = split;
+        self
+    }
+
+    /// Sets the size of the pages that the guest memory transfers of the `In` and `Out` requests
+    /// must not cross, e.g. when the guest memory is accessed through an IOMMU mapping pages
+    /// individually.
+    ///
+    /// The transfers of the data descriptors crossing a page boundary are either split at the
+    /// boundaries, or the requests fail with [`Error::DmaPageCrossing`] before any data is
+    /// transferred. There is no constraint by default.
+    ///
+    /// # Arguments
+    /// * `page_size` - The size of the pages, or 0 for no constraint.
+    /// * `split` - Whether the transfers crossing a page boundary are split instead of rejected.
+    pub fn with_dma_page_size(mut self, page_size: u64, split: bool) -> Self {
+        self.dma_page_size = page_size;
+        self.split_dma = split;
+        self
+    }
+
+    /// Enables interrupt coalescing for the requests executed with
+    /// [`execute_coalesced`](#method.execute_coalesced).
+    ///
+    /// The completions are batched until either `max_pending` of them are pending or
+    /// `max_latency_us` microseconds passed since the first one, and then a single interrupt is
+    /// fired. Since the latency is only checked when a request completes, the VMM should also
+    /// call [`poll_interrupt`](#method.poll_interrupt) periodically, e.g. from a timer, so the
+    /// last completions of a burst are not delayed indefinitely.
+    ///
+    /// # Arguments
+    /// * `max_pending` - The maximum number of completions per interrupt.
+    /// * `max_latency_us` - The maximum delay of an interrupt, in microseconds.
+    pub fn with_interrupt_coalescing(mut self, max_pending: usize, max_latency_us: u64) -> Self {
+        self.interrupt_coalescing = Some((max_pending, Duration::from_micros(max_latency_us)));
+        self
+    }
+
+    /// Sets the write-ahead journal of the `Out` requests.
+    ///
+    /// Before the data of an `Out` request is written to the backing object, it is logged to
+    /// `journal` as a [`JournalEntry`](../journal/struct.JournalEntry.html), and `journal` is
+    /// synced. Once the write completes and the backing object is synced, e.g. by a flush
+    /// request, a commit record for the entry is appended. If the backing object is never synced
+    /// according to the [`FlushPolicy`] and the negotiated features, the commit is appended as
+    /// soon as the write completes. The discard and write zeroes requests are logged too, as
+    /// entries that zero out their sectors, and so are the writes of
+    /// [`write_at`](#method.write_at), [`write_same`](#method.write_same),
+    /// [`write_zeroes_range`](#method.write_zeroes_range),
+    /// [`punch_hole_range`](#method.punch_hole_range) and [`copy_range`](#method.copy_range).
+    /// After a crash, [`replay_journal`](#method.replay_journal) performs the writes that were not
+    /// committed, in the order in which they were logged.
+    ///
+    /// The log sequence numbers start from 0, so the journal should start empty, e.g. truncated
+    /// after it was replayed, unless [`replay_journal`](#method.replay_journal) is called with its
+    /// previous content first. The executors cloned from this one, e.g. with
+    /// [`clone_for_queue`](#method.clone_for_queue), log their writes to the same journal.
+    ///
+    /// # Arguments
+    /// * `journal` - The object the journal is appended to.
+    pub fn with_journal<W: JournalWriter + 'static>(mut self, journal: W) -> Self {
+        self.journal = Some(Arc::new(Mutex::new(Journal::new(Box::new(journal)))));
+        self
+    }
+
+    /// Sets the metric backend that the executed requests are reported to.
+    ///
+    /// After every request that is executed, the metric backend records either the I/O or the
+    /// error. Requests that stopped because the backend would block are reported only once they
+    /// complete. The executors cloned from this one afterwards, e.g. with
+    /// [`clone_for_queue`](#method.clone_for_queue), report to the same metric backend.
+    ///
+    /// # Arguments
+    /// * `metrics` - The metric backend, or `None` for reporting only to the built-in
+    ///               [`BlockStats`].
+    pub fn with_metrics(mut self, metrics: Option<Box<dyn BlockDeviceMetrics>>) -> Self {
+        self.metrics = metrics.map(|metrics| Arc::new(Mutex::new(metrics)));
+        self
+    }
+
+    /// Flushes the metrics recorded by the metric backend, if any.
+    pub fn flush_metrics(&mut self) {
+        if let Some(metrics) = self.metrics.as_ref() {
+            metrics.lock().unwrap().flush_metrics();
+        }
+    }
+
+    /// Performs again the writes of `journal` that were not committed, and syncs the backing
+    /// object. Returns the number of replayed writes.
+    ///
+    /// If the executor has a journal, which should append to `journal`, the commits of the
+    /// replayed writes are logged to it, and the log sequence numbers of its new entries continue
+    /// after the ones of `journal`.
+    ///
+    /// # Arguments
+    /// * `journal` - The journal written by a previous executor of the same disk.
+    pub fn replay_journal<R: Read>(&mut self, journal: R) -> Result<usize> {
+        let (entries, last_lsn) = journal::read_journal(journal).map_err(Error::Journal)?;
+        for entry in entries.iter().cloned() {
+            match entry.data {
+                JournalData::Write(mut data) => self.write_sectors(entry.sector, &mut data)?,
+                JournalData::Zeroes(num_sectors) => self.zero_sectors(entry.sector, num_sectors)?,
+            }
+        }
+        self.sync()?;
+        if let Some(journal) = self.journal.as_ref() {
+            let mut journal = journal.lock().unwrap();
+            if let Some(lsn) = last_lsn {
+                journal.continue_after(lsn);
+            }
+            // The replayed writes were synced, so they don't have to be replayed again.
+            let lsns: Vec<u64> = entries.iter().map(|entry| entry.lsn).collect();
+            journal.log_commits(&lsns).map_err(Error::Journal)?;
+        }
+        Ok(entries.len())
+    }
+
+    // Logs the data of an `Out` request to the journal, if any, returning the LSN of the entry.
+    fn log_write<M: GuestMemory>(&mut self, mem: &M, request: &Request) -> Result<Option<u64>> {
+        if self.journal.is_none() {
+            return Ok(None);
+        }
+        let mut data = vec![0u8; request.total_data_len() as usize];
+        let mut pos = 0;
+        for DataDescriptor {
+            addr: data_addr,
+            len_bytes: data_len,
+        } in request.data()
+        {
+            mem.read_slice(&mut data[pos..pos + *data_len as usize], *data_addr)
+                .map_err(Error::GuestMemory)?;
+            pos += *data_len as usize;
+        }
+        self.log_sectors(request.sector(), JournalData::Write(data))
+    }
+
+    // Logs `data`, which is about to be written to the sectors starting with `sector`, to the
+    // journal, if any, returning the LSN of the entry.
+    fn log_sectors(&self, sector: u64, data: JournalData) -> Result<Option<u64>> {
+        match self.journal.as_ref() {
+            Some(journal) => journal
+                .lock()
+                .unwrap()
+                .log_write(sector, data)
+                .map(Some)
+                .map_err(Error::Journal),
+            None => Ok(None),
+        }
+    }
+
+    // Records that the write logged with `lsn`, if any, is over, whether it succeeded or not.
+    // Its entry is committed by the next sync, or right away if the writes are never synced.
+    fn complete_write(&self, lsn: Option<u64>) {
+        let (journal, lsn) = match (self.journal.as_ref(), lsn) {
+            (Some(journal), Some(lsn)) => (journal, lsn),
+            _ => return,
+        };
+        let mut journal = journal.lock().unwrap();
+        if self.syncs_writes() {
+            journal.complete(lsn);
+        } else if let Err(e) = journal.log_commits(&[lsn]) {
+            // The write is replayed on recovery, which is harmless.
+            warn!("failed committing journal entry {}: {}", lsn, e);
+        }
+    }
+
+    // Creates a `StdIoBackend` with the same configuration, statistics, metric backend, last
+    // error, range lock, flush state and journal, on top of `inner`. The new executor doesn't
+    // sync `inner` when dropped.
+    pub(crate) fn with_inner(&self, inner: B) -> Self {
+        StdIoBackend {
+            inner: FlushOnDrop::new(inner),
+            num_sectors: self.num_sectors,
+            features: self.features,
+            logical_block_size: self.logical_block_size,
+            physical_block_size: self.physical_block_size,
+            device_id: self.device_id,
+            size_max: self.size_max,
+            seg_max: self.seg_max,
+            max_discard_seg: self.max_discard_seg,
+            max_write_zeroes_seg: self.max_write_zeroes_seg,
+            max_discard_sectors: self.max_discard_sectors,
+            max_write_zeroes_sectors: self.max_write_zeroes_sectors,
+            stats: self.stats.clone(),
+            deadline: None,
+            protected_ranges: self.protected_ranges.clone(),
+            range_lock: self.range_lock.clone(),
+            flush_state: self.flush_state.clone(),
+            prefetch_threshold: self.prefetch_threshold,
+            max_io_sectors: self.max_io_sectors,
+            split_io: self.split_io,
+            dma_page_size: self.dma_page_size,
+            split_dma: self.split_dma,
+            interrupt_coalescing: self.interrupt_coalescing,
+            pending_completions: 0,
+            first_pending_completion: None,
+            journal: self.journal.clone(),
+            metrics: self.metrics.clone(),
+            read_ahead_sectors: self.read_ahead_sectors,
+            read_ahead_cache: self.read_ahead_cache.clone(),
+            last_read_end: None,
+            poll_timeout: self.poll_timeout,
+            write_audit_hook: self.write_audit_hook.clone(),
+            last_error: self.last_error.clone(),
+            flush_policy: self.flush_policy,
+            max_queue_depth: self.max_queue_depth,
+            current_depth: self.current_depth.clone(),
+            sector_buf: Some(Box::new(AlignedSector([0u8; SECTOR_SIZE as usize]))),
+        }
+    }
+
+    /// Checks that `features` satisfies the constraints of the specification on the combinations
+    /// of virtio block features.
+    ///
+    /// `VIRTIO_BLK_F_CONFIG_WCE` requires `VIRTIO_BLK_F_FLUSH`, since the writeback mode can only
+    /// be made durable with flush requests, and `VIRTIO_BLK_F_ZONED` can't be combined with
+    /// `VIRTIO_BLK_F_RO`, since the zones of read-only devices can't be written. The constraints
+    /// that depend on the configuration space, like the number of queues required by
+    /// `VIRTIO_BLK_F_MQ`, are not checked.
+    ///
+    /// # Arguments
+    /// * `features` - The feature bits to check.
+    pub fn validate_features(features: u64) -> result::Result<(), FeatureError> {
+        let has = |bit: u32| features & (1 << bit) != 0;
+        if has(VIRTIO_BLK_F_CONFIG_WCE) && !has(VIRTIO_BLK_F_FLUSH) {
+            return Err(FeatureError::MissingDependency {
+                required: VIRTIO_BLK_F_FLUSH,
+                depends_on: VIRTIO_BLK_F_CONFIG_WCE,
+            });
+        }
+        if has(VIRTIO_BLK_F_ZONED) && has(VIRTIO_BLK_F_RO) {
+            return Err(FeatureError::ConflictingFeatures {
+                a: VIRTIO_BLK_F_ZONED,
+                b: VIRTIO_BLK_F_RO,
+            });
+        }
+        Ok(())
+    }
+
+    /// Returns the logical block size reported to the driver.
+    pub fn logical_block_size(&self) -> u32 {
+        self.logical_block_size
+    }
+
+    fn check_logical_block_size(logical_block_size: u32) -> Result<()> {
+        if !logical_block_size.is_power_of_two() || u64::from(logical_block_size) < SECTOR_SIZE {
+            return Err(Error::InvalidBlockSize(logical_block_size));
+        }
+        Ok(())
+    }
+
+    /// Returns the features that were negotiated between driver and device.
+    pub fn features(&self) -> u64 {
+        self.features
+    }
+
+    /// Restores the state saved in `snapshot`, e.g. when resuming a suspended guest.
+    ///
+    /// The backing object is left unchanged. The number of sectors from the snapshot can not
+    /// exceed the current one, since the device would access sectors that are not backed anymore;
+    /// smaller values, such as the ones resulting from [`shrink_sectors`](#method.shrink_sectors),
+    /// are restored as well. The features are checked with
+    /// [`validate_features`](#method.validate_features), like the ones of the builder.
+    ///
+    /// # Arguments
+    /// * `snapshot` - The previously saved state of the device.
+    pub fn restore_from_snapshot(&mut self, snapshot: &BlockDeviceSnapshot) -> Result<()> {
+        if snapshot.num_sectors > self.num_sectors() {
+            return Err(Error::InvalidNumSectors(snapshot.num_sectors));
+        }
+        Self::validate_features(snapshot.features).map_err(Error::Features)?;
+        Self::check_logical_block_size(snapshot.logical_block_size)?;
+        self.num_sectors = snapshot.num_sectors;
+        self.features = snapshot.features;
+        self.logical_block_size = snapshot.logical_block_size;
+        Ok(())
+    }
+
+    /// Returns the description of the error returned by the last request that failed, if any.
+    ///
+    /// The description is kept until the next failure, so it can be logged after the fact. The
+    /// failures of the executors cloned from this one, e.g. with
+    /// [`clone_for_queue`](#method.clone_for_queue) or by
+    /// [`concurrent_execute`](#method.concurrent_execute), are recorded as well, so the
+    /// description is returned by value.
+    pub fn last_error(&self) -> Option<String> {
+        self.last_error.lock().unwrap().clone()
+    }
+
+    /// Returns the statistics of the requests executed by this backend.
+    ///
+    /// The returned object can be cloned and read from other threads.
+    pub fn stats(&self) -> &Arc<BlockStats> {
+        &self.stats
+    }
+
+    fn has_feature(&self, feature_pos: u64) -> bool {
+        (self.features & (1u64 << feature_pos)) != 0
+    }
+
+    pub(crate) fn num_sectors(&self) -> u64 {
+        self.num_sectors
+    }
+
+    /// Returns the capacity of the device in bytes.
+    #[inline]
+    pub fn capacity_bytes(&self) -> u64 {
+        self.num_sectors << SECTOR_SHIFT
+    }
+
+    /// Returns the capacity of the device in MiB, for reporting.
+    #[inline]
+    pub fn capacity_mib(&self) -> f64 {
+        self.capacity_bytes() as f64 / (1024.0 * 1024.0)
+    }
+
+    /// Returns the geometry of the disk.
+    ///
+    /// The values are the ones cached by the executor, so the backend is not queried again.
+    pub fn disk_metadata(&self) -> DiskMetadata {
+        DiskMetadata {
+            capacity_bytes: self.capacity_bytes(),
+            num_sectors: self.num_sectors,
+            logical_block_size: self.logical_block_size,
+        }
+    }
+
+    /// Processes the `request` execution result, writes its status in memory and returns the used
+    /// length (i.e. the total number of bytes written into the memory buffer, including the status
+    /// byte).
+    ///
+    /// Errors for which [`Error::is_retryable`] is true are returned as
+    /// [`ProcessReqError::Retry`] instead, without writing the status, since the request is not
+    /// completed.
+    ///
+    /// # Arguments
+    /// * `mem` - A reference to the guest memory.
+    /// * `request` - The request to execute.
+    pub fn process_request<M: GuestMemory>(
+        &mut self,
         mem: &M,
         request: &Request,
     ) -> result::Result<u32, ProcessReqError> {
         let (status, length) = match self.execute(mem, request) {
-            Ok(length) => (VIRTIO_BLK_S_OK as u8, length),
+            Ok(length) => (VirtioBlkReqStatus::Ok, length),
+            Err(e) if e.is_retryable() => return Err(ProcessReqError::Retry(e)),
             Err(e) => {
                 error!("failed executing block request: {}", e);
                 match e {
-                    Error::Read(_, bytes_to_mem) => (e.status(), bytes_to_mem),
-                    _ => (e.status(), 0),
+                    Error::Read(_, bytes_to_mem) => (e.to_virtio_status(), bytes_to_mem),
+                    _ => (e.to_virtio_status(), 0),
                 }
             }
         };
-        mem.write_obj(status, request.status_addr())?;
+        mem.write_obj(status as u8, request.status_addr())?;
         // Adding +1 here for the status byte. `length` should not be u32::MAX since it is expected
         // to be a multiple of SECTOR_SIZE, but using `checked_add` here for safety.
         length.checked_add(1).ok_or(ProcessReqError::Overflow)
@@ -277,8 +1861,49 @@ impl<B: Backend> StdIoBackend<B> {
         Ok(())
     }
 
+    /// Protects the sectors from `start` to `end` (inclusive) from being modified.
+    ///
+    /// `Out`, `Discard` and `WriteZeroes` requests that touch any of these sectors fail with
+    /// `Error::ProtectedRange`, even if `VIRTIO_BLK_F_RO` was not negotiated. This can be used
+    /// e.g. for keeping firmware regions of the disk read-only.
+    ///
+    /// # Arguments
+    /// * `start` - The first protected sector.
+    /// * `end` - The last protected sector.
+    pub fn add_protected_range(&mut self, start: u64, end: u64) {
+        let index = self
+            .protected_ranges
+            .partition_point(|range| *range.start() <= start);
+        self.protected_ranges.insert(index, start..=end);
+    }
+
+    // Checks that none of the `num_sectors` sectors starting with `sector` is protected.
+    fn check_protected(&self, sector: u64, num_sectors: u64) -> Result<()> {
+        if num_sectors == 0 {
+            return Ok(());
+        }
+        let last = sector.saturating_add(num_sectors - 1);
+        // The ranges are sorted by their first sector, so the ones starting after `last` can't
+        // overlap with the accessed sectors.
+        for range in self
+            .protected_ranges
+            .iter()
+            .take_while(|range| *range.start() <= last)
+        {
+            if *range.end() >= sector {
+                return Err(Error::ProtectedRange {
+                    sector: cmp::max(*range.start(), sector),
+                });
+            }
+        }
+        Ok(())
+    }
+
     fn check_request(&self, request_type: RequestType) -> Result<()> {
-        if self.has_feature(VIRTIO_BLK_F_RO.into()) && request_type != RequestType::In {
+        if self.has_feature(VIRTIO_BLK_F_RO.into())
+            && request_type != RequestType::In
+            && request_type != RequestType::ZoneReport
+        {
             return Err(Error::ReadOnly);
         }
         match request_type {
@@ -291,6 +1916,9 @@ impl<B: Backend> StdIoBackend<B> {
             RequestType::WriteZeroes if !self.has_feature(VIRTIO_BLK_F_WRITE_ZEROES.into()) => {
                 Err(Error::Unsupported(VIRTIO_BLK_T_WRITE_ZEROES))
             }
+            RequestType::ZoneReport if !self.has_feature(VIRTIO_BLK_F_ZONED.into()) => {
+                Err(Error::Unsupported(VIRTIO_BLK_T_ZONE_REPORT))
+            }
             _ => Ok(()),
         }
     }
@@ -298,77 +1926,456 @@ impl<B: Backend> StdIoBackend<B> {
     /// Executes `request` Request on `B` and `mem` and returns the number of bytes that were
     /// written into the memory buffer during execution (status byte not included).
     ///
+    /// Requests with the [`RequestTypeFlags::BARRIER`] flag go through [`barrier`](#method.barrier)
+    /// before they are executed, and after they modified the device.
+    ///
     /// # Arguments
     /// * `mem` - A reference to the guest memory.
     /// * `request` - The request to execute.
     pub fn execute<M: GuestMemory>(&mut self, mem: &M, request: &Request) -> Result<u32> {
-        let offset = request
-            .sector()
-            .checked_shl(u32::from(SECTOR_SHIFT))
-            .ok_or(Error::InvalidAccess)?;
-        self.inner
-            .seek(SeekFrom::Start(offset))
-            .map_err(Error::Seek)?;
-        // This will count the number of bytes written by the device to the memory. It must fit in
-        // an u32 for further writing in the used ring.
-        let mut bytes_to_mem: u32 = 0;
-        let request_type = request.request_type();
-        self.check_request(request_type)?;
-
-        let total_len = request.total_data_len();
+        let _depth_guard = self.enter_queue()?;
+        let result = self.execute_with(mem, request, Self::read_to_mem, Self::write_from_mem);
+        #[cfg(debug_assertions)]
+        self.assert_invariants();
+        result
+    }
 
-        if (request_type == RequestType::In || request_type == RequestType::Out)
-            && (total_len % SECTOR_SIZE != 0)
-        {
-            return Err(Error::InvalidDataLength);
+    // Counts a request as being executed until the returned guard is dropped. Fails with
+    // `Error::QueueFull` if the maximum queue depth was reached.
+    fn enter_queue(&self) -> Result<DepthGuard> {
+        let depth = self.current_depth.fetch_add(1, Ordering::SeqCst);
+        let depth_guard = DepthGuard(self.current_depth.clone());
+        if self.max_queue_depth != 0 && depth >= self.max_queue_depth {
+            return Err(Error::QueueFull {
+                depth,
+                max: self.max_queue_depth,
+            });
         }
+        Ok(depth_guard)
+    }
 
-        match request_type {
-            RequestType::In => {
-                self.check_access(total_len / SECTOR_SIZE, request.sector())?;
-                // Total data length should fit in an u32 for further writing in the used ring.
-                if total_len > u32::MAX as u64 {
-                    return Err(Error::InvalidDataLength);
-                }
-                for (data_addr, data_len) in request.data() {
-                    mem.read_exact_volatile_from(*data_addr, &mut self.inner, *data_len as usize)
-                        .map_err(|e| {
-                            if let GuestMemoryError::PartialBuffer {
-                                completed,
-                                expected: _,
-                            } = e
-                            {
-                                // The `as u32` cast is safe, since completed < data_len (which is
-                                // an u32).
-                                bytes_to_mem += completed as u32
-                            }
-                            Error::Read(e, bytes_to_mem)
-                        })?;
-                    // This can not overflow since we checked right before the loop that `total_len`
-                    // fits in an u32.
-                    bytes_to_mem += data_len;
+    // Panics if the state of the executor is inconsistent: the features and block sizes must be
+    // ones that the constructors and `restore_from_snapshot` accept, the sectors of the device
+    // must be backed by the backing object, and its position must not be past its end. The size
+    // and position are only checked for the backing objects that can report them.
+    #[cfg(debug_assertions)]
+    fn assert_invariants(&mut self) {
+        assert!(
+            Self::validate_features(self.features).is_ok(),
+            "invalid features {:#x}",
+            self.features
+        );
+        assert!(
+            Self::check_logical_block_size(self.logical_block_size).is_ok()
+                && self
+                    .physical_block_size
+                    .is_none_or(|size| Self::check_logical_block_size(size).is_ok()),
+            "invalid block sizes {} and {:?}",
+            self.logical_block_size,
+            self.physical_block_size
+        );
+        let position = match self.inner.stream_position() {
+            Ok(position) => position,
+            Err(_) => return,
+        };
+        let disk_size = match self.inner.seek(SeekFrom::End(0)) {
+            Ok(disk_size) => disk_size,
+            Err(_) => return,
+        };
+        // The position is restored, since it is where a resumed request continues.
+        let _ = self.inner.seek(SeekFrom::Start(position));
+        assert!(
+            self.num_sectors <= disk_size >> SECTOR_SHIFT,
+            "{} sectors don't fit in the {} bytes backing object",
+            self.num_sectors,
+            disk_size
+        );
+        assert!(
+            position <= disk_size,
+            "position {} is past the end of the {} bytes backing object",
+            position,
+            disk_size
+        );
+    }
+
+    // Executes `request`, using `read_data` and `write_data` for transferring the data of `In`
+    // and `Out` requests respectively, and records it in the statistics.
+    fn execute_with<M: GuestMemory>(
+        &mut self,
+        mem: &M,
+        request: &Request,
+        read_data: fn(&mut Self, &M, &Request) -> Result<u32>,
+        write_data: fn(&mut Self, &M, &Request) -> Result<()>,
+    ) -> Result<u32> {
+        let start = Instant::now();
+        let result = self.execute_request(mem, request, read_data, write_data);
+        self.record_stats(request, &result, start);
+        result
+    }
+
+    // Records the execution of `request`, which started at `start`, in the statistics, unless it
+    // is still pending. Errors are recorded as the last error as well.
+    fn record_stats(&mut self, request: &Request, result: &Result<u32>, start: Instant) {
+        if let Err(e) = result {
+            *self.last_error.lock().unwrap() = Some(e.to_string());
+        }
+        if !matches!(result, Err(Error::WouldBlock(_))) {
+            let latency = start.elapsed();
+            self.stats.record(
+                request.request_type(),
+                request.total_data_len(),
+                result.is_ok(),
+            );
+            self.stats.record_latency(request.request_type(), latency);
+            if let Some(metrics) = self.metrics.as_ref() {
+                let mut metrics = metrics.lock().unwrap();
+                match result {
+                    Ok(_) => metrics.record_io(
+                        request.request_type(),
+                        u64::try_from(latency.as_nanos()).unwrap_or(u64::MAX),
+                        request.total_data_len(),
+                    ),
+                    Err(e) => metrics.record_error(e.io_error_kind()),
                 }
             }
-            RequestType::Out => {
-                self.check_access(total_len / SECTOR_SIZE, request.sector())?;
-                for (data_addr, data_len) in request.data() {
-                    mem.write_all_volatile_to(*data_addr, &mut self.inner, *data_len as usize)
-                        .map_err(Error::Write)?;
-                }
+        }
+    }
+
+    // Converts an error caused by a backend that would block into `Error::WouldBlock`. The
+    // progress of the transfer is computed from the position of the backend, which is left where
+    // the transfer stopped.
+    //
+    // # Arguments
+    // * `err` - The error returned by the data transfer.
+    // * `offset` - The offset in bytes of the request data on the backend.
+    // * `total_len` - The length of the request data.
+    fn check_would_block(&mut self, err: Error, offset: u64, total_len: u64) -> Error {
+        let would_block = matches!(
+            &err,
+            Error::Read(GuestMemoryError::IOError(e), _) | Error::Write(GuestMemoryError::IOError(e))
+                if e.kind() == io::ErrorKind::WouldBlock
+        );
+        if !would_block {
+            return err;
+        }
+        match self.inner.stream_position() {
+            Ok(position) => {
+                let transferred_bytes = cmp::min(position.saturating_sub(offset), total_len);
+                Error::WouldBlock(PendingRequest {
+                    transferred_bytes,
+                    remaining_bytes: total_len - transferred_bytes,
+                })
             }
-            RequestType::Flush => return self.inner.fsync().map(|_| 0).map_err(Error::Flush),
-            RequestType::GetDeviceID => {
-                let device_id = self
-                    .device_id
-                    .ok_or(Error::Unsupported(VIRTIO_BLK_T_GET_ID))?;
-                // The length of data MUST be VIRTIO_BLK_ID_BYTES bytes for VIRTIO_BLK_T_GET_ID
-                // requests.
-                if total_len != VIRTIO_BLK_ID_BYTES as u64 {
-                    return Err(Error::InvalidDataLength);
-                }
-                for (data_addr, data_len) in request.data() {
-                    // The device_id accesses are safe because we checked that the total data length
-                    // is VIRTIO_BLK_ID_BYTES, which is the size of the id as well.
+            Err(_) => err,
+        }
+    }
+
+    /// Resumes the execution of an `In` or `Out` request that stopped with
+    /// [`Error::WouldBlock`], and returns the number of bytes that were written into the memory
+    /// buffer during the whole execution (status byte not included).
+    ///
+    /// The transfer restarts with the first sector that was not completely transferred, and the
+    /// rest of the request is executed like a new request: it is validated again, and counts
+    /// towards the maximum queue depth. If the backend would block again, a new
+    /// `Error::WouldBlock` is returned with the updated progress.
+    ///
+    /// # Arguments
+    /// * `mem` - A reference to the guest memory.
+    /// * `request` - The request to resume.
+    /// * `pending` - The progress returned with the `Error::WouldBlock` error.
+    pub fn resume<M: GuestMemory>(
+        &mut self,
+        mem: &M,
+        request: &Request,
+        pending: &PendingRequest,
+    ) -> Result<u32> {
+        let _depth_guard = self.enter_queue()?;
+        let start = Instant::now();
+        let result = self.resume_request(mem, request, pending);
+        self.record_stats(request, &result, start);
+        #[cfg(debug_assertions)]
+        self.assert_invariants();
+        result
+    }
+
+    fn resume_request<M: GuestMemory>(
+        &mut self,
+        mem: &M,
+        request: &Request,
+        pending: &PendingRequest,
+    ) -> Result<u32> {
+        let request_type = request.request_type();
+        // Only the data of `In` and `Out` requests can be pending.
+        if (request_type != RequestType::In && request_type != RequestType::Out)
+            || pending
+                .transferred_bytes
+                .checked_add(pending.remaining_bytes)
+                != Some(request.total_data_len())
+        {
+            return Err(Error::InvalidDataLength);
+        }
+        self.validate_request(mem, request)?;
+
+        // The sectors that were completely transferred are skipped, and the rest of the request
+        // goes through the same path as a new request.
+        let skipped_sectors = pending.transferred_bytes / SECTOR_SIZE;
+        let skipped_bytes = skipped_sectors << SECTOR_SHIFT;
+        let rest = request.skip_sectors(skipped_sectors);
+        // The `as u32` casts are safe since the data length of `In` requests fits in an u32.
+        match self.execute_request(mem, &rest, Self::read_to_mem, Self::write_from_mem) {
+            Ok(bytes_to_mem) if request_type == RequestType::In => {
+                Ok(skipped_bytes as u32 + bytes_to_mem)
+            }
+            Ok(bytes_to_mem) => Ok(bytes_to_mem),
+            // The data transferred by the previous executions is not lost if this one stops
+            // earlier, since it restarted with the sector where they stopped.
+            Err(Error::Read(e, completed)) => Err(Error::Read(
+                e,
+                cmp::max(
+                    pending.transferred_bytes,
+                    skipped_bytes + u64::from(completed),
+                ) as u32,
+            )),
+            Err(Error::WouldBlock(rest_pending)) => {
+                let transferred_bytes = cmp::max(
+                    pending.transferred_bytes,
+                    skipped_bytes + rest_pending.transferred_bytes,
+                );
+                Err(Error::WouldBlock(PendingRequest {
+                    transferred_bytes,
+                    remaining_bytes: request.total_data_len() - transferred_bytes,
+                }))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Executes the in-flight request identified by `id` from `queue`, like
+    /// [`execute`](#method.execute) does, and then removes it from `queue`.
+    ///
+    /// The request is completed even if its execution failed.
+    ///
+    /// # Arguments
+    /// * `mem` - A reference to the guest memory.
+    /// * `queue` - The in-flight requests.
+    /// * `id` - The identifier of the request to execute.
+    pub fn execute_queued<M: GuestMemory>(
+        &mut self,
+        mem: &M,
+        queue: &mut RequestQueue,
+        id: RequestId,
+    ) -> Result<u32> {
+        let request = queue.get(id).ok_or(Error::InvalidRequestId(id))?;
+        let result = self.execute(mem, request);
+        queue.complete(id);
+        result
+    }
+
+    /// Executes `request` like [`execute`](#method.execute) does, but fails with
+    /// `Error::DeadlineExceeded` if `deadline` passes before the execution completes.
+    ///
+    /// The deadline is checked before and after each operation on the backend, so the request
+    /// is not executed at all if the deadline already passed, but a single operation is never
+    /// interrupted. Since the operations are not rolled back, a request that exceeded its
+    /// deadline may have been partially executed.
+    ///
+    /// # Arguments
+    /// * `mem` - A reference to the guest memory.
+    /// * `request` - The request to execute.
+    /// * `deadline` - The moment when the execution should be completed.
+    pub fn execute_with_deadline<M: GuestMemory>(
+        &mut self,
+        mem: &M,
+        request: &Request,
+        deadline: Instant,
+    ) -> Result<u32> {
+        self.deadline = Some(deadline);
+        let result = self.execute(mem, request);
+        self.deadline = None;
+        result
+    }
+
+    /// Executes `request` like [`execute`](#method.execute) does, and also returns whether the
+    /// guest has to be interrupted, according to the interrupt coalescing configuration.
+    ///
+    /// Without interrupt coalescing, every completion fires an interrupt.
+    ///
+    /// # Arguments
+    /// * `mem` - A reference to the guest memory.
+    /// * `request` - The request to execute.
+    pub fn execute_coalesced<M: GuestMemory>(
+        &mut self,
+        mem: &M,
+        request: &Request,
+    ) -> (Result<u32>, CompletionAction) {
+        let result = self.execute(mem, request);
+        self.pending_completions += 1;
+        self.first_pending_completion
+            .get_or_insert_with(Instant::now);
+        let fire_interrupt = match self.interrupt_coalescing {
+            Some((max_pending, _)) if self.pending_completions < max_pending => {
+                self.coalescing_latency_expired()
+            }
+            _ => true,
+        };
+        (result, self.complete_interrupt(fire_interrupt))
+    }
+
+    /// Returns whether the guest has to be interrupted because the pending completions exceeded
+    /// the maximum latency of the interrupt coalescing.
+    pub fn poll_interrupt(&mut self) -> CompletionAction {
+        let fire_interrupt = self.pending_completions > 0 && self.coalescing_latency_expired();
+        self.complete_interrupt(fire_interrupt)
+    }
+
+    // Checks whether the first pending completion waits for more than the maximum latency.
+    fn coalescing_latency_expired(&self) -> bool {
+        match (self.interrupt_coalescing, self.first_pending_completion) {
+            (Some((_, max_latency)), Some(first)) => first.elapsed() >= max_latency,
+            _ => true,
+        }
+    }
+
+    // Resets the pending completions if the interrupt is fired.
+    fn complete_interrupt(&mut self, fire_interrupt: bool) -> CompletionAction {
+        if fire_interrupt {
+            self.pending_completions = 0;
+            self.first_pending_completion = None;
+        }
+        CompletionAction { fire_interrupt }
+    }
+
+    // Checks that the deadline of the request, if any, didn't pass.
+    fn check_deadline(&self) -> Result<()> {
+        match self.deadline {
+            Some(deadline) if Instant::now() >= deadline => Err(Error::DeadlineExceeded),
+            _ => Ok(()),
+        }
+    }
+
+    fn execute_request<M: GuestMemory>(
+        &mut self,
+        mem: &M,
+        request: &Request,
+        read_data: fn(&mut Self, &M, &Request) -> Result<u32>,
+        write_data: fn(&mut Self, &M, &Request) -> Result<()>,
+    ) -> Result<u32> {
+        if let Some(WriteAuditHook(hook)) = self.write_audit_hook.as_ref() {
+            if request.is_write_modifying() {
+                hook(request);
+            }
+        }
+        let ValidatedRequest {
+            sub_requests,
+            segments,
+        } = self.validate_request(mem, request)?;
+        self.check_deadline()?;
+        let barrier = request.type_flags().contains(RequestTypeFlags::BARRIER);
+        if barrier {
+            self.barrier()?;
+        }
+        if let Some((timeout, raw_fd)) = self.poll_timeout {
+            let events = match request.request_type() {
+                RequestType::In => libc::POLLIN,
+                RequestType::Out => libc::POLLOUT,
+                _ => 0,
+            };
+            if events != 0 {
+                poll_ready(raw_fd(&self.inner), events, timeout)?;
+            }
+        }
+        // `validate_request` checked that the shift doesn't overflow.
+        let offset = request.sector() << SECTOR_SHIFT;
+        // Only the `In` and `Out` requests transfer data at the position of the backing object.
+        // The sector of the other requests isn't checked against the size of the device, so it
+        // must not move the position past the end of the backing object.
+        if matches!(request.request_type(), RequestType::In | RequestType::Out) {
+            self.inner
+                .seek(SeekFrom::Start(offset))
+                .map_err(Error::Seek)?;
+        }
+        // This will count the number of bytes written by the device to the memory. It must fit in
+        // an u32 for further writing in the used ring.
+        let mut bytes_to_mem: u32 = 0;
+        let request_type = request.request_type();
+        let total_len = request.total_data_len();
+        let modifies = matches!(
+            request_type,
+            RequestType::Out | RequestType::Discard | RequestType::WriteZeroes
+        );
+        let _written = modifies.then(|| self.start_write());
+
+        match request_type {
+            RequestType::In => {
+                let range_lock = self.range_lock.clone();
+                // `validate_request` guarantees that the end of the range doesn't overflow.
+                let end = request.sector() + total_len / SECTOR_SIZE;
+                let _guard = range_lock
+                    .as_ref()
+                    .map(|lock| lock.lock_shared(request.sector(), end));
+                let sequential = self.last_read_end == Some(request.sector());
+                if self.read_cached(mem, request)? {
+                    self.last_read_end = Some(end);
+                    if !self.read_ahead_cache.lock().unwrap().contains_key(&end) {
+                        self.read_ahead(end);
+                    }
+                    // The conversion is safe since the length was validated.
+                    return Ok(total_len as u32);
+                }
+                if self.prefetch_threshold != 0
+                    && total_len / SECTOR_SIZE >= self.prefetch_threshold
+                {
+                    Self::prefetch(mem, request);
+                }
+                for sub_request in sub_requests.as_deref().unwrap_or(slice::from_ref(request)) {
+                    // This can not overflow since the total data length fits in an u32.
+                    bytes_to_mem += read_data(self, mem, sub_request)
+                        .map_err(|e| match e {
+                            Error::Read(e, completed) => Error::Read(e, bytes_to_mem + completed),
+                            e => e,
+                        })
+                        .map_err(|e| self.check_would_block(e, offset, total_len))?;
+                }
+                self.last_read_end = Some(end);
+                if sequential {
+                    self.read_ahead(end);
+                }
+            }
+            RequestType::Out => {
+                let range_lock = self.range_lock.clone();
+                // `validate_request` guarantees that the end of the range doesn't overflow.
+                let (start, end) = self
+                    .write_lock_range(request.sector(), request.sector() + total_len / SECTOR_SIZE);
+                let _guard = range_lock
+                    .as_ref()
+                    .map(|lock| lock.lock_exclusive(start, end));
+                let lsn = self.log_write(mem, request)?;
+                let result = self.write_request(mem, request, sub_requests.as_deref(), write_data);
+                self.complete_write(lsn);
+                result?;
+                if self.flush_policy == FlushPolicy::Always {
+                    self.sync()?;
+                }
+            }
+            RequestType::Flush => {
+                // Nothing has to be synced if there were no writes since the last flush.
+                if self.flush_policy != FlushPolicy::Never && self.flush_state.needs_flush() {
+                    self.sync()?;
+                }
+                self.check_deadline()?;
+            }
+            RequestType::GetDeviceID => {
+                let device_id = self
+                    .device_id
+                    .ok_or(Error::Unsupported(VIRTIO_BLK_T_GET_ID))?;
+                for DataDescriptor {
+                    addr: data_addr,
+                    len_bytes: data_len,
+                } in request.data()
+                {
+                    // The device_id accesses are safe because `validate_request` checked that the
+                    // total data length is VIRTIO_BLK_ID_BYTES, which is the size of the id as
+                    // well.
                     mem.read_exact_volatile_from(
                         *data_addr,
                         &mut &device_id[bytes_to_mem as usize..(*data_len + bytes_to_mem) as usize],
@@ -392,888 +2399,7380 @@ impl<B: Backend> StdIoBackend<B> {
                 }
             }
             RequestType::Discard | RequestType::WriteZeroes => {
-                for (data_addr, data_len) in request.data() {
-                    // We support for now only data descriptors with the `len` field = multiple of
-                    // the size of `virtio_blk_discard_write_zeroes` segment. The specification,
-                    // however, requires that only `total_len` be such multiple (a segment can be
-                    // divided between several descriptors). Once we switch to a more general
-                    // approach regarding how we store and parse the device buffers, we'll fix this
-                    // too.
-                    if *data_len as u64 % DiscardWriteZeroes::LEN != 0 {
-                        return Err(Error::InvalidDataLength);
-                    }
-                    let mut available_bytes = *data_len as u64;
-                    let mut crt_addr = *data_addr;
-                    crt_addr
-                        .checked_add(*data_len as u64)
-                        .ok_or(Error::Overflow)?;
-
-                    while available_bytes >= DiscardWriteZeroes::LEN {
-                        let segment = mem.read_obj(crt_addr).map_err(Error::GuestMemory)?;
-                        self.handle_discard_write_zeroes(&segment, request.request_type())?;
-                        // Using `unchecked_add` here, since the overflow is not possible at this
-                        // point (it is checked right before the current loop) and `read_obj` fails
-                        // if the memory access is invalid.
-                        crt_addr = crt_addr.unchecked_add(DiscardWriteZeroes::LEN);
-                        available_bytes -= DiscardWriteZeroes::LEN;
-                    }
+                // All the segments were checked by `validate_request`, so none of them is
+                // executed if any of them is invalid.
+                for segment in segments.iter() {
+                    self.handle_discard_write_zeroes(segment, request_type)?;
+                    self.check_deadline()?;
                 }
             }
+            // Zone reports need a `ZonedBackend`, see `execute_zoned`.
+            RequestType::ZoneReport => return Err(Error::Unsupported(VIRTIO_BLK_T_ZONE_REPORT)),
             RequestType::Unsupported(t) => return Err(Error::Unsupported(t)),
         };
 
+        if modifies && barrier {
+            self.barrier()?;
+        }
+
         Ok(bytes_to_mem)
     }
 
-    fn handle_discard_write_zeroes(
+    // Writes the data of the `Out` request, or of its `sub_requests` if it was split, with
+    // `write_data`, or with `write_padded` for the writes that are not aligned to the physical
+    // block size.
+    fn write_request<M: GuestMemory>(
         &mut self,
-        segment: &DiscardWriteZeroes,
-        request_type: RequestType,
-    ) -> Result<u32> {
-        let sector = segment.sector;
-        let num_sectors = segment.num_sectors;
-        let flags = segment.flags;
+        mem: &M,
+        request: &Request,
+        sub_requests: Option<&[Request]>,
+        write_data: fn(&mut Self, &M, &Request) -> Result<()>,
+    ) -> Result<()> {
+        let offset = request.sector() << SECTOR_SHIFT;
+        let total_len = request.total_data_len();
+        for sub_request in sub_requests.unwrap_or(slice::from_ref(request)) {
+            let sub_offset = sub_request.sector() << SECTOR_SHIFT;
+            let sub_len = sub_request.total_data_len();
+            match self.physical_block_size.map(u64::from) {
+                Some(block_size)
+                    if !sub_offset.is_multiple_of(block_size)
+                        || !sub_len.is_multiple_of(block_size) =>
+                {
+                    self.write_padded(mem, sub_request, sub_offset, block_size)?
+                }
+                _ => {
+                    // A padded write leaves the backend at the end of the last block.
+                    if sub_requests.is_some() {
+                        self.inner
+                            .seek(SeekFrom::Start(sub_offset))
+                            .map_err(Error::Seek)?;
+                    }
+                    write_data(self, mem, sub_request)
+                        .map_err(|e| self.check_would_block(e, offset, total_len))?
+                }
+            }
+        }
+        Ok(())
+    }
 
-        // For Discard, unmap bit (the least significant bit from segment flags) MUST be 0, for
-        // Write Zeroes it can be either 0 or 1.
-        // The other bits are reserved and MUST not be set (for both request types).
-        // If any of these conditions are not met, status must be set to VIRTIO_BLK_S_UNSUPP.
-        let valid_flags = if request_type == RequestType::WriteZeroes {
-            DiscardWriteZeroes::UNMAP
-        } else {
-            0
+    // Copies the data of the `In` request from the read-ahead cache to the guest memory, if all
+    // its sectors are cached. Returns whether the request was served from the cache.
+    fn read_cached<M: GuestMemory>(&self, mem: &M, request: &Request) -> Result<bool> {
+        if self.read_ahead_sectors == 0 {
+            return Ok(false);
+        }
+        let data = {
+            let cache = self.read_ahead_cache.lock().unwrap();
+            let first = request.sector();
+            let num_sectors = request.total_data_len() / SECTOR_SIZE;
+            // The range was checked to be valid by `check_access`.
+            let sectors = first..first + num_sectors;
+            if num_sectors == 0 || !sectors.clone().all(|sector| cache.contains_key(&sector)) {
+                return Ok(false);
+            }
+            sectors
+                .flat_map(|sector| cache[&sector])
+                .collect::<Vec<u8>>()
         };
-        if (flags & !valid_flags) != 0 {
-            return Err(Error::InvalidFlags);
+        let mut copied = 0;
+        for desc in request.data() {
+            let len = desc.len_bytes as usize;
+            mem.write_slice(&data[copied..copied + len], desc.addr)
+                .map_err(|e| Error::Read(e, copied as u32))?;
+            copied += len;
         }
+        Ok(true)
+    }
 
-        let offset = sector
-            .checked_shl(u32::from(SECTOR_SHIFT))
-            .ok_or(Error::InvalidAccess)?;
-        let length = u64::from(num_sectors)
-            .checked_shl(u32::from(SECTOR_SHIFT))
-            .ok_or(Error::InvalidAccess)?;
-        self.check_access(num_sectors as u64, sector)?;
+    // Reads up to `read_ahead_sectors` sectors starting with `sector` into the read-ahead cache,
+    // replacing its previous content.
+    //
+    // The cache stays locked while the sectors are read, so a write that overlaps them either
+    // completes before they are read, or invalidates the cache once it completes, after they
+    // were inserted. Either way, the old data of the written sectors isn't kept in the cache.
+    fn read_ahead(&mut self, sector: u64) {
+        let count = cmp::min(
+            self.read_ahead_sectors,
+            self.num_sectors().saturating_sub(sector),
+        );
+        if count == 0 {
+            return;
+        }
+        let range_lock = self.range_lock.clone();
+        // The end of the range can't overflow since it's at most the number of sectors.
+        let _guard = range_lock
+            .as_ref()
+            .map(|lock| lock.lock_shared(sector, sector + count));
+        let mut buf = vec![0u8; (count * SECTOR_SIZE) as usize];
+        let read_ahead_cache = self.read_ahead_cache.clone();
+        let mut cache = read_ahead_cache.lock().unwrap();
+        // Reading ahead is just an optimization, so errors are ignored.
+        if self.read_sectors(sector, &mut buf).is_ok() {
+            cache.clear();
+            cache.extend(
+                (sector..)
+                    .zip(buf.chunks_exact(SECTOR_SIZE as usize))
+                    // The conversion can't fail since the chunks have exactly SECTOR_SIZE bytes.
+                    .map(|(sector, data)| (sector, data.try_into().unwrap())),
+            );
+        }
+    }
 
-        if request_type == RequestType::Discard {
-            // Since Discard is just a hint and some filesystems may not implement
-            // FALLOC_FL_PUNCH_HOLE, ignore punch_hole() errors.
-            let _ = self.inner.punch_hole(offset, length);
-        } else {
-            // If unmap is set, try at first to punch a hole, if it fails, fall back to just
-            // writing zeroes.
-            // After a write zeroes command is completed, reads of the specified ranges of sectors
-            // MUST return zeroes, independent of unmap value.
-            if flags & DiscardWriteZeroes::UNMAP == 0
-                || self.inner.punch_hole(offset, length).is_err()
-            {
-                self.inner
-                    .write_all_zeroes_at(offset, length as usize)
-                    .map_err(Error::DiscardWriteZeroes)?;
-            }
+    // Drops the sectors that were read ahead, since they may have been modified.
+    fn invalidate_read_ahead(&self) {
+        if self.read_ahead_sectors != 0 {
+            self.read_ahead_cache.lock().unwrap().clear();
         }
-        Ok(0)
     }
 
-    /// Obtains an immutable reference to the backing object.
-    pub fn inner(&self) -> &B {
-        &self.inner
+    // Returns the sub-requests that `request` has to be split into, if it has more sectors than
+    // `max_io_sectors`. Fails with `Error::RequestTooLarge` if splitting is disabled.
+    fn split_io(&self, request: &Request) -> Result<Option<Vec<Request>>> {
+        let request_sectors = request.total_data_len() / SECTOR_SIZE;
+        if self.max_io_sectors == 0 || request_sectors <= u64::from(self.max_io_sectors) {
+            return Ok(None);
+        }
+        if !self.split_io {
+            return Err(Error::RequestTooLarge {
+                request_sectors,
+                max_sectors: self.max_io_sectors,
+            });
+        }
+        Ok(Some(request.split(u64::from(self.max_io_sectors))))
     }
 
-    /// Obtains a mutable reference to the backing object.
-    pub fn inner_mut(&mut self) -> &mut B {
-        &mut self.inner
+    // Reads the data of an `In` request, one descriptor at a time, starting with the current
+    // position of the backend. The caller has to check that the total data length fits in an u32.
+    fn read_to_mem<M: GuestMemory>(&mut self, mem: &M, request: &Request) -> Result<u32> {
+        let mut bytes_to_mem: u32 = 0;
+        for DataDescriptor {
+            addr: data_addr,
+            len_bytes: data_len,
+        } in request.data()
+        {
+            let mut addr = *data_addr;
+            let mut remaining = *data_len;
+            while remaining != 0 {
+                let len = self.dma_chunk_len(addr, remaining);
+                mem.read_exact_volatile_from(addr, &mut *self.inner, len as usize)
+                    .map_err(|e| {
+                        if let GuestMemoryError::PartialBuffer {
+                            completed,
+                            expected: _,
+                        } = e
+                        {
+                            // The `as u32` cast is safe, since completed < len (which is an u32).
+                            bytes_to_mem += completed as u32
+                        }
+                        Error::Read(e, bytes_to_mem)
+                    })?;
+                // This can not overflow since the total data length fits in an u32.
+                bytes_to_mem += len;
+                remaining -= len;
+                addr = addr.checked_add(u64::from(len)).ok_or(Error::Overflow)?;
+            }
+            self.check_deadline()?;
+        }
+        Ok(bytes_to_mem)
     }
 
-    /// Consumes the [`StdIoBackend`], returning its backing object.
-    pub fn into_inner(self) -> B {
-        self.inner
+    // Writes the data of an `Out` request, one descriptor at a time, starting with the current
+    // position of the backend.
+    fn write_from_mem<M: GuestMemory>(&mut self, mem: &M, request: &Request) -> Result<()> {
+        for DataDescriptor {
+            addr: data_addr,
+            len_bytes: data_len,
+        } in request.data()
+        {
+            let mut addr = *data_addr;
+            let mut remaining = *data_len;
+            while remaining != 0 {
+                let len = self.dma_chunk_len(addr, remaining);
+                mem.write_all_volatile_to(addr, &mut *self.inner, len as usize)
+                    .map_err(Error::Write)?;
+                remaining -= len;
+                addr = addr.checked_add(u64::from(len)).ok_or(Error::Overflow)?;
+            }
+            self.check_deadline()?;
+        }
+        Ok(())
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    // Fails with `Error::DmaPageCrossing` if a data descriptor of `request` crosses a DMA page
+    // boundary and splitting the transfers is disabled.
+    fn check_dma_pages(&self, request: &Request) -> Result<()> {
+        if self.split_dma {
+            return Ok(());
+        }
+        match request
+            .data()
+            .iter()
+            .find(|desc| self.crosses_dma_page(desc))
+        {
+            Some(desc) => Err(Error::DmaPageCrossing {
+                addr: desc.addr.raw_value(),
+                page_size: self.dma_page_size,
+            }),
+            None => Ok(()),
+        }
+    }
 
-    use std::io::{Read, Write};
+    // Returns whether the buffer of `desc` crosses a DMA page boundary.
+    fn crosses_dma_page(&self, desc: &DataDescriptor) -> bool {
+        self.dma_page_size != 0
+            && desc.addr.raw_value() % self.dma_page_size + u64::from(desc.len_bytes)
+                > self.dma_page_size
+    }
 
-    use vm_memory::guest_memory::Error::{InvalidGuestAddress, PartialBuffer};
-    use vm_memory::{GuestAddress, GuestMemoryMmap};
-    use vmm_sys_util::tempfile::TempFile;
+    // Returns how many of the `len` bytes at `addr` can be transferred at once, which is up to
+    // the next DMA page boundary when splitting the transfers is enabled.
+    fn dma_chunk_len(&self, addr: GuestAddress, len: u32) -> u32 {
+        if self.dma_page_size == 0 || !self.split_dma {
+            return len;
+        }
+        let to_boundary = self.dma_page_size - addr.raw_value() % self.dma_page_size;
+        // The conversion is safe since the result is at most `len`.
+        cmp::min(u64::from(len), to_boundary) as u32
+    }
 
-    impl PartialEq for Error {
-        fn eq(&self, other: &Self) -> bool {
-            use self::Error::*;
-            match (self, other) {
-                (DiscardWriteZeroes(ref e), DiscardWriteZeroes(ref other_e)) => {
-                    format!("{}", e).eq(&format!("{}", other_e))
-                }
-                (Flush(ref e), Flush(ref other_e)) => format!("{}", e).eq(&format!("{}", other_e)),
-                (GuestMemory(ref e), GuestMemory(ref other_e)) => {
-                    format!("{}", e).eq(&format!("{}", other_e))
-                }
-                (InvalidAccess, InvalidAccess) => true,
-                (InvalidDataLength, InvalidDataLength) => true,
-                (InvalidFlags, InvalidFlags) => true,
-                (Overflow, Overflow) => true,
-                (Read(ref e, bytes), Read(ref other_e, other_bytes)) => {
-                    format!("{}", e).eq(&format!("{}", other_e)) && bytes == other_bytes
-                }
-                (ReadOnly, ReadOnly) => true,
-                (Write(ref e), Write(ref other_e)) => format!("{}", e).eq(&format!("{}", other_e)),
-                (Seek(ref e), Seek(ref other_e)) => format!("{}", e).eq(&format!("{}", other_e)),
-                (Unsupported(val), Unsupported(other_val)) => val == other_val,
-                _ => false,
+    /// Validates `request` against `mem` and the device, without performing any I/O.
+    ///
+    /// This runs the same checks as [`execute`](#method.execute) does before touching the
+    /// backing object: negotiated features, data lengths, device bounds, protected ranges,
+    /// descriptor, DMA page and request size limits, and discard/write zeroes segments. It also
+    /// verifies that all the request buffers are valid guest memory. VMMs can use it for auditing
+    /// a request before committing to executing it.
+    ///
+    /// # Arguments
+    /// * `mem` - A reference to the guest memory.
+    /// * `request` - The request to validate.
+    pub fn execute_dry_run<M: GuestMemory>(&mut self, mem: &M, request: &Request) -> Result<()> {
+        self.validate_request(mem, request)?;
+        for DataDescriptor {
+            addr: data_addr,
+            len_bytes: data_len,
+        } in request.data()
+        {
+            if !mem.check_range(*data_addr, *data_len as usize) {
+                return Err(Error::GuestMemory(GuestMemoryError::InvalidGuestAddress(
+                    *data_addr,
+                )));
             }
         }
+        Ok(())
     }
 
-    #[test]
-    fn test_execute_request() {
-        const NON_ZERO_VALUE: u8 = 0x55;
+    // Checks `request` against the negotiated features and the limits of the device, before
+    // anything is done on the backing object. The segments of discard/write zeroes requests are
+    // read from `mem` and returned, so they are not read again after being checked.
+    fn validate_request<M: GuestMemory>(
+        &self,
+        mem: &M,
+        request: &Request,
+    ) -> Result<ValidatedRequest> {
+        let request_type = request.request_type();
+        request
+            .sector()
+            .checked_shl(u32::from(SECTOR_SHIFT))
+            .ok_or(Error::InvalidAccess)?;
+        self.check_request(request_type)?;
+        self.check_data_descriptors(request)?;
 
-        let mut f = TempFile::new().unwrap().into_file();
+        let total_len = request.total_data_len();
+        match request_type {
+            RequestType::In | RequestType::Out => {
+                if !total_len.is_multiple_of(SECTOR_SIZE) {
+                    return Err(Error::InvalidDataLength);
+                }
+                self.check_access(total_len / SECTOR_SIZE, request.sector())?;
+                // Total data length should fit in an u32 for further writing in the used ring.
+                if request_type == RequestType::In && total_len > u32::MAX as u64 {
+                    return Err(Error::InvalidDataLength);
+                }
+                if request_type == RequestType::Out {
+                    self.check_protected(request.sector(), total_len / SECTOR_SIZE)?;
+                }
+                self.check_dma_pages(request)?;
+                Ok(ValidatedRequest {
+                    sub_requests: self.split_io(request)?,
+                    ..Default::default()
+                })
+            }
+            RequestType::Flush => Ok(ValidatedRequest::default()),
+            RequestType::GetDeviceID => {
+                if self.device_id.is_none() {
+                    return Err(Error::Unsupported(VIRTIO_BLK_T_GET_ID));
+                }
+                // The length of data MUST be VIRTIO_BLK_ID_BYTES bytes for VIRTIO_BLK_T_GET_ID
+                // requests.
+                if total_len != VIRTIO_BLK_ID_BYTES as u64 {
+                    return Err(Error::InvalidDataLength);
+                }
+                Ok(ValidatedRequest::default())
+            }
+            RequestType::Discard | RequestType::WriteZeroes => {
+                self.check_segments_count(request)?;
+                let mut segments = Vec::new();
+                for DataDescriptor {
+                    addr: data_addr,
+                    len_bytes: data_len,
+                } in request.data()
+                {
+                    // We support for now only data descriptors with the `len` field = multiple of
+                    // the size of `virtio_blk_discard_write_zeroes` segment. The specification,
+                    // however, requires that only `total_len` be such multiple (a segment can be
+                    // divided between several descriptors). Once we switch to a more general
+                    // approach regarding how we store and parse the device buffers, we'll fix this
+                    // too.
+                    if !u64::from(*data_len).is_multiple_of(VIRTIO_BLK_DISCARD_SEGMENT_LEN) {
+                        return Err(Error::InvalidDataLength);
+                    }
+                    let mut crt_addr = *data_addr;
+                    let end_addr = crt_addr
+                        .checked_add(*data_len as u64)
+                        .ok_or(Error::Overflow)?;
+                    while crt_addr < end_addr {
+                        let segment = mem.read_obj(crt_addr).map_err(Error::GuestMemory)?;
+                        self.check_segment(&segment, request_type)?;
+                        segments.push(segment);
+                        // The addition can not overflow since `end_addr` didn't.
+                        crt_addr = crt_addr.unchecked_add(VIRTIO_BLK_DISCARD_SEGMENT_LEN);
+                    }
+                }
+                Ok(ValidatedRequest {
+                    segments,
+                    ..Default::default()
+                })
+            }
+            RequestType::ZoneReport => {
+                Self::check_zone_report(request)?;
+                self.check_access(1, request.sector())?;
+                Ok(ValidatedRequest::default())
+            }
+            RequestType::Unsupported(t) => Err(Error::Unsupported(t)),
+        }
+    }
+
+    // Checks that the data of a zone report request can hold at least the report header, and
+    // that its length fits in an u32 for further writing in the used ring.
+    fn check_zone_report(request: &Request) -> Result<()> {
+        let total_len = request.total_data_len();
+        if total_len < ZoneReportHeader::LEN as u64 || total_len > u32::MAX as u64 {
+            return Err(Error::InvalidDataLength);
+        }
+        Ok(())
+    }
+
+    // Checks that the data descriptors of `request` respect the device limits.
+    fn check_data_descriptors(&self, request: &Request) -> Result<()> {
+        let count = request.data().len();
+        if count as u64 > u64::from(self.seg_max) {
+            return Err(Error::TooManySegments {
+                count,
+                max: self.seg_max,
+            });
+        }
+        if let Some(desc) = request
+            .data()
+            .iter()
+            .find(|desc| desc.len_bytes > self.size_max)
+        {
+            return Err(Error::DescriptorTooLarge {
+                len: desc.len_bytes,
+                max: self.size_max,
+            });
+        }
+        Ok(())
+    }
+
+    // Returns the range of sectors that has to be locked for writing the sectors from `start` to
+    // `end` (exclusive). It is extended to whole physical blocks, since `write_padded` rewrites
+    // the rest of the blocks that are written partially.
+    fn write_lock_range(&self, start: u64, end: u64) -> (u64, u64) {
+        match self.physical_block_size {
+            Some(block_size) => {
+                let block_sectors = u64::from(block_size) / SECTOR_SIZE;
+                (
+                    start - start % block_sectors,
+                    end.div_ceil(block_sectors) * block_sectors,
+                )
+            }
+            None => (start, end),
+        }
+    }
+
+    // Writes the data of `request` at `offset` in whole blocks of `block_size` bytes, from a
+    // buffer aligned to `block_size` bytes as required by `O_DIRECT`. The partial blocks at the
+    // edges of the range are read from the backing object first.
+    fn write_padded<M: GuestMemory>(
+        &mut self,
+        mem: &M,
+        request: &Request,
+        offset: u64,
+        block_size: u64,
+    ) -> Result<()> {
+        let end = offset + request.total_data_len();
+        let aligned_start = offset - offset % block_size;
+        let aligned_end = end.div_ceil(block_size) * block_size;
+        let len = (aligned_end - aligned_start) as usize;
+        let mut storage = vec![0u8; len + block_size as usize];
+        let align_offset = storage.as_ptr().align_offset(block_size as usize);
+        let buf = &mut storage[align_offset..align_offset + len];
+
+        let head_len = (offset - aligned_start) as usize;
+        if head_len != 0 {
+            self.read_block(aligned_start, &mut buf[..block_size as usize])?;
+        }
+        // The last block was already read if it's also the first one.
+        if end != aligned_end && (head_len == 0 || len as u64 > block_size) {
+            self.read_block(
+                aligned_end - block_size,
+                &mut buf[len - block_size as usize..],
+            )?;
+        }
+
+        let mut pos = head_len;
+        for DataDescriptor {
+            addr: data_addr,
+            len_bytes: data_len,
+        } in request.data()
+        {
+            mem.read_slice(&mut buf[pos..pos + *data_len as usize], *data_addr)
+                .map_err(Error::GuestMemory)?;
+            pos += *data_len as usize;
+        }
+
+        self.inner
+            .seek(SeekFrom::Start(aligned_start))
+            .and_then(|_| {
+                self.inner
+                    .write_all_volatile(&VolatileSlice::from(&mut buf[..]))
+                    .map_err(Self::volatile_to_io_error)
+            })
+            .map_err(Error::AlignmentPaddingFailed)
+    }
+
+    // Fills `buf` with the data of the backing object at `offset`.
+    fn read_block(&mut self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        self.inner
+            .seek(SeekFrom::Start(offset))
+            .and_then(|_| {
+                self.inner
+                    .read_exact_volatile(&mut VolatileSlice::from(buf))
+                    .map_err(Self::volatile_to_io_error)
+            })
+            .map_err(Error::AlignmentPaddingFailed)
+    }
+
+    // Converts an error of a volatile memory operation on the backing object to an I/O error.
+    fn volatile_to_io_error(err: VolatileMemoryError) -> io::Error {
+        match err {
+            VolatileMemoryError::IOError(err) => err,
+            err => io::Error::other(err),
+        }
+    }
+
+    // Advises the kernel that the guest memory buffers of `request` will be written soon.
+    // Prefetching is only a hint, so the buffers that can't be advised are skipped.
+    fn prefetch<M: GuestMemory>(mem: &M, request: &Request) {
+        // SAFETY: `sysconf` has no preconditions.
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        for slice in request.data_slices(mem).flatten() {
+            if slice.is_empty() {
+                continue;
+            }
+            let addr = slice.ptr_guard().as_ptr() as usize;
+            let start = addr - addr % page_size;
+            // SAFETY: The range is within the pages that back `slice`, which is a valid guest
+            // memory mapping, and `MADV_WILLNEED` doesn't change its content.
+            let ret = unsafe {
+                libc::madvise(
+                    start as *mut libc::c_void,
+                    addr + slice.len() - start,
+                    libc::MADV_WILLNEED,
+                )
+            };
+            if ret < 0 {
+                warn!(
+                    "Failed to prefetch guest memory: {}",
+                    io::Error::last_os_error()
+                );
+            }
+        }
+    }
+
+    // Checks that a discard/write zeroes request doesn't have more segments than allowed.
+    fn check_segments_count(&self, request: &Request) -> Result<()> {
+        let max = if request.request_type() == RequestType::Discard {
+            self.max_discard_seg
+        } else {
+            self.max_write_zeroes_seg
+        };
+        // Incomplete segments are reported by the data length checks.
+        let count = request.request_size_bytes() / VIRTIO_BLK_DISCARD_SEGMENT_LEN;
+        if count > u64::from(max) {
+            return Err(Error::TooManySegments {
+                count: usize::try_from(count).unwrap_or(usize::MAX),
+                max,
+            });
+        }
+        Ok(())
+    }
+
+    // Checks that a discard/write zeroes segment has valid flags and targets a valid range.
+    fn check_segment(&self, segment: &DiscardWriteZeroes, request_type: RequestType) -> Result<()> {
+        let flags = segment.flags;
+
+        // For Discard, unmap bit (the least significant bit from segment flags) MUST be 0, for
+        // Write Zeroes it can be either 0 or 1.
+        // The other bits are reserved and MUST not be set (for both request types).
+        // If any of these conditions are not met, status must be set to VIRTIO_BLK_S_UNSUPP.
+        let valid_flags = if request_type == RequestType::WriteZeroes {
+            DiscardWriteZeroes::UNMAP
+        } else {
+            0
+        };
+        if (flags & !valid_flags) != 0 {
+            return Err(Error::InvalidFlags);
+        }
+
+        let max_sectors = if request_type == RequestType::WriteZeroes {
+            self.max_write_zeroes_sectors
+        } else {
+            self.max_discard_sectors
+        };
+        if segment.num_sectors > max_sectors {
+            return Err(Error::RequestTooLarge {
+                request_sectors: u64::from(segment.num_sectors),
+                max_sectors,
+            });
+        }
+
+        self.check_access(segment.num_sectors as u64, segment.sector)?;
+        self.check_protected(segment.sector, segment.num_sectors as u64)
+    }
+
+    fn handle_discard_write_zeroes(
+        &mut self,
+        segment: &DiscardWriteZeroes,
+        request_type: RequestType,
+    ) -> Result<u32> {
+        let sector = segment.sector;
+        let num_sectors = u64::from(segment.num_sectors);
+        let flags = segment.flags;
+
+        let range_lock = self.range_lock.clone();
+        // The segment was checked by `validate_request`, so the end of the range doesn't
+        // overflow.
+        let (start, end) = self.write_lock_range(sector, sector + num_sectors);
+        let _guard = range_lock
+            .as_ref()
+            .map(|lock| lock.lock_exclusive(start, end));
+
+        if request_type == RequestType::Discard {
+            // Since Discard is just a hint and some filesystems may not implement
+            // FALLOC_FL_PUNCH_HOLE, ignore punch_hole() errors.
+            let _ = self.punch_hole_locked(sector, num_sectors);
+        } else {
+            // If unmap is set, try at first to punch a hole, if it fails, fall back to just
+            // writing zeroes. For `File` backends, `write_zeroes_at` already tries
+            // `fallocate(FALLOC_FL_ZERO_RANGE)` before physically writing the zeroes.
+            // After a write zeroes command is completed, reads of the specified ranges of sectors
+            // MUST return zeroes, independent of unmap value.
+            if flags & DiscardWriteZeroes::UNMAP == 0
+                || self.punch_hole_locked(sector, num_sectors).is_err()
+            {
+                self.write_zeroes_locked(sector, num_sectors)?;
+            }
+        }
+        Ok(0)
+    }
+
+    // Returns the (offset, length) in bytes of a range of sectors, after checking that the range
+    // is valid.
+    fn sectors_to_bytes(&self, sector: u64, num_sectors: u64) -> Result<(u64, u64)> {
+        self.check_access(num_sectors, sector)?;
+        let offset = sector
+            .checked_shl(u32::from(SECTOR_SHIFT))
+            .ok_or(Error::InvalidAccess)?;
+        let length = num_sectors
+            .checked_shl(u32::from(SECTOR_SHIFT))
+            .ok_or(Error::InvalidAccess)?;
+        Ok((offset, length))
+    }
+
+    /// Writes zeroes to `num_sectors` sectors starting with `sector`.
+    ///
+    /// This performs the same bounds checks as a write zeroes request, without requiring the
+    /// `VIRTIO_BLK_F_WRITE_ZEROES` feature, so it can be used for disk management operations
+    /// initiated by the VMM. The sectors are locked against the requests executed concurrently
+    /// by the clones of this executor, and the zeroing is logged to the journal, if any, like
+    /// for a write zeroes request.
+    ///
+    /// # Arguments
+    /// * `sector` - The first sector to zero out.
+    /// * `num_sectors` - The number of sectors to zero out.
+    pub fn write_zeroes_range(&mut self, sector: u64, num_sectors: u64) -> Result<()> {
+        self.check_access(num_sectors, sector)?;
+        let range_lock = self.range_lock.clone();
+        // The range was checked above, so its end doesn't overflow.
+        let (start, end) = self.write_lock_range(sector, sector + num_sectors);
+        let _guard = range_lock
+            .as_ref()
+            .map(|lock| lock.lock_exclusive(start, end));
+        self.write_zeroes_locked(sector, num_sectors)
+    }
+
+    // Writes zeroes like `write_zeroes_range`, to sectors that the caller already locked.
+    fn write_zeroes_locked(&mut self, sector: u64, num_sectors: u64) -> Result<()> {
+        let lsn = self.log_sectors(sector, JournalData::Zeroes(num_sectors))?;
+        let result = self.zero_sectors(sector, num_sectors);
+        self.complete_write(lsn);
+        result
+    }
+
+    // Writes zeroes to `num_sectors` sectors starting with `sector`, without logging them to the
+    // journal.
+    fn zero_sectors(&mut self, sector: u64, num_sectors: u64) -> Result<()> {
+        let (offset, length) = self.sectors_to_bytes(sector, num_sectors)?;
+        let length = usize::try_from(length).map_err(|_| Error::InvalidAccess)?;
+        let _written = self.start_write();
+        self.inner
+            .write_all_zeroes_at(offset, length)
+            .map_err(Error::DiscardWriteZeroes)
+    }
+
+    /// Writes `pattern` to each of the `num_sectors` sectors starting with `sector`, like the
+    /// SCSI `WRITE SAME` command does.
+    ///
+    /// An all-zero pattern is written with [`write_zeroes_range`](#method.write_zeroes_range),
+    /// which for block devices on Linux results in the same operation as the `BLKZEROOUT` ioctl.
+    /// Other patterns are written in chunks of sectors.
+    ///
+    /// # Arguments
+    /// * `sector` - The first sector to write.
+    /// * `num_sectors` - The number of sectors to write.
+    /// * `pattern` - The data of each sector.
+    pub fn write_same(
+        &mut self,
+        sector: u64,
+        num_sectors: u64,
+        pattern: &[u8; SECTOR_SIZE as usize],
+    ) -> Result<()> {
+        if pattern.iter().all(|&b| b == 0) {
+            return self.write_zeroes_range(sector, num_sectors);
+        }
+        self.check_access(num_sectors, sector)?;
+
+        let chunk_sectors = cmp::min(COPY_CHUNK_SECTORS, num_sectors);
+        // The conversion is safe since `chunk_sectors` is at most COPY_CHUNK_SECTORS.
+        let mut buf = pattern.repeat(chunk_sectors as usize);
+        let mut written = 0;
+        while written < num_sectors {
+            let count = cmp::min(chunk_sectors, num_sectors - written);
+            // The addition can not overflow since the range was checked above.
+            self.write_sectors_logged(
+                sector + written,
+                &mut buf[..(count * SECTOR_SIZE) as usize],
+            )?;
+            written += count;
+        }
+        Ok(())
+    }
+
+    /// Deallocates `num_sectors` sectors starting with `sector`, which will read as zeroes
+    /// afterwards.
+    ///
+    /// This performs the same bounds checks as a discard request, without requiring the
+    /// `VIRTIO_BLK_F_DISCARD` feature, so it can be used for disk management operations
+    /// initiated by the VMM. The sectors are locked and the deallocation is logged like for
+    /// [`write_zeroes_range`](#method.write_zeroes_range). Unlike for discard requests, errors
+    /// are not ignored.
+    ///
+    /// # Arguments
+    /// * `sector` - The first sector to deallocate.
+    /// * `num_sectors` - The number of sectors to deallocate.
+    pub fn punch_hole_range(&mut self, sector: u64, num_sectors: u64) -> Result<()> {
+        self.check_access(num_sectors, sector)?;
+        let range_lock = self.range_lock.clone();
+        // The range was checked above, so its end doesn't overflow.
+        let (start, end) = self.write_lock_range(sector, sector + num_sectors);
+        let _guard = range_lock
+            .as_ref()
+            .map(|lock| lock.lock_exclusive(start, end));
+        self.punch_hole_locked(sector, num_sectors)
+    }
+
+    // Deallocates sectors like `punch_hole_range`, that the caller already locked.
+    fn punch_hole_locked(&mut self, sector: u64, num_sectors: u64) -> Result<()> {
+        let (offset, length) = self.sectors_to_bytes(sector, num_sectors)?;
+        // The sectors read as zeroes afterwards, so that's what is replayed.
+        let lsn = self.log_sectors(sector, JournalData::Zeroes(num_sectors))?;
+        let _written = self.start_write();
+        let result = self
+            .inner
+            .punch_hole(offset, length)
+            .map_err(Error::DiscardWriteZeroes);
+        self.complete_write(lsn);
+        result
+    }
+
+    /// Reads `num_sectors` sectors starting with `start_sector` into a new buffer.
+    ///
+    /// The returned buffer always contains complete sectors, so its length is exactly
+    /// `num_sectors * SECTOR_SIZE` bytes.
+    ///
+    /// # Arguments
+    /// * `start_sector` - The first sector to read.
+    /// * `num_sectors` - The number of sectors to read.
+    pub fn sector_aligned_read(&mut self, start_sector: u64, num_sectors: u64) -> Result<Vec<u8>> {
+        let (_, length) = self.sectors_to_bytes(start_sector, num_sectors)?;
+        let mut buf = vec![0u8; usize::try_from(length).map_err(|_| Error::InvalidAccess)?];
+        self.read_sectors(start_sector, &mut buf)?;
+        Ok(buf)
+    }
+
+    /// Calls `f` with a sector sized buffer, and returns its result.
+    ///
+    /// The buffer is allocated on the heap once and reused by the following calls, so the
+    /// callers don't need a sector sized buffer on their stack. It is aligned to 4096 bytes, so
+    /// it can be used for direct I/O, and it is zeroed before every call.
+    ///
+    /// # Arguments
+    /// * `f` - The function to call with the buffer.
+    pub fn with_aligned_buffer<F: FnOnce(&mut [u8]) -> R, R>(&mut self, f: F) -> R {
+        self.with_sector_buf(|_, buf| f(buf))
+    }
+
+    // Calls `f` with the backend and the zeroed sector buffer. The buffer is moved out of the
+    // backend during the call, so a nested call allocates a new one.
+    fn with_sector_buf<F, R>(&mut self, f: F) -> R
+    where
+        F: FnOnce(&mut Self, &mut [u8; SECTOR_SIZE as usize]) -> R,
+    {
+        let mut buf = match self.sector_buf.take() {
+            Some(mut buf) => {
+                buf.0.fill(0);
+                buf
+            }
+            None => Box::new(AlignedSector([0u8; SECTOR_SIZE as usize])),
+        };
+        let result = f(self, &mut buf.0);
+        self.sector_buf = Some(buf);
+        result
+    }
+
+    // Reads the sector at index `sector` into `buf`.
+    fn read_sector(&mut self, sector: u64, buf: &mut [u8; SECTOR_SIZE as usize]) -> Result<()> {
+        self.read_sectors(sector, buf)
+    }
+
+    // Reads the sectors starting with `sector` into `buf`, whose length must be a multiple of
+    // the sector size.
+    fn read_sectors(&mut self, sector: u64, buf: &mut [u8]) -> Result<()> {
+        let (offset, _) = self.sectors_to_bytes(sector, buf.len() as u64 / SECTOR_SIZE)?;
+        self.inner
+            .seek(SeekFrom::Start(offset))
+            .map_err(Error::Seek)?;
+        self.inner
+            .read_exact_volatile(&mut VolatileSlice::from(buf))
+            .map_err(Error::Backend)
+    }
+
+    // Writes `buf`, whose length must be a multiple of the sector size, to the sectors starting
+    // with `sector`.
+    fn write_sectors(&mut self, sector: u64, buf: &mut [u8]) -> Result<()> {
+        let (offset, _) = self.sectors_to_bytes(sector, buf.len() as u64 / SECTOR_SIZE)?;
+        let _written = self.start_write();
+        self.inner
+            .seek(SeekFrom::Start(offset))
+            .map_err(Error::Seek)?;
+        self.inner
+            .write_all_volatile(&VolatileSlice::from(buf))
+            .map_err(Error::Backend)
+    }
+
+    // Writes `buf` like `write_sectors`, after logging it to the journal, if any.
+    fn write_sectors_logged(&mut self, sector: u64, buf: &mut [u8]) -> Result<()> {
+        let lsn = self.log_sectors(sector, JournalData::Write(buf.to_vec()))?;
+        let result = self.write_sectors(sector, buf);
+        self.complete_write(lsn);
+        result
+    }
+
+    /// Copies `num_sectors` sectors starting with `src_sector` to the sectors starting with
+    /// `dst_sector`, e.g. for creating copy-on-write snapshots inside the same backing object.
+    ///
+    /// The data is copied through a bounce buffer, which works for any backend. The ranges may
+    /// overlap, in which case the result is the same as if the source range was first copied to
+    /// a temporary buffer. Backends with a file descriptor can use
+    /// [`copy_range_in_kernel`](#method.copy_range_in_kernel) instead.
+    ///
+    /// # Arguments
+    /// * `src_sector` - The first sector to copy from.
+    /// * `dst_sector` - The first sector to copy to.
+    /// * `num_sectors` - The number of sectors to copy.
+    pub fn copy_range(&mut self, src_sector: u64, dst_sector: u64, num_sectors: u64) -> Result<()> {
+        self.check_access(num_sectors, src_sector)?;
+        self.check_access(num_sectors, dst_sector)?;
+
+        let mut buf = vec![0u8; (COPY_CHUNK_SECTORS * SECTOR_SIZE) as usize];
+        // When the destination overlaps the end of the source, copy backwards so that the source
+        // sectors are read before being overwritten.
+        let backwards = dst_sector > src_sector && dst_sector - src_sector < num_sectors;
+        let mut copied = 0;
+        while copied < num_sectors {
+            let count = cmp::min(COPY_CHUNK_SECTORS, num_sectors - copied);
+            let start = if backwards {
+                num_sectors - copied - count
+            } else {
+                copied
+            };
+            // The conversion is safe since `count` is at most COPY_CHUNK_SECTORS.
+            let chunk = &mut buf[..(count * SECTOR_SIZE) as usize];
+            // The additions can not overflow since both ranges were checked above.
+            self.read_sectors(src_sector + start, chunk)?;
+            self.write_sectors_logged(dst_sector + start, chunk)?;
+            copied += count;
+        }
+        Ok(())
+    }
+
+    /// Copies all the sectors of the device to the same sectors of `dst`, e.g. for cloning the
+    /// disk of a VM, and returns the number of bytes that were copied.
+    ///
+    /// The data is copied in chunks of sectors. `dst` must have at least as many sectors as the
+    /// device. Each chunk is written with the sectors locked in the range lock of `dst`, if any,
+    /// and logged to its journal, if any, like the `Out` requests executed by `dst`.
+    ///
+    /// # Arguments
+    /// * `dst` - The device to copy the sectors to.
+    /// * `progress` - Called after each chunk with the number of sectors copied so far and the
+    ///                total number of sectors.
+    pub fn copy_to<B2: Backend>(
+        &mut self,
+        dst: &mut StdIoBackend<B2>,
+        mut progress: Option<&mut dyn FnMut(u64, u64)>,
+    ) -> Result<u64> {
+        let total_sectors = self.num_sectors();
+        dst.check_access(total_sectors, 0)?;
+
+        let mut buf = vec![0u8; (COPY_CHUNK_SECTORS * SECTOR_SIZE) as usize];
+        let mut copied = 0;
+        while copied < total_sectors {
+            let count = cmp::min(COPY_CHUNK_SECTORS, total_sectors - copied);
+            // The conversion is safe since `count` is at most COPY_CHUNK_SECTORS.
+            let chunk = &mut buf[..(count * SECTOR_SIZE) as usize];
+            self.read_sectors(copied, chunk)?;
+            let range_lock = dst.range_lock.clone();
+            // The end of the range can't overflow since it's at most the number of sectors.
+            let (start, end) = dst.write_lock_range(copied, copied + count);
+            let _guard = range_lock
+                .as_ref()
+                .map(|lock| lock.lock_exclusive(start, end));
+            dst.write_sectors_logged(copied, chunk)?;
+            copied += count;
+            if let Some(progress) = progress.as_mut() {
+                progress(copied, total_sectors);
+            }
+        }
+        Ok(total_sectors << SECTOR_SHIFT)
+    }
+
+    /// Writes the raw data of the sectors in `sector_range`, or of the whole device if it is
+    /// `None`, to `writer`, e.g. a Unix domain socket when migrating a VM, and returns the number
+    /// of bytes that were written.
+    ///
+    /// The sectors are read and written in chunks, so the data is never buffered completely in
+    /// memory. Errors writing to `writer` are returned as `Error::Pipe`.
+    ///
+    /// # Arguments
+    /// * `writer` - The destination of the data.
+    /// * `sector_range` - The sectors to write, or `None` for all of them.
+    pub fn pipe_to<W: Write>(
+        &mut self,
+        mut writer: W,
+        sector_range: Option<RangeInclusive<u64>>,
+    ) -> Result<u64> {
+        let (first, last) = match sector_range {
+            Some(range) => (*range.start(), *range.end()),
+            None => match self.num_sectors().checked_sub(1) {
+                Some(last) => (0, last),
+                None => return Ok(0),
+            },
+        };
+        // Empty ranges are rejected, like the other invalid ranges.
+        let total_sectors = last
+            .checked_sub(first)
+            .and_then(|count| count.checked_add(1))
+            .ok_or(Error::InvalidAccess)?;
+        self.check_access(total_sectors, first)?;
+
+        let mut buf =
+            vec![0u8; (cmp::min(COPY_CHUNK_SECTORS, total_sectors) * SECTOR_SIZE) as usize];
+        let mut written = 0;
+        while written < total_sectors {
+            let count = cmp::min(COPY_CHUNK_SECTORS, total_sectors - written);
+            // The conversion is safe since `count` is at most COPY_CHUNK_SECTORS.
+            let chunk = &mut buf[..(count * SECTOR_SIZE) as usize];
+            // The addition can not overflow since the range was checked above.
+            self.read_sectors(first + written, chunk)?;
+            writer.write_all(chunk).map_err(Error::Pipe)?;
+            written += count;
+        }
+        writer.flush().map_err(Error::Pipe)?;
+        Ok(total_sectors << SECTOR_SHIFT)
+    }
+
+    /// Compares the sectors of the device with the same sectors of `other`, e.g. for incremental
+    /// backups, and returns the indices of the sectors whose contents differ, in ascending order.
+    ///
+    /// The devices, which must have the same number of sectors, are read in parallel by a
+    /// thread each, in chunks that are compared by the calling thread while the next ones are
+    /// read.
+    ///
+    /// # Arguments
+    /// * `other` - The device to compare with.
+    pub fn diff_from<B2: Backend + Send>(
+        &mut self,
+        other: &mut StdIoBackend<B2>,
+    ) -> Result<Vec<u64>>
+    where
+        B: Send,
+    {
+        let total_sectors = self.num_sectors();
+        if other.num_sectors() != total_sectors {
+            return Err(Error::InvalidAccess);
+        }
+
+        thread::scope(|s| {
+            let (tx, rx) = mpsc::sync_channel(DIFF_QUEUED_CHUNKS);
+            let (other_tx, other_rx) = mpsc::sync_channel(DIFF_QUEUED_CHUNKS);
+            s.spawn(move || self.send_chunks(total_sectors, tx));
+            s.spawn(move || other.send_chunks(total_sectors, other_tx));
+
+            // Returning early drops the receivers, which stops the readers.
+            let mut diff = Vec::new();
+            let mut compared = 0;
+            for (chunk, other_chunk) in rx.iter().zip(other_rx.iter()) {
+                let (chunk, other_chunk) = (chunk?, other_chunk?);
+                diff.extend(
+                    (compared..)
+                        .zip(chunk.chunks_exact(SECTOR_SIZE as usize))
+                        .zip(other_chunk.chunks_exact(SECTOR_SIZE as usize))
+                        .filter(|((_, data), other_data)| data != other_data)
+                        .map(|((sector, _), _)| sector),
+                );
+                compared += chunk.len() as u64 / SECTOR_SIZE;
+            }
+            Ok(diff)
+        })
+    }
+
+    // Reads the first `total_sectors` sectors in chunks and sends them to `tx`, until the first
+    // error is sent or the receiver is dropped.
+    fn send_chunks(&mut self, total_sectors: u64, tx: SyncSender<Result<Vec<u8>>>) {
+        let mut sent = 0;
+        while sent < total_sectors {
+            let count = cmp::min(COPY_CHUNK_SECTORS, total_sectors - sent);
+            // The conversion is safe since `count` is at most COPY_CHUNK_SECTORS.
+            let mut chunk = vec![0u8; (count * SECTOR_SIZE) as usize];
+            let result = self.read_sectors(sent, &mut chunk).map(|_| chunk);
+            let failed = result.is_err();
+            if tx.send(result).is_err() || failed {
+                return;
+            }
+            sent += count;
+        }
+    }
+
+    // Drops all sectors starting with `num_sectors` from the device. The apparent size of the
+    // backing object is left unchanged since `Backend` provides no way of truncating it, but the
+    // released range is punched out so that it no longer consumes storage space.
+    fn truncate_to_sectors(&mut self, num_sectors: u64) -> Result<()> {
+        let removed_sectors = self
+            .num_sectors()
+            .checked_sub(num_sectors)
+            .ok_or(Error::InvalidAccess)?;
+        // Releasing the space is just an optimization, so ignore punch_hole() errors.
+        let _ = self.punch_hole_range(num_sectors, removed_sectors);
+        self.num_sectors = num_sectors;
+        #[cfg(debug_assertions)]
+        self.assert_invariants();
+        Ok(())
+    }
+
+    /// Removes the last `sectors_to_remove` sectors from the device and returns the new number of
+    /// sectors.
+    ///
+    /// This is meant for compacting the end of a disk after the guest discarded it, so all the
+    /// removed sectors must contain only zeroes. Otherwise, `Error::NonZeroSector` is returned
+    /// with the index of the first non-zero sector, and the device is left unchanged.
+    ///
+    /// # Arguments
+    /// * `sectors_to_remove` - The number of sectors to remove from the end of the device.
+    pub fn shrink_sectors(&mut self, sectors_to_remove: u64) -> Result<u64> {
+        let new_num_sectors = self
+            .num_sectors()
+            .checked_sub(sectors_to_remove)
+            .ok_or(Error::InvalidAccess)?;
+        self.with_sector_buf(|this, buf| {
+            for sector in new_num_sectors..this.num_sectors() {
+                this.read_sector(sector, buf)?;
+                if buf.iter().any(|&b| b != 0) {
+                    return Err(Error::NonZeroSector(sector));
+                }
+            }
+            Ok(())
+        })?;
+        self.truncate_to_sectors(new_num_sectors)?;
+        Ok(new_num_sectors)
+    }
+
+    /// Erases the whole device according to `standard`, e.g. when the VM is decommissioned, and
+    /// syncs the backing object.
+    ///
+    /// Every pass overwrites complete sectors with data buffers, since zeroing operations like
+    /// punching holes or `FALLOC_FL_ZERO_RANGE` don't overwrite the media. Protected ranges and
+    /// the read-only feature are not taken into account, like for the other operations initiated
+    /// by the VMM. All the sectors stay locked in the range lock, if any, until the wipe
+    /// completes, and the journal, if any, is reset after the final sync, so the writes logged
+    /// before the wipe are never replayed over the erased data.
+    ///
+    /// # Arguments
+    /// * `standard` - How to erase the device.
+    /// * `progress` - Set to the number of passes completed so far, so it can be polled from
+    ///                another thread.
+    pub fn wipe_for_decommission(
+        &mut self,
+        standard: WipeStandard,
+        progress: Arc<AtomicU64>,
+    ) -> Result<()> {
+        progress.store(0, Ordering::SeqCst);
+        let range_lock = self.range_lock.clone();
+        let _guard = range_lock
+            .as_ref()
+            .map(|lock| lock.lock_exclusive(0, self.num_sectors()));
+        match standard {
+            WipeStandard::Zeros | WipeStandard::NIST800_88 => {
+                self.wipe_pass(|_| {})?;
+                progress.store(1, Ordering::SeqCst);
+            }
+            WipeStandard::DoD5220_22M => {
+                self.wipe_pass(|_| {})?;
+                progress.store(1, Ordering::SeqCst);
+                self.wipe_pass(|buf| buf.fill(0xFF))?;
+                progress.store(2, Ordering::SeqCst);
+                let seed = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |time| time.as_nanos() as u64);
+                let mut rng = SplitMix64::new(seed);
+                self.wipe_pass(|buf| rng.fill_bytes(buf))?;
+                progress.store(3, Ordering::SeqCst);
+            }
+        }
+        self.sync()?;
+        match self.journal.as_ref() {
+            Some(journal) => journal.lock().unwrap().reset().map_err(Error::Journal),
+            None => Ok(()),
+        }
+    }
+
+    // Writes every sector of the device, in chunks whose data is set by `fill`. The chunks are
+    // zeroed before the first call.
+    fn wipe_pass<F: FnMut(&mut [u8])>(&mut self, mut fill: F) -> Result<()> {
+        let num_sectors = self.num_sectors();
+        let chunk_sectors = cmp::min(COPY_CHUNK_SECTORS, num_sectors);
+        // The conversion is safe since `chunk_sectors` is at most COPY_CHUNK_SECTORS.
+        let mut buf = vec![0u8; (chunk_sectors * SECTOR_SIZE) as usize];
+        let mut written = 0;
+        while written < num_sectors {
+            let count = cmp::min(chunk_sectors, num_sectors - written);
+            let chunk = &mut buf[..(count * SECTOR_SIZE) as usize];
+            fill(chunk);
+            self.write_sectors(written, chunk)?;
+            written += count;
+        }
+        Ok(())
+    }
+
+    /// Reads every sector of the device, and reports the ones that can't be read.
+    ///
+    /// The sectors are read in chunks, and the chunks that fail are read again one sector at a
+    /// time, so the errors are attributed to the right sectors. Errors are recorded in the
+    /// report instead of stopping the scan.
+    ///
+    /// # Arguments
+    /// * `report_interval` - Called after each chunk with the number of sectors scanned so far
+    ///                       and the total number of sectors.
+    pub fn integrity_scan(
+        &mut self,
+        report_interval: impl Fn(u64, u64),
+    ) -> Result<IntegrityScanReport> {
+        let total_sectors = self.num_sectors();
+        let mut report = IntegrityScanReport::default();
+        let mut buf = vec![0u8; (COPY_CHUNK_SECTORS * SECTOR_SIZE) as usize];
+        let mut scanned = 0;
+        while scanned < total_sectors {
+            let count = cmp::min(COPY_CHUNK_SECTORS, total_sectors - scanned);
+            // The conversion is safe since `count` is at most COPY_CHUNK_SECTORS.
+            if self
+                .read_sectors(scanned, &mut buf[..(count * SECTOR_SIZE) as usize])
+                .is_err()
+            {
+                self.with_sector_buf(|this, sector_buf| {
+                    for sector in scanned..scanned + count {
+                        if let Err(e) = this.read_sector(sector, sector_buf) {
+                            report.bad_sectors.push(sector);
+                            report.io_errors.push((sector, e.into()));
+                        }
+                    }
+                });
+            }
+            scanned += count;
+            report_interval(scanned, total_sectors);
+        }
+        Ok(report)
+    }
+
+    /// Runs `iterations` pseudo-random reads, writes, flushes, discards and write zeroes on the
+    /// device, and checks that the reads return the data written before.
+    ///
+    /// This is meant for validating new backend types before deploying them. The operations only
+    /// depend on `seed`, so failures can be reproduced. The content of the device is overwritten,
+    /// so the test should only be run on scratch backing objects.
+    ///
+    /// # Arguments
+    /// * `iterations` - The number of operations to run.
+    /// * `seed` - The seed the operations and the written data are derived from.
+    pub fn stress_test(&mut self, iterations: usize, seed: u64) -> StressTestReport {
+        let mut rng = SplitMix64::new(seed);
+        let mut report = StressTestReport::default();
+        // The seed of the data expected in each sector modified by the test, or `None` for
+        // zeroes. Discarded sectors have no expected content.
+        let mut expected: HashMap<u64, Option<u64>> = HashMap::new();
+
+        for _ in 0..iterations {
+            let num_sectors = self.num_sectors();
+            if num_sectors == 0 {
+                break;
+            }
+            let sector = rng.next_u64() % num_sectors;
+            let count =
+                1 + rng.next_u64() % cmp::min(STRESS_TEST_MAX_SECTORS, num_sectors - sector);
+            let sectors = sector..sector + count;
+
+            let result = match rng.next_u64() % 5 {
+                0 => self.sector_aligned_read(sector, count).map(|buf| {
+                    let mut data = [0u8; SECTOR_SIZE as usize];
+                    sectors
+                        .zip(buf.chunks_exact(SECTOR_SIZE as usize))
+                        .find(|(sector, actual)| match expected.get(sector) {
+                            Some(Some(data_seed)) => {
+                                SplitMix64::new(*data_seed).fill_bytes(&mut data);
+                                *actual != data
+                            }
+                            Some(None) => actual.iter().any(|&b| b != 0),
+                            None => false,
+                        })
+                        .map(|(sector, _)| sector)
+                }),
+                1 => {
+                    let mut buf = vec![0u8; (count * SECTOR_SIZE) as usize];
+                    let data_seeds: Vec<u64> = (0..count).map(|_| rng.next_u64()).collect();
+                    for (data, data_seed) in
+                        buf.chunks_exact_mut(SECTOR_SIZE as usize).zip(&data_seeds)
+                    {
+                        SplitMix64::new(*data_seed).fill_bytes(data);
+                    }
+                    let result = self.write_sectors(sector, &mut buf);
+                    for (sector, data_seed) in sectors.zip(data_seeds) {
+                        match result {
+                            Ok(_) => expected.insert(sector, Some(data_seed)),
+                            // The content of the sectors is unknown after a failed write.
+                            Err(_) => expected.remove(&sector),
+                        };
+                    }
+                    result.map(|_| None)
+                }
+                2 => self.sync().map(|_| None),
+                3 => {
+                    let result = self.punch_hole_range(sector, count);
+                    sectors.for_each(|sector| {
+                        expected.remove(&sector);
+                    });
+                    result.map(|_| None)
+                }
+                _ => {
+                    let result = self.write_zeroes_range(sector, count);
+                    for sector in sectors {
+                        match result {
+                            Ok(_) => expected.insert(sector, None),
+                            Err(_) => expected.remove(&sector),
+                        };
+                    }
+                    result.map(|_| None)
+                }
+            };
+
+            match result {
+                Ok(None) => report.passed += 1,
+                Ok(Some(sector)) => {
+                    report.failed += 1;
+                    report.errors.push(StressError::Mismatch(sector));
+                }
+                Err(e) => {
+                    report.failed += 1;
+                    report.errors.push(StressError::Operation(e));
+                }
+            }
+        }
+        report
+    }
+
+    /// Makes sure that all the writes executed so far reach the storage before any of the
+    /// following ones.
+    ///
+    /// The barrier syncs the backing object when `VIRTIO_BLK_F_FLUSH` was negotiated, and is a
+    /// no-op otherwise, since without the feature the device is expected to operate in
+    /// writethrough mode. This way VMMs don't have to check the negotiated features themselves.
+    /// The [`FlushPolicy`] can change this, by always or never syncing.
+    pub fn barrier(&mut self) -> Result<()> {
+        trace!("barrier called");
+        if self.syncs_writes() {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    // Returns whether the writes are synced, by flush requests and barriers or after every
+    // `Out` request, according to the flush policy and the negotiated features.
+    fn syncs_writes(&self) -> bool {
+        match self.flush_policy {
+            FlushPolicy::Always => true,
+            FlushPolicy::OnFeature => self.has_feature(VIRTIO_BLK_F_FLUSH.into()),
+            FlushPolicy::Never => false,
+        }
+    }
+
+    /// Executes `groups` of requests in order, syncing the backing object between consecutive
+    /// groups, and returns the results of the requests of each group.
+    ///
+    /// The requests of a group are executed in submission order, and the sync acts as a write
+    /// barrier: none of the requests of a group is executed before the writes of the previous
+    /// groups reached the storage. The sync is issued regardless of the negotiated features. When
+    /// it fails, the requests of the following groups are not executed and fail with
+    /// [`Error::Flush`](enum.Error.html#variant.Flush).
+    ///
+    /// # Arguments
+    /// * `mem` - The guest memory.
+    /// * `groups` - The groups of requests to execute.
+    pub fn execute_ordered<M: GuestMemory>(
+        &mut self,
+        mem: &M,
+        groups: &[&[Request]],
+    ) -> Vec<Vec<Result<u32>>> {
+        let mut results = Vec::with_capacity(groups.len());
+        let mut barrier_error = None;
+        for (i, group) in groups.iter().enumerate() {
+            if i > 0 && barrier_error.is_none() {
+                if let Err(e) = self.sync() {
+                    barrier_error = Some(e.io_error_kind());
+                }
+            }
+            let group_results = match barrier_error {
+                Some(kind) => group
+                    .iter()
+                    .map(|_| Err(Error::Flush(io::Error::from(kind))))
+                    .collect(),
+                None => group
+                    .iter()
+                    .map(|request| self.execute(mem, request))
+                    .collect(),
+            };
+            results.push(group_results);
+        }
+        results
+    }
+
+    // Records that the backing object may have been modified, so the next flush can't be
+    // skipped.
+    fn mark_written(&mut self) {
+        self.flush_state.write_lsn.fetch_add(1, Ordering::SeqCst);
+        self.invalidate_read_ahead();
+    }
+
+    // Marks the backing object as written like `mark_written`, for a write that lasts until the
+    // returned guard is dropped. The read-ahead cache is invalidated again at that point.
+    fn start_write(&mut self) -> WriteGuard {
+        self.mark_written();
+        WriteGuard((self.read_ahead_sectors != 0).then(|| self.read_ahead_cache.clone()))
+    }
+
+    // Syncs the backing object and records that all the writes so far were flushed.
+    fn sync(&mut self) -> Result<()> {
+        self.sync_backing().map_err(Error::Flush)
+    }
+
+    // Same as `sync`, for the callers outside of the request execution, which report the errors
+    // of the backing object on their own.
+    pub(crate) fn sync_backing(&mut self) -> io::Result<()> {
+        // The writes that happen while syncing aren't covered by this sync.
+        let write_lsn = self.flush_state.write_lsn.load(Ordering::SeqCst);
+        let completed = self
+            .journal
+            .as_ref()
+            .map(|journal| journal.lock().unwrap().take_completed());
+        if let Err(e) = self.inner.fsync() {
+            // The writes stay uncommitted until a later sync succeeds.
+            if let (Some(journal), Some(completed)) = (self.journal.as_ref(), completed) {
+                let mut journal = journal.lock().unwrap();
+                completed.into_iter().for_each(|lsn| journal.complete(lsn));
+            }
+            return Err(e);
+        }
+        self.flush_state
+            .flushed_lsn
+            .fetch_max(write_lsn + 1, Ordering::SeqCst);
+        // The journal entries of the synced writes can be committed.
+        if let (Some(journal), Some(completed)) = (self.journal.as_ref(), completed) {
+            journal.lock().unwrap().log_commits(&completed)?;
+        }
+        Ok(())
+    }
+
+    /// Obtains an immutable reference to the backing object.
+    pub fn inner(&self) -> &B {
+        &self.inner
+    }
+
+    /// Obtains a mutable reference to the backing object.
+    ///
+    /// The backing object may be modified through the reference, so the next flush request
+    /// syncs it even if no request modified it since the previous one.
+    pub fn inner_mut(&mut self) -> &mut B {
+        self.mark_written();
+        &mut self.inner
+    }
+
+    /// Consumes the [`StdIoBackend`], returning its backing object.
+    pub fn into_inner(self) -> B {
+        self.inner.into_inner()
+    }
+}
+
+impl StdIoBackend<VecBackend> {
+    /// Creates a new `StdIoBackend` whose data is kept in memory, e.g. for tests that don't need
+    /// a backing file.
+    ///
+    /// # Arguments
+    /// * `data` - The initial data of the device, whose length is its size.
+    /// * `features` - The features that were negotiated between driver and device.
+    pub fn from_vec(data: Vec<u8>, features: u64) -> Result<Self> {
+        Self::new(VecBackend::new(data), features)
+    }
+}
+
+impl StdIoBackend<File> {
+    /// Opens the file at `path` and creates a new `StdIoBackend` based on it.
+    ///
+    /// The file is opened for reading and writing, unless `VIRTIO_BLK_F_RO` is set in
+    /// `features`, in which case it is opened for reading only.
+    ///
+    /// # Arguments
+    /// * `path` - The path of the block device backing file.
+    /// * `features` - The features that were negotiated between driver and device.
+    pub fn open(path: &Path, features: u64) -> Result<Self> {
+        let read_only = (features & (1u64 << VIRTIO_BLK_F_RO)) != 0;
+        let file = OpenOptions::new()
+            .read(true)
+            .write(!read_only)
+            .open(path)
+            .map_err(Error::Open)?;
+        Self::new(file, features)
+    }
+
+    /// Opens the file at `path` for reading only, and creates a new read-only `StdIoBackend`
+    /// based on it.
+    ///
+    /// # Arguments
+    /// * `path` - The path of the block device backing file.
+    pub fn open_readonly(path: &Path) -> Result<Self> {
+        Self::open(path, 1 << VIRTIO_BLK_F_RO)
+    }
+}
+
+impl<B: Backend + BackendCapabilities> StdIoBackend<B> {
+    /// Returns the virtio block feature bits that `inner` is able to support, according to the
+    /// capabilities it reports.
+    ///
+    /// The result can be ANDed with the features offered by the driver to compute the ones that
+    /// should be negotiated. `VIRTIO_BLK_F_DISCARD` is set if `inner` can deallocate its storage
+    /// by punching holes, `VIRTIO_BLK_F_WRITE_ZEROES` if it can zero ranges without writing
+    /// buffers of zeroes, and `VIRTIO_BLK_F_FLUSH` if syncing makes the written data persistent.
+    ///
+    /// The operations are probed on the `inner` instance through [`BackendCapabilities`],
+    /// because the traits of a backend type don't tell them apart: `PunchHole` and `FileSync`
+    /// can be implemented by emulating the operation, e.g. by zeroing memory or by doing
+    /// nothing, which is indistinguishable at the type level from real support. Masking the
+    /// offered features with the result can therefore remove `VIRTIO_BLK_F_DISCARD` and
+    /// `VIRTIO_BLK_F_FLUSH` even though the executor can execute the requests, e.g. for a
+    /// [`VecBackend`], whose data is neither deallocated nor made persistent.
+    ///
+    /// # Arguments
+    /// * `inner` - The backend the executor is going to be created for.
+    pub fn feature_bits_supported_by_backend(inner: &B) -> u64 {
+        let info = inner.info();
+        let mut features = 0;
+        if info.supports_punch_hole {
+            features |= 1 << VIRTIO_BLK_F_DISCARD;
+        }
+        if info.supports_write_zeroes {
+            features |= 1 << VIRTIO_BLK_F_WRITE_ZEROES;
+        }
+        if info.supports_fsync {
+            features |= 1 << VIRTIO_BLK_F_FLUSH;
+        }
+        features
+    }
+}
+
+impl<B: Backend + TryClone> StdIoBackend<B> {
+    /// Creates an executor for another queue of the same device, which accesses the same data
+    /// with a duplicate of the backend.
+    ///
+    /// The new executor has the same configuration, but its own statistics, which are tagged
+    /// with `queue_id`. Executors of the same disk that can run concurrently should also share a
+    /// [`SectorRangeLock`], set with [`with_range_lock`](#method.with_range_lock) before cloning.
+    ///
+    /// # Arguments
+    /// * `queue_id` - The index of the queue served by the new executor.
+    pub fn clone_for_queue(&self, queue_id: u16) -> io::Result<StdIoBackend<B>> {
+        let mut clone = self.with_inner(self.inner.try_clone()?);
+        clone.stats = Arc::new(BlockStats::for_queue(queue_id));
+        Ok(clone)
+    }
+}
+
+impl<B: Backend + TryClone + Send> StdIoBackend<B> {
+    /// Executes `requests` like [`execute`](#method.execute) does, running independent requests
+    /// in parallel, and returns their results in the same order.
+    ///
+    /// Consecutive `In` and `Out` requests are executed concurrently, as long as no `Out`
+    /// request overlaps another request of the group. When a physical block size is set, the
+    /// requests are compared in whole physical blocks, since the writes rewrite the rest of the
+    /// blocks they modify partially. Overlapping writes, and all the other request types, are
+    /// serialised in submission order. The requests are distributed among at most as many
+    /// threads as the available parallelism, each one with its own clone of the backend, which
+    /// is reused for all the groups. If the backend can't be cloned at all, the requests of the
+    /// group fail with `Error::Clone`.
+    ///
+    /// # Arguments
+    /// * `mem` - The guest memory.
+    /// * `requests` - The requests to execute.
+    pub fn concurrent_execute<M: GuestMemory + Sync>(
+        &self,
+        mem: Arc<M>,
+        requests: Vec<Request>,
+    ) -> Vec<Result<u32>> {
+        let max_workers = thread::available_parallelism().map_or(1, |n| n.get());
+        let mut workers = Vec::new();
+        let mut results = Vec::with_capacity(requests.len());
+        let mut group: Vec<&Request> = Vec::new();
+        for request in requests.iter() {
+            if !group.iter().all(|other| self.independent(request, other)) {
+                results.extend(self.execute_group(mem.as_ref(), &group, &mut workers, max_workers));
+                group.clear();
+            }
+            group.push(request);
+        }
+        results.extend(self.execute_group(mem.as_ref(), &group, &mut workers, max_workers));
+        results
+    }
+
+    // Returns whether two requests can be executed concurrently, which is the case for `In` and
+    // `Out` requests that don't write to each other's physical blocks.
+    fn independent(&self, request: &Request, other: &Request) -> bool {
+        let range = |r: &Request| {
+            self.write_lock_range(
+                r.sector(),
+                r.sector()
+                    .saturating_add(r.total_data_len().div_ceil(SECTOR_SIZE)),
+            )
+        };
+        match (request.request_type(), other.request_type()) {
+            (RequestType::In, RequestType::In) => true,
+            (RequestType::In | RequestType::Out, RequestType::In | RequestType::Out) => {
+                let ((a_start, a_end), (b_start, b_end)) = (range(request), range(other));
+                a_end <= b_start || b_end <= a_start
+            }
+            _ => false,
+        }
+    }
+
+    // Executes independent requests in parallel on up to `max_workers` clones of the executor,
+    // one thread each. The clones are created as needed and kept in `workers` for the next
+    // groups.
+    fn execute_group<M: GuestMemory + Sync>(
+        &self,
+        mem: &M,
+        requests: &[&Request],
+        workers: &mut Vec<Self>,
+        max_workers: usize,
+    ) -> Vec<Result<u32>> {
+        while workers.len() < cmp::min(requests.len(), max_workers) {
+            match self.inner.try_clone() {
+                Ok(inner) => workers.push(self.with_inner(inner)),
+                // The requests can still be executed by the existing clones, if any.
+                Err(_) if !workers.is_empty() => break,
+                Err(e) => {
+                    return requests
+                        .iter()
+                        .map(|_| Err(Error::Clone(io::Error::new(e.kind(), e.to_string()))))
+                        .collect()
+                }
+            }
+        }
+
+        let num_workers = cmp::min(requests.len(), workers.len());
+        thread::scope(|s| {
+            let handles: Vec<_> = workers[..num_workers]
+                .iter_mut()
+                .enumerate()
+                .map(|(index, worker)| {
+                    s.spawn(move || {
+                        requests
+                            .iter()
+                            .skip(index)
+                            .step_by(num_workers)
+                            .map(|request| worker.execute(mem, request))
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect();
+            let mut worker_results: Vec<_> = handles
+                .into_iter()
+                .map(|handle| {
+                    handle
+                        .join()
+                        .expect("request execution thread panicked")
+                        .into_iter()
+                })
+                .collect();
+            // The requests were assigned to the workers round-robin.
+            (0..requests.len())
+                .map(|index| worker_results[index % num_workers].next().unwrap())
+                .collect()
+        })
+    }
+}
+
+impl<B: Backend + ZonedBackend> StdIoBackend<B> {
+    /// Executes `request` like [`execute`](#method.execute) does, but also supports the zone
+    /// report requests of zoned block devices, which need `VIRTIO_BLK_F_ZONED` to be negotiated.
+    /// Zone reports count towards the maximum queue depth like the other requests.
+    ///
+    /// # Arguments
+    /// * `mem` - A reference to the guest memory.
+    /// * `request` - The request to execute.
+    pub fn execute_zoned<M: GuestMemory>(&mut self, mem: &M, request: &Request) -> Result<u32> {
+        if request.request_type() != RequestType::ZoneReport {
+            return self.execute(mem, request);
+        }
+        let _depth_guard = self.enter_queue()?;
+        let start = Instant::now();
+        let result = self.report_zones(mem, request);
+        self.record_stats(request, &result, start);
+        #[cfg(debug_assertions)]
+        self.assert_invariants();
+        result
+    }
+
+    // Writes the report of the zones starting with the one that contains the request sector to
+    // the request data, and returns the length of the report.
+    fn report_zones<M: GuestMemory>(&mut self, mem: &M, request: &Request) -> Result<u32> {
+        self.check_deadline()?;
+        self.validate_request(mem, request)?;
+
+        // The report contains as many zones as fit in the request data.
+        let max_zones =
+            (request.total_data_len() - ZoneReportHeader::LEN as u64) / ZoneDescriptor::LEN as u64;
+        let zones = self
+            .inner
+            .report_zones(request.sector(), max_zones as usize)
+            .map_err(Error::ZoneReport)?;
+        let zones = &zones[..cmp::min(zones.len(), max_zones as usize)];
+
+        let mut report = ZoneReportHeader::new(zones.len() as u64)
+            .as_slice()
+            .to_vec();
+        for zone in zones {
+            report.extend_from_slice(zone.as_slice());
+        }
+
+        let mut bytes_to_mem = 0;
+        for DataDescriptor {
+            addr: data_addr,
+            len_bytes: data_len,
+        } in request.data()
+        {
+            if bytes_to_mem == report.len() {
+                break;
+            }
+            let len = cmp::min(*data_len as usize, report.len() - bytes_to_mem);
+            mem.write_slice(&report[bytes_to_mem..bytes_to_mem + len], *data_addr)
+                .map_err(Error::GuestMemory)?;
+            bytes_to_mem += len;
+        }
+        // The cast is safe since the report is not longer than the request data, whose length
+        // fits in an u32.
+        Ok(bytes_to_mem as u32)
+    }
+}
+
+impl<B: Backend + VectoredBackend> StdIoBackend<B> {
+    /// Executes `request` like [`execute`](#method.execute) does, but transfers the data of
+    /// requests with multiple data descriptors with vectored operations, which need fewer
+    /// syscalls.
+    ///
+    /// Requests with a single data descriptor, or with buffers that are not contiguous in the
+    /// host address space, are executed like [`execute`](#method.execute) does.
+    ///
+    /// # Arguments
+    /// * `mem` - A reference to the guest memory.
+    /// * `request` - The request to execute.
+    pub fn execute_vectored<M: GuestMemory>(&mut self, mem: &M, request: &Request) -> Result<u32> {
+        let _depth_guard = self.enter_queue()?;
+        let result = self.execute_with(mem, request, Self::read_vectored, Self::write_vectored);
+        #[cfg(debug_assertions)]
+        self.assert_invariants();
+        result
+    }
+
+    /// Reads the sectors starting with `sector` into `buf`, whose length must be a multiple of
+    /// the sector size, and returns the number of bytes that were read.
+    ///
+    /// Unlike [`sector_aligned_read`](#method.sector_aligned_read), the data is read with
+    /// positional reads (`preadv` for files), so the position of the backend is not used or
+    /// changed, and concurrent reads through descriptors sharing it don't interfere. Fewer
+    /// bytes than requested are only read if the backing object ends before the last sector.
+    ///
+    /// # Arguments
+    /// * `sector` - The first sector to read.
+    /// * `buf` - The buffer to fill.
+    pub fn read_at(&mut self, sector: u64, buf: &mut [u8]) -> Result<usize> {
+        let offset = self.positional_offset(sector, buf.len())?;
+        let mut read = 0;
+        while read < buf.len() {
+            let slice = VolatileSlice::from(&mut buf[read..]);
+            match self
+                .inner
+                .preadv(slice::from_ref(&slice), offset + read as u64)
+            {
+                Ok(0) => break,
+                Ok(count) => read += count,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(Error::Backend(VolatileMemoryError::IOError(e))),
+            }
+        }
+        Ok(read)
+    }
+
+    /// Writes `buf`, whose length must be a multiple of the sector size, to the sectors starting
+    /// with `sector`, and returns the number of bytes that were written.
+    ///
+    /// The data is written with positional writes (`pwritev` for files), so the position of the
+    /// backend is not used or changed.
+    ///
+    /// # Arguments
+    /// * `sector` - The first sector to write.
+    /// * `buf` - The data to write.
+    pub fn write_at(&mut self, sector: u64, buf: &[u8]) -> Result<usize> {
+        let offset = self.positional_offset(sector, buf.len())?;
+        let lsn = self.log_sectors(sector, JournalData::Write(buf.to_vec()))?;
+        let result = self.pwrite_all(offset, buf);
+        self.complete_write(lsn);
+        result
+    }
+
+    // Writes all of `buf` at `offset` of the backend with positional writes.
+    fn pwrite_all(&mut self, offset: u64, buf: &[u8]) -> Result<usize> {
+        let _written = self.start_write();
+        // `VolatileSlice` can only be created from mutable buffers.
+        let mut data = buf.to_vec();
+        let mut written = 0;
+        while written < data.len() {
+            let slice = VolatileSlice::from(&mut data[written..]);
+            match self
+                .inner
+                .pwritev(slice::from_ref(&slice), offset + written as u64)
+            {
+                Ok(0) => {
+                    return Err(Error::Backend(VolatileMemoryError::IOError(
+                        io::Error::from(io::ErrorKind::WriteZero),
+                    )))
+                }
+                Ok(count) => written += count,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(Error::Backend(VolatileMemoryError::IOError(e))),
+            }
+        }
+        Ok(written)
+    }
+
+    // Returns the offset of `sector` on the backend, after checking that the `len` bytes starting
+    // with it are complete sectors of the device.
+    fn positional_offset(&self, sector: u64, len: usize) -> Result<u64> {
+        if !(len as u64).is_multiple_of(SECTOR_SIZE) {
+            return Err(Error::InvalidDataLength);
+        }
+        let (offset, _) = self.sectors_to_bytes(sector, len as u64 / SECTOR_SIZE)?;
+        Ok(offset)
+    }
+
+    // Returns the buffers of a request with multiple data descriptors, if they are all
+    // contiguous in the host address space and none of them has to be split at DMA page
+    // boundaries.
+    fn vectored_bufs<'a, M: GuestMemory>(
+        &self,
+        mem: &'a M,
+        request: &Request,
+    ) -> Option<Vec<VolatileSlice<'a, MS<'a, M>>>> {
+        // Requests that cross DMA pages are only validated if the transfers are split, which
+        // the sequential path does.
+        if request.data().len() < 2
+            || request
+                .data()
+                .iter()
+                .any(|desc| self.crosses_dma_page(desc))
+        {
+            return None;
+        }
+        // Buffers that cross memory regions, or that are invalid, are left to the sequential
+        // path, which also reports the errors.
+        request
+            .data()
+            .iter()
+            .map(|desc| mem.get_slice(desc.addr, desc.len_bytes as usize).ok())
+            .collect()
+    }
+
+    // Reads the data of an `In` request with vectored reads, when possible. The caller has to
+    // check that the request targets a valid range, and that its data length fits in an u32.
+    fn read_vectored<M: GuestMemory>(&mut self, mem: &M, request: &Request) -> Result<u32> {
+        let bufs = match self.vectored_bufs(mem, request) {
+            Some(bufs) => bufs,
+            None => return self.read_to_mem(mem, request),
+        };
+
+        // The shift can not overflow since the request targets a valid range.
+        let offset = request.sector() << SECTOR_SHIFT;
+        // The conversion is safe since the total data length fits in an u32.
+        let total_len = request.total_data_len() as usize;
+        let mut bytes_to_mem = 0;
+        while bytes_to_mem < total_len {
+            let remaining_bufs = advance_slices(&bufs, bytes_to_mem)?;
+            // The `as u32` casts are safe since `bytes_to_mem` is smaller than `total_len`.
+            match self
+                .inner
+                .preadv(&remaining_bufs, offset + bytes_to_mem as u64)
+            {
+                Ok(0) => {
+                    return Err(Error::Read(
+                        GuestMemoryError::IOError(io::Error::from(io::ErrorKind::UnexpectedEof)),
+                        bytes_to_mem as u32,
+                    ))
+                }
+                Ok(bytes_read) => bytes_to_mem += bytes_read,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    // Leave the backend where the transfer stopped, as a sequential read does.
+                    self.inner
+                        .seek(SeekFrom::Start(offset + bytes_to_mem as u64))
+                        .map_err(Error::Seek)?;
+                    return Err(Error::Read(
+                        GuestMemoryError::IOError(e),
+                        bytes_to_mem as u32,
+                    ));
+                }
+            }
+        }
+        // Leave the backend at the same position as a sequential read does.
+        self.inner
+            .seek(SeekFrom::Start(offset + total_len as u64))
+            .map_err(Error::Seek)?;
+        Ok(total_len as u32)
+    }
+
+    // Writes the data of an `Out` request with vectored writes, when possible. The caller has to
+    // check that the request targets a valid range.
+    fn write_vectored<M: GuestMemory>(&mut self, mem: &M, request: &Request) -> Result<()> {
+        let bufs = match self.vectored_bufs(mem, request) {
+            Some(bufs) => bufs,
+            None => return self.write_from_mem(mem, request),
+        };
+
+        // The shift can not overflow since the request targets a valid range.
+        let offset = request.sector() << SECTOR_SHIFT;
+        let total_len = request.total_data_len();
+        let mut written = 0;
+        while written < total_len {
+            // The conversion is safe since `written` is smaller than the length of the buffers.
+            let remaining_bufs = advance_slices(&bufs, written as usize)?;
+            match self.inner.pwritev(&remaining_bufs, offset + written) {
+                Ok(0) => {
+                    return Err(Error::Write(GuestMemoryError::IOError(io::Error::from(
+                        io::ErrorKind::WriteZero,
+                    ))))
+                }
+                Ok(bytes_written) => written += bytes_written as u64,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    // Leave the backend where the transfer stopped, as a sequential write does.
+                    self.inner
+                        .seek(SeekFrom::Start(offset + written))
+                        .map_err(Error::Seek)?;
+                    return Err(Error::Write(GuestMemoryError::IOError(e)));
+                }
+            }
+        }
+        // Leave the backend at the same position as a sequential write does.
+        self.inner
+            .seek(SeekFrom::Start(offset + total_len))
+            .map_err(Error::Seek)?;
+        Ok(())
+    }
+}
+
+impl<B: Backend + AsRawFd> StdIoBackend<B> {
+    /// Sets the maximum time to wait for the backing file descriptor to be ready for the I/O of
+    /// `In` and `Out` requests.
+    ///
+    /// The descriptor is polled before the data is transferred, and the requests fail with
+    /// [`Error::PollTimeout`] if it doesn't become ready within `timeout`. This bounds the time
+    /// requests can block on descriptors such as pipes, sockets and character devices. Regular
+    /// files and block devices are always reported as ready. Polling is disabled by default.
+    ///
+    /// # Arguments
+    /// * `timeout` - The maximum time to wait for the descriptor.
+    pub fn with_poll_timeout(mut self, timeout: Duration) -> Self {
+        self.poll_timeout = Some((timeout, B::as_raw_fd));
+        self
+    }
+
+    // Returns the ranges of sectors that may contain data, in order. On Linux, the holes of
+    // sparse files are skipped by walking the extents with `lseek(SEEK_DATA)` and
+    // `lseek(SEEK_HOLE)`. On other platforms, the whole device is returned as a single range.
+    fn data_ranges(&self) -> Result<Vec<Range<u64>>> {
+        let num_sectors = self.num_sectors();
+        #[cfg(target_os = "linux")]
+        {
+            // The shift is safe since the size of the device in bytes fits in a u64.
+            let end = num_sectors << SECTOR_SHIFT;
+            let mut ranges = Vec::new();
+            let mut offset = 0;
+            while offset < end {
+                let data_start = match self.seek_extent(offset, libc::SEEK_DATA)? {
+                    Some(data_start) if data_start < end => data_start,
+                    _ => break,
+                };
+                let data_end = self
+                    .seek_extent(data_start, libc::SEEK_HOLE)?
+                    .map_or(end, |hole| cmp::min(hole, end));
+                ranges.push(data_start >> SECTOR_SHIFT..data_end.div_ceil(SECTOR_SIZE));
+                offset = data_end;
+            }
+            Ok(ranges)
+        }
+        #[cfg(not(target_os = "linux"))]
+        Ok(vec![0..num_sectors])
+    }
+
+    // Returns the offset of the first data (`SEEK_DATA`) or hole (`SEEK_HOLE`) at or after
+    // `offset` in the backing file, or `None` if there is no data at or after `offset`. The
+    // file offset is restored afterwards, since `lseek` moves it.
+    #[cfg(target_os = "linux")]
+    fn seek_extent(&self, offset: u64, whence: libc::c_int) -> Result<Option<u64>> {
+        let fd = self.inner.as_raw_fd();
+        let offset = libc::off64_t::try_from(offset).map_err(|_| Error::InvalidAccess)?;
+        // SAFETY: Safe because the file descriptor is valid for the lifetime of `inner` and
+        // `lseek64` doesn't access any memory.
+        let position = unsafe { libc::lseek64(fd, 0, libc::SEEK_CUR) };
+        if position < 0 {
+            return Err(Error::Seek(io::Error::last_os_error()));
+        }
+        // SAFETY: Same as above.
+        let result = unsafe { libc::lseek64(fd, offset, whence) };
+        // The error has to be saved before the offset is restored.
+        let err = io::Error::last_os_error();
+        // SAFETY: Same as above.
+        if unsafe { libc::lseek64(fd, position, libc::SEEK_SET) } < 0 {
+            return Err(Error::Seek(io::Error::last_os_error()));
+        }
+        if result < 0 {
+            // ENXIO means there is no data at or after `offset`.
+            return match err.raw_os_error() {
+                Some(libc::ENXIO) => Ok(None),
+                _ => Err(Error::Seek(err)),
+            };
+        }
+        // The cast is safe since `result` is not negative.
+        Ok(Some(result as u64))
+    }
+
+    /// Checks whether the backing file has data allocated at `sector`.
+    ///
+    /// Holes of sparse files are reported as unallocated, so VMMs can skip I/O for them. This
+    /// relies on `lseek(SEEK_DATA)` on Linux. On other platforms every sector is conservatively
+    /// reported as allocated.
+    ///
+    /// # Arguments
+    /// * `sector` - The index of the sector to check.
+    pub fn sector_is_allocated(&self, sector: u64) -> Result<bool> {
+        self.check_access(1, sector)?;
+        #[cfg(target_os = "linux")]
+        {
+            // The shift is safe since `sector` is smaller than the number of sectors.
+            let offset = sector << SECTOR_SHIFT;
+            Ok(self
+                .seek_extent(offset, libc::SEEK_DATA)?
+                .is_some_and(|data_offset| data_offset < offset + SECTOR_SIZE))
+        }
+        #[cfg(not(target_os = "linux"))]
+        Ok(true)
+    }
+
+    /// Loads the sectors in `sector_range`, or all the sectors if it is `None`, into the page
+    /// cache, so the first accesses to a cold disk image don't have to wait for the storage.
+    ///
+    /// On Linux, the range is mapped and populated with `madvise(MADV_POPULATE_READ)`, which is
+    /// available since Linux 5.14. On older kernels and on other platforms, the range is read
+    /// and the data is discarded.
+    ///
+    /// # Arguments
+    /// * `sector_range` - The sectors to load, or `None` for the whole device.
+    pub fn prefault_backing_file(&self, sector_range: Option<RangeInclusive<u64>>) -> Result<()> {
+        let (first, num_sectors) = match sector_range {
+            Some(range) if range.is_empty() => return Ok(()),
+            Some(range) => {
+                let (first, last) = range.into_inner();
+                let num_sectors = (last - first).checked_add(1).ok_or(Error::InvalidAccess)?;
+                (first, num_sectors)
+            }
+            None => (0, self.num_sectors()),
+        };
+        let (offset, length) = self.sectors_to_bytes(first, num_sectors)?;
+        if length == 0 {
+            return Ok(());
+        }
+        #[cfg(target_os = "linux")]
+        if self.populate_read(offset, length).is_ok() {
+            return Ok(());
+        }
+
+        let mut buf = vec![0u8; (COPY_CHUNK_SECTORS * SECTOR_SIZE) as usize];
+        let mut read = 0;
+        while read < length {
+            // The conversion is safe since the result is at most the length of `buf`.
+            let len = cmp::min(buf.len() as u64, length - read) as usize;
+            let pos = libc::off_t::try_from(offset + read).map_err(|_| Error::InvalidAccess)?;
+            // SAFETY: Safe because `buf` is valid for `len` bytes, and the return value is
+            // checked.
+            let ret =
+                unsafe { libc::pread(self.inner.as_raw_fd(), buf.as_mut_ptr().cast(), len, pos) };
+            match ret {
+                0 => break,
+                // The cast is safe since `ret` is positive.
+                ret if ret > 0 => read += ret as u64,
+                _ => {
+                    let err = io::Error::last_os_error();
+                    if err.kind() != io::ErrorKind::Interrupted {
+                        return Err(Error::Backend(VolatileMemoryError::IOError(err)));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Maps the `length` bytes of the backing file starting with `offset`, and populates the
+    // mapping with `madvise(MADV_POPULATE_READ)`.
+    #[cfg(target_os = "linux")]
+    fn populate_read(&self, offset: u64, length: u64) -> io::Result<()> {
+        // The value of the advice, which is missing from older versions of libc.
+        const MADV_POPULATE_READ: libc::c_int = 22;
+
+        let invalid = || io::Error::from_raw_os_error(libc::EINVAL);
+        // SAFETY: Safe because `sysconf` doesn't access any memory.
+        let page_size =
+            u64::try_from(unsafe { libc::sysconf(libc::_SC_PAGESIZE) }).map_err(|_| invalid())?;
+        // Mappings have to start at a page boundary.
+        let start = offset - offset % page_size;
+        let len = usize::try_from(offset + length - start).map_err(|_| invalid())?;
+        let start = libc::off_t::try_from(start).map_err(|_| invalid())?;
+        // SAFETY: Safe because a new mapping is created, which is only advised and then unmapped
+        // below.
+        let addr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_SHARED,
+                self.inner.as_raw_fd(),
+                start,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: Safe because the range was mapped above.
+        let result = match unsafe { libc::madvise(addr, len, MADV_POPULATE_READ) } {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        };
+        // SAFETY: Safe because the range was mapped above, and it is not used afterwards.
+        unsafe { libc::munmap(addr, len) };
+        result
+    }
+
+    /// Copies sectors like [`copy_range`](#method.copy_range), without transferring the data
+    /// through user space when possible.
+    ///
+    /// On Linux, the data is copied with `copy_file_range`, which lets filesystems share the
+    /// extents instead of copying them, e.g. with reflinks. The bounce buffer of
+    /// [`copy_range`](#method.copy_range) is used on other platforms, when the ranges overlap,
+    /// when the filesystem doesn't support `copy_file_range`, and when there is a journal, which
+    /// needs the copied data.
+    ///
+    /// # Arguments
+    /// * `src_sector` - The first sector to copy from.
+    /// * `dst_sector` - The first sector to copy to.
+    /// * `num_sectors` - The number of sectors to copy.
+    pub fn copy_range_in_kernel(
+        &mut self,
+        src_sector: u64,
+        dst_sector: u64,
+        num_sectors: u64,
+    ) -> Result<()> {
+        #[cfg(target_os = "linux")]
+        {
+            let (src_offset, length) = self.sectors_to_bytes(src_sector, num_sectors)?;
+            let (dst_offset, _) = self.sectors_to_bytes(dst_sector, num_sectors)?;
+            if self.journal.is_none() && src_sector.abs_diff(dst_sector) >= num_sectors {
+                match self.copy_file_range(src_offset, dst_offset, length) {
+                    Err(Error::Backend(VolatileMemoryError::IOError(e)))
+                        if matches!(
+                            e.raw_os_error(),
+                            Some(libc::ENOSYS | libc::EOPNOTSUPP | libc::EXDEV | libc::EINVAL)
+                        ) => {}
+                    result => return result,
+                }
+            }
+        }
+        self.copy_range(src_sector, dst_sector, num_sectors)
+    }
+
+    // Copies `length` bytes of the backing file from `src_offset` to `dst_offset` with
+    // `copy_file_range`. The ranges must not overlap.
+    #[cfg(target_os = "linux")]
+    fn copy_file_range(&mut self, src_offset: u64, dst_offset: u64, length: u64) -> Result<()> {
+        let invalid = || {
+            Error::Backend(VolatileMemoryError::IOError(io::Error::from_raw_os_error(
+                libc::EINVAL,
+            )))
+        };
+        let mut src_offset = libc::loff_t::try_from(src_offset).map_err(|_| invalid())?;
+        let mut dst_offset = libc::loff_t::try_from(dst_offset).map_err(|_| invalid())?;
+        let fd = self.inner.as_raw_fd();
+        let _written = self.start_write();
+        let mut remaining = length;
+        while remaining > 0 {
+            let len = usize::try_from(remaining).unwrap_or(usize::MAX);
+            // SAFETY: Safe because the offsets are valid references, and the kernel only accesses
+            // the file descriptor of the backend.
+            let copied =
+                unsafe { libc::copy_file_range(fd, &mut src_offset, fd, &mut dst_offset, len, 0) };
+            match copied {
+                0 => {
+                    return Err(Error::Backend(VolatileMemoryError::IOError(
+                        io::Error::from(io::ErrorKind::UnexpectedEof),
+                    )))
+                }
+                // The conversion is safe since the value is positive.
+                copied if copied > 0 => remaining -= copied as u64,
+                _ => {
+                    let err = io::Error::last_os_error();
+                    if err.kind() != io::ErrorKind::Interrupted {
+                        return Err(Error::Backend(VolatileMemoryError::IOError(err)));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes the sectors at the end of the device that don't contain any data, and returns the
+    /// number of removed sectors.
+    ///
+    /// This is meant for releasing the space at the end of a disk after the guest discarded it.
+    /// The unallocated sectors are skipped without being read. Holes are only punched in whole
+    /// filesystem blocks, so the allocated sectors that contain only zeroes are removed as well.
+    pub fn shrink_to_fit(&mut self) -> Result<u64> {
+        let mut new_num_sectors = 0;
+        let mut buf = vec![0u8; (COPY_CHUNK_SECTORS * SECTOR_SIZE) as usize];
+        // The allocated ranges are read from the end, one chunk at a time.
+        'ranges: for range in self.data_ranges()?.into_iter().rev() {
+            let mut end = range.end;
+            while end > range.start {
+                let count = cmp::min(COPY_CHUNK_SECTORS, end - range.start);
+                let start = end - count;
+                // The conversion is safe since `count` is at most COPY_CHUNK_SECTORS.
+                let chunk = &mut buf[..(count * SECTOR_SIZE) as usize];
+                self.read_sectors(start, chunk)?;
+                if let Some(pos) = chunk.iter().rposition(|&b| b != 0) {
+                    new_num_sectors = start + pos as u64 / SECTOR_SIZE + 1;
+                    break 'ranges;
+                }
+                end = start;
+            }
+        }
+        let removed_sectors = self.num_sectors() - new_num_sectors;
+        if removed_sectors != 0 {
+            self.truncate_to_sectors(new_num_sectors)?;
+        }
+        Ok(removed_sectors)
+    }
+
+    /// Returns a bitmap of the sectors that contain non-zero data.
+    ///
+    /// VMMs can use it to transfer only the meaningful sectors during live migration. The
+    /// unallocated sectors are skipped without being read, and the allocated ones are read, so
+    /// the ones that only contain zeroes are left out as well.
+    pub fn sector_map(&mut self) -> Result<SectorMap> {
+        let mut map = SectorMap::new(self.num_sectors());
+        let mut buf = vec![0u8; (COPY_CHUNK_SECTORS * SECTOR_SIZE) as usize];
+        for range in self.data_ranges()? {
+            let mut start = range.start;
+            while start < range.end {
+                let count = cmp::min(COPY_CHUNK_SECTORS, range.end - start);
+                // The conversion is safe since `count` is at most COPY_CHUNK_SECTORS.
+                let chunk = &mut buf[..(count * SECTOR_SIZE) as usize];
+                self.read_sectors(start, chunk)?;
+                for (sector, data) in (start..).zip(chunk.chunks_exact(SECTOR_SIZE as usize)) {
+                    if data.iter().any(|&b| b != 0) {
+                        map.set(sector);
+                    }
+                }
+                start += count;
+            }
+        }
+        Ok(map)
+    }
+}
+
+/// Builder for a [`StdIoBackend`] with a more complex configuration.
+///
+/// # Example
+///
+/// ```rust
+/// # use virtio_blk::stdio_executor::StdIoBackendBuilder;
+/// # use virtio_bindings::bindings::virtio_blk::VIRTIO_BLK_F_FLUSH;
+/// # use vmm_sys_util::tempfile::TempFile;
+/// let file = TempFile::new().unwrap();
+/// let request_exec = StdIoBackendBuilder::new()
+///     .inner(file.into_file())
+///     .features(1 << VIRTIO_BLK_F_FLUSH)
+///     .logical_block_size(4096)
+///     .serial("disk0".to_string())
+///     .build()
+///     .unwrap();
+/// ```
+#[derive(Debug)]
+pub struct StdIoBackendBuilder<B: Backend> {
+    inner: Option<B>,
+    features: u64,
+    logical_block_size: u32,
+    physical_block_size: Option<u32>,
+    serial: Option<String>,
+    max_discard_seg: u32,
+    max_write_zeroes_seg: u32,
+}
+
+impl<B: Backend> Default for StdIoBackendBuilder<B> {
+    fn default() -> Self {
+        StdIoBackendBuilder {
+            inner: None,
+            features: 0,
+            logical_block_size: SECTOR_SIZE as u32,
+            physical_block_size: None,
+            serial: None,
+            max_discard_seg: u32::MAX,
+            max_write_zeroes_seg: u32::MAX,
+        }
+    }
+}
+
+impl<B: Backend> StdIoBackendBuilder<B> {
+    /// Creates a new `StdIoBackendBuilder` with the default configuration.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the block device backend. This is mandatory.
+    ///
+    /// # Arguments
+    /// * `inner` - The block device backend.
+    pub fn inner(mut self, inner: B) -> Self {
+        self.inner = Some(inner);
+        self
+    }
+
+    /// Sets the features that were negotiated between driver and device. Defaults to 0.
+    ///
+    /// # Arguments
+    /// * `features` - The negotiated features.
+    pub fn features(mut self, features: u64) -> Self {
+        self.features = features;
+        self
+    }
+
+    /// Sets the logical block size. Defaults to `SECTOR_SIZE`.
+    ///
+    /// # Arguments
+    /// * `logical_block_size` - The `blk_size` value from the device configuration space. It
+    ///                          must be a power of two of at least `SECTOR_SIZE` bytes.
+    pub fn logical_block_size(mut self, logical_block_size: u32) -> Self {
+        self.logical_block_size = logical_block_size;
+        self
+    }
+
+    /// Sets the block size the writes to the backing object have to be aligned to, e.g. when
+    /// it is opened with `O_DIRECT`. The unaligned `Out` requests are then padded to whole
+    /// blocks with the data already stored in the backing object. By default, the writes are not
+    /// padded.
+    ///
+    /// # Arguments
+    /// * `physical_block_size` - The alignment of the writes. It must be a power of two of at
+    ///                           least `SECTOR_SIZE` bytes.
+    pub fn physical_block_size(mut self, physical_block_size: u32) -> Self {
+        self.physical_block_size = Some(physical_block_size);
+        self
+    }
+
+    /// Sets the serial returned for `VIRTIO_BLK_T_GET_ID` requests. By default, these requests
+    /// are not supported.
+    ///
+    /// # Arguments
+    /// * `serial` - The block device serial. It must be an ASCII string of at most
+    ///              `VIRTIO_BLK_ID_BYTES` bytes.
+    pub fn serial(mut self, serial: String) -> Self {
+        self.serial = Some(serial);
+        self
+    }
+
+    /// Sets the maximum number of segments in a discard request. Defaults to `u32::MAX`.
+    ///
+    /// # Arguments
+    /// * `max_discard_seg` - The `max_discard_seg` value from the device configuration space.
+    pub fn max_discard_segments(mut self, max_discard_seg: u32) -> Self {
+        self.max_discard_seg = max_discard_seg;
+        self
+    }
+
+    /// Sets the maximum number of segments in a write zeroes request. Defaults to `u32::MAX`.
+    ///
+    /// # Arguments
+    /// * `max_write_zeroes_seg` - The `max_write_zeroes_seg` value from the device configuration
+    ///                            space.
+    pub fn max_write_zeroes_segments(mut self, max_write_zeroes_seg: u32) -> Self {
+        self.max_write_zeroes_seg = max_write_zeroes_seg;
+        self
+    }
+
+    /// Creates the configured [`StdIoBackend`].
+    pub fn build(self) -> Result<StdIoBackend<B>> {
+        StdIoBackend::from_builder(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{Read, Write};
+    use std::os::unix::io::FromRawFd;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use virtio_bindings::bindings::virtio_blk::{
+        virtio_blk_outhdr, VIRTIO_BLK_S_IOERR, VIRTIO_BLK_S_OK, VIRTIO_BLK_S_UNSUPP,
+        VIRTIO_BLK_T_BARRIER, VIRTIO_BLK_T_IN, VIRTIO_BLK_T_OUT, VIRTIO_BLK_ZS_EMPTY,
+        VIRTIO_BLK_ZT_SWR,
+    };
+
+    use vm_memory::guest_memory::Error::{InvalidGuestAddress, PartialBuffer};
+    use vm_memory::{GuestAddress, GuestMemoryMmap};
+    use vmm_sys_util::tempfile::TempFile;
+
+    use crate::testing::{BlockDeviceHarness, DeterministicBackend};
+
+    // A file backend that counts the `fsync` calls and the write operations.
+    #[derive(Debug)]
+    struct CountingBackend {
+        file: File,
+        fsyncs: usize,
+        writes: usize,
+        // The number of clones, shared with them.
+        clones: Arc<AtomicUsize>,
+    }
+
+    impl CountingBackend {
+        fn new(file: File) -> Self {
+            CountingBackend {
+                file,
+                fsyncs: 0,
+                writes: 0,
+                clones: Arc::new(AtomicUsize::new(0)),
+            }
+        }
+    }
+
+    impl ReadVolatile for CountingBackend {
+        fn read_volatile<S: BitmapSlice>(
+            &mut self,
+            buf: &mut VolatileSlice<S>,
+        ) -> result::Result<usize, VolatileMemoryError> {
+            self.file.read_volatile(buf)
+        }
+    }
+
+    impl WriteVolatile for CountingBackend {
+        fn write_volatile<S: BitmapSlice>(
+            &mut self,
+            buf: &VolatileSlice<S>,
+        ) -> result::Result<usize, VolatileMemoryError> {
+            self.writes += 1;
+            self.file.write_volatile(buf)
+        }
+    }
+
+    impl TryClone for CountingBackend {
+        fn try_clone(&self) -> io::Result<Self> {
+            self.clones.fetch_add(1, Ordering::SeqCst);
+            Ok(CountingBackend {
+                clones: self.clones.clone(),
+                ..CountingBackend::new(TryClone::try_clone(&self.file)?)
+            })
+        }
+    }
+
+    // A file backend that would block after transferring `budget` bytes.
+    #[derive(Debug)]
+    struct BlockingBackend {
+        file: File,
+        budget: usize,
+    }
+
+    impl BlockingBackend {
+        // Returns the part of `buf` that fits in the budget.
+        fn limit<'a, S: BitmapSlice>(
+            &mut self,
+            buf: &VolatileSlice<'a, S>,
+        ) -> result::Result<VolatileSlice<'a, S>, VolatileMemoryError> {
+            if self.budget == 0 {
+                return Err(VolatileMemoryError::IOError(io::Error::from(
+                    io::ErrorKind::WouldBlock,
+                )));
+            }
+            let len = cmp::min(buf.len(), self.budget);
+            self.budget -= len;
+            buf.subslice(0, len)
+        }
+    }
+
+    impl ReadVolatile for BlockingBackend {
+        fn read_volatile<S: BitmapSlice>(
+            &mut self,
+            buf: &mut VolatileSlice<S>,
+        ) -> result::Result<usize, VolatileMemoryError> {
+            let mut buf = self.limit(buf)?;
+            self.file.read_volatile(&mut buf)
+        }
+    }
+
+    impl WriteVolatile for BlockingBackend {
+        fn write_volatile<S: BitmapSlice>(
+            &mut self,
+            buf: &VolatileSlice<S>,
+        ) -> result::Result<usize, VolatileMemoryError> {
+            let buf = self.limit(buf)?;
+            self.file.write_volatile(&buf)
+        }
+    }
+
+    impl Seek for BlockingBackend {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.file.seek(pos)
+        }
+    }
+
+    impl FileSync for BlockingBackend {
+        fn fsync(&mut self) -> io::Result<()> {
+            self.file.fsync()
+        }
+    }
+
+    impl PunchHole for BlockingBackend {
+        fn punch_hole(&mut self, offset: u64, length: u64) -> io::Result<()> {
+            self.file.punch_hole(offset, length)
+        }
+    }
+
+    impl WriteZeroesAt for BlockingBackend {
+        fn write_zeroes_at(&mut self, offset: u64, length: usize) -> io::Result<usize> {
+            self.file.write_zeroes_at(offset, length)
+        }
+    }
+
+    // The read end of a pipe, posing as a device of 8 sectors.
+    struct PipeBackend {
+        pipe: File,
+    }
+
+    impl PipeBackend {
+        // Returns the backend and the write end of the pipe.
+        fn new() -> (Self, File) {
+            let mut fds = [0; 2];
+            // SAFETY: Safe because `fds` has room for the two descriptors, and the return value
+            // is checked.
+            assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+            // SAFETY: Safe because the descriptors were just created and are owned by nobody else.
+            let (read_end, write_end) =
+                unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+            (PipeBackend { pipe: read_end }, write_end)
+        }
+    }
+
+    impl ReadVolatile for PipeBackend {
+        fn read_volatile<S: BitmapSlice>(
+            &mut self,
+            buf: &mut VolatileSlice<S>,
+        ) -> result::Result<usize, VolatileMemoryError> {
+            self.pipe.read_volatile(buf)
+        }
+    }
+
+    impl WriteVolatile for PipeBackend {
+        fn write_volatile<S: BitmapSlice>(
+            &mut self,
+            _buf: &VolatileSlice<S>,
+        ) -> result::Result<usize, VolatileMemoryError> {
+            Err(VolatileMemoryError::IOError(io::Error::from_raw_os_error(
+                libc::EBADF,
+            )))
+        }
+    }
+
+    impl Seek for PipeBackend {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            match pos {
+                SeekFrom::End(0) => Ok(8 * SECTOR_SIZE),
+                _ => Ok(0),
+            }
+        }
+    }
+
+    impl FileSync for PipeBackend {
+        fn fsync(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl PunchHole for PipeBackend {
+        fn punch_hole(&mut self, _offset: u64, _length: u64) -> io::Result<()> {
+            Err(io::Error::from_raw_os_error(libc::ESPIPE))
+        }
+    }
+
+    impl WriteZeroesAt for PipeBackend {
+        fn write_zeroes_at(&mut self, _offset: u64, _length: usize) -> io::Result<usize> {
+            Err(io::Error::from_raw_os_error(libc::ESPIPE))
+        }
+    }
+
+    impl AsRawFd for PipeBackend {
+        fn as_raw_fd(&self) -> RawFd {
+            self.pipe.as_raw_fd()
+        }
+    }
+
+    impl VectoredBackend for CountingBackend {
+        fn preadv<S: BitmapSlice>(
+            &mut self,
+            bufs: &[VolatileSlice<S>],
+            offset: u64,
+        ) -> io::Result<usize> {
+            self.file.preadv(bufs, offset)
+        }
+
+        fn pwritev<S: BitmapSlice>(
+            &mut self,
+            bufs: &[VolatileSlice<S>],
+            offset: u64,
+        ) -> io::Result<usize> {
+            self.writes += 1;
+            self.file.pwritev(bufs, offset)
+        }
+    }
+
+    impl Seek for CountingBackend {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.file.seek(pos)
+        }
+    }
+
+    impl FileSync for CountingBackend {
+        fn fsync(&mut self) -> io::Result<()> {
+            self.fsyncs += 1;
+            self.file.fsync()
+        }
+    }
+
+    impl PunchHole for CountingBackend {
+        fn punch_hole(&mut self, offset: u64, length: u64) -> io::Result<()> {
+            self.file.punch_hole(offset, length)
+        }
+    }
+
+    impl WriteZeroesAt for CountingBackend {
+        fn write_zeroes_at(&mut self, offset: u64, length: usize) -> io::Result<usize> {
+            self.file.write_zeroes_at(offset, length)
+        }
+    }
+
+    // A file backend that reports the zones of a zoned block device.
+    #[derive(Debug)]
+    struct ZonedMockBackend {
+        file: File,
+        zones: Vec<ZoneDescriptor>,
+    }
+
+    impl ReadVolatile for ZonedMockBackend {
+        fn read_volatile<S: BitmapSlice>(
+            &mut self,
+            buf: &mut VolatileSlice<S>,
+        ) -> result::Result<usize, VolatileMemoryError> {
+            self.file.read_volatile(buf)
+        }
+    }
+
+    impl WriteVolatile for ZonedMockBackend {
+        fn write_volatile<S: BitmapSlice>(
+            &mut self,
+            buf: &VolatileSlice<S>,
+        ) -> result::Result<usize, VolatileMemoryError> {
+            self.file.write_volatile(buf)
+        }
+    }
+
+    impl Seek for ZonedMockBackend {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.file.seek(pos)
+        }
+    }
+
+    impl FileSync for ZonedMockBackend {
+        fn fsync(&mut self) -> io::Result<()> {
+            self.file.fsync()
+        }
+    }
+
+    impl PunchHole for ZonedMockBackend {
+        fn punch_hole(&mut self, offset: u64, length: u64) -> io::Result<()> {
+            self.file.punch_hole(offset, length)
+        }
+    }
+
+    impl WriteZeroesAt for ZonedMockBackend {
+        fn write_zeroes_at(&mut self, offset: u64, length: usize) -> io::Result<usize> {
+            self.file.write_zeroes_at(offset, length)
+        }
+    }
+
+    impl ZonedBackend for ZonedMockBackend {
+        fn report_zones(
+            &mut self,
+            sector: u64,
+            nr_zones: usize,
+        ) -> io::Result<Vec<ZoneDescriptor>> {
+            Ok(self
+                .zones
+                .iter()
+                .filter(|zone| zone.start() + zone.capacity() > sector)
+                .take(nr_zones)
+                .copied()
+                .collect())
+        }
+    }
+
+    impl PartialEq for Error {
+        fn eq(&self, other: &Self) -> bool {
+            use self::Error::*;
+            match (self, other) {
+                (AlignmentPaddingFailed(ref e), AlignmentPaddingFailed(ref other_e)) => {
+                    e.kind() == other_e.kind()
+                }
+                (Backend(ref e), Backend(ref other_e)) => {
+                    format!("{}", e).eq(&format!("{}", other_e))
+                }
+                (Clone(ref e), Clone(ref other_e)) => e.kind() == other_e.kind(),
+                (DeadlineExceeded, DeadlineExceeded) => true,
+                (
+                    DescriptorTooLarge { len, max },
+                    DescriptorTooLarge {
+                        len: other_len,
+                        max: other_max,
+                    },
+                ) => len == other_len && max == other_max,
+                (DiscardWriteZeroes(ref e), DiscardWriteZeroes(ref other_e)) => {
+                    format!("{}", e).eq(&format!("{}", other_e))
+                }
+                (
+                    DmaPageCrossing { addr, page_size },
+                    DmaPageCrossing {
+                        addr: other_addr,
+                        page_size: other_page_size,
+                    },
+                ) => addr == other_addr && page_size == other_page_size,
+                (Flush(ref e), Flush(ref other_e)) => format!("{}", e).eq(&format!("{}", other_e)),
+                (GuestMemory(ref e), GuestMemory(ref other_e)) => {
+                    format!("{}", e).eq(&format!("{}", other_e))
+                }
+                (Features(e), Features(other_e)) => e == other_e,
+                (InvalidAccess, InvalidAccess) => true,
+                (InvalidBlockSize(size), InvalidBlockSize(other_size)) => size == other_size,
+                (InvalidDataLength, InvalidDataLength) => true,
+                (InvalidFlags, InvalidFlags) => true,
+                (InvalidNumSectors(n), InvalidNumSectors(other_n)) => n == other_n,
+                (InvalidRequestId(id), InvalidRequestId(other_id)) => id == other_id,
+                (InvalidSerial, InvalidSerial) => true,
+                (Journal(ref e), Journal(ref other_e)) => e.kind() == other_e.kind(),
+                (MissingBackend, MissingBackend) => true,
+                (NonZeroSector(sector), NonZeroSector(other_sector)) => sector == other_sector,
+                (Open(ref e), Open(ref other_e)) => e.kind() == other_e.kind(),
+                (Overflow, Overflow) => true,
+                (Pipe(ref e), Pipe(ref other_e)) => e.kind() == other_e.kind(),
+                (PollTimeout { fd }, PollTimeout { fd: other_fd }) => fd == other_fd,
+                (
+                    ProtectedRange { sector },
+                    ProtectedRange {
+                        sector: other_sector,
+                    },
+                ) => sector == other_sector,
+                (
+                    QueueFull { depth, max },
+                    QueueFull {
+                        depth: other_depth,
+                        max: other_max,
+                    },
+                ) => depth == other_depth && max == other_max,
+                (Read(ref e, bytes), Read(ref other_e, other_bytes)) => {
+                    format!("{}", e).eq(&format!("{}", other_e)) && bytes == other_bytes
+                }
+                (ReadOnly, ReadOnly) => true,
+                (
+                    RequestTooLarge {
+                        request_sectors,
+                        max_sectors,
+                    },
+                    RequestTooLarge {
+                        request_sectors: other_request_sectors,
+                        max_sectors: other_max_sectors,
+                    },
+                ) => request_sectors == other_request_sectors && max_sectors == other_max_sectors,
+                (Write(ref e), Write(ref other_e)) => format!("{}", e).eq(&format!("{}", other_e)),
+                (Seek(ref e), Seek(ref other_e)) => format!("{}", e).eq(&format!("{}", other_e)),
+                (
+                    TooManySegments { count, max },
+                    TooManySegments {
+                        count: other_count,
+                        max: other_max,
+                    },
+                ) => count == other_count && max == other_max,
+                (Unsupported(val), Unsupported(other_val)) => val == other_val,
+                (WouldBlock(pending), WouldBlock(other_pending)) => pending == other_pending,
+                (ZoneReport(ref e), ZoneReport(ref other_e)) => {
+                    format!("{}", e).eq(&format!("{}", other_e))
+                }
+                _ => false,
+            }
+        }
+    }
+
+    #[test]
+    fn test_execute_request() {
+        const NON_ZERO_VALUE: u8 = 0x55;
+
+        let mut f = TempFile::new().unwrap().into_file();
+        f.set_len(0x1000).unwrap();
+
+        // Write buffer of non-zero bytes to offsets 256 and 1024.
+        f.seek(SeekFrom::Start(0x100)).unwrap();
+        f.write_all(&[NON_ZERO_VALUE; 0x200]).unwrap();
+        f.seek(SeekFrom::Start(0x400)).unwrap();
+        f.write_all(&[NON_ZERO_VALUE + 1; 0x80]).unwrap();
+
+        let mem = GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x1000_0000)]).unwrap();
+        let flush_req = Request::new(
+            RequestType::Flush,
+            vec![DataDescriptor::new(GuestAddress(0x100), 0x400)],
+            0,
+            GuestAddress(0x600),
+        );
+
+        let mut req_exec = StdIoBackend::new(f, 1 << VIRTIO_BLK_F_RO).unwrap();
+        assert_eq!(
+            req_exec.execute(&mem, &flush_req).unwrap_err(),
+            Error::ReadOnly
+        );
+        // VIRTIO_BLK_F_FLUSH not negotiated.
+        req_exec.features = 0;
+        assert_eq!(
+            req_exec.execute(&mem, &flush_req).unwrap_err(),
+            Error::Unsupported(VIRTIO_BLK_T_FLUSH)
+        );
+        // Flush should be successful if VIRTIO_BLK_F_FLUSH is supported.
+        req_exec.features = 1 << VIRTIO_BLK_F_FLUSH;
+        assert_eq!(req_exec.execute(&mem, &flush_req).unwrap(), 0x00);
+
+        let in_req = Request::new(
+            RequestType::In,
+            vec![
+                DataDescriptor::new(GuestAddress(0x100), 0x400),
+                DataDescriptor::new(GuestAddress(0x600), 0x200),
+            ],
+            0,
+            GuestAddress(0x900),
+        );
+        // 0x600 bytes should've been written in memory.
+        assert_eq!(req_exec.execute(&mem, &in_req).unwrap(), 0x600);
+        for addr in 0x100..0x1ff {
+            assert_eq!(mem.read_obj::<u8>(GuestAddress(addr)).unwrap(), 0x00);
+        }
+        for addr in 0x200..0x3ff {
+            assert_eq!(
+                mem.read_obj::<u8>(GuestAddress(addr)).unwrap(),
+                NON_ZERO_VALUE
+            );
+        }
+        for addr in 0x400..0x4ff {
+            assert_eq!(mem.read_obj::<u8>(GuestAddress(addr)).unwrap(), 0x00);
+        }
+        for addr in 0x600..0x67f {
+            assert_eq!(
+                mem.read_obj::<u8>(GuestAddress(addr)).unwrap(),
+                NON_ZERO_VALUE + 1
+            );
+        }
+        for addr in 0x680..0x7ff {
+            assert_eq!(mem.read_obj::<u8>(GuestAddress(addr)).unwrap(), 0x00);
+        }
+
+        let mem = GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x1000_0000)]).unwrap();
+        let out_req = Request::new(
+            RequestType::Out,
+            vec![
+                DataDescriptor::new(GuestAddress(0x100), 0x400),
+                DataDescriptor::new(GuestAddress(0x800), 0x200),
+            ],
+            1,
+            GuestAddress(0x200),
+        );
+        // Clear the file.
+        req_exec.inner_mut().write_zeroes_at(0x00, 0x1000).unwrap();
+
+        mem.write_slice(&[NON_ZERO_VALUE; 0x200], GuestAddress(0x200))
+            .unwrap();
+        mem.write_slice(&[NON_ZERO_VALUE; 0x100], GuestAddress(0x880))
+            .unwrap();
+        // We will write in file at sector 1 (offset 0x200) 0x400 bytes from 0x100 guest memory
+        // address and 0x200 bytes from 0x800 address. 0 bytes should've been written in memory.
+        assert_eq!(req_exec.execute(&mem, &out_req).unwrap(), 0x00);
+
+        req_exec.inner().rewind().unwrap();
+        let mut v = vec![0x00; 0x300];
+        assert_eq!(req_exec.inner().read(&mut v).unwrap(), 0x300);
+        assert_eq!(v, vec![0x00; 0x300]);
+
+        // We are at offset 0x300.
+        v = vec![0x00; 0x200];
+        assert_eq!(req_exec.inner().read(&mut v).unwrap(), 0x200);
+        assert_eq!(v, vec![NON_ZERO_VALUE; 0x200]);
+
+        // We are at offset 0x500.
+        v = vec![0x00; 0x100];
+        assert_eq!(req_exec.inner().read(&mut v).unwrap(), 0x100);
+        assert_eq!(v, vec![0x00; 0x100]);
+
+        // We are at offset 0x600.
+        v = vec![0x00; 0x80];
+        assert_eq!(req_exec.inner().read(&mut v).unwrap(), 0x80);
+        assert_eq!(v, vec![0x00; 0x80]);
+
+        // We are at offset 0x680.
+        v = vec![0x00; 0x100];
+        assert_eq!(req_exec.inner().read(&mut v).unwrap(), 0x100);
+        assert_eq!(v, vec![NON_ZERO_VALUE; 0x100]);
+
+        // We are at offset 0x780.
+        v = vec![0x00; 0x80];
+        assert_eq!(req_exec.inner().read(&mut v).unwrap(), 0x80);
+        assert_eq!(v, vec![0x00; 0x80]);
+
+        // Writing 512 bytes to the last sector should be successful.
+        let out_req = Request::new(
+            RequestType::Out,
+            vec![DataDescriptor::new(GuestAddress(0x100), 0x200)],
+            7,
+            GuestAddress(0x200),
+        );
+        assert!(req_exec.execute(&mem, &out_req).is_ok());
+
+        // Writing 1024 bytes to the last sector should not be successful.
+        let out_req = Request::new(
+            RequestType::Out,
+            vec![DataDescriptor::new(GuestAddress(0x100), 0x400)],
+            7,
+            GuestAddress(0x200),
+        );
+        assert_eq!(
+            req_exec.execute(&mem, &out_req).unwrap_err(),
+            Error::InvalidAccess
+        );
+
+        // Invalid data length for IN/OUT request.
+        let out_req = Request::new(
+            RequestType::Out,
+            vec![
+                DataDescriptor::new(GuestAddress(0x100), 0x400),
+                DataDescriptor::new(GuestAddress(0x500), 0x100),
+            ],
+            2,
+            GuestAddress(0x200),
+        );
+        assert_eq!(
+            req_exec.execute(&mem, &out_req).unwrap_err(),
+            Error::InvalidDataLength
+        );
+        let in_req = Request::new(
+            RequestType::In,
+            vec![DataDescriptor::new(GuestAddress(0x100), 0x201)],
+            2,
+            GuestAddress(0x200),
+        );
+        assert_eq!(
+            req_exec.execute(&mem, &in_req).unwrap_err(),
+            Error::InvalidDataLength
+        );
+
+        // It's ok to have partial data lengths that are not multiple of 512 bytes as long as their
+        // sum is a multiple.
+        let in_req = Request::new(
+            RequestType::In,
+            vec![
+                DataDescriptor::new(GuestAddress(0x100), 0x500),
+                DataDescriptor::new(GuestAddress(0x500), 0x300),
+            ],
+            2,
+            GuestAddress(0x200),
+        );
+        assert_eq!(req_exec.execute(&mem, &in_req).unwrap(), 0x800);
+
+        // Invalid memory address for write operation.
+        let out_req = Request::new(
+            RequestType::Out,
+            vec![DataDescriptor::new(GuestAddress(0xFFF_FFF0), 0x200)],
+            7,
+            GuestAddress(0x200),
+        );
+        assert_eq!(
+            req_exec.execute(&mem, &out_req).unwrap_err(),
+            Error::Write(PartialBuffer {
+                expected: 512,
+                completed: 16
+            })
+        );
+
+        // Invalid memory address for read operation.
+        let in_req = Request::new(
+            RequestType::In,
+            vec![DataDescriptor::new(GuestAddress(0xFFF_FFF0), 0x200)],
+            7,
+            GuestAddress(0x200),
+        );
+        assert_eq!(
+            req_exec.execute(&mem, &in_req).unwrap_err(),
+            Error::Read(
+                PartialBuffer {
+                    expected: 512,
+                    completed: 16
+                },
+                16
+            )
+        );
+
+        // Invalid request type.
+        let invalid_req = Request::new(
+            RequestType::Unsupported(8),
+            vec![DataDescriptor::new(GuestAddress(0x100), 0x200)],
+            7,
+            GuestAddress(0x200),
+        );
+        assert_eq!(
+            req_exec.execute(&mem, &invalid_req).unwrap_err(),
+            Error::Unsupported(8)
+        );
+
+        // Let's create a file large enough for the request.
+        let f = TempFile::new().unwrap().into_file();
+        f.set_len(u32::MAX as u64 * 2).unwrap();
+        let mut req_exec = StdIoBackend::new(f, 0).unwrap();
+
+        // Total data length > u32::MAX.
+        let invalid_req = Request::new(
+            RequestType::In,
+            vec![
+                DataDescriptor::new(GuestAddress(0x100), u32::MAX - SECTOR_SIZE as u32 + 1),
+                DataDescriptor::new(GuestAddress(0x1000), SECTOR_SIZE as u32),
+            ],
+            0,
+            GuestAddress(0x200),
+        );
+        assert_eq!(
+            req_exec.execute(&mem, &invalid_req).unwrap_err(),
+            Error::InvalidDataLength
+        );
+    }
+
+    #[test]
+    fn test_discard_wr_zeroes_request() {
+        const NON_ZERO_VALUE: u8 = 0x55;
+
+        let mut harness = BlockDeviceHarness::with_temp_file(
+            8,
+            (1 << VIRTIO_BLK_F_DISCARD) | (1 << VIRTIO_BLK_F_WRITE_ZEROES),
+        );
+        let (mem, req_exec) = harness.split_mut();
+
+        let out_req = Request::new(
+            RequestType::Out,
+            vec![
+                DataDescriptor::new(GuestAddress(0x100), 0x400),
+                DataDescriptor::new(GuestAddress(0x800), 0x200),
+            ],
+            1,
+            GuestAddress(0x200),
+        );
+
+        mem.write_slice(&[NON_ZERO_VALUE; 0x200], GuestAddress(0x200))
+            .unwrap();
+        mem.write_slice(&[NON_ZERO_VALUE; 0x100], GuestAddress(0x880))
+            .unwrap();
+        // We will write in file at sector 1 (offset 0x200) 0x400 bytes from 0x100 guest memory
+        // address and 0x200 bytes from 0x800 address. 0 bytes should've been written in memory.
+        assert_eq!(req_exec.execute(mem, &out_req).unwrap(), 0x00);
+
+        // Let's write some more bytes to the file.
+        mem.write_slice(&[NON_ZERO_VALUE + 1; 0x600], GuestAddress(0x3100))
+            .unwrap();
+
+        // Write at offset 0x600 in file, 800 bytes: the first 100 bytes = 0, the next 600 bytes =
+        // = NON_ZERO_VALUE + 1 and the last 100 bytes = 0; and then at offset 0x600 + 0x800 =
+        // = 0xE00, which is the last sector, 200 bytes = NON_ZERO_VALUE.
+        let out_req = Request::new(
+            RequestType::Out,
+            vec![
+                DataDescriptor::new(GuestAddress(0x3000), 0x800),
+                DataDescriptor::new(GuestAddress(0x200), 0x200),
+            ],
+            3,
+            GuestAddress(0x200),
+        );
+        assert!(req_exec.execute(mem, &out_req).is_ok());
+
+        // Test write zeroes request.
+        let wr_zeroes_1 = DiscardWriteZeroes {
+            sector: 2,
+            num_sectors: 2,
+            flags: 0,
+        };
+        mem.write_obj::<DiscardWriteZeroes>(wr_zeroes_1, GuestAddress(0x1000))
+            .unwrap();
+        let wr_zeroes_2 = DiscardWriteZeroes {
+            sector: 5,
+            num_sectors: 1,
+            flags: 0,
+        };
+        mem.write_obj::<DiscardWriteZeroes>(wr_zeroes_2, GuestAddress(0x4000))
+            .unwrap();
+
+        let wr_zeroes_req = Request::new(
+            RequestType::WriteZeroes,
+            vec![
+                DataDescriptor::new(GuestAddress(0x1000), VIRTIO_BLK_DISCARD_SEGMENT_LEN as u32),
+                DataDescriptor::new(GuestAddress(0x4000), VIRTIO_BLK_DISCARD_SEGMENT_LEN as u32),
+            ],
+            2,
+            GuestAddress(0x2000),
+        );
+
+        // 0 bytes should've been written in memory.
+        assert_eq!(req_exec.execute(mem, &wr_zeroes_req).unwrap(), 0x00);
+
+        req_exec.inner().rewind().unwrap();
+        let mut v = vec![0x00; 0x300];
+        assert_eq!(req_exec.inner().read(&mut v).unwrap(), 0x300);
+        assert_eq!(v, vec![0x00; 0x300]);
+
+        // We are at offset 0x300.
+        v = vec![0x00; 0x100];
+        assert_eq!(req_exec.inner().read(&mut v).unwrap(), 0x100);
+        assert_eq!(v, vec![NON_ZERO_VALUE; 0x100]);
+
+        // We are at offset 0x400 -> 0x400 bytes should've been zeroed out.
+        v = vec![0x00; 0x400];
+        assert_eq!(req_exec.inner().read(&mut v).unwrap(), 0x400);
+        assert_eq!(v, vec![0x00; 0x400]);
+
+        // We are at offset 0x800.
+        v = vec![0x00; 0x200];
+        assert_eq!(req_exec.inner().read(&mut v).unwrap(), 0x200);
+        assert_eq!(v, vec![NON_ZERO_VALUE + 1; 0x200]);
+
+        // We are at offset 0xA00 -> 0x200 bytes should've been zeroed out.
+        v = vec![0x00; 0x200];
+        assert_eq!(req_exec.inner().read(&mut v).unwrap(), 0x200);
+        assert_eq!(v, vec![0; 0x200]);
+
+        // We are at offset 0xC00.
+        v = vec![0x00; 0x100];
+        assert_eq!(req_exec.inner().read(&mut v).unwrap(), 0x100);
+        assert_eq!(v, vec![NON_ZERO_VALUE + 1; 0x100]);
+
+        // We are at offset 0xD00.
+        v = vec![0x00; 0x100];
+        assert_eq!(req_exec.inner().read(&mut v).unwrap(), 0x100);
+        assert_eq!(v, vec![0; 0x100]);
+
+        // We are at offset 0xE00.
+        v = vec![0x00; 0x200];
+        assert_eq!(req_exec.inner().read(&mut v).unwrap(), 0x200);
+        assert_eq!(v, vec![NON_ZERO_VALUE; 0x200]);
+
+        // Test discard request.
+        let discard_req = DiscardWriteZeroes {
+            sector: 7,
+            num_sectors: 1,
+            flags: 0,
+        };
+        mem.write_obj::<DiscardWriteZeroes>(discard_req, GuestAddress(0x1000))
+            .unwrap();
+
+        let discard_req = Request::new(
+            RequestType::Discard,
+            vec![DataDescriptor::new(
+                GuestAddress(0x1000),
+                VIRTIO_BLK_DISCARD_SEGMENT_LEN as u32,
+            )],
+            7,
+            GuestAddress(0x2000),
+        );
+
+        // 0 bytes should've been written in memory.
+        assert_eq!(req_exec.execute(mem, &discard_req).unwrap(), 0x00);
+
+        req_exec.inner().seek(SeekFrom::Start(0xE00)).unwrap();
+        let mut v = vec![0x00; 0x200];
+        assert_eq!(req_exec.inner().read(&mut v).unwrap(), 0x200);
+        assert_eq!(v, vec![0x00; 0x200]);
+
+        // Even though we punched a hole at the end of the file, the file size should remain the
+        // same since FALLOC_FL_PUNCH_HOLE is used with FALLOC_FL_KEEP_SIZE.
+        assert_eq!(req_exec.inner().metadata().unwrap().len(), 0x1000);
+
+        // Test that write zeroes request with unmap bit set is okay.
+        let wr_zeroes_req = DiscardWriteZeroes {
+            sector: 4,
+            num_sectors: 1,
+            flags: 0x0001,
+        };
+        mem.write_obj::<DiscardWriteZeroes>(wr_zeroes_req, GuestAddress(0x1000))
+            .unwrap();
+
+        let wr_zeroes_req = Request::new(
+            RequestType::WriteZeroes,
+            vec![DataDescriptor::new(
+                GuestAddress(0x1000),
+                VIRTIO_BLK_DISCARD_SEGMENT_LEN as u32,
+            )],
+            7,
+            GuestAddress(0x2000),
+        );
+
+        req_exec.inner().seek(SeekFrom::Start(0x800)).unwrap();
+        let mut v = vec![0x00; 0x200];
+        assert_eq!(req_exec.inner().read(&mut v).unwrap(), 0x200);
+        // Data is != 0 before the write zeroes request.
+        assert_eq!(v, vec![NON_ZERO_VALUE + 1; 0x200]);
+        // Let's write some data in the file right before and after the fourth sector to confirm
+        // that those regions won't be zeroed out.
+        // After the fourth sector:
+        let v = vec![NON_ZERO_VALUE + 2; 0x200];
+        assert_eq!(req_exec.inner().write(&v).unwrap(), 0x200);
+        // Before the fourth sector:
+        req_exec.inner().seek(SeekFrom::Start(0x600)).unwrap();
+        assert_eq!(req_exec.inner().write(&v).unwrap(), 0x200);
+
+        // 0 bytes should've been written in memory.
+        assert_eq!(req_exec.execute(mem, &wr_zeroes_req).unwrap(), 0x00);
+
+        req_exec.inner().seek(SeekFrom::Start(0x600)).unwrap();
+        let mut v = vec![0x00; 0x200];
+        assert_eq!(req_exec.inner().read(&mut v).unwrap(), 0x200);
+        assert_eq!(v, vec![NON_ZERO_VALUE + 2; 0x200]);
+        assert_eq!(req_exec.inner().read(&mut v).unwrap(), 0x200);
+        assert_eq!(v, vec![0; 0x200]);
+        assert_eq!(req_exec.inner().read(&mut v).unwrap(), 0x200);
+        assert_eq!(v, vec![NON_ZERO_VALUE + 2; 0x200]);
+
+        // VIRTIO_BLK_F_DISCARD not negotiated.
+        req_exec.features = 0;
+        assert_eq!(
+            req_exec.execute(mem, &discard_req).unwrap_err(),
+            Error::Unsupported(VIRTIO_BLK_T_DISCARD)
+        );
+        req_exec.features = (1 << VIRTIO_BLK_F_DISCARD) | (1 << VIRTIO_BLK_F_WRITE_ZEROES);
+
+        // Test discard request with invalid data length.
+        let discard_req = Request::new(
+            RequestType::Discard,
+            vec![
+                DataDescriptor::new(
+                    GuestAddress(0x5000),
+                    VIRTIO_BLK_DISCARD_SEGMENT_LEN as u32 / 2,
+                ),
+                DataDescriptor::new(
+                    GuestAddress(0x1000),
+                    VIRTIO_BLK_DISCARD_SEGMENT_LEN as u32 / 2,
+                ),
+            ],
+            7,
+            GuestAddress(0x2000),
+        );
+        assert_eq!(
+            req_exec.execute(mem, &discard_req).unwrap_err(),
+            Error::InvalidDataLength
+        );
+
+        let discard_req = Request::new(
+            RequestType::Discard,
+            vec![DataDescriptor::new(
+                GuestAddress(0x1000),
+                VIRTIO_BLK_DISCARD_SEGMENT_LEN as u32 - 1,
+            )],
+            7,
+            GuestAddress(0x2000),
+        );
+        assert_eq!(
+            req_exec.execute(mem, &discard_req).unwrap_err(),
+            Error::InvalidDataLength
+        );
+
+        // Test discard request with an invalid memory access that would cause an overflow.
+        let discard_req = Request::new(
+            RequestType::Discard,
+            vec![DataDescriptor::new(
+                GuestAddress(u64::MAX - VIRTIO_BLK_DISCARD_SEGMENT_LEN),
+                2 * VIRTIO_BLK_DISCARD_SEGMENT_LEN as u32,
+            )],
+            7,
+            GuestAddress(0x2000),
+        );
+        assert_eq!(
+            req_exec.execute(mem, &discard_req).unwrap_err(),
+            Error::Overflow
+        );
+
+        // Test discard request with invalid sectors.
+        let discard_req = DiscardWriteZeroes {
+            sector: 7,
+            num_sectors: 2,
+            flags: 0,
+        };
+        mem.write_obj::<DiscardWriteZeroes>(discard_req, GuestAddress(0x1000))
+            .unwrap();
+
+        let discard_req = Request::new(
+            RequestType::Discard,
+            vec![DataDescriptor::new(
+                GuestAddress(0x1000),
+                VIRTIO_BLK_DISCARD_SEGMENT_LEN as u32,
+            )],
+            7,
+            GuestAddress(0x2000),
+        );
+        assert_eq!(
+            req_exec.execute(mem, &discard_req).unwrap_err(),
+            Error::InvalidAccess
+        );
+
+        // Test discard request with invalid flags (unmap bit set).
+        let discard_req = DiscardWriteZeroes {
+            sector: 3,
+            num_sectors: 2,
+            flags: 0x0001,
+        };
+        mem.write_obj::<DiscardWriteZeroes>(discard_req, GuestAddress(0x1000))
+            .unwrap();
+
+        let discard_req = Request::new(
+            RequestType::Discard,
+            vec![DataDescriptor::new(
+                GuestAddress(0x1000),
+                VIRTIO_BLK_DISCARD_SEGMENT_LEN as u32,
+            )],
+            7,
+            GuestAddress(0x2000),
+        );
+        assert_eq!(
+            req_exec.execute(mem, &discard_req).unwrap_err(),
+            Error::InvalidFlags
+        );
+
+        // Test write zeroes request with invalid flags (reserved bit set).
+        let wr_zeroes_req = DiscardWriteZeroes {
+            sector: 3,
+            num_sectors: 2,
+            flags: 0xA000,
+        };
+        mem.write_obj::<DiscardWriteZeroes>(wr_zeroes_req, GuestAddress(0x1000))
+            .unwrap();
+
+        let wr_zeroes_req = Request::new(
+            RequestType::WriteZeroes,
+            vec![DataDescriptor::new(
+                GuestAddress(0x1000),
+                VIRTIO_BLK_DISCARD_SEGMENT_LEN as u32,
+            )],
+            7,
+            GuestAddress(0x2000),
+        );
+        assert_eq!(
+            req_exec.execute(mem, &wr_zeroes_req).unwrap_err(),
+            Error::InvalidFlags
+        );
+
+        // Invalid data address.
+        let wr_zeroes_req = Request::new(
+            RequestType::WriteZeroes,
+            vec![DataDescriptor::new(
+                GuestAddress(0x1100_0000),
+                VIRTIO_BLK_DISCARD_SEGMENT_LEN as u32,
+            )],
+            7,
+            GuestAddress(0x2000),
+        );
+
+        assert_eq!(
+            req_exec.execute(mem, &wr_zeroes_req).unwrap_err(),
+            Error::GuestMemory(InvalidGuestAddress(GuestAddress(0x1100_0000)))
+        );
+    }
+
+    #[test]
+    fn test_get_device_id() {
+        let f = TempFile::new().unwrap().into_file();
+        f.set_len(0x1000).unwrap();
+
+        let mem = GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x1000_0000)]).unwrap();
+        let dev_id = [
+            0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x00A, 0x0B, 0x0C, 0x0D, 0x0E,
+            0x0F, 0x10, 0x11, 0x12, 0x13, 0x14,
+        ];
+        let mut req_exec = StdIoBackend::new(f, 0).unwrap();
+        assert_eq!(req_exec.device_id(), None);
+
+        let get_id_req = Request::new(
+            RequestType::GetDeviceID,
+            vec![DataDescriptor::new(
+                GuestAddress(0x100),
+                VIRTIO_BLK_ID_BYTES,
+            )],
+            1,
+            GuestAddress(0x200),
+        );
+
+        // Device id was not set, so a GetDeviceID request is unsupported.
+        assert_eq!(
+            req_exec.execute(&mem, &get_id_req).unwrap_err(),
+            Error::Unsupported(VIRTIO_BLK_T_GET_ID)
+        );
+
+        req_exec = req_exec.with_device_id(dev_id);
+        assert_eq!(req_exec.device_id(), Some(&dev_id));
+
+        // Invalid get device ID request, data length should be VIRTIO_BLK_ID_BYTES.
+        let get_id_req = Request::new(
+            RequestType::GetDeviceID,
+            vec![DataDescriptor::new(
+                GuestAddress(0x100),
+                VIRTIO_BLK_ID_BYTES - 1,
+            )],
+            1,
+            GuestAddress(0x200),
+        );
+
+        assert_eq!(
+            req_exec.execute(&mem, &get_id_req).unwrap_err(),
+            Error::InvalidDataLength
+        );
+
+        let get_id_req = Request::new(
+            RequestType::GetDeviceID,
+            vec![DataDescriptor::new(
+                GuestAddress(0x100),
+                VIRTIO_BLK_ID_BYTES,
+            )],
+            1,
+            GuestAddress(0x200),
+        );
+
+        // VIRTIO_BLK_ID_BYTES bytes should've been written in memory.
+        assert_eq!(
+            req_exec.execute(&mem, &get_id_req).unwrap(),
+            VIRTIO_BLK_ID_BYTES
+        );
+        let mut buf = [0x00; VIRTIO_BLK_ID_BYTES as usize];
+        mem.read_slice(&mut buf, GuestAddress(0x100)).unwrap();
+        assert_eq!(buf, dev_id);
+
+        let get_id_req = Request::new(
+            RequestType::GetDeviceID,
+            vec![
+                DataDescriptor::new(GuestAddress(0x100), 0x08),
+                DataDescriptor::new(GuestAddress(0x200), 0x0C),
+            ],
+            1,
+            GuestAddress(0x200),
+        );
+
+        mem.write_slice(&[0; 20], GuestAddress(0x100)).unwrap();
+
+        // VIRTIO_BLK_ID_BYTES bytes should've been written in memory.
+        assert_eq!(
+            req_exec.execute(&mem, &get_id_req).unwrap(),
+            VIRTIO_BLK_ID_BYTES
+        );
+        let mut buf = [0x00; 8];
+        mem.read_slice(&mut buf, GuestAddress(0x100)).unwrap();
+        assert_eq!(buf, dev_id[0..8]);
+        let mut buf = [0x00; 12];
+        mem.read_slice(&mut buf, GuestAddress(0x200)).unwrap();
+        assert_eq!(buf, dev_id[8..VIRTIO_BLK_ID_BYTES as usize]);
+
+        // The id returned by the requests can be replaced at runtime.
+        let new_dev_id = [0xAB; VIRTIO_BLK_ID_BYTES as usize];
+        req_exec.set_device_id(new_dev_id);
+        assert_eq!(req_exec.device_id(), Some(&new_dev_id));
+        req_exec.execute(&mem, &get_id_req).unwrap();
+        let mut buf = [0x00; 8];
+        mem.read_slice(&mut buf, GuestAddress(0x100)).unwrap();
+        assert_eq!(buf, new_dev_id[0..8]);
+    }
+
+    #[test]
+    fn test_process_request() {
+        let f = TempFile::new().unwrap().into_file();
+        f.set_len(0x1000).unwrap();
+
+        let mem = GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x1000_0000)]).unwrap();
+        let flush_req = Request::new(
+            RequestType::Flush,
+            vec![DataDescriptor::new(GuestAddress(0x100), 0x400)],
+            0,
+            GuestAddress(0x600),
+        );
+
+        // VIRTIO_BLK_F_FLUSH not negotiated.
+        let mut req_exec = StdIoBackend::new(f, 0).unwrap();
+        assert_eq!(req_exec.process_request(&mem, &flush_req).unwrap(), 1);
+        assert_eq!(
+            mem.read_obj::<u8>(GuestAddress(0x600)).unwrap(),
+            VIRTIO_BLK_S_UNSUPP as u8
+        );
+
+        // VIRTIO_BLK_F_FLUSH negotiated.
+        req_exec.features = 1 << VIRTIO_BLK_F_FLUSH;
+        assert_eq!(req_exec.process_request(&mem, &flush_req).unwrap(), 1);
+        assert_eq!(
+            mem.read_obj::<u8>(GuestAddress(0x600)).unwrap(),
+            VIRTIO_BLK_S_OK as u8
+        );
+
+        // Ok In request.
+        let in_req = Request::new(
+            RequestType::In,
+            vec![
+                DataDescriptor::new(GuestAddress(0x100), 0x400),
+                DataDescriptor::new(GuestAddress(0x600), 0x200),
+            ],
+            0,
+            GuestAddress(0x900),
+        );
+        // 0x600 bytes should've been written in memory.
+        assert_eq!(req_exec.process_request(&mem, &in_req).unwrap(), 0x601);
+        assert_eq!(
+            mem.read_obj::<u8>(GuestAddress(0x900)).unwrap(),
+            VIRTIO_BLK_S_OK as u8
+        );
+
+        // Invalid status address.
+        let in_req = Request::new(
+            RequestType::In,
+            vec![
+                DataDescriptor::new(GuestAddress(0x100), 0x400),
+                DataDescriptor::new(GuestAddress(0x600), 0x200),
+            ],
+            0,
+            GuestAddress(0x1000_0001),
+        );
+        assert!(matches!(
+            req_exec.process_request(&mem, &in_req).unwrap_err(),
+            ProcessReqError::GuestMemory(InvalidGuestAddress(GuestAddress(0x1000_0001)))
+        ));
+
+        req_exec.features = 1 << VIRTIO_BLK_F_DISCARD;
+        // Test discard request with invalid flags.
+        let discard_req = DiscardWriteZeroes {
+            sector: 3,
+            num_sectors: 2,
+            flags: 0x0001,
+        };
+        mem.write_obj::<DiscardWriteZeroes>(discard_req, GuestAddress(0x1000))
+            .unwrap();
+        let discard_req = Request::new(
+            RequestType::Discard,
+            vec![DataDescriptor::new(
+                GuestAddress(0x1000),
+                VIRTIO_BLK_DISCARD_SEGMENT_LEN as u32,
+            )],
+            7,
+            GuestAddress(0x2000),
+        );
+        assert_eq!(req_exec.process_request(&mem, &discard_req).unwrap(), 1);
+        assert_eq!(
+            mem.read_obj::<u8>(GuestAddress(0x2000)).unwrap(),
+            VIRTIO_BLK_S_UNSUPP as u8
+        );
+
+        // Invalid memory address for write operation.
+        let out_req = Request::new(
+            RequestType::Out,
+            vec![DataDescriptor::new(GuestAddress(0xFFF_FFF0), 0x200)],
+            7,
+            GuestAddress(0x200),
+        );
+        assert_eq!(req_exec.process_request(&mem, &out_req).unwrap(), 1);
+        assert_eq!(
+            mem.read_obj::<u8>(GuestAddress(0x200)).unwrap(),
+            VIRTIO_BLK_S_IOERR as u8
+        );
+
+        // Invalid memory address for read operation.
+        let in_req = Request::new(
+            RequestType::In,
+            vec![DataDescriptor::new(GuestAddress(0xFFF_FFF0), 0x200)],
+            7,
+            GuestAddress(0x200),
+        );
+        assert_eq!(
+            req_exec.process_request(&mem, &in_req).unwrap(),
+            0x1000_0000 - 0xFFF_FFF0 + 1
+        );
+        assert_eq!(
+            mem.read_obj::<u8>(GuestAddress(0x200)).unwrap(),
+            VIRTIO_BLK_S_IOERR as u8
+        );
+
+        let dev_id = [
+            0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x00A, 0x0B, 0x0C, 0x0D, 0x0E,
+            0x0F, 0x10, 0x11, 0x12, 0x13, 0x14,
+        ];
+        req_exec = req_exec.with_device_id(dev_id);
+
+        // Invalid memory address for get device id operation.
+        let get_id_req = Request::new(
+            RequestType::GetDeviceID,
+            vec![DataDescriptor::new(
+                GuestAddress(0xFFF_FFFA),
+                VIRTIO_BLK_ID_BYTES,
+            )],
+            7,
+            GuestAddress(0x200),
+        );
+        assert_eq!(
+            req_exec.process_request(&mem, &get_id_req).unwrap(),
+            0x1000_0000 - 0xFFF_FFFA + 1
+        );
+        assert_eq!(
+            mem.read_obj::<u8>(GuestAddress(0x200)).unwrap(),
+            VIRTIO_BLK_S_IOERR as u8
+        );
+    }
+
+    #[test]
+    fn test_shrink_sectors() {
+        const NON_ZERO_VALUE: u8 = 0x55;
+
+        let mut harness = BlockDeviceHarness::new(16, 1 << VIRTIO_BLK_F_DISCARD);
+        let (mem, req_exec) = harness.split_mut();
+
+        // Populate the whole disk with non-zero data.
+        mem.write_slice(&[NON_ZERO_VALUE; 0x2000], GuestAddress(0x1000))
+            .unwrap();
+        let out_req = Request::new(
+            RequestType::Out,
+            vec![DataDescriptor::new(GuestAddress(0x1000), 0x2000)],
+            0,
+            GuestAddress(0x100),
+        );
+        req_exec.execute(mem, &out_req).unwrap();
+
+        // The tail still contains data.
+        assert_eq!(
+            req_exec.shrink_sectors(4).unwrap_err(),
+            Error::NonZeroSector(12)
+        );
+        assert_eq!(req_exec.num_sectors(), 16);
+
+        // Discard the last 4 sectors.
+        let discard_segment = DiscardWriteZeroes {
+            sector: 12,
+            num_sectors: 4,
+            flags: 0,
+        };
+        mem.write_obj::<DiscardWriteZeroes>(discard_segment, GuestAddress(0x4000))
+            .unwrap();
+        let discard_req = Request::new(
+            RequestType::Discard,
+            vec![DataDescriptor::new(
+                GuestAddress(0x4000),
+                VIRTIO_BLK_DISCARD_SEGMENT_LEN as u32,
+            )],
+            0,
+            GuestAddress(0x100),
+        );
+        req_exec.execute(mem, &discard_req).unwrap();
+
+        // Only the discarded sectors can be removed.
+        assert_eq!(
+            req_exec.shrink_sectors(5).unwrap_err(),
+            Error::NonZeroSector(11)
+        );
+        assert_eq!(req_exec.shrink_sectors(4).unwrap(), 12);
+        assert_eq!(req_exec.num_sectors(), 12);
+
+        // The removed sectors are no longer accessible.
+        let in_req = Request::new(
+            RequestType::In,
+            vec![DataDescriptor::new(GuestAddress(0x1000), 0x200)],
+            12,
+            GuestAddress(0x100),
+        );
+        assert_eq!(
+            req_exec.execute(mem, &in_req).unwrap_err(),
+            Error::InvalidAccess
+        );
+        assert_eq!(
+            req_exec.shrink_sectors(13).unwrap_err(),
+            Error::InvalidAccess
+        );
+
+        // The remaining data is untouched.
+        let in_req = Request::new(
+            RequestType::In,
+            vec![DataDescriptor::new(GuestAddress(0x8000), 0x1800)],
+            0,
+            GuestAddress(0x100),
+        );
+        req_exec.execute(mem, &in_req).unwrap();
+        let mut v = vec![0x00; 0x1800];
+        mem.read_slice(&mut v, GuestAddress(0x8000)).unwrap();
+        assert_eq!(v, vec![NON_ZERO_VALUE; 0x1800]);
+    }
+
+    #[test]
+    fn test_sector_is_allocated() {
+        let mut harness = BlockDeviceHarness::with_temp_file(32, 1 << VIRTIO_BLK_F_DISCARD);
+        let (mem, req_exec) = harness.split_mut();
+
+        // Write the first 0x3000 bytes of the disk.
+        mem.write_slice(&[0x55; 0x3000], GuestAddress(0x1000))
+            .unwrap();
+        let out_req = Request::new(
+            RequestType::Out,
+            vec![DataDescriptor::new(GuestAddress(0x1000), 0x3000)],
+            0,
+            GuestAddress(0x100),
+        );
+        req_exec.execute(mem, &out_req).unwrap();
+
+        // Punch a hole that covers a whole filesystem block.
+        let discard_segment = DiscardWriteZeroes {
+            sector: 8,
+            num_sectors: 8,
+            flags: 0,
+        };
+        mem.write_obj::<DiscardWriteZeroes>(discard_segment, GuestAddress(0x8000))
+            .unwrap();
+        let discard_req = Request::new(
+            RequestType::Discard,
+            vec![DataDescriptor::new(
+                GuestAddress(0x8000),
+                VIRTIO_BLK_DISCARD_SEGMENT_LEN as u32,
+            )],
+            0,
+            GuestAddress(0x100),
+        );
+        req_exec.execute(mem, &discard_req).unwrap();
+
+        #[cfg(target_os = "linux")]
+        {
+            assert!(req_exec.sector_is_allocated(0).unwrap());
+            assert!(req_exec.sector_is_allocated(7).unwrap());
+            assert!(!req_exec.sector_is_allocated(8).unwrap());
+            assert!(!req_exec.sector_is_allocated(15).unwrap());
+            assert!(req_exec.sector_is_allocated(16).unwrap());
+            // The sparse tail of the file was never written.
+            assert!(!req_exec.sector_is_allocated(31).unwrap());
+
+            // The position of the backing file isn't changed.
+            req_exec.inner_mut().seek(SeekFrom::Start(0x200)).unwrap();
+            assert!(req_exec.sector_is_allocated(16).unwrap());
+            assert!(!req_exec.sector_is_allocated(31).unwrap());
+            assert_eq!(req_exec.inner_mut().stream_position().unwrap(), 0x200);
+        }
+        assert_eq!(
+            req_exec.sector_is_allocated(32).unwrap_err(),
+            Error::InvalidAccess
+        );
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut harness = BlockDeviceHarness::new(8, 1 << VIRTIO_BLK_F_FLUSH);
+        let stats = harness.backend_mut().stats().clone();
+
+        let in_req = Request::new(
+            RequestType::In,
+            vec![DataDescriptor::new(GuestAddress(0x1000), 0x400)],
+            0,
+            GuestAddress(0x100),
+        );
+        harness.send_request(in_req).unwrap();
+        let out_req = Request::new(
+            RequestType::Out,
+            vec![DataDescriptor::new(GuestAddress(0x1000), 0x200)],
+            7,
+            GuestAddress(0x100),
+        );
+        harness.send_request(out_req).unwrap();
+        let flush_req = Request::new(RequestType::Flush, vec![], 0, GuestAddress(0x100));
+        harness.send_request(flush_req).unwrap();
+        // Out of bounds write.
+        let out_req = Request::new(
+            RequestType::Out,
+            vec![DataDescriptor::new(GuestAddress(0x1000), 0x400)],
+            7,
+            GuestAddress(0x100),
+        );
+        harness.send_request(out_req).unwrap_err();
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.reads, 1);
+        assert_eq!(snapshot.read_bytes, 0x400);
+        assert_eq!(snapshot.writes, 2);
+        assert_eq!(snapshot.write_bytes, 0x600);
+        assert_eq!(snapshot.flushes, 1);
+        assert_eq!(snapshot.errors, 1);
+        // The execution times are recorded too, including the ones of failed requests.
+        assert!(snapshot.read_latency_ns_sum > 0);
+        assert_eq!(snapshot.read_latency_count, 1);
+        assert!(snapshot.write_latency_ns_sum > 0);
+        assert_eq!(snapshot.write_latency_count, 2);
+        assert!(snapshot.flush_latency_ns_sum > 0);
+        assert_eq!(snapshot.flush_latency_count, 1);
+    }
+
+    #[test]
+    fn test_execute_dry_run() {
+        const NON_ZERO_VALUE: u8 = 0x55;
+
+        let mut f = TempFile::new().unwrap().into_file();
+        f.set_len(0x1000).unwrap();
+        f.write_all(&[NON_ZERO_VALUE; 0x1000]).unwrap();
+
+        let mem = GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x1000_0000)]).unwrap();
+        let mut req_exec = StdIoBackend::new(
+            f,
+            (1 << VIRTIO_BLK_F_FLUSH) | (1 << VIRTIO_BLK_F_WRITE_ZEROES),
+        )
+        .unwrap();
+
+        // Valid requests.
+        let out_req = Request::new(
+            RequestType::Out,
+            vec![DataDescriptor::new(GuestAddress(0x1000), 0x400)],
+            6,
+            GuestAddress(0x100),
+        );
+        req_exec.execute_dry_run(&mem, &out_req).unwrap();
+        let flush_req = Request::new(RequestType::Flush, vec![], 0, GuestAddress(0x100));
+        req_exec.execute_dry_run(&mem, &flush_req).unwrap();
+
+        // Out of bounds write.
+        let out_req = Request::new(
+            RequestType::Out,
+            vec![DataDescriptor::new(GuestAddress(0x1000), 0x400)],
+            7,
+            GuestAddress(0x100),
+        );
+        assert_eq!(
+            req_exec.execute_dry_run(&mem, &out_req).unwrap_err(),
+            Error::InvalidAccess
+        );
+
+        // Invalid data length.
+        let in_req = Request::new(
+            RequestType::In,
+            vec![DataDescriptor::new(GuestAddress(0x1000), 0x100)],
+            0,
+            GuestAddress(0x100),
+        );
+        assert_eq!(
+            req_exec.execute_dry_run(&mem, &in_req).unwrap_err(),
+            Error::InvalidDataLength
+        );
+
+        // Invalid guest memory buffer.
+        let in_req = Request::new(
+            RequestType::In,
+            vec![DataDescriptor::new(GuestAddress(0xFFF_FF00), 0x200)],
+            0,
+            GuestAddress(0x100),
+        );
+        assert_eq!(
+            req_exec.execute_dry_run(&mem, &in_req).unwrap_err(),
+            Error::GuestMemory(InvalidGuestAddress(GuestAddress(0xFFF_FF00)))
+        );
+
+        // Discard is not negotiated.
+        let discard_req = Request::new(
+            RequestType::Discard,
+            vec![DataDescriptor::new(
+                GuestAddress(0x4000),
+                VIRTIO_BLK_DISCARD_SEGMENT_LEN as u32,
+            )],
+            0,
+            GuestAddress(0x100),
+        );
+        assert_eq!(
+            req_exec.execute_dry_run(&mem, &discard_req).unwrap_err(),
+            Error::Unsupported(VIRTIO_BLK_T_DISCARD)
+        );
+
+        // Write zeroes segment out of bounds.
+        let segment = DiscardWriteZeroes {
+            sector: 6,
+            num_sectors: 4,
+            flags: DiscardWriteZeroes::UNMAP,
+        };
+        mem.write_obj::<DiscardWriteZeroes>(segment, GuestAddress(0x4000))
+            .unwrap();
+        let wr_zeroes_req = Request::new(
+            RequestType::WriteZeroes,
+            vec![DataDescriptor::new(
+                GuestAddress(0x4000),
+                VIRTIO_BLK_DISCARD_SEGMENT_LEN as u32,
+            )],
+            0,
+            GuestAddress(0x100),
+        );
+        assert_eq!(
+            req_exec.execute_dry_run(&mem, &wr_zeroes_req).unwrap_err(),
+            Error::InvalidAccess
+        );
+
+        // The DMA page and request size limits are checked as well.
+        let mut req_exec = req_exec
+            .with_dma_page_size(0x1000, false)
+            .with_max_io_sectors(1, false);
+        let out_req = Request::new(
+            RequestType::Out,
+            vec![DataDescriptor::new(GuestAddress(0x1F00), 0x200)],
+            0,
+            GuestAddress(0x100),
+        );
+        assert_eq!(
+            req_exec.execute_dry_run(&mem, &out_req).unwrap_err(),
+            Error::DmaPageCrossing {
+                addr: 0x1F00,
+                page_size: 0x1000
+            }
+        );
+        let out_req = Request::new(
+            RequestType::Out,
+            vec![DataDescriptor::new(GuestAddress(0x1000), 0x400)],
+            0,
+            GuestAddress(0x100),
+        );
+        assert_eq!(
+            req_exec.execute_dry_run(&mem, &out_req).unwrap_err(),
+            Error::RequestTooLarge {
+                request_sectors: 2,
+                max_sectors: 1
+            }
+        );
+
+        // The file was not modified by any of the requests.
+        let mut v = vec![0x00; 0x1000];
+        req_exec.inner().rewind().unwrap();
+        req_exec.inner().read_exact(&mut v).unwrap();
+        assert_eq!(v, vec![NON_ZERO_VALUE; 0x1000]);
+
+        // Zone reports have to start within the device.
+        let mut req_exec =
+            StdIoBackend::from_vec(vec![0; 0x1000], 1 << VIRTIO_BLK_F_ZONED).unwrap();
+        let zone_report_req = |sector| {
+            Request::new(
+                RequestType::ZoneReport,
+                vec![DataDescriptor::new(GuestAddress(0x1000), 0x1000)],
+                sector,
+                GuestAddress(0x100),
+            )
+        };
+        req_exec.execute_dry_run(&mem, &zone_report_req(7)).unwrap();
+        assert_eq!(
+            req_exec
+                .execute_dry_run(&mem, &zone_report_req(8))
+                .unwrap_err(),
+            Error::InvalidAccess
+        );
+    }
+
+    #[test]
+    fn test_sector_remapping() {
+        const NON_ZERO_VALUE: u8 = 0x55;
+
+        let mut harness = BlockDeviceHarness::with_temp_file(128, 0);
+        let (mem, req_exec) = harness.split_mut();
+
+        mem.write_slice(&[NON_ZERO_VALUE; 0x200], GuestAddress(0x1000))
+            .unwrap();
+        let out_req = Request::new(
+            RequestType::Out,
+            vec![DataDescriptor::new(GuestAddress(0x1000), 0x200)],
+            0,
+            GuestAddress(0x100),
+        )
+        .with_sector_offset(100);
+        assert_eq!(out_req.sector(), 100);
+        req_exec.execute(mem, &out_req).unwrap();
+
+        // The guest sector 0 is untouched.
+        let mut v = vec![0x00; 0x200];
+        req_exec.inner().rewind().unwrap();
+        req_exec.inner().read_exact(&mut v).unwrap();
+        assert_eq!(v, vec![0x00; 0x200]);
+
+        // The data was written at the host sector 100.
+        req_exec
+            .inner()
+            .seek(SeekFrom::Start(100 << SECTOR_SHIFT))
+            .unwrap();
+        req_exec.inner().read_exact(&mut v).unwrap();
+        assert_eq!(v, vec![NON_ZERO_VALUE; 0x200]);
+    }
+
+    #[test]
+    fn test_feature_bits_supported_by_backend() {
+        let f = TempFile::new().unwrap().into_file();
+        let features = StdIoBackend::feature_bits_supported_by_backend(&f);
+        assert_ne!(features & (1 << VIRTIO_BLK_F_DISCARD), 0);
+        assert_ne!(features & (1 << VIRTIO_BLK_F_WRITE_ZEROES), 0);
+        assert_ne!(features & (1 << VIRTIO_BLK_F_FLUSH), 0);
+        assert_eq!(features & (1 << VIRTIO_BLK_F_RO), 0);
+
+        // Only the features supported by the backend are negotiated.
+        let driver_features = (1 << VIRTIO_BLK_F_FLUSH) | (1 << VIRTIO_BLK_F_RO);
+        assert_eq!(features & driver_features, 1 << VIRTIO_BLK_F_FLUSH);
+
+        // The data of a `VecBackend` can't be deallocated, nor made persistent.
+        assert_eq!(
+            StdIoBackend::feature_bits_supported_by_backend(&VecBackend::default()),
+            1 << VIRTIO_BLK_F_WRITE_ZEROES
+        );
+    }
+
+    #[test]
+    fn test_max_segments() {
+        let mut f = TempFile::new().unwrap().into_file();
+        f.set_len(0x1000).unwrap();
+        f.write_all(&[0x55; 0x1000]).unwrap();
+
+        let mem = GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x1000_0000)]).unwrap();
+        let mut req_exec = StdIoBackend::new(
+            f,
+            (1 << VIRTIO_BLK_F_DISCARD) | (1 << VIRTIO_BLK_F_WRITE_ZEROES),
+        )
+        .unwrap()
+        .with_max_discard_seg(1)
+        .with_max_write_zeroes_seg(2);
+
+        for (i, sector) in [0, 4].iter().enumerate() {
+            let segment = DiscardWriteZeroes {
+                sector: *sector,
+                num_sectors: 2,
+                flags: 0,
+            };
+            mem.write_obj::<DiscardWriteZeroes>(
+                segment,
+                GuestAddress(0x1000 + i as u64 * VIRTIO_BLK_DISCARD_SEGMENT_LEN),
+            )
+            .unwrap();
+        }
+
+        // Two segments in a discard request.
+        let discard_req = Request::new(
+            RequestType::Discard,
+            vec![DataDescriptor::new(
+                GuestAddress(0x1000),
+                2 * VIRTIO_BLK_DISCARD_SEGMENT_LEN as u32,
+            )],
+            0,
+            GuestAddress(0x100),
+        );
+        assert_eq!(
+            req_exec.execute(&mem, &discard_req).unwrap_err(),
+            Error::TooManySegments { count: 2, max: 1 }
+        );
+        assert_eq!(
+            req_exec.execute_dry_run(&mem, &discard_req).unwrap_err(),
+            Error::TooManySegments { count: 2, max: 1 }
+        );
+
+        // None of the segments was executed.
+        let mut v = vec![0x00; 0x1000];
+        req_exec.inner().rewind().unwrap();
+        req_exec.inner().read_exact(&mut v).unwrap();
+        assert_eq!(v, vec![0x55; 0x1000]);
+
+        // Two segments are fine for write zeroes requests.
+        let wr_zeroes_req = Request::new(
+            RequestType::WriteZeroes,
+            vec![DataDescriptor::new(
+                GuestAddress(0x1000),
+                2 * VIRTIO_BLK_DISCARD_SEGMENT_LEN as u32,
+            )],
+            0,
+            GuestAddress(0x100),
+        );
+        req_exec.execute(&mem, &wr_zeroes_req).unwrap();
+
+        // One segment is fine for discard requests.
+        let discard_req = Request::new(
+            RequestType::Discard,
+            vec![DataDescriptor::new(
+                GuestAddress(0x1000),
+                VIRTIO_BLK_DISCARD_SEGMENT_LEN as u32,
+            )],
+            0,
+            GuestAddress(0x100),
+        );
+        req_exec.execute(&mem, &discard_req).unwrap();
+    }
+
+    #[test]
+    fn test_max_sectors() {
+        let mut harness = BlockDeviceHarness::new(
+            32,
+            (1 << VIRTIO_BLK_F_DISCARD) | (1 << VIRTIO_BLK_F_WRITE_ZEROES),
+        )
+        .configure(|backend| {
+            backend
+                .with_max_discard_sectors(8)
+                .with_max_write_zeroes_sectors(16)
+        });
+        let (mem, req_exec) = harness.split_mut();
+
+        let segment = DiscardWriteZeroes {
+            sector: 0,
+            num_sectors: 9,
+            flags: 0,
+        };
+        mem.write_obj::<DiscardWriteZeroes>(segment, GuestAddress(0x1000))
+            .unwrap();
+
+        let discard_req = Request::new(
+            RequestType::Discard,
+            vec![DataDescriptor::new(
+                GuestAddress(0x1000),
+                VIRTIO_BLK_DISCARD_SEGMENT_LEN as u32,
+            )],
+            0,
+            GuestAddress(0x100),
+        );
+        assert_eq!(
+            req_exec.execute(mem, &discard_req).unwrap_err(),
+            Error::RequestTooLarge {
+                request_sectors: 9,
+                max_sectors: 8
+            }
+        );
+        assert_eq!(
+            req_exec.execute_dry_run(mem, &discard_req).unwrap_err(),
+            Error::RequestTooLarge {
+                request_sectors: 9,
+                max_sectors: 8
+            }
+        );
+
+        // The write zeroes limit is separate.
+        let wr_zeroes_req = Request::new(
+            RequestType::WriteZeroes,
+            vec![DataDescriptor::new(
+                GuestAddress(0x1000),
+                VIRTIO_BLK_DISCARD_SEGMENT_LEN as u32,
+            )],
+            0,
+            GuestAddress(0x100),
+        );
+        req_exec.execute(mem, &wr_zeroes_req).unwrap();
+
+        let segment = DiscardWriteZeroes {
+            sector: 0,
+            num_sectors: 8,
+            flags: 0,
+        };
+        mem.write_obj::<DiscardWriteZeroes>(segment, GuestAddress(0x1000))
+            .unwrap();
+        req_exec.execute(mem, &discard_req).unwrap();
+
+        // A segment over the limit is rejected before any sector is zeroed.
+        mem.write_slice(&[0xAB; 0x200], GuestAddress(0x2000))
+            .unwrap();
+        let out_req = Request::new(
+            RequestType::Out,
+            vec![DataDescriptor::new(GuestAddress(0x2000), 0x200)],
+            0,
+            GuestAddress(0x100),
+        );
+        req_exec.execute(mem, &out_req).unwrap();
+        let segment = DiscardWriteZeroes {
+            sector: 0,
+            num_sectors: 17,
+            flags: 0,
+        };
+        mem.write_obj::<DiscardWriteZeroes>(segment, GuestAddress(0x1000))
+            .unwrap();
+        assert_eq!(
+            req_exec.execute(mem, &wr_zeroes_req).unwrap_err(),
+            Error::RequestTooLarge {
+                request_sectors: 17,
+                max_sectors: 16
+            }
+        );
+        let mut data = [0u8; 0x200];
+        req_exec.read_sector(0, &mut data).unwrap();
+        assert_eq!(data, [0xAB; 0x200]);
+    }
+
+    #[test]
+    fn test_sector_map() {
+        let mut harness = BlockDeviceHarness::with_temp_file(129, 0);
+        let (mem, req_exec) = harness.split_mut();
+        // 129 sectors need 3 words.
+        let map = req_exec.sector_map().unwrap();
+        assert_eq!(map.len(), 129);
+        assert_eq!(map.as_words(), [0, 0, 0]);
+        assert_eq!(map.set_sectors().count(), 0);
+
+        mem.write_slice(&[0x55; 0x400], GuestAddress(0x1000))
+            .unwrap();
+        for sector in [1, 63, 64, 128] {
+            let out_req = Request::new(
+                RequestType::Out,
+                vec![DataDescriptor::new(GuestAddress(0x1000), 0x200)],
+                sector,
+                GuestAddress(0x100),
+            );
+            req_exec.execute(mem, &out_req).unwrap();
+        }
+        // A sector with a single non-zero byte.
+        req_exec
+            .inner()
+            .seek(SeekFrom::Start((100 << SECTOR_SHIFT) + 0x1FF))
+            .unwrap();
+        req_exec.inner().write_all(&[0x01]).unwrap();
+
+        let map = req_exec.sector_map().unwrap();
+        assert_eq!(
+            map.as_words(),
+            [(1 << 1) | (1 << 63), 1 | (1 << (100 - 64)), 1]
+        );
+        assert!(map.is_set(63));
+        assert!(!map.is_set(62));
+        assert!(!map.is_set(129));
+        assert_eq!(map.set_sectors().collect::<Vec<_>>(), [1, 63, 64, 100, 128]);
+    }
+
+    #[test]
+    fn test_zeroes_ranges() {
+        const NON_ZERO_VALUE: u8 = 0x55;
+
+        let mut f = TempFile::new().unwrap().into_file();
+        f.write_all(&[NON_ZERO_VALUE; 0x2000]).unwrap();
+
+        // Neither discard nor write zeroes are negotiated.
+        let mut req_exec = StdIoBackend::new(f, 0).unwrap();
+
+        req_exec.write_zeroes_range(1, 2).unwrap();
+        req_exec.punch_hole_range(8, 8).unwrap();
+
+        let mut v = vec![0x00; 0x2000];
+        req_exec.inner().rewind().unwrap();
+        req_exec.inner().read_exact(&mut v).unwrap();
+        assert_eq!(v[..0x200], [NON_ZERO_VALUE; 0x200]);
+        assert_eq!(v[0x200..0x600], [0x00; 0x400]);
+        assert_eq!(v[0x600..0x1000], [NON_ZERO_VALUE; 0xA00]);
+        assert_eq!(v[0x1000..], [0x00; 0x1000]);
+        // Punching holes doesn't change the file size.
+        assert_eq!(req_exec.inner().metadata().unwrap().len(), 0x2000);
+
+        assert_eq!(
+            req_exec.write_zeroes_range(15, 2).unwrap_err(),
+            Error::InvalidAccess
+        );
+        assert_eq!(
+            req_exec.punch_hole_range(u64::MAX, 1).unwrap_err(),
+            Error::InvalidAccess
+        );
+    }
+
+    #[test]
+    fn test_copy_range() {
+        let mut f = TempFile::new().unwrap().into_file();
+        // Give each of the 512 sectors a different pattern.
+        for sector in 0..512u64 {
+            f.write_all(&[sector as u8; SECTOR_SIZE as usize]).unwrap();
+        }
+        let mut req_exec = StdIoBackend::new(f, 0).unwrap();
+
+        let read_sector = |req_exec: &mut StdIoBackend<File>, sector: u64| {
+            let mut buf = [0u8; SECTOR_SIZE as usize];
+            req_exec.read_sector(sector, &mut buf).unwrap();
+            assert!(buf.iter().all(|&b| b == buf[0]));
+            buf[0]
+        };
+
+        // Copy more sectors than fit in a chunk.
+        req_exec.copy_range(0, 300, 200).unwrap();
+        for sector in 0..200 {
+            assert_eq!(read_sector(&mut req_exec, 300 + sector), sector as u8);
+        }
+
+        // Modifying the original doesn't change the copy.
+        req_exec.write_zeroes_range(0, 200).unwrap();
+        assert_eq!(read_sector(&mut req_exec, 0), 0);
+        assert_eq!(read_sector(&mut req_exec, 301), 1);
+        assert_eq!(read_sector(&mut req_exec, 499), 199);
+
+        // Overlapping ranges, in both directions.
+        req_exec.copy_range(300, 310, 150).unwrap();
+        for sector in 0..150 {
+            assert_eq!(read_sector(&mut req_exec, 310 + sector), sector as u8);
+        }
+        req_exec.copy_range(310, 300, 150).unwrap();
+        for sector in 0..150 {
+            assert_eq!(read_sector(&mut req_exec, 300 + sector), sector as u8);
+        }
+
+        assert_eq!(
+            req_exec.copy_range(0, 400, 113).unwrap_err(),
+            Error::InvalidAccess
+        );
+        assert_eq!(
+            req_exec.copy_range(400, 0, 113).unwrap_err(),
+            Error::InvalidAccess
+        );
+
+        // The same copies are done in the kernel, falling back to the bounce buffer for the
+        // overlapping ranges.
+        let sectors = |req_exec: &mut StdIoBackend<File>, range: Range<u64>| {
+            range
+                .map(|sector| read_sector(req_exec, sector))
+                .collect::<Vec<_>>()
+        };
+        let original = sectors(&mut req_exec, 300..500);
+        req_exec.copy_range_in_kernel(300, 0, 200).unwrap();
+        assert_eq!(sectors(&mut req_exec, 0..200), original);
+        req_exec.write_zeroes_range(300, 200).unwrap();
+        assert_eq!(sectors(&mut req_exec, 0..200), original);
+        req_exec.copy_range_in_kernel(0, 10, 150).unwrap();
+        assert_eq!(sectors(&mut req_exec, 10..160), original[..150]);
+        assert_eq!(
+            req_exec.copy_range_in_kernel(0, 400, 113).unwrap_err(),
+            Error::InvalidAccess
+        );
+    }
+
+    #[test]
+    fn test_barrier() {
+        let f = TempFile::new().unwrap().into_file();
+        f.set_len(0x1000).unwrap();
+
+        // Without VIRTIO_BLK_F_FLUSH, the barrier is a no-op.
+        let mut req_exec = StdIoBackend::new(CountingBackend::new(f), 0).unwrap();
+        req_exec.barrier().unwrap();
+        assert_eq!(req_exec.inner().fsyncs, 0);
+
+        req_exec.features = 1 << VIRTIO_BLK_F_FLUSH;
+        req_exec.barrier().unwrap();
+        req_exec.barrier().unwrap();
+        assert_eq!(req_exec.inner().fsyncs, 2);
+
+        // Requests with the barrier flag are synced before and after they modify the device.
+        let mem = GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let header = |type_| virtio_blk_outhdr {
+            type_: type_ | VIRTIO_BLK_T_BARRIER,
+            ioprio: 0,
+            sector: 0,
+        };
+        let data = vec![DataDescriptor::new(GuestAddress(0x200), 0x200)];
+        let out_req = Request::from_header_and_descriptors(
+            header(VIRTIO_BLK_T_OUT),
+            data.clone(),
+            GuestAddress(0x100),
+        )
+        .unwrap();
+        req_exec.execute(&mem, &out_req).unwrap();
+        assert_eq!(req_exec.inner().fsyncs, 4);
+        let in_req = Request::from_header_and_descriptors(
+            header(VIRTIO_BLK_T_IN),
+            data,
+            GuestAddress(0x100),
+        )
+        .unwrap();
+        assert_eq!(req_exec.execute(&mem, &in_req).unwrap(), 0x200);
+        assert_eq!(req_exec.inner().fsyncs, 5);
+    }
+
+    #[test]
+    fn test_builder() {
+        let f = TempFile::new().unwrap().into_file();
+        f.set_len(0x2000).unwrap();
+
+        let req_exec = StdIoBackendBuilder::new()
+            .inner(f)
+            .features(1 << VIRTIO_BLK_F_FLUSH)
+            .logical_block_size(4096)
+            .serial("serial".to_string())
+            .max_discard_segments(2)
+            .max_write_zeroes_segments(3)
+            .build()
+            .unwrap();
+        assert_eq!(req_exec.num_sectors(), 16);
+        assert_eq!(req_exec.features, 1 << VIRTIO_BLK_F_FLUSH);
+        assert_eq!(req_exec.logical_block_size(), 4096);
+        let mut device_id = [0u8; VIRTIO_BLK_ID_BYTES as usize];
+        device_id[..6].copy_from_slice(b"serial");
+        assert_eq!(req_exec.device_id, Some(device_id));
+        assert_eq!(req_exec.max_discard_seg, 2);
+        assert_eq!(req_exec.max_write_zeroes_seg, 3);
+
+        // `new` uses the default configuration.
+        let req_exec = StdIoBackend::new(req_exec.into_inner(), 0).unwrap();
+        assert_eq!(req_exec.logical_block_size(), SECTOR_SIZE as u32);
+        assert_eq!(req_exec.device_id, None);
+        assert_eq!(req_exec.max_discard_seg, u32::MAX);
+        assert_eq!(req_exec.max_write_zeroes_seg, u32::MAX);
+
+        assert_eq!(
+            StdIoBackendBuilder::<File>::new().build().unwrap_err(),
+            Error::MissingBackend
+        );
+        for size in [0, 256, 1000] {
+            assert_eq!(
+                StdIoBackendBuilder::new()
+                    .inner(TempFile::new().unwrap().into_file())
+                    .logical_block_size(size)
+                    .build()
+                    .unwrap_err(),
+                Error::InvalidBlockSize(size)
+            );
+        }
+        assert_eq!(
+            StdIoBackendBuilder::new()
+                .inner(TempFile::new().unwrap().into_file())
+                .features((1 << VIRTIO_BLK_F_ZONED) | (1 << VIRTIO_BLK_F_RO))
+                .build()
+                .unwrap_err(),
+            Error::Features(FeatureError::ConflictingFeatures {
+                a: VIRTIO_BLK_F_ZONED,
+                b: VIRTIO_BLK_F_RO,
+            })
+        );
+        for serial in [
+            "s".repeat(VIRTIO_BLK_ID_BYTES as usize + 1),
+            "séria".to_string(),
+        ] {
+            assert_eq!(
+                StdIoBackendBuilder::new()
+                    .inner(TempFile::new().unwrap().into_file())
+                    .serial(serial)
+                    .build()
+                    .unwrap_err(),
+                Error::InvalidSerial
+            );
+        }
+        // A serial of exactly VIRTIO_BLK_ID_BYTES bytes has no NUL terminator.
+        let req_exec = StdIoBackendBuilder::new()
+            .inner(TempFile::new().unwrap().into_file())
+            .serial("s".repeat(VIRTIO_BLK_ID_BYTES as usize))
+            .build()
+            .unwrap();
+        assert_eq!(
+            req_exec.device_id,
+            Some([b's'; VIRTIO_BLK_ID_BYTES as usize])
+        );
+    }
+
+    #[test]
+    fn test_execute_queued() {
+        let mut harness = BlockDeviceHarness::new(8, 0);
+        let (mem, req_exec) = harness.split_mut();
+
+        let mut queue = RequestQueue::new();
+        let in_id = queue.submit(Request::new(
+            RequestType::In,
+            vec![DataDescriptor::new(GuestAddress(0x1000), 0x400)],
+            0,
+            GuestAddress(0x100),
+        ));
+        // VIRTIO_BLK_F_FLUSH is not negotiated.
+        let flush_id = queue.submit(Request::new(
+            RequestType::Flush,
+            vec![],
+            0,
+            GuestAddress(0x100),
+        ));
+        assert_eq!(queue.in_flight(), 2);
+
+        assert_eq!(
+            req_exec.execute_queued(mem, &mut queue, in_id).unwrap(),
+            0x400
+        );
+        assert_eq!(queue.in_flight(), 1);
+        assert_eq!(
+            req_exec
+                .execute_queued(mem, &mut queue, flush_id)
+                .unwrap_err(),
+            Error::Unsupported(VIRTIO_BLK_T_FLUSH)
+        );
+        assert_eq!(queue.in_flight(), 0);
+        assert_eq!(
+            req_exec.execute_queued(mem, &mut queue, in_id).unwrap_err(),
+            Error::InvalidRequestId(in_id)
+        );
+    }
+
+    #[test]
+    fn test_execute_vectored() {
+        let mut f = TempFile::new().unwrap().into_file();
+        // Give each of the 16 sectors a different pattern.
+        for sector in 0..16u8 {
+            f.write_all(&[sector + 1; SECTOR_SIZE as usize]).unwrap();
+        }
+        // Two adjacent memory regions, so buffers can cross the boundary between them.
+        let mem = GuestMemoryMmap::<()>::from_ranges(&[
+            (GuestAddress(0), 0x10_0000),
+            (GuestAddress(0x10_0000), 0x10_0000),
+        ])
+        .unwrap();
+        let mut req_exec = StdIoBackend::new(f, 0).unwrap();
+
+        let data = vec![
+            DataDescriptor::new(GuestAddress(0x1000), 0x300),
+            DataDescriptor::new(GuestAddress(0x5000), 0x100),
+            DataDescriptor::new(GuestAddress(0x3000), 0x400),
+        ];
+        let in_req = Request::new(RequestType::In, data.clone(), 2, GuestAddress(0x100));
+        assert_eq!(req_exec.execute_vectored(&mem, &in_req).unwrap(), 0x800);
+        // The position of the backend is the same as after a sequential read.
+        assert_eq!(req_exec.inner_mut().stream_position().unwrap(), 0xC00);
+        let mut vectored = vec![0u8; 0x800];
+        let mut pos = 0;
+        for desc in data.iter() {
+            mem.read_slice(&mut vectored[pos..pos + desc.len_bytes as usize], desc.addr)
+                .unwrap();
+            pos += desc.len_bytes as usize;
+        }
+
+        // Read the same sectors into a single buffer.
+        let in_req = Request::new(
+            RequestType::In,
+            vec![DataDescriptor::new(GuestAddress(0x8000), 0x800)],
+            2,
+            GuestAddress(0x100),
+        );
+        assert_eq!(req_exec.execute_vectored(&mem, &in_req).unwrap(), 0x800);
+        let mut sequential = vec![0u8; 0x800];
+        mem.read_slice(&mut sequential, GuestAddress(0x8000))
+            .unwrap();
+        assert_eq!(vectored, sequential);
+        assert_eq!(sequential[0], 3);
+        assert_eq!(sequential[0x7FF], 6);
+
+        // A buffer that crosses the memory regions falls back to sequential reads.
+        let in_req = Request::new(
+            RequestType::In,
+            vec![
+                DataDescriptor::new(GuestAddress(0xF_FF00), 0x200),
+                DataDescriptor::new(GuestAddress(0x1000), 0x600),
+            ],
+            2,
+            GuestAddress(0x100),
+        );
+        assert_eq!(req_exec.execute_vectored(&mem, &in_req).unwrap(), 0x800);
+        let mut crossing = vec![0u8; 0x800];
+        mem.read_slice(&mut crossing[..0x200], GuestAddress(0xF_FF00))
+            .unwrap();
+        mem.read_slice(&mut crossing[0x200..], GuestAddress(0x1000))
+            .unwrap();
+        assert_eq!(crossing, sequential);
+
+        // The errors are the same as for `execute`.
+        let in_req = Request::new(
+            RequestType::In,
+            vec![
+                DataDescriptor::new(GuestAddress(0x1000), 0x200),
+                DataDescriptor::new(GuestAddress(0x1F_FFF0), 0x200),
+            ],
+            14,
+            GuestAddress(0x100),
+        );
+        assert_eq!(
+            req_exec.execute_vectored(&mem, &in_req).unwrap_err(),
+            req_exec.execute(&mem, &in_req).unwrap_err()
+        );
+        let in_req = Request::new(
+            RequestType::In,
+            vec![
+                DataDescriptor::new(GuestAddress(0x1000), 0x200),
+                DataDescriptor::new(GuestAddress(0x2000), 0x200),
+            ],
+            15,
+            GuestAddress(0x100),
+        );
+        assert_eq!(
+            req_exec.execute_vectored(&mem, &in_req).unwrap_err(),
+            Error::InvalidAccess
+        );
+        assert_eq!(req_exec.current_depth.load(Ordering::SeqCst), 0);
+
+        // The requests are rejected when the queue is full, like for `execute`.
+        let mut req_exec = req_exec.with_max_queue_depth(1);
+        req_exec.current_depth.store(1, Ordering::SeqCst);
+        assert_eq!(
+            req_exec.execute_vectored(&mem, &in_req).unwrap_err(),
+            Error::QueueFull { depth: 1, max: 1 }
+        );
+    }
+
+    #[test]
+    fn test_execute_vectored_write() {
+        let f = TempFile::new().unwrap().into_file();
+        f.set_len(0x2000).unwrap();
+        let mem = GuestMemoryMmap::<()>::from_ranges(&[
+            (GuestAddress(0), 0x10_0000),
+            (GuestAddress(0x10_0000), 0x10_0000),
+        ])
+        .unwrap();
+        mem.write_slice(&[0x11; 0x300], GuestAddress(0x1000))
+            .unwrap();
+        mem.write_slice(&[0x22; 0x100], GuestAddress(0x5000))
+            .unwrap();
+        mem.write_slice(&[0x33; 0x400], GuestAddress(0x3000))
+            .unwrap();
+        let mut req_exec = StdIoBackend::new(CountingBackend::new(f), 0).unwrap();
+
+        let data = vec![
+            DataDescriptor::new(GuestAddress(0x1000), 0x300),
+            DataDescriptor::new(GuestAddress(0x5000), 0x100),
+            DataDescriptor::new(GuestAddress(0x3000), 0x400),
+        ];
+        let out_req = Request::new(RequestType::Out, data.clone(), 1, GuestAddress(0x100));
+        assert_eq!(req_exec.execute(&mem, &out_req).unwrap(), 0);
+        assert_eq!(req_exec.inner().writes, 3);
+
+        let out_req = Request::new(RequestType::Out, data, 6, GuestAddress(0x100));
+        assert_eq!(req_exec.execute_vectored(&mem, &out_req).unwrap(), 0);
+        assert_eq!(req_exec.inner().writes, 4);
+        // The position of the backend is the same as after a sequential write.
+        assert_eq!(req_exec.inner_mut().stream_position().unwrap(), 0x1400);
+
+        // Both writes have the same result.
+        let mut v = vec![0u8; 0x2000];
+        req_exec.inner_mut().file.rewind().unwrap();
+        req_exec.inner_mut().file.read_exact(&mut v).unwrap();
+        let mut expected = [0x11; 0x300].to_vec();
+        expected.extend_from_slice(&[0x22; 0x100]);
+        expected.extend_from_slice(&[0x33; 0x400]);
+        assert_eq!(v[0x200..0xA00], expected[..]);
+        assert_eq!(v[0xC00..0x1400], expected[..]);
+        assert_eq!(v[0xA00..0xC00], [0u8; 0x200]);
+
+        // A single descriptor, or a buffer that crosses the memory regions, are written
+        // sequentially.
+        let out_req = Request::new(
+            RequestType::Out,
+            vec![DataDescriptor::new(GuestAddress(0x1000), 0x200)],
+            0,
+            GuestAddress(0x100),
+        );
+        req_exec.execute_vectored(&mem, &out_req).unwrap();
+        assert_eq!(req_exec.inner().writes, 5);
+        let out_req = Request::new(
+            RequestType::Out,
+            vec![
+                DataDescriptor::new(GuestAddress(0xF_FF00), 0x200),
+                DataDescriptor::new(GuestAddress(0x1000), 0x200),
+            ],
+            0,
+            GuestAddress(0x100),
+        );
+        req_exec.execute_vectored(&mem, &out_req).unwrap();
+        assert_eq!(req_exec.inner().writes, 8);
+    }
+
+    #[test]
+    fn test_is_retryable() {
+        let io_err = |kind| io::Error::from(kind);
+        let mem_err = |kind| GuestMemoryError::IOError(io::Error::from(kind));
+        let backend_err = |kind| VolatileMemoryError::IOError(io::Error::from(kind));
+
+        for kind in [
+            io::ErrorKind::WouldBlock,
+            io::ErrorKind::Interrupted,
+            io::ErrorKind::TimedOut,
+        ] {
+            assert!(Error::DiscardWriteZeroes(io_err(kind)).is_retryable());
+            assert!(Error::Flush(io_err(kind)).is_retryable());
+            assert!(Error::Seek(io_err(kind)).is_retryable());
+            assert!(Error::Read(mem_err(kind), 0).is_retryable());
+            assert!(Error::Write(mem_err(kind)).is_retryable());
+            assert!(Error::Backend(backend_err(kind)).is_retryable());
+        }
+
+        let kind = io::ErrorKind::PermissionDenied;
+        assert!(!Error::DiscardWriteZeroes(io_err(kind)).is_retryable());
+        assert!(!Error::Flush(io_err(kind)).is_retryable());
+        assert!(!Error::Seek(io_err(kind)).is_retryable());
+        assert!(!Error::Read(mem_err(kind), 0).is_retryable());
+        assert!(!Error::Write(mem_err(kind)).is_retryable());
+        assert!(!Error::Backend(backend_err(kind)).is_retryable());
+        assert!(!Error::Backend(VolatileMemoryError::OutOfBounds { addr: 0 }).is_retryable());
+        assert!(!Error::Read(InvalidGuestAddress(GuestAddress(0)), 0).is_retryable());
+        assert!(!Error::Write(InvalidGuestAddress(GuestAddress(0))).is_retryable());
+        assert!(!Error::ReadOnly.is_retryable());
+        assert!(!Error::Unsupported(8).is_retryable());
+        assert!(!Error::InvalidAccess.is_retryable());
+    }
+
+    #[test]
+    fn test_size_max() {
+        let mut harness =
+            BlockDeviceHarness::new(128, 0).configure(|backend| backend.with_size_max(4096));
+        let (mem, req_exec) = harness.split_mut();
+
+        let out_req = Request::new(
+            RequestType::Out,
+            vec![
+                DataDescriptor::new(GuestAddress(0x1000), 0x1000),
+                DataDescriptor::new(GuestAddress(0x4000), 0x2000),
+            ],
+            0,
+            GuestAddress(0x100),
+        );
+        assert_eq!(
+            req_exec.execute(mem, &out_req).unwrap_err(),
+            Error::DescriptorTooLarge {
+                len: 0x2000,
+                max: 0x1000
+            }
+        );
+        assert_eq!(
+            req_exec.execute_dry_run(mem, &out_req).unwrap_err(),
+            Error::DescriptorTooLarge {
+                len: 0x2000,
+                max: 0x1000
+            }
+        );
+
+        // The limit applies to each descriptor, not to the whole request.
+        let in_req = Request::new(
+            RequestType::In,
+            vec![
+                DataDescriptor::new(GuestAddress(0x1000), 0x1000),
+                DataDescriptor::new(GuestAddress(0x4000), 0x1000),
+            ],
+            0,
+            GuestAddress(0x100),
+        );
+        assert_eq!(req_exec.execute(mem, &in_req).unwrap(), 0x2000);
+    }
+
+    #[test]
+    fn test_seg_max() {
+        let mut harness =
+            BlockDeviceHarness::new(128, 0).configure(|backend| backend.with_seg_max(2));
+        let (mem, req_exec) = harness.split_mut();
+
+        let in_req = Request::new(
+            RequestType::In,
+            vec![
+                DataDescriptor::new(GuestAddress(0x1000), 0x200),
+                DataDescriptor::new(GuestAddress(0x2000), 0x200),
+                DataDescriptor::new(GuestAddress(0x3000), 0x200),
+            ],
+            0,
+            GuestAddress(0x100),
+        );
+        assert_eq!(
+            req_exec.execute(mem, &in_req).unwrap_err(),
+            Error::TooManySegments { count: 3, max: 2 }
+        );
+        assert_eq!(
+            req_exec.execute_dry_run(mem, &in_req).unwrap_err(),
+            Error::TooManySegments { count: 3, max: 2 }
+        );
+
+        let in_req = Request::new(
+            RequestType::In,
+            vec![
+                DataDescriptor::new(GuestAddress(0x1000), 0x200),
+                DataDescriptor::new(GuestAddress(0x2000), 0x200),
+            ],
+            0,
+            GuestAddress(0x100),
+        );
+        assert_eq!(req_exec.execute(mem, &in_req).unwrap(), 0x400);
+    }
+
+    #[test]
+    fn test_open() {
+        const NON_ZERO_VALUE: u8 = 0x55;
+
+        let temp_file = TempFile::new().unwrap();
+        temp_file.as_file().set_len(0x1000).unwrap();
+        let mem = GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x1000_0000)]).unwrap();
+        mem.write_slice(&[NON_ZERO_VALUE; 0x200], GuestAddress(0x1000))
+            .unwrap();
+        let out_req = Request::new(
+            RequestType::Out,
+            vec![DataDescriptor::new(GuestAddress(0x1000), 0x200)],
+            1,
+            GuestAddress(0x100),
+        );
+        let in_req = Request::new(
+            RequestType::In,
+            vec![DataDescriptor::new(GuestAddress(0x2000), 0x400)],
+            0,
+            GuestAddress(0x100),
+        );
+
+        let mut req_exec = StdIoBackend::open(temp_file.as_path(), 0).unwrap();
+        assert_eq!(req_exec.num_sectors(), 8);
+        req_exec.execute(&mem, &out_req).unwrap();
+        req_exec.execute(&mem, &in_req).unwrap();
+        let mut v = vec![0u8; 0x400];
+        mem.read_slice(&mut v, GuestAddress(0x2000)).unwrap();
+        assert_eq!(v[..0x200], [0u8; 0x200]);
+        assert_eq!(v[0x200..], [NON_ZERO_VALUE; 0x200]);
+
+        for mut req_exec in [
+            StdIoBackend::open(temp_file.as_path(), 1 << VIRTIO_BLK_F_RO).unwrap(),
+            StdIoBackend::open_readonly(temp_file.as_path()).unwrap(),
+        ] {
+            assert_eq!(req_exec.execute(&mem, &in_req).unwrap(), 0x400);
+            assert_eq!(
+                req_exec.execute(&mem, &out_req).unwrap_err(),
+                Error::ReadOnly
+            );
+            // The file is not writable either.
+            assert!(req_exec.inner_mut().write_all(&[0u8]).is_err());
+        }
+
+        let path = temp_file.as_path().to_path_buf();
+        drop(temp_file);
+        assert!(matches!(
+            StdIoBackend::open(&path, 0).unwrap_err(),
+            Error::Open(e) if e.kind() == io::ErrorKind::NotFound
+        ));
+    }
+
+    #[test]
+    fn test_would_block() {
+        let mut f = TempFile::new().unwrap().into_file();
+        // Give each of the 8 sectors a different pattern.
+        for sector in 0..8u8 {
+            f.write_all(&[sector + 1; SECTOR_SIZE as usize]).unwrap();
+        }
+        let mem = GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x1000_0000)]).unwrap();
+        let backend = BlockingBackend {
+            file: f,
+            budget: 0x250,
+        };
+        let mut req_exec = StdIoBackend::new(backend, 0).unwrap();
+
+        let in_req = Request::new(
+            RequestType::In,
+            vec![
+                DataDescriptor::new(GuestAddress(0x1000), 0x200),
+                DataDescriptor::new(GuestAddress(0x2000), 0x300),
+                DataDescriptor::new(GuestAddress(0x3000), 0x100),
+            ],
+            1,
+            GuestAddress(0x100),
+        );
+        let err = req_exec.execute(&mem, &in_req).unwrap_err();
+        assert!(err.is_retryable());
+        let pending = match err {
+            Error::WouldBlock(pending) => pending,
+            e => panic!("unexpected error: {}", e),
+        };
+        assert_eq!(
+            pending,
+            PendingRequest {
+                transferred_bytes: 0x250,
+                remaining_bytes: 0x3B0
+            }
+        );
+
+        // The transfer restarts with the partially transferred sector.
+        req_exec.inner_mut().budget = 0x100;
+        let pending = match req_exec.resume(&mem, &in_req, &pending).unwrap_err() {
+            Error::WouldBlock(pending) => pending,
+            e => panic!("unexpected error: {}", e),
+        };
+        assert_eq!(
+            pending,
+            PendingRequest {
+                transferred_bytes: 0x300,
+                remaining_bytes: 0x300
+            }
+        );
+        // The progress doesn't go back if the sector can't be transferred again.
+        req_exec.inner_mut().budget = 0x80;
+        let pending = match req_exec.resume(&mem, &in_req, &pending).unwrap_err() {
+            Error::WouldBlock(pending) => pending,
+            e => panic!("unexpected error: {}", e),
+        };
+        assert_eq!(
+            pending,
+            PendingRequest {
+                transferred_bytes: 0x300,
+                remaining_bytes: 0x300
+            }
+        );
+        // Pending requests are not accounted for in the statistics.
+        assert_eq!(req_exec.stats().snapshot().reads, 0);
+
+        req_exec.inner_mut().budget = usize::MAX;
+        assert_eq!(req_exec.resume(&mem, &in_req, &pending).unwrap(), 0x600);
+        assert_eq!(req_exec.stats().snapshot().reads, 1);
+        let mut v = vec![0u8; 0x600];
+        mem.read_slice(&mut v[..0x200], GuestAddress(0x1000))
+            .unwrap();
+        mem.read_slice(&mut v[0x200..0x500], GuestAddress(0x2000))
+            .unwrap();
+        mem.read_slice(&mut v[0x500..], GuestAddress(0x3000))
+            .unwrap();
+        assert_eq!(v[..0x200], [2; 0x200]);
+        assert_eq!(v[0x200..0x400], [3; 0x200]);
+        assert_eq!(v[0x400..], [4; 0x200]);
+
+        // Write the data back, at sector 4.
+        req_exec.inner_mut().budget = 0x300;
+        let out_req = Request::new(
+            RequestType::Out,
+            vec![
+                DataDescriptor::new(GuestAddress(0x1000), 0x200),
+                DataDescriptor::new(GuestAddress(0x2000), 0x300),
+                DataDescriptor::new(GuestAddress(0x3000), 0x100),
+            ],
+            4,
+            GuestAddress(0x100),
+        );
+        let pending = match req_exec.execute(&mem, &out_req).unwrap_err() {
+            Error::WouldBlock(pending) => pending,
+            e => panic!("unexpected error: {}", e),
+        };
+        assert_eq!(
+            pending,
+            PendingRequest {
+                transferred_bytes: 0x300,
+                remaining_bytes: 0x300
+            }
+        );
+        req_exec.inner_mut().budget = usize::MAX;
+        assert_eq!(req_exec.resume(&mem, &out_req, &pending).unwrap(), 0);
+        let mut v = vec![0u8; 0x600];
+        req_exec
+            .inner_mut()
+            .file
+            .seek(SeekFrom::Start(0x800))
+            .unwrap();
+        req_exec.inner_mut().file.read_exact(&mut v).unwrap();
+        assert_eq!(v[..0x200], [2; 0x200]);
+        assert_eq!(v[0x200..0x400], [3; 0x200]);
+        assert_eq!(v[0x400..], [4; 0x200]);
+
+        // The progress has to match the request.
+        assert_eq!(
+            req_exec
+                .resume(
+                    &mem,
+                    &out_req,
+                    &PendingRequest {
+                        transferred_bytes: 0x300,
+                        remaining_bytes: 0x200
+                    }
+                )
+                .unwrap_err(),
+            Error::InvalidDataLength
+        );
+
+        // Resumed requests are validated like new ones.
+        req_exec.inner_mut().budget = 0x250;
+        let pending = match req_exec.execute(&mem, &out_req).unwrap_err() {
+            Error::WouldBlock(pending) => pending,
+            e => panic!("unexpected error: {}", e),
+        };
+        req_exec.add_protected_range(5, 5);
+        assert_eq!(
+            req_exec.resume(&mem, &out_req, &pending).unwrap_err(),
+            Error::ProtectedRange { sector: 5 }
+        );
+
+        // A failed read reports the data transferred by all the executions.
+        let in_req = Request::new(
+            RequestType::In,
+            vec![
+                DataDescriptor::new(GuestAddress(0x1000), 0x400),
+                DataDescriptor::new(GuestAddress(0x2000_0000), 0x200),
+            ],
+            0,
+            GuestAddress(0x100),
+        );
+        req_exec.inner_mut().budget = 0x250;
+        let pending = match req_exec.execute(&mem, &in_req).unwrap_err() {
+            Error::WouldBlock(pending) => pending,
+            e => panic!("unexpected error: {}", e),
+        };
+        req_exec.inner_mut().budget = usize::MAX;
+        assert!(matches!(
+            req_exec.resume(&mem, &in_req, &pending).unwrap_err(),
+            Error::Read(_, 0x400)
+        ));
+    }
+
+    #[test]
+    fn test_execute_with_deadline() {
+        let f = TempFile::new().unwrap().into_file();
         f.set_len(0x1000).unwrap();
+        let mem = GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x1000_0000)]).unwrap();
+        let mut req_exec =
+            StdIoBackend::new(CountingBackend::new(f), 1 << VIRTIO_BLK_F_FLUSH).unwrap();
 
-        // Write buffer of non-zero bytes to offsets 256 and 1024.
-        f.seek(SeekFrom::Start(0x100)).unwrap();
-        f.write_all(&[NON_ZERO_VALUE; 0x200]).unwrap();
-        f.seek(SeekFrom::Start(0x400)).unwrap();
-        f.write_all(&[NON_ZERO_VALUE + 1; 0x80]).unwrap();
+        let out_req = Request::new(
+            RequestType::Out,
+            vec![
+                DataDescriptor::new(GuestAddress(0x1000), 0x200),
+                DataDescriptor::new(GuestAddress(0x2000), 0x200),
+            ],
+            0,
+            GuestAddress(0x100),
+        );
+        let flush_req = Request::new(RequestType::Flush, vec![], 0, GuestAddress(0x100));
+
+        // No I/O is performed if the deadline already passed.
+        let deadline = Instant::now();
+        assert_eq!(
+            req_exec
+                .execute_with_deadline(&mem, &out_req, deadline)
+                .unwrap_err(),
+            Error::DeadlineExceeded
+        );
+        assert_eq!(
+            req_exec
+                .execute_with_deadline(&mem, &flush_req, deadline)
+                .unwrap_err(),
+            Error::DeadlineExceeded
+        );
+        assert_eq!(req_exec.inner().writes, 0);
+        assert_eq!(req_exec.inner().fsyncs, 0);
+        assert_eq!(req_exec.stats().snapshot().errors, 2);
+
+        let deadline = Instant::now() + std::time::Duration::from_secs(3600);
+        assert_eq!(
+            req_exec
+                .execute_with_deadline(&mem, &out_req, deadline)
+                .unwrap(),
+            0
+        );
+        assert_eq!(
+            req_exec
+                .execute_with_deadline(&mem, &flush_req, deadline)
+                .unwrap(),
+            0
+        );
+        assert_eq!(req_exec.inner().writes, 2);
+        assert_eq!(req_exec.inner().fsyncs, 1);
+
+        // The deadline only applies to a single execution.
+        req_exec
+            .execute_with_deadline(&mem, &out_req, Instant::now())
+            .unwrap_err();
+        req_exec.execute(&mem, &out_req).unwrap();
+        assert_eq!(req_exec.inner().writes, 4);
+    }
+
+    #[test]
+    fn test_flush_on_drop() {
+        const NON_ZERO_VALUE: u8 = 0x55;
 
+        let temp_file = TempFile::new().unwrap();
+        temp_file.as_file().set_len(0x1000).unwrap();
         let mem = GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x1000_0000)]).unwrap();
-        let flush_req = Request::new(
-            RequestType::Flush,
-            vec![(GuestAddress(0x100), 0x400)],
-            0,
-            GuestAddress(0x600),
+        mem.write_slice(&[NON_ZERO_VALUE; 0x200], GuestAddress(0x1000))
+            .unwrap();
+        let out_req = Request::new(
+            RequestType::Out,
+            vec![DataDescriptor::new(GuestAddress(0x1000), 0x200)],
+            1,
+            GuestAddress(0x100),
         );
 
-        let mut req_exec = StdIoBackend::new(f, 1 << VIRTIO_BLK_F_RO).unwrap();
+        let mut req_exec = StdIoBackend::open(temp_file.as_path(), 0)
+            .unwrap()
+            .with_flush_on_drop(true);
+        req_exec.execute(&mem, &out_req).unwrap();
+        drop(req_exec);
+
+        let mut v = vec![0u8; 0x400];
+        File::open(temp_file.as_path())
+            .unwrap()
+            .read_exact(&mut v)
+            .unwrap();
+        assert_eq!(v[..0x200], [0u8; 0x200]);
+        assert_eq!(v[0x200..], [NON_ZERO_VALUE; 0x200]);
+
+        // `into_inner` doesn't sync the backing object.
+        let req_exec = StdIoBackend::new(
+            CountingBackend::new(temp_file.as_file().try_clone().unwrap()),
+            0,
+        )
+        .unwrap()
+        .with_flush_on_drop(true);
+        let backend = req_exec.into_inner();
+        assert_eq!(backend.fsyncs, 0);
+    }
+
+    #[test]
+    fn test_protected_range() {
+        let features = (1 << VIRTIO_BLK_F_DISCARD) | (1 << VIRTIO_BLK_F_WRITE_ZEROES);
+        let mut harness = BlockDeviceHarness::new(32, features);
+        let (mem, req_exec) = harness.split_mut();
+        // Protect the MBR and a range in the middle of the disk.
+        req_exec.add_protected_range(16, 19);
+        req_exec.add_protected_range(0, 7);
+
+        let out_req = |sector, len| {
+            Request::new(
+                RequestType::Out,
+                vec![DataDescriptor::new(GuestAddress(0x1000), len)],
+                sector,
+                GuestAddress(0x100),
+            )
+        };
         assert_eq!(
-            req_exec.execute(&mem, &flush_req).unwrap_err(),
-            Error::ReadOnly
+            req_exec.execute(mem, &out_req(0, 0x200)).unwrap_err(),
+            Error::ProtectedRange { sector: 0 }
         );
-        // VIRTIO_BLK_F_FLUSH not negotiated.
-        req_exec.features = 0;
         assert_eq!(
-            req_exec.execute(&mem, &flush_req).unwrap_err(),
-            Error::Unsupported(VIRTIO_BLK_T_FLUSH)
+            req_exec.execute(mem, &out_req(6, 0x400)).unwrap_err(),
+            Error::ProtectedRange { sector: 6 }
         );
-        // Flush should be successful if VIRTIO_BLK_F_FLUSH is supported.
-        req_exec.features = 1 << VIRTIO_BLK_F_FLUSH;
-        assert_eq!(req_exec.execute(&mem, &flush_req).unwrap(), 0x00);
+        assert_eq!(
+            req_exec
+                .execute_dry_run(mem, &out_req(6, 0x400))
+                .unwrap_err(),
+            Error::ProtectedRange { sector: 6 }
+        );
+        // The request overlaps only with the second range.
+        assert_eq!(
+            req_exec.execute(mem, &out_req(12, 0x1000)).unwrap_err(),
+            Error::ProtectedRange { sector: 16 }
+        );
+        assert_eq!(req_exec.execute(mem, &out_req(8, 0x1000)).unwrap(), 0);
+        assert_eq!(req_exec.execute(mem, &out_req(20, 0x200)).unwrap(), 0);
 
+        // Protected sectors can still be read.
         let in_req = Request::new(
             RequestType::In,
-            vec![(GuestAddress(0x100), 0x400), (GuestAddress(0x600), 0x200)],
+            vec![DataDescriptor::new(GuestAddress(0x1000), 0x400)],
             0,
-            GuestAddress(0x900),
+            GuestAddress(0x100),
         );
-        // 0x600 bytes should've been written in memory.
-        assert_eq!(req_exec.execute(&mem, &in_req).unwrap(), 0x600);
-        for addr in 0x100..0x1ff {
-            assert_eq!(mem.read_obj::<u8>(GuestAddress(addr)).unwrap(), 0x00);
-        }
-        for addr in 0x200..0x3ff {
+        assert_eq!(req_exec.execute(mem, &in_req).unwrap(), 0x400);
+
+        for request_type in [RequestType::Discard, RequestType::WriteZeroes] {
+            let segment = DiscardWriteZeroes {
+                sector: 4,
+                num_sectors: 8,
+                flags: 0,
+            };
+            mem.write_obj::<DiscardWriteZeroes>(segment, GuestAddress(0x2000))
+                .unwrap();
+            let request = Request::new(
+                request_type,
+                vec![DataDescriptor::new(
+                    GuestAddress(0x2000),
+                    VIRTIO_BLK_DISCARD_SEGMENT_LEN as u32,
+                )],
+                0,
+                GuestAddress(0x100),
+            );
             assert_eq!(
-                mem.read_obj::<u8>(GuestAddress(addr)).unwrap(),
-                NON_ZERO_VALUE
+                req_exec.execute(mem, &request).unwrap_err(),
+                Error::ProtectedRange { sector: 4 }
             );
         }
-        for addr in 0x400..0x4ff {
-            assert_eq!(mem.read_obj::<u8>(GuestAddress(addr)).unwrap(), 0x00);
+
+        // A protected segment fails the request before any of its segments is executed.
+        mem.write_slice(&[0xAB; 0x400], GuestAddress(0x1000))
+            .unwrap();
+        req_exec.execute(mem, &out_req(8, 0x400)).unwrap();
+        let segments = [
+            DiscardWriteZeroes {
+                sector: 8,
+                num_sectors: 2,
+                flags: 0,
+            },
+            DiscardWriteZeroes {
+                sector: 16,
+                num_sectors: 1,
+                flags: 0,
+            },
+        ];
+        for (i, segment) in segments.into_iter().enumerate() {
+            let addr = GuestAddress(0x2000 + i as u64 * VIRTIO_BLK_DISCARD_SEGMENT_LEN);
+            mem.write_obj(segment, addr).unwrap();
         }
-        for addr in 0x600..0x67f {
-            assert_eq!(
-                mem.read_obj::<u8>(GuestAddress(addr)).unwrap(),
-                NON_ZERO_VALUE + 1
-            );
+        let request = Request::new(
+            RequestType::WriteZeroes,
+            vec![DataDescriptor::new(
+                GuestAddress(0x2000),
+                2 * VIRTIO_BLK_DISCARD_SEGMENT_LEN as u32,
+            )],
+            0,
+            GuestAddress(0x100),
+        );
+        assert_eq!(
+            req_exec.execute(mem, &request).unwrap_err(),
+            Error::ProtectedRange { sector: 16 }
+        );
+        assert_eq!(req_exec.sector_aligned_read(8, 2).unwrap(), [0xAB; 0x400]);
+    }
+
+    #[test]
+    fn test_execute_frozen() {
+        const NON_ZERO_VALUE: u8 = 0x55;
+
+        let mut f = TempFile::new().unwrap().into_file();
+        f.write_all(&[NON_ZERO_VALUE; 0x1000]).unwrap();
+        let request = Arc::new(
+            Request::new(
+                RequestType::In,
+                vec![DataDescriptor::new(GuestAddress(0x1000), 0x200)],
+                1,
+                GuestAddress(0x100),
+            )
+            .freeze(),
+        );
+
+        // Both threads execute the same request, without having mutable access to it.
+        let handles: Vec<_> = (0..2)
+            .map(|_| {
+                let request = request.clone();
+                let f = f.try_clone().unwrap();
+                std::thread::spawn(move || {
+                    let mem =
+                        GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+                    let mut req_exec = StdIoBackend::new(f, 0).unwrap();
+                    assert_eq!(req_exec.execute(&mem, &request).unwrap(), 0x200);
+                    let mut v = vec![0u8; 0x200];
+                    mem.read_slice(&mut v, GuestAddress(0x1000)).unwrap();
+                    v
+                })
+            })
+            .collect();
+        for handle in handles {
+            assert_eq!(handle.join().unwrap(), vec![NON_ZERO_VALUE; 0x200]);
         }
-        for addr in 0x680..0x7ff {
-            assert_eq!(mem.read_obj::<u8>(GuestAddress(addr)).unwrap(), 0x00);
+        assert_eq!(Arc::try_unwrap(request).unwrap().thaw().sector(), 1);
+    }
+
+    #[test]
+    fn test_range_lock() {
+        let temp_file = TempFile::new().unwrap();
+        temp_file.as_file().set_len(0x4000).unwrap();
+        let mem = GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let range_lock = Arc::new(SectorRangeLock::new());
+        let out_req = |sector| {
+            Request::new(
+                RequestType::Out,
+                vec![DataDescriptor::new(GuestAddress(0x1000), 0x1000)],
+                sector,
+                GuestAddress(0x100),
+            )
+        };
+
+        // Another executor is accessing the sectors 8 to 15.
+        let guard = range_lock.lock_shared(8, 16);
+
+        // Requests that don't overlap with the locked range are not blocked.
+        let mut req_exec = StdIoBackend::open(temp_file.as_path(), 0)
+            .unwrap()
+            .with_range_lock(range_lock.clone());
+        assert_eq!(req_exec.execute(&mem, &out_req(0)).unwrap(), 0);
+        let in_req = Request::new(
+            RequestType::In,
+            vec![DataDescriptor::new(GuestAddress(0x1000), 0x1000)],
+            8,
+            GuestAddress(0x100),
+        );
+        assert_eq!(req_exec.execute(&mem, &in_req).unwrap(), 0x1000);
+
+        let done = Arc::new(AtomicBool::new(false));
+        let handle = {
+            let done = done.clone();
+            let mem = mem.clone();
+            std::thread::spawn(move || {
+                req_exec.execute(&mem, &out_req(4)).unwrap();
+                done.store(true, Ordering::SeqCst);
+            })
+        };
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        assert!(!done.load(Ordering::SeqCst));
+        drop(guard);
+        handle.join().unwrap();
+        assert!(done.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_range_lock_padded_and_discard() {
+        let temp_file = TempFile::new().unwrap();
+        temp_file.as_file().set_len(0x4000).unwrap();
+        let mem = GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let range_lock = Arc::new(SectorRangeLock::new());
+        let segment = DiscardWriteZeroes {
+            sector: 16,
+            num_sectors: 2,
+            flags: 0,
+        };
+        mem.write_obj::<DiscardWriteZeroes>(segment, GuestAddress(0x2000))
+            .unwrap();
+        type Operation = Box<dyn FnOnce(&mut StdIoBackend<File>, &GuestMemoryMmap<()>) + Send>;
+        let execute = |request: Request| -> Operation {
+            Box::new(move |req_exec, mem| {
+                req_exec.execute(mem, &request).unwrap();
+            })
+        };
+        let operations: [Operation; 4] = [
+            // A write of the sector 1 rewrites the whole physical block of the sectors 0 to 7.
+            execute(Request::new(
+                RequestType::Out,
+                vec![DataDescriptor::new(GuestAddress(0x1000), 0x200)],
+                1,
+                GuestAddress(0x100),
+            )),
+            // The segment of the sectors 16 and 17 is locked with the rest of their block.
+            execute(Request::new(
+                RequestType::WriteZeroes,
+                vec![DataDescriptor::new(
+                    GuestAddress(0x2000),
+                    VIRTIO_BLK_DISCARD_SEGMENT_LEN as u32,
+                )],
+                0,
+                GuestAddress(0x100),
+            )),
+            // So are the ranges zeroed out or deallocated by the VMM.
+            Box::new(|req_exec, _| req_exec.write_zeroes_range(9, 1).unwrap()),
+            Box::new(|req_exec, _| req_exec.punch_hole_range(20, 1).unwrap()),
+        ];
+        let locked = [(7, 8), (23, 24), (15, 16), (23, 24)];
+
+        for (operation, (start, end)) in operations.into_iter().zip(locked) {
+            let guard = range_lock.lock_shared(start, end);
+            let mut req_exec = StdIoBackendBuilder::new()
+                .inner(TryClone::try_clone(temp_file.as_file()).unwrap())
+                .features(1 << VIRTIO_BLK_F_WRITE_ZEROES)
+                .physical_block_size(0x1000)
+                .build()
+                .unwrap()
+                .with_range_lock(range_lock.clone());
+            let done = Arc::new(AtomicBool::new(false));
+            let handle = {
+                let done = done.clone();
+                let mem = mem.clone();
+                std::thread::spawn(move || {
+                    operation(&mut req_exec, &mem);
+                    done.store(true, Ordering::SeqCst);
+                })
+            };
+            std::thread::sleep(std::time::Duration::from_millis(10));
+            assert!(!done.load(Ordering::SeqCst));
+            drop(guard);
+            handle.join().unwrap();
+            assert!(done.load(Ordering::SeqCst));
         }
+    }
 
-        let mem = GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x1000_0000)]).unwrap();
+    #[test]
+    fn test_data_slices() {
+        let f = TempFile::new().unwrap().into_file();
+        f.set_len(0x1000).unwrap();
+        let mem = GuestMemoryMmap::<()>::from_ranges(&[
+            (GuestAddress(0), 0x10000),
+            (GuestAddress(0x10000), 0x10000),
+        ])
+        .unwrap();
+        let mut req_exec = StdIoBackend::new(f, 0).unwrap();
+
+        mem.write_slice(&[0x11; 0x200], GuestAddress(0x1000))
+            .unwrap();
+        mem.write_slice(&[0x22; 0x200], GuestAddress(0x3000))
+            .unwrap();
         let out_req = Request::new(
             RequestType::Out,
-            vec![(GuestAddress(0x100), 0x400), (GuestAddress(0x800), 0x200)],
-            1,
-            GuestAddress(0x200),
+            vec![
+                DataDescriptor::new(GuestAddress(0x1000), 0x200),
+                DataDescriptor::new(GuestAddress(0x3000), 0x200),
+            ],
+            2,
+            GuestAddress(0x100),
         );
-        // Clear the file.
-        req_exec.inner_mut().write_zeroes_at(0x00, 0x1000).unwrap();
+        req_exec.execute(&mem, &out_req).unwrap();
 
-        mem.write_slice(&[NON_ZERO_VALUE; 0x200], GuestAddress(0x200))
+        let in_req = Request::new(
+            RequestType::In,
+            vec![
+                DataDescriptor::new(GuestAddress(0x5000), 0x100),
+                DataDescriptor::new(GuestAddress(0x7000), 0x300),
+            ],
+            2,
+            GuestAddress(0x100),
+        );
+        assert_eq!(req_exec.execute(&mem, &in_req).unwrap(), 0x400);
+        let mut data = Vec::new();
+        for slice in in_req.data_slices(&mem) {
+            let slice = slice.unwrap();
+            let mut v = vec![0u8; slice.len()];
+            slice.copy_to(&mut v);
+            data.extend(v);
+        }
+        assert_eq!(data[..0x200], [0x11; 0x200]);
+        assert_eq!(data[0x200..], [0x22; 0x200]);
+
+        // The slice of a descriptor that crosses the boundary between memory regions can't be
+        // obtained.
+        let in_req = Request::new(
+            RequestType::In,
+            vec![
+                DataDescriptor::new(GuestAddress(0x5000), 0x200),
+                DataDescriptor::new(GuestAddress(0xFF00), 0x200),
+            ],
+            2,
+            GuestAddress(0x100),
+        );
+        let mut slices = in_req.data_slices(&mem);
+        assert_eq!(slices.next().unwrap().unwrap().len(), 0x200);
+        assert!(slices.next().unwrap().is_err());
+        assert!(slices.next().is_none());
+    }
+
+    #[test]
+    fn test_execute_zoned() {
+        let f = TempFile::new().unwrap().into_file();
+        f.set_len(0x4000).unwrap();
+        let mem = GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        // Four zones of 8 sectors each.
+        let zones: Vec<_> = (0..4)
+            .map(|i| {
+                ZoneDescriptor::new(
+                    VIRTIO_BLK_ZT_SWR as u8,
+                    VIRTIO_BLK_ZS_EMPTY as u8,
+                    i * 8,
+                    8,
+                    i * 8,
+                )
+            })
+            .collect();
+        let backend = ZonedMockBackend {
+            file: f,
+            zones: zones.clone(),
+        };
+        let mut req_exec = StdIoBackend::new(backend, 1 << VIRTIO_BLK_F_ZONED).unwrap();
+
+        // The report is split between two descriptors and has room for two zones only.
+        let zone_report = Request::new(
+            RequestType::ZoneReport,
+            vec![
+                DataDescriptor::new(GuestAddress(0x1000), 0x50),
+                DataDescriptor::new(GuestAddress(0x2000), 0x70),
+            ],
+            10,
+            GuestAddress(0x100),
+        );
+        let report_len = ZoneReportHeader::LEN + 2 * ZoneDescriptor::LEN;
+        assert_eq!(
+            req_exec.execute_zoned(&mem, &zone_report).unwrap(),
+            report_len as u32
+        );
+        let header: ZoneReportHeader = mem.read_obj(GuestAddress(0x1000)).unwrap();
+        assert_eq!(header.nr_zones(), 2);
+        // The first reported zone is split between the two descriptors.
+        let mut report = vec![0u8; report_len];
+        mem.read_slice(&mut report[..0x50], GuestAddress(0x1000))
             .unwrap();
-        mem.write_slice(&[NON_ZERO_VALUE; 0x100], GuestAddress(0x880))
+        mem.read_slice(&mut report[0x50..], GuestAddress(0x2000))
             .unwrap();
-        // We will write in file at sector 1 (offset 0x200) 0x400 bytes from 0x100 guest memory
-        // address and 0x200 bytes from 0x800 address. 0 bytes should've been written in memory.
-        assert_eq!(req_exec.execute(&mem, &out_req).unwrap(), 0x00);
+        assert_eq!(&report[0x40..0x80], zones[1].as_slice());
+        assert_eq!(&report[0x80..], zones[2].as_slice());
+        assert_eq!(req_exec.stats().snapshot().errors, 0);
+        assert_eq!(req_exec.current_depth.load(Ordering::SeqCst), 0);
+
+        // Zone reports are rejected when the queue is full, like the other requests.
+        let mut req_exec = req_exec.with_max_queue_depth(1);
+        req_exec.current_depth.store(1, Ordering::SeqCst);
+        assert_eq!(
+            req_exec.execute_zoned(&mem, &zone_report).unwrap_err(),
+            Error::QueueFull { depth: 1, max: 1 }
+        );
+        req_exec.current_depth.store(0, Ordering::SeqCst);
 
-        req_exec.inner().rewind().unwrap();
-        let mut v = vec![0x00; 0x300];
-        assert_eq!(req_exec.inner().read(&mut v).unwrap(), 0x300);
-        assert_eq!(v, vec![0x00; 0x300]);
+        // The data has to hold at least the report header.
+        let zone_report = Request::new(
+            RequestType::ZoneReport,
+            vec![DataDescriptor::new(GuestAddress(0x1000), 0x20)],
+            0,
+            GuestAddress(0x100),
+        );
+        assert_eq!(
+            req_exec.execute_zoned(&mem, &zone_report).unwrap_err(),
+            Error::InvalidDataLength
+        );
+        assert_eq!(
+            req_exec.execute_dry_run(&mem, &zone_report).unwrap_err(),
+            Error::InvalidDataLength
+        );
+        // Zone reports are not supported without a `ZonedBackend`.
+        let zone_report = Request::new(
+            RequestType::ZoneReport,
+            vec![DataDescriptor::new(GuestAddress(0x1000), 0x1000)],
+            0,
+            GuestAddress(0x100),
+        );
+        assert_eq!(
+            req_exec.execute(&mem, &zone_report).unwrap_err(),
+            Error::Unsupported(VIRTIO_BLK_T_ZONE_REPORT)
+        );
 
-        // We are at offset 0x300.
-        v = vec![0x00; 0x200];
-        assert_eq!(req_exec.inner().read(&mut v).unwrap(), 0x200);
-        assert_eq!(v, vec![NON_ZERO_VALUE; 0x200]);
+        // VIRTIO_BLK_F_ZONED is not negotiated.
+        let backend = ZonedMockBackend {
+            file: TempFile::new().unwrap().into_file(),
+            zones,
+        };
+        let mut req_exec = StdIoBackend::new(backend, 0).unwrap();
+        assert_eq!(
+            req_exec.execute_zoned(&mem, &zone_report).unwrap_err(),
+            Error::Unsupported(VIRTIO_BLK_T_ZONE_REPORT)
+        );
+    }
 
-        // We are at offset 0x500.
-        v = vec![0x00; 0x100];
-        assert_eq!(req_exec.inner().read(&mut v).unwrap(), 0x100);
-        assert_eq!(v, vec![0x00; 0x100]);
+    #[test]
+    fn test_flush_dedup() {
+        let f = TempFile::new().unwrap().into_file();
+        f.set_len(0x1000).unwrap();
+        let mem = GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let features = (1 << VIRTIO_BLK_F_FLUSH) | (1 << VIRTIO_BLK_F_WRITE_ZEROES);
+        let mut req_exec = StdIoBackend::new(CountingBackend::new(f), features).unwrap();
+        let flush_req = Request::new(RequestType::Flush, vec![], 0, GuestAddress(0x100));
+        let out_req = Request::new(
+            RequestType::Out,
+            vec![DataDescriptor::new(GuestAddress(0x1000), 0x200)],
+            1,
+            GuestAddress(0x100),
+        );
 
-        // We are at offset 0x600.
-        v = vec![0x00; 0x80];
-        assert_eq!(req_exec.inner().read(&mut v).unwrap(), 0x80);
-        assert_eq!(v, vec![0x00; 0x80]);
+        // The first flush always syncs, since the data may have been written before.
+        req_exec.execute(&mem, &flush_req).unwrap();
+        req_exec.execute(&mem, &flush_req).unwrap();
+        assert_eq!(req_exec.inner().fsyncs, 1);
 
-        // We are at offset 0x680.
-        v = vec![0x00; 0x100];
-        assert_eq!(req_exec.inner().read(&mut v).unwrap(), 0x100);
-        assert_eq!(v, vec![NON_ZERO_VALUE; 0x100]);
+        req_exec.execute(&mem, &out_req).unwrap();
+        req_exec.execute(&mem, &flush_req).unwrap();
+        req_exec.execute(&mem, &flush_req).unwrap();
+        assert_eq!(req_exec.inner().fsyncs, 2);
 
-        // We are at offset 0x780.
-        v = vec![0x00; 0x80];
-        assert_eq!(req_exec.inner().read(&mut v).unwrap(), 0x80);
-        assert_eq!(v, vec![0x00; 0x80]);
+        // Reads don't need a new flush.
+        let in_req = Request::new(
+            RequestType::In,
+            vec![DataDescriptor::new(GuestAddress(0x1000), 0x200)],
+            0,
+            GuestAddress(0x100),
+        );
+        req_exec.execute(&mem, &in_req).unwrap();
+        req_exec.execute(&mem, &flush_req).unwrap();
+        assert_eq!(req_exec.inner().fsyncs, 2);
 
-        // Writing 512 bytes to the last sector should be successful.
+        // Write zeroes requests and direct accesses to the backing object do.
+        let segment = DiscardWriteZeroes {
+            sector: 0,
+            num_sectors: 1,
+            flags: 0,
+        };
+        mem.write_obj::<DiscardWriteZeroes>(segment, GuestAddress(0x2000))
+            .unwrap();
+        let wr_zeroes_req = Request::new(
+            RequestType::WriteZeroes,
+            vec![DataDescriptor::new(
+                GuestAddress(0x2000),
+                VIRTIO_BLK_DISCARD_SEGMENT_LEN as u32,
+            )],
+            0,
+            GuestAddress(0x100),
+        );
+        req_exec.execute(&mem, &wr_zeroes_req).unwrap();
+        req_exec.execute(&mem, &flush_req).unwrap();
+        assert_eq!(req_exec.inner().fsyncs, 3);
+        req_exec.inner_mut();
+        req_exec.execute(&mem, &flush_req).unwrap();
+        assert_eq!(req_exec.inner().fsyncs, 4);
+    }
+
+    #[test]
+    fn test_flush_dedup_clones() {
+        let f = TempFile::new().unwrap().into_file();
+        f.set_len(0x1000).unwrap();
+        let mem = GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let mut req_exec =
+            StdIoBackend::new(CountingBackend::new(f), 1 << VIRTIO_BLK_F_FLUSH).unwrap();
+        let flush_req = Request::new(RequestType::Flush, vec![], 0, GuestAddress(0x100));
         let out_req = Request::new(
             RequestType::Out,
-            vec![(GuestAddress(0x100), 0x200)],
-            7,
-            GuestAddress(0x200),
+            vec![DataDescriptor::new(GuestAddress(0x1000), 0x200)],
+            1,
+            GuestAddress(0x100),
         );
-        assert!(req_exec.execute(&mem, &out_req).is_ok());
+        req_exec.execute(&mem, &flush_req).unwrap();
+        assert_eq!(req_exec.inner().fsyncs, 1);
+
+        // A write through a clone must be synced by the next flush of the original executor.
+        let mut clone = req_exec.clone_for_queue(1).unwrap();
+        clone.execute(&mem, &out_req).unwrap();
+        req_exec.execute(&mem, &flush_req).unwrap();
+        assert_eq!(req_exec.inner().fsyncs, 2);
+
+        // And the other way around, a flush through the clone syncs the writes of the original.
+        req_exec.execute(&mem, &out_req).unwrap();
+        clone.execute(&mem, &flush_req).unwrap();
+        req_exec.execute(&mem, &flush_req).unwrap();
+        assert_eq!(clone.inner().fsyncs, 1);
+        assert_eq!(req_exec.inner().fsyncs, 2);
+    }
 
-        // Writing 1024 bytes to the last sector should not be successful.
+    #[test]
+    fn test_prefetch() {
+        const NON_ZERO_VALUE: u8 = 0x55;
+
+        let mut f = TempFile::new().unwrap().into_file();
+        f.write_all(&[NON_ZERO_VALUE; 0x8000]).unwrap();
+        let mem = GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let mut req_exec = StdIoBackend::new(f, 0).unwrap().with_prefetch_threshold(8);
+
+        // The buffers don't have to be page aligned.
+        let in_req = Request::new(
+            RequestType::In,
+            vec![
+                DataDescriptor::new(GuestAddress(0x1100), 0x4000),
+                DataDescriptor::new(GuestAddress(0x8000), 0x3000),
+            ],
+            0,
+            GuestAddress(0x100),
+        );
+        assert_eq!(req_exec.execute(&mem, &in_req).unwrap(), 0x7000);
+        let mut v = vec![0u8; 0x4000];
+        mem.read_slice(&mut v, GuestAddress(0x1100)).unwrap();
+        assert_eq!(v, vec![NON_ZERO_VALUE; 0x4000]);
+
+        // Requests below the threshold are not prefetched.
+        let in_req = Request::new(
+            RequestType::In,
+            vec![DataDescriptor::new(GuestAddress(0x20000), 0x200)],
+            0,
+            GuestAddress(0x100),
+        );
+        assert_eq!(req_exec.execute(&mem, &in_req).unwrap(), 0x200);
+    }
+
+    #[test]
+    fn test_write_padded() {
+        use std::os::unix::fs::{FileExt, OpenOptionsExt};
+
+        const NON_ZERO_VALUE: u8 = 0x55;
+
+        let temp_file = TempFile::new().unwrap();
+        temp_file
+            .as_file()
+            .write_all(&[NON_ZERO_VALUE; 0x2000])
+            .unwrap();
+        let f = match OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_DIRECT)
+            .open(temp_file.as_path())
+        {
+            Ok(f) => f,
+            // The file system of the temporary file doesn't support `O_DIRECT`.
+            Err(_) => return,
+        };
+        let mem = GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        mem.write_slice(&[0xAA; 0x200], GuestAddress(0x1000))
+            .unwrap();
+        mem.write_slice(&[0xBB; 0x200], GuestAddress(0x2000))
+            .unwrap();
+        let mut req_exec = StdIoBackendBuilder::new()
+            .inner(f)
+            .physical_block_size(0x1000)
+            .build()
+            .unwrap();
+
+        // A 1024-byte write in the middle of the first block.
+        let out_req = Request::new(
+            RequestType::Out,
+            vec![
+                DataDescriptor::new(GuestAddress(0x1000), 0x200),
+                DataDescriptor::new(GuestAddress(0x2000), 0x200),
+            ],
+            1,
+            GuestAddress(0x100),
+        );
+        req_exec.execute(&mem, &out_req).unwrap();
+        let mut v = vec![0u8; 0x1000];
+        temp_file.as_file().read_exact_at(&mut v, 0).unwrap();
+        assert_eq!(v[..0x200], [NON_ZERO_VALUE; 0x200]);
+        assert_eq!(v[0x200..0x400], [0xAA; 0x200]);
+        assert_eq!(v[0x400..0x600], [0xBB; 0x200]);
+        assert_eq!(v[0x600..], [NON_ZERO_VALUE; 0xA00]);
+
+        // A write across the boundary between the two blocks.
         let out_req = Request::new(
             RequestType::Out,
-            vec![(GuestAddress(0x100), 0x400)],
+            vec![
+                DataDescriptor::new(GuestAddress(0x2000), 0x200),
+                DataDescriptor::new(GuestAddress(0x1000), 0x200),
+            ],
             7,
-            GuestAddress(0x200),
-        );
-        assert_eq!(
-            req_exec.execute(&mem, &out_req).unwrap_err(),
-            Error::InvalidAccess
+            GuestAddress(0x100),
         );
-
-        // Invalid data length for IN/OUT request.
+        req_exec.execute(&mem, &out_req).unwrap();
+        let mut v = vec![0u8; 0x2000];
+        temp_file.as_file().read_exact_at(&mut v, 0).unwrap();
+        assert_eq!(v[0xC00..0xE00], [NON_ZERO_VALUE; 0x200]);
+        assert_eq!(v[0xE00..0x1000], [0xBB; 0x200]);
+        assert_eq!(v[0x1000..0x1200], [0xAA; 0x200]);
+        assert_eq!(v[0x1200..], [NON_ZERO_VALUE; 0xE00]);
+
+        // The partial last block can't be read past the end of the backing file.
+        temp_file.as_file().set_len(0x1800).unwrap();
+        req_exec.num_sectors = 0x1800 / SECTOR_SIZE;
         let out_req = Request::new(
             RequestType::Out,
-            vec![(GuestAddress(0x100), 0x400), (GuestAddress(0x500), 0x100)],
-            2,
-            GuestAddress(0x200),
+            vec![DataDescriptor::new(GuestAddress(0x2000), 0x200)],
+            8,
+            GuestAddress(0x100),
         );
         assert_eq!(
             req_exec.execute(&mem, &out_req).unwrap_err(),
-            Error::InvalidDataLength
-        );
-        let in_req = Request::new(
-            RequestType::In,
-            vec![(GuestAddress(0x100), 0x201)],
-            2,
-            GuestAddress(0x200),
-        );
-        assert_eq!(
-            req_exec.execute(&mem, &in_req).unwrap_err(),
-            Error::InvalidDataLength
+            Error::AlignmentPaddingFailed(io::Error::from(io::ErrorKind::UnexpectedEof))
         );
 
-        // It's ok to have partial data lengths that are not multiple of 512 bytes as long as their
-        // sum is a multiple.
-        let in_req = Request::new(
-            RequestType::In,
-            vec![(GuestAddress(0x100), 0x500), (GuestAddress(0x500), 0x300)],
-            2,
-            GuestAddress(0x200),
-        );
-        assert_eq!(req_exec.execute(&mem, &in_req).unwrap(), 0x800);
+        let builder = StdIoBackendBuilder::new()
+            .inner(TempFile::new().unwrap().into_file())
+            .physical_block_size(0x300);
+        assert_eq!(builder.build().unwrap_err(), Error::InvalidBlockSize(0x300));
+    }
 
-        // Invalid memory address for write operation.
+    #[test]
+    fn test_shrink_to_fit() {
+        let f = TempFile::new().unwrap().into_file();
+        f.set_len(0x2000).unwrap();
+        let mem = GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let mut req_exec = StdIoBackend::new(f, 1 << VIRTIO_BLK_F_DISCARD).unwrap();
+
+        mem.write_slice(&[0x55; 0x2000], GuestAddress(0x1000))
+            .unwrap();
         let out_req = Request::new(
             RequestType::Out,
-            vec![(GuestAddress(0xFFF_FFF0), 0x200)],
-            7,
-            GuestAddress(0x200),
+            vec![DataDescriptor::new(GuestAddress(0x1000), 0x2000)],
+            0,
+            GuestAddress(0x100),
         );
-        assert_eq!(
-            req_exec.execute(&mem, &out_req).unwrap_err(),
-            Error::Write(PartialBuffer {
-                expected: 512,
-                completed: 16
-            })
+        req_exec.execute(&mem, &out_req).unwrap();
+        // The whole disk contains data.
+        assert_eq!(req_exec.shrink_to_fit().unwrap(), 0);
+        assert_eq!(req_exec.num_sectors(), 16);
+
+        // Discard the last 10 sectors.
+        let discard_segment = DiscardWriteZeroes {
+            sector: 6,
+            num_sectors: 10,
+            flags: 0,
+        };
+        mem.write_obj::<DiscardWriteZeroes>(discard_segment, GuestAddress(0x8000))
+            .unwrap();
+        let discard_req = Request::new(
+            RequestType::Discard,
+            vec![DataDescriptor::new(
+                GuestAddress(0x8000),
+                VIRTIO_BLK_DISCARD_SEGMENT_LEN as u32,
+            )],
+            0,
+            GuestAddress(0x100),
         );
+        req_exec.execute(&mem, &discard_req).unwrap();
 
-        // Invalid memory address for read operation.
-        let in_req = Request::new(
-            RequestType::In,
-            vec![(GuestAddress(0xFFF_FFF0), 0x200)],
-            7,
-            GuestAddress(0x200),
-        );
+        assert_eq!(req_exec.shrink_to_fit().unwrap(), 10);
+        assert_eq!(req_exec.num_sectors(), 6);
+        assert_eq!(req_exec.shrink_to_fit().unwrap(), 0);
+
+        // Data between holes of a large sparse file, whose holes are skipped without being read.
+        use std::os::unix::fs::FileExt;
+        let f = TempFile::new().unwrap().into_file();
+        f.set_len(1 << 36).unwrap();
+        f.write_all_at(&[0x55; 0x200], 0x10_0000).unwrap();
+        f.write_all_at(&[0x55; 0x200], 0x100_0000).unwrap();
+        let mut req_exec = StdIoBackend::new(f, 0).unwrap();
         assert_eq!(
-            req_exec.execute(&mem, &in_req).unwrap_err(),
-            Error::Read(
-                PartialBuffer {
-                    expected: 512,
-                    completed: 16
-                },
-                16
-            )
+            req_exec.shrink_to_fit().unwrap(),
+            ((1 << 36) - 0x100_0200) / SECTOR_SIZE
         );
+        assert_eq!(req_exec.num_sectors(), 0x100_0200 / SECTOR_SIZE);
+    }
 
-        // Invalid request type.
-        let invalid_req = Request::new(
-            RequestType::Unsupported(8),
-            vec![(GuestAddress(0x100), 0x200)],
-            7,
-            GuestAddress(0x200),
-        );
+    #[test]
+    fn test_sector_aligned_read() {
+        let mut f = TempFile::new().unwrap().into_file();
+        for sector in 0..8u8 {
+            f.write_all(&[sector; SECTOR_SIZE as usize]).unwrap();
+        }
+        let mut req_exec = StdIoBackend::new(f, 0).unwrap();
+
+        let buf = req_exec.sector_aligned_read(2, 3).unwrap();
+        assert_eq!(buf.len(), 3 * SECTOR_SIZE as usize);
+        for (i, data) in buf.chunks(SECTOR_SIZE as usize).enumerate() {
+            assert_eq!(data, [i as u8 + 2; SECTOR_SIZE as usize]);
+        }
+        assert!(req_exec.sector_aligned_read(8, 0).unwrap().is_empty());
         assert_eq!(
-            req_exec.execute(&mem, &invalid_req).unwrap_err(),
-            Error::Unsupported(8)
+            req_exec.sector_aligned_read(6, 3).unwrap_err(),
+            Error::InvalidAccess
         );
+    }
 
-        // Let's create a file large enough for the request.
+    #[test]
+    fn test_stress_test() {
         let f = TempFile::new().unwrap().into_file();
-        f.set_len(u32::MAX as u64 * 2).unwrap();
-        let mut req_exec = StdIoBackend::new(f, 0).unwrap();
+        f.set_len(0x20000).unwrap();
+        let mut req_exec = StdIoBackend::new(f, 1 << VIRTIO_BLK_F_FLUSH).unwrap();
+
+        let report = req_exec.stress_test(100, 0x1234);
+        assert_eq!(report.passed, 100);
+        assert_eq!(report.failed, 0);
+        assert!(report.errors.is_empty());
+
+        // A backend that doesn't persist the writes fails the test.
+        let mut req_exec = StdIoBackend::new(DeterministicBackend::new(0, 256), 0).unwrap();
+        let report = req_exec.stress_test(100, 0x1234);
+        assert_eq!(report.passed + report.failed, 100);
+        assert!(report.failed > 0);
+        assert!(report
+            .errors
+            .iter()
+            .all(|e| matches!(e, StressError::Mismatch(_))));
+    }
 
-        // Total data length > u32::MAX.
-        let invalid_req = Request::new(
-            RequestType::In,
-            vec![
-                (GuestAddress(0x100), u32::MAX - SECTOR_SIZE as u32 + 1),
-                (GuestAddress(0x1000), SECTOR_SIZE as u32),
-            ],
-            0,
-            GuestAddress(0x200),
-        );
+    #[test]
+    fn test_max_io_sectors() {
+        let f = TempFile::new().unwrap().into_file();
+        f.set_len(0x4000).unwrap();
+        let mem = GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        mem.write_slice(&[0x11; 0x1000], GuestAddress(0x1000))
+            .unwrap();
+        mem.write_slice(&[0x22; 0x1000], GuestAddress(0x2000))
+            .unwrap();
+        let data = vec![DataDescriptor::new(GuestAddress(0x1000), 0x2000)];
+        let out_req = Request::new(RequestType::Out, data, 2, GuestAddress(0x100));
+
+        // Without splitting, the request is rejected.
+        let mut req_exec = StdIoBackend::new(CountingBackend::new(f), 0)
+            .unwrap()
+            .with_max_io_sectors(8, false);
         assert_eq!(
-            req_exec.execute(&mem, &invalid_req).unwrap_err(),
-            Error::InvalidDataLength
+            req_exec.execute(&mem, &out_req).unwrap_err(),
+            Error::RequestTooLarge {
+                request_sectors: 16,
+                max_sectors: 8
+            }
         );
+        assert_eq!(req_exec.inner().writes, 0);
+
+        // With splitting, two 8-sector writes are issued.
+        let mut req_exec = req_exec.with_max_io_sectors(8, true);
+        assert_eq!(req_exec.execute(&mem, &out_req).unwrap(), 0);
+        assert_eq!(req_exec.inner().writes, 2);
+
+        // The data is read back with two sub-requests as well.
+        let data = vec![
+            DataDescriptor::new(GuestAddress(0x4000), 0x600),
+            DataDescriptor::new(GuestAddress(0x4600), 0x1a00),
+        ];
+        let in_req = Request::new(RequestType::In, data, 2, GuestAddress(0x100));
+        assert_eq!(req_exec.execute(&mem, &in_req).unwrap(), 0x2000);
+        let mut v = vec![0u8; 0x2000];
+        mem.read_slice(&mut v, GuestAddress(0x4000)).unwrap();
+        let mut expected = vec![0x11; 0x1000];
+        expected.extend_from_slice(&[0x22; 0x1000]);
+        assert_eq!(v, expected);
     }
 
     #[test]
-    fn test_discard_wr_zeroes_request() {
-        const NON_ZERO_VALUE: u8 = 0x55;
+    fn test_concurrent_execute() {
+        use std::os::unix::fs::FileExt;
 
         let f = TempFile::new().unwrap().into_file();
-        f.set_len(0x1000).unwrap();
-
-        let mem = GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x1000_0000)]).unwrap();
+        f.set_len(0x2000).unwrap();
+        for i in 0..4u8 {
+            f.write_all_at(&[i + 1; 0x200], u64::from(i) * 0x400)
+                .unwrap();
+        }
+        let mem =
+            Arc::new(GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap());
+        let req_exec = StdIoBackend::new(f, 0).unwrap();
+
+        // Four non-overlapping reads.
+        let requests = (0..4)
+            .map(|i| {
+                Request::new(
+                    RequestType::In,
+                    vec![DataDescriptor::new(GuestAddress(0x1000 * (i + 1)), 0x200)],
+                    2 * i,
+                    GuestAddress(0x100),
+                )
+            })
+            .collect();
+        let results = req_exec.concurrent_execute(mem.clone(), requests);
+        assert_eq!(results.len(), 4);
+        for (i, result) in results.into_iter().enumerate() {
+            assert_eq!(result.unwrap(), 0x200);
+            let mut v = vec![0u8; 0x200];
+            mem.read_slice(&mut v, GuestAddress(0x1000 * (i as u64 + 1)))
+                .unwrap();
+            assert_eq!(v, vec![i as u8 + 1; 0x200]);
+        }
+        assert_eq!(req_exec.stats().snapshot().reads, 4);
 
-        let mut req_exec = StdIoBackend::new(
-            f,
-            (1 << VIRTIO_BLK_F_DISCARD) | (1 << VIRTIO_BLK_F_WRITE_ZEROES),
-        )
-        .unwrap();
+        // A read that overlaps a previous write sees its data.
+        mem.write_slice(&[0xaa; 0x200], GuestAddress(0x5000))
+            .unwrap();
+        let requests = vec![
+            Request::new(
+                RequestType::Out,
+                vec![DataDescriptor::new(GuestAddress(0x5000), 0x200)],
+                6,
+                GuestAddress(0x100),
+            ),
+            Request::new(
+                RequestType::In,
+                vec![DataDescriptor::new(GuestAddress(0x6000), 0x400)],
+                5,
+                GuestAddress(0x100),
+            ),
+        ];
+        let results = req_exec.concurrent_execute(mem.clone(), requests);
+        assert_eq!(results[0].as_ref().unwrap(), &0);
+        assert_eq!(results[1].as_ref().unwrap(), &0x400);
+        let mut v = vec![0u8; 0x200];
+        mem.read_slice(&mut v, GuestAddress(0x6200)).unwrap();
+        assert_eq!(v, vec![0xaa; 0x200]);
+
+        assert!(req_exec.independent(
+            &Request::new(RequestType::In, vec![], 0, GuestAddress(0)),
+            &Request::new(RequestType::In, vec![], 0, GuestAddress(0)),
+        ));
+        assert!(!req_exec.independent(
+            &Request::new(RequestType::Flush, vec![], 0, GuestAddress(0)),
+            &Request::new(RequestType::In, vec![], 0, GuestAddress(0)),
+        ));
+    }
 
-        let out_req = Request::new(
-            RequestType::Out,
-            vec![(GuestAddress(0x100), 0x400), (GuestAddress(0x800), 0x200)],
-            1,
-            GuestAddress(0x200),
-        );
+    #[test]
+    fn test_concurrent_execute_physical_block() {
+        use std::os::unix::fs::FileExt;
 
-        mem.write_slice(&[NON_ZERO_VALUE; 0x200], GuestAddress(0x200))
+        let f = TempFile::new().unwrap().into_file();
+        f.set_len(0x2000).unwrap();
+        let mem =
+            Arc::new(GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap());
+        mem.write_slice(&[0x11; 0x200], GuestAddress(0x1000))
             .unwrap();
-        mem.write_slice(&[NON_ZERO_VALUE; 0x100], GuestAddress(0x880))
+        mem.write_slice(&[0x22; 0x200], GuestAddress(0x2000))
             .unwrap();
-        // We will write in file at sector 1 (offset 0x200) 0x400 bytes from 0x100 guest memory
-        // address and 0x200 bytes from 0x800 address. 0 bytes should've been written in memory.
-        assert_eq!(req_exec.execute(&mem, &out_req).unwrap(), 0x00);
+        let req_exec = StdIoBackendBuilder::new()
+            .inner(f.try_clone().unwrap())
+            .physical_block_size(0x1000)
+            .build()
+            .unwrap();
+        let out_req = |addr, sector| {
+            Request::new(
+                RequestType::Out,
+                vec![DataDescriptor::new(GuestAddress(addr), 0x200)],
+                sector,
+                GuestAddress(0x100),
+            )
+        };
 
-        // Let's write some more bytes to the file.
-        mem.write_slice(&[NON_ZERO_VALUE + 1; 0x600], GuestAddress(0x3100))
+        // Two writes to different sectors of the same physical block are serialised, since each
+        // one rewrites the whole block.
+        let requests = vec![out_req(0x1000, 1), out_req(0x2000, 6)];
+        assert!(!req_exec.independent(&requests[0], &requests[1]));
+        assert!(req_exec.independent(&requests[0], &out_req(0x2000, 8)));
+        for result in req_exec.concurrent_execute(mem, requests) {
+            assert_eq!(result.unwrap(), 0);
+        }
+        let mut v = vec![0u8; 0x200];
+        f.read_exact_at(&mut v, 0x200).unwrap();
+        assert_eq!(v, vec![0x11; 0x200]);
+        f.read_exact_at(&mut v, 0xC00).unwrap();
+        assert_eq!(v, vec![0x22; 0x200]);
+    }
+
+    #[test]
+    fn test_concurrent_execute_workers() {
+        let f = TempFile::new().unwrap().into_file();
+        f.set_len(0x20000).unwrap();
+        let mem =
+            Arc::new(GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap());
+        let backend = CountingBackend::new(f);
+        let clones = backend.clones.clone();
+        let req_exec = StdIoBackend::new(backend, 1 << VIRTIO_BLK_F_FLUSH).unwrap();
+
+        // Two large groups of reads, separated by a flush.
+        let in_req = |sector| {
+            Request::new(
+                RequestType::In,
+                vec![DataDescriptor::new(GuestAddress(0x1000), 0x200)],
+                sector,
+                GuestAddress(0x100),
+            )
+        };
+        let mut requests: Vec<_> = (0..200).map(in_req).collect();
+        requests.push(Request::new(
+            RequestType::Flush,
+            vec![],
+            0,
+            GuestAddress(0x100),
+        ));
+        requests.extend((0..200).map(in_req));
+        let results = req_exec.concurrent_execute(mem, requests);
+        assert_eq!(results.len(), 401);
+        assert!(results
+            .iter()
+            .enumerate()
+            .all(|(i, result)| result.as_ref().unwrap() == if i == 200 { &0 } else { &0x200 }));
+
+        // The backend is cloned at most once per thread, and the clones are reused.
+        let max_workers = thread::available_parallelism().map_or(1, |n| n.get());
+        assert!(clones.load(Ordering::SeqCst) <= max_workers);
+    }
+
+    #[test]
+    fn test_capacity() {
+        let f = TempFile::new().unwrap().into_file();
+        f.set_len(0x30_0000 + 0x300).unwrap();
+        let req_exec = StdIoBackend::new(f, 0).unwrap();
+
+        // The partial sector at the end is not part of the capacity.
+        assert_eq!(req_exec.capacity_bytes(), req_exec.num_sectors() * 512);
+        assert_eq!(req_exec.capacity_bytes(), 0x30_0200);
+        assert_eq!(
+            req_exec.capacity_mib(),
+            req_exec.capacity_bytes() as f64 / (1024.0 * 1024.0)
+        );
+        assert!(req_exec.capacity_mib() > 3.0 && req_exec.capacity_mib() < 3.001);
+        assert_eq!(
+            req_exec.disk_metadata(),
+            DiskMetadata {
+                capacity_bytes: 0x30_0200,
+                num_sectors: 0x1801,
+                logical_block_size: 512,
+            }
+        );
+    }
+
+    #[test]
+    fn test_interrupt_coalescing() {
+        let f = TempFile::new().unwrap().into_file();
+        f.set_len(0x1000).unwrap();
+        let mem = GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let in_req = Request::new(
+            RequestType::In,
+            vec![DataDescriptor::new(GuestAddress(0x1000), 0x200)],
+            0,
+            GuestAddress(0x100),
+        );
+
+        // Without coalescing, every completion fires an interrupt.
+        let mut req_exec = StdIoBackend::new(f, 0).unwrap();
+        let (result, action) = req_exec.execute_coalesced(&mem, &in_req);
+        assert_eq!(result.unwrap(), 0x200);
+        assert!(action.fire_interrupt);
+
+        // Five completions fire a single interrupt.
+        let mut req_exec = req_exec.with_interrupt_coalescing(5, 3_600_000_000);
+        let interrupts = (0..5)
+            .filter(|_| req_exec.execute_coalesced(&mem, &in_req).1.fire_interrupt)
+            .count();
+        assert_eq!(interrupts, 1);
+        assert!(!req_exec.poll_interrupt().fire_interrupt);
+
+        // Failed requests are completions as well.
+        let invalid_req = Request::new(
+            RequestType::In,
+            vec![DataDescriptor::new(GuestAddress(0x1000), 0x200)],
+            8,
+            GuestAddress(0x100),
+        );
+        let (result, action) = req_exec.execute_coalesced(&mem, &invalid_req);
+        assert_eq!(result.unwrap_err(), Error::InvalidAccess);
+        assert!(!action.fire_interrupt);
+
+        // The pending completions are signaled once the latency expires.
+        let mut req_exec = req_exec.with_interrupt_coalescing(5, 1000);
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        assert!(req_exec.poll_interrupt().fire_interrupt);
+        assert!(!req_exec.poll_interrupt().fire_interrupt);
+    }
+
+    #[test]
+    fn test_clone_for_queue() {
+        let f = TempFile::new().unwrap().into_file();
+        f.set_len(0x2000).unwrap();
+        let mem = GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        mem.write_slice(&[0x66; 0x200], GuestAddress(0x1000))
             .unwrap();
+        let req_exec = StdIoBackend::new(f, 0).unwrap();
 
-        // Write at offset 0x600 in file, 800 bytes: the first 100 bytes = 0, the next 600 bytes =
-        // = NON_ZERO_VALUE + 1 and the last 100 bytes = 0; and then at offset 0x600 + 0x800 =
-        // = 0xE00, which is the last sector, 200 bytes = NON_ZERO_VALUE.
+        let mut queue0 = req_exec.clone_for_queue(0).unwrap();
+        let mut queue1 = req_exec.clone_for_queue(1).unwrap();
         let out_req = Request::new(
             RequestType::Out,
-            vec![(GuestAddress(0x3000), 0x800), (GuestAddress(0x200), 0x200)],
+            vec![DataDescriptor::new(GuestAddress(0x1000), 0x200)],
             3,
-            GuestAddress(0x200),
+            GuestAddress(0x100),
         );
-        assert!(req_exec.execute(&mem, &out_req).is_ok());
-
-        // Test write zeroes request.
-        let wr_zeroes_1 = DiscardWriteZeroes {
-            sector: 2,
-            num_sectors: 2,
-            flags: 0,
-        };
-        mem.write_obj::<DiscardWriteZeroes>(wr_zeroes_1, GuestAddress(0x1000))
-            .unwrap();
-        let wr_zeroes_2 = DiscardWriteZeroes {
-            sector: 5,
-            num_sectors: 1,
-            flags: 0,
-        };
-        mem.write_obj::<DiscardWriteZeroes>(wr_zeroes_2, GuestAddress(0x4000))
-            .unwrap();
-
-        let wr_zeroes_req = Request::new(
-            RequestType::WriteZeroes,
-            vec![
-                (GuestAddress(0x1000), DiscardWriteZeroes::LEN as u32),
-                (GuestAddress(0x4000), DiscardWriteZeroes::LEN as u32),
-            ],
+        let in_req = Request::new(
+            RequestType::In,
+            vec![DataDescriptor::new(GuestAddress(0x2000), 0x400)],
             2,
-            GuestAddress(0x2000),
+            GuestAddress(0x100),
         );
+        queue0.execute(&mem, &out_req).unwrap();
+        // The position of the other clone is not affected by the write.
+        queue1.inner_mut().seek(SeekFrom::Start(0x100)).unwrap();
+        queue0.inner_mut().seek(SeekFrom::Start(0x300)).unwrap();
+        assert_eq!(queue1.inner_mut().stream_position().unwrap(), 0x100);
+        // The clones access the same data.
+        assert_eq!(queue1.execute(&mem, &in_req).unwrap(), 0x400);
+        let mut v = vec![0u8; 0x200];
+        mem.read_slice(&mut v, GuestAddress(0x2200)).unwrap();
+        assert_eq!(v, vec![0x66; 0x200]);
+
+        // The statistics are independent.
+        let stats0 = queue0.stats().snapshot();
+        assert_eq!(
+            (stats0.queue_id, stats0.writes, stats0.reads),
+            (Some(0), 1, 0)
+        );
+        let stats1 = queue1.stats().snapshot();
+        assert_eq!(
+            (stats1.queue_id, stats1.writes, stats1.reads),
+            (Some(1), 0, 1)
+        );
+        assert_eq!(req_exec.stats().snapshot(), Default::default());
+    }
 
-        // 0 bytes should've been written in memory.
-        assert_eq!(req_exec.execute(&mem, &wr_zeroes_req).unwrap(), 0x00);
-
-        req_exec.inner().rewind().unwrap();
-        let mut v = vec![0x00; 0x300];
-        assert_eq!(req_exec.inner().read(&mut v).unwrap(), 0x300);
-        assert_eq!(v, vec![0x00; 0x300]);
+    #[test]
+    fn test_journal() {
+        use std::os::unix::fs::FileExt;
 
-        // We are at offset 0x300.
-        v = vec![0x00; 0x100];
-        assert_eq!(req_exec.inner().read(&mut v).unwrap(), 0x100);
-        assert_eq!(v, vec![NON_ZERO_VALUE; 0x100]);
+        let f = TempFile::new().unwrap().into_file();
+        f.set_len(0x2000).unwrap();
+        let journal = TempFile::new().unwrap().into_file();
+        let mem = GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        mem.write_slice(&[0x11; 0x400], GuestAddress(0x1000))
+            .unwrap();
+        mem.write_slice(&[0x22; 0x400], GuestAddress(0x2000))
+            .unwrap();
+        let first_req = Request::new(
+            RequestType::Out,
+            vec![DataDescriptor::new(GuestAddress(0x1000), 0x400)],
+            0,
+            GuestAddress(0x100),
+        );
+        let second_req = Request::new(
+            RequestType::Out,
+            vec![DataDescriptor::new(GuestAddress(0x2000), 0x400)],
+            4,
+            GuestAddress(0x100),
+        );
 
-        // We are at offset 0x400 -> 0x400 bytes should've been zeroed out.
-        v = vec![0x00; 0x400];
-        assert_eq!(req_exec.inner().read(&mut v).unwrap(), 0x400);
-        assert_eq!(v, vec![0x00; 0x400]);
+        // The second write "crashes" after transferring only part of its data.
+        let backend = BlockingBackend {
+            file: f.try_clone().unwrap(),
+            budget: 0x600,
+        };
+        let mut req_exec = StdIoBackend::new(backend, 1 << VIRTIO_BLK_F_FLUSH)
+            .unwrap()
+            .with_journal(journal.try_clone().unwrap());
+        req_exec.execute(&mem, &first_req).unwrap();
+        // The write is committed only once it is flushed.
+        let mut records = vec![0u8; journal.metadata().unwrap().len() as usize];
+        journal.read_exact_at(&mut records, 0).unwrap();
+        assert_eq!(journal::uncommitted_entries(&records[..]).unwrap().len(), 1);
+        let flush_req = Request::new(RequestType::Flush, vec![], 0, GuestAddress(0x100));
+        req_exec.execute(&mem, &flush_req).unwrap();
+        assert!(matches!(
+            req_exec.execute(&mem, &second_req).unwrap_err(),
+            Error::WouldBlock(_)
+        ));
+        drop(req_exec);
 
-        // We are at offset 0x800.
-        v = vec![0x00; 0x200];
-        assert_eq!(req_exec.inner().read(&mut v).unwrap(), 0x200);
-        assert_eq!(v, vec![NON_ZERO_VALUE + 1; 0x200]);
+        let mut v = vec![0u8; 0x400];
+        f.read_exact_at(&mut v, 0x800).unwrap();
+        assert_ne!(v, vec![0x22; 0x400]);
 
-        // We are at offset 0xA00 -> 0x200 bytes should've been zeroed out.
-        v = vec![0x00; 0x200];
-        assert_eq!(req_exec.inner().read(&mut v).unwrap(), 0x200);
-        assert_eq!(v, vec![0; 0x200]);
+        // Only the uncommitted write is replayed.
+        let mut req_exec = StdIoBackend::new(f, 0).unwrap();
+        let mut journal = journal;
+        journal.rewind().unwrap();
+        assert_eq!(req_exec.replay_journal(journal).unwrap(), 1);
+        req_exec.inner_mut().read_exact_at(&mut v, 0x800).unwrap();
+        assert_eq!(v, vec![0x22; 0x400]);
+        req_exec.inner_mut().read_exact_at(&mut v, 0).unwrap();
+        assert_eq!(v, vec![0x11; 0x400]);
+
+        // A corrupted journal is not replayed.
+        assert_eq!(
+            req_exec.replay_journal([7u8; 9].as_slice()).unwrap_err(),
+            Error::Journal(io::Error::from(io::ErrorKind::InvalidData))
+        );
+    }
 
-        // We are at offset 0xC00.
-        v = vec![0x00; 0x100];
-        assert_eq!(req_exec.inner().read(&mut v).unwrap(), 0x100);
-        assert_eq!(v, vec![NON_ZERO_VALUE + 1; 0x100]);
+    #[test]
+    fn test_journal_commits() {
+        use crate::journal::{JournalData, JournalEntry, JournalRecord};
+        use std::os::unix::fs::FileExt;
 
-        // We are at offset 0xD00.
-        v = vec![0x00; 0x100];
-        assert_eq!(req_exec.inner().read(&mut v).unwrap(), 0x100);
-        assert_eq!(v, vec![0; 0x100]);
+        let mem = GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let out_req = Request::new(
+            RequestType::Out,
+            vec![DataDescriptor::new(GuestAddress(0x1000), 0x200)],
+            0,
+            GuestAddress(0x100),
+        );
+        let read_records = |journal: &File| {
+            let mut records = vec![0u8; journal.metadata().unwrap().len() as usize];
+            journal.read_exact_at(&mut records, 0).unwrap();
+            records
+        };
 
-        // We are at offset 0xE00.
-        v = vec![0x00; 0x200];
-        assert_eq!(req_exec.inner().read(&mut v).unwrap(), 0x200);
-        assert_eq!(v, vec![NON_ZERO_VALUE; 0x200]);
+        // Writes that are never synced are committed as soon as they complete, including the
+        // ones that don't come from requests.
+        for (features, flush_policy) in [
+            (0, FlushPolicy::OnFeature),
+            (1 << VIRTIO_BLK_F_FLUSH, FlushPolicy::Never),
+        ] {
+            let f = TempFile::new().unwrap().into_file();
+            f.set_len(0x2000).unwrap();
+            let journal = TempFile::new().unwrap().into_file();
+            let mut req_exec = StdIoBackend::new(f, features)
+                .unwrap()
+                .with_flush_policy(flush_policy)
+                .with_journal(journal.try_clone().unwrap());
+            req_exec.execute(&mem, &out_req).unwrap();
+            req_exec.write_at(1, &[0xAB; 0x200]).unwrap();
+            req_exec.write_same(2, 2, &[0xCD; 0x200]).unwrap();
+            req_exec.copy_range(0, 4, 2).unwrap();
+            req_exec.write_same(6, 1, &[0; 0x200]).unwrap();
+            req_exec.punch_hole_range(7, 1).unwrap();
+            assert_eq!(
+                journal::read_journal(&read_records(&journal)[..]).unwrap(),
+                (vec![], Some(5))
+            );
+        }
 
-        // Test discard request.
-        let discard_req = DiscardWriteZeroes {
-            sector: 7,
-            num_sectors: 1,
-            flags: 0,
+        // The replayed writes are committed, and the new entries continue after the replayed
+        // journal.
+        let f = TempFile::new().unwrap().into_file();
+        f.set_len(0x2000).unwrap();
+        let journal = TempFile::new().unwrap().into_file();
+        let entry = JournalEntry {
+            lsn: 3,
+            sector: 1,
+            data: JournalData::Write(vec![0xAB; 0x200]),
         };
-        mem.write_obj::<DiscardWriteZeroes>(discard_req, GuestAddress(0x1000))
+        JournalRecord::Entry(entry).write_to(&mut &journal).unwrap();
+        JournalRecord::Commit { lsn: 7 }
+            .write_to(&mut &journal)
+            .unwrap();
+        let mut req_exec = StdIoBackend::new(f, 1 << VIRTIO_BLK_F_FLUSH)
+            .unwrap()
+            .with_journal(journal.try_clone().unwrap());
+        assert_eq!(
+            req_exec
+                .replay_journal(&read_records(&journal)[..])
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            journal::read_journal(&read_records(&journal)[..]).unwrap(),
+            (vec![], Some(7))
+        );
+        req_exec.execute(&mem, &out_req).unwrap();
+        let (entries, _) = journal::read_journal(&read_records(&journal)[..]).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].lsn, 8);
+
+        // The entries are replayed in order, so zeroed sectors are not written again.
+        let mut records = Vec::new();
+        for (lsn, data) in [
+            (0, JournalData::Write(vec![0xAB; 0x400])),
+            (1, JournalData::Zeroes(1)),
+        ] {
+            JournalRecord::Entry(JournalEntry {
+                lsn,
+                sector: 2,
+                data,
+            })
+            .write_to(&mut records)
             .unwrap();
+        }
+        assert_eq!(req_exec.replay_journal(&records[..]).unwrap(), 2);
+        let data = req_exec.sector_aligned_read(2, 2).unwrap();
+        assert_eq!(data[..0x200], [0; 0x200]);
+        assert_eq!(data[0x200..], [0xAB; 0x200]);
+    }
 
-        let discard_req = Request::new(
-            RequestType::Discard,
-            vec![(GuestAddress(0x1000), DiscardWriteZeroes::LEN as u32)],
-            7,
-            GuestAddress(0x2000),
+    #[test]
+    fn test_journal_clones() {
+        use crate::journal::JournalRecord;
+
+        let journal = TempFile::new().unwrap().into_file();
+        let out_req = Request::new(
+            RequestType::Out,
+            vec![DataDescriptor::new(GuestAddress(0x1000), 0x400)],
+            0,
+            GuestAddress(0x100),
         );
+        let flush_req = Request::new(RequestType::Flush, vec![], 0, GuestAddress(0x100));
+
+        let mut harness = BlockDeviceHarness::with_temp_file(16, 1 << VIRTIO_BLK_F_FLUSH)
+            .configure(|backend| backend.with_journal(journal.try_clone().unwrap()));
+        let (mem, req_exec) = harness.split_mut();
+
+        // The writes of the clones are logged to the same journal, with their own LSNs, and
+        // are committed by a flush of any of the executors.
+        let mut first = req_exec.clone_for_queue(1).unwrap();
+        let mut second = req_exec.clone_for_queue(2).unwrap();
+        first.execute(mem, &out_req).unwrap();
+        second.execute(mem, &out_req).unwrap();
+        req_exec.execute(mem, &flush_req).unwrap();
+
+        let mut journal = journal;
+        journal.rewind().unwrap();
+        let mut records = Vec::new();
+        while let Some(record) = JournalRecord::read_from(&mut journal).unwrap() {
+            records.push(match record {
+                JournalRecord::Entry(entry) => (true, entry.lsn),
+                JournalRecord::Commit { lsn } => (false, lsn),
+                JournalRecord::Reset { .. } => unreachable!(),
+            });
+        }
+        assert_eq!(records, vec![(true, 0), (true, 1), (false, 0), (false, 1)]);
+    }
 
-        // 0 bytes should've been written in memory.
-        assert_eq!(req_exec.execute(&mem, &discard_req).unwrap(), 0x00);
+    #[test]
+    fn test_execute_ordered() {
+        let f = TempFile::new().unwrap().into_file();
+        f.set_len(0x1000).unwrap();
+        let mem = GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        mem.write_slice(&[0xaa; 0x200], GuestAddress(0x1000))
+            .unwrap();
+        mem.write_slice(&[0xbb; 0x200], GuestAddress(0x2000))
+            .unwrap();
+        let mut req_exec = StdIoBackend::new(CountingBackend::new(f), 0).unwrap();
+
+        let request = |request_type, addr| {
+            Request::new(
+                request_type,
+                vec![DataDescriptor::new(GuestAddress(addr), 0x200)],
+                1,
+                GuestAddress(0x100),
+            )
+        };
+        let first = [request(RequestType::Out, 0x1000)];
+        // The second group reads the data of the first one before overwriting it.
+        let second = [
+            request(RequestType::In, 0x3000),
+            request(RequestType::Out, 0x2000),
+        ];
+        let third = [request(RequestType::In, 0x4000)];
+        let results = req_exec.execute_ordered(&mem, &[&first, &second, &third]);
+
+        let results: Vec<Vec<u32>> = results
+            .into_iter()
+            .map(|group| group.into_iter().map(Result::unwrap).collect())
+            .collect();
+        assert_eq!(results, [vec![0], vec![0x200, 0], vec![0x200]]);
+        // The backing object was synced between the groups only.
+        assert_eq!(req_exec.inner().fsyncs, 2);
+
+        let mut v = vec![0u8; 0x200];
+        mem.read_slice(&mut v, GuestAddress(0x3000)).unwrap();
+        assert_eq!(v, vec![0xaa; 0x200]);
+        mem.read_slice(&mut v, GuestAddress(0x4000)).unwrap();
+        assert_eq!(v, vec![0xbb; 0x200]);
+        assert!(req_exec.execute_ordered(&mem, &[]).is_empty());
+    }
 
-        req_exec.inner().seek(SeekFrom::Start(0xE00)).unwrap();
-        let mut v = vec![0x00; 0x200];
-        assert_eq!(req_exec.inner().read(&mut v).unwrap(), 0x200);
-        assert_eq!(v, vec![0x00; 0x200]);
+    #[test]
+    fn test_write_same() {
+        let f = TempFile::new().unwrap().into_file();
+        f.set_len(0x40000).unwrap();
+        let mut req_exec = StdIoBackend::new(f, 0).unwrap();
 
-        // Even though we punched a hole at the end of the file, the file size should remain the
-        // same since FALLOC_FL_PUNCH_HOLE is used with FALLOC_FL_KEEP_SIZE.
-        assert_eq!(req_exec.inner().metadata().unwrap().len(), 0x1000);
+        let mut pattern = [0u8; SECTOR_SIZE as usize];
+        pattern
+            .iter_mut()
+            .enumerate()
+            .for_each(|(i, b)| *b = i as u8 ^ 0xA5);
+        // More sectors than fit in a single chunk.
+        req_exec.write_same(4, 300, &pattern).unwrap();
+
+        let mut rng = crate::rand::SplitMix64::new(7);
+        for _ in 0..16 {
+            let sector = 4 + rng.next_u64() % 300;
+            assert_eq!(req_exec.sector_aligned_read(sector, 1).unwrap(), pattern);
+        }
+        assert_eq!(req_exec.sector_aligned_read(3, 1).unwrap(), [0u8; 0x200]);
+        assert_eq!(req_exec.sector_aligned_read(304, 1).unwrap(), [0u8; 0x200]);
 
-        // Test that write zeroes request with unmap bit set is okay.
-        let wr_zeroes_req = DiscardWriteZeroes {
-            sector: 4,
-            num_sectors: 1,
-            flags: 0x0001,
-        };
-        mem.write_obj::<DiscardWriteZeroes>(wr_zeroes_req, GuestAddress(0x1000))
-            .unwrap();
+        // A zero pattern zeroes the sectors out.
+        req_exec.write_same(10, 2, &[0u8; 0x200]).unwrap();
+        assert_eq!(req_exec.sector_aligned_read(9, 1).unwrap(), pattern);
+        assert_eq!(req_exec.sector_aligned_read(10, 2).unwrap(), [0u8; 0x400]);
+        assert_eq!(req_exec.sector_aligned_read(12, 1).unwrap(), pattern);
 
-        let wr_zeroes_req = Request::new(
-            RequestType::WriteZeroes,
-            vec![(GuestAddress(0x1000), DiscardWriteZeroes::LEN as u32)],
-            7,
-            GuestAddress(0x2000),
+        assert_eq!(
+            req_exec.write_same(500, 13, &pattern).unwrap_err(),
+            Error::InvalidAccess
         );
+    }
 
-        req_exec.inner().seek(SeekFrom::Start(0x800)).unwrap();
-        let mut v = vec![0x00; 0x200];
-        assert_eq!(req_exec.inner().read(&mut v).unwrap(), 0x200);
-        // Data is != 0 before the write zeroes request.
-        assert_eq!(v, vec![NON_ZERO_VALUE + 1; 0x200]);
-        // Let's write some data in the file right before and after the fourth sector to confirm
-        // that those regions won't be zeroed out.
-        // After the fourth sector:
-        let v = vec![NON_ZERO_VALUE + 2; 0x200];
-        assert_eq!(req_exec.inner().write(&v).unwrap(), 0x200);
-        // Before the fourth sector:
-        req_exec.inner().seek(SeekFrom::Start(0x600)).unwrap();
-        assert_eq!(req_exec.inner().write(&v).unwrap(), 0x200);
+    #[test]
+    fn test_into_io_error() {
+        let io_err = || io::Error::from(io::ErrorKind::PermissionDenied);
+        let io_errors = [
+            Error::AlignmentPaddingFailed(io_err()),
+            Error::Backend(VolatileMemoryError::IOError(io_err())),
+            Error::DiscardWriteZeroes(io_err()),
+            Error::Flush(io_err()),
+            Error::Read(GuestMemoryError::IOError(io_err()), 0),
+            Error::Seek(io_err()),
+            Error::Write(GuestMemoryError::IOError(io_err())),
+            Error::ZoneReport(io_err()),
+        ];
+        for err in io_errors {
+            let message = err.to_string();
+            assert_eq!(
+                err.into_io_error().unwrap().kind(),
+                io::ErrorKind::PermissionDenied,
+                "{}",
+                message
+            );
+        }
+        let err: io::Error = Error::Flush(io_err()).into();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+
+        let other_errors = [
+            Error::DeadlineExceeded,
+            Error::InvalidAccess,
+            Error::InvalidFlags,
+            Error::Overflow,
+            Error::ReadOnly,
+            Error::Unsupported(0xFF),
+            Error::Write(GuestMemoryError::InvalidBackendAddress),
+        ];
+        for err in other_errors {
+            let message = err.to_string();
+            assert!(err.into_io_error().is_none(), "{}", message);
+        }
+        let err: io::Error = Error::ReadOnly.into();
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+        assert_eq!(err.to_string(), Error::ReadOnly.to_string());
+        assert_eq!(
+            err.into_inner()
+                .unwrap()
+                .downcast::<Error>()
+                .unwrap()
+                .as_ref(),
+            &Error::ReadOnly
+        );
 
-        // 0 bytes should've been written in memory.
-        assert_eq!(req_exec.execute(&mem, &wr_zeroes_req).unwrap(), 0x00);
+        let pending = PendingRequest {
+            transferred_bytes: 0,
+            remaining_bytes: 0x200,
+        };
+        let err: io::Error = Error::WouldBlock(pending).into();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+    }
 
-        req_exec.inner().seek(SeekFrom::Start(0x600)).unwrap();
-        let mut v = vec![0x00; 0x200];
-        assert_eq!(req_exec.inner().read(&mut v).unwrap(), 0x200);
-        assert_eq!(v, vec![NON_ZERO_VALUE + 2; 0x200]);
-        assert_eq!(req_exec.inner().read(&mut v).unwrap(), 0x200);
-        assert_eq!(v, vec![0; 0x200]);
-        assert_eq!(req_exec.inner().read(&mut v).unwrap(), 0x200);
-        assert_eq!(v, vec![NON_ZERO_VALUE + 2; 0x200]);
+    #[test]
+    fn test_copy_to() {
+        let mut data = vec![0u8; 300 * 0x200];
+        crate::rand::SplitMix64::new(3).fill_bytes(&mut data);
+        let mut src_file = TempFile::new().unwrap().into_file();
+        src_file.write_all(&data).unwrap();
+        let mut src = StdIoBackend::new(src_file, 0).unwrap();
+
+        let dst_file = TempFile::new().unwrap().into_file();
+        dst_file.set_len(0x40000).unwrap();
+        let mut dst = StdIoBackend::new(dst_file, 0).unwrap();
+
+        let mut calls = Vec::new();
+        let mut progress = |copied, total| calls.push((copied, total));
+        assert_eq!(
+            src.copy_to(&mut dst, Some(&mut progress)).unwrap(),
+            300 * 0x200
+        );
+        assert_eq!(calls, [(128, 300), (256, 300), (300, 300)]);
+        assert_eq!(dst.sector_aligned_read(0, 300).unwrap(), data);
+        assert_eq!(dst.sector_aligned_read(300, 1).unwrap(), [0u8; 0x200]);
+
+        // The destination is too small.
+        let small_file = TempFile::new().unwrap().into_file();
+        small_file.set_len(0x200).unwrap();
+        let mut small = StdIoBackend::new(small_file, 0).unwrap();
+        assert_eq!(
+            src.copy_to(&mut small, None).unwrap_err(),
+            Error::InvalidAccess
+        );
+    }
 
-        // VIRTIO_BLK_F_DISCARD not negotiated.
-        req_exec.features = 0;
+    #[test]
+    fn test_copy_to_journal() {
+        use crate::journal::{JournalData, JournalEntry};
+        use std::os::unix::fs::FileExt;
+
+        let mut data = vec![0u8; 200 * 0x200];
+        crate::rand::SplitMix64::new(5).fill_bytes(&mut data);
+        let mut src = StdIoBackend::from_vec(data.clone(), 0).unwrap();
+        let journal = TempFile::new().unwrap().into_file();
+        let range_lock = Arc::new(SectorRangeLock::new());
+        let mut dst = StdIoBackend::from_vec(vec![0u8; 200 * 0x200], 1 << VIRTIO_BLK_F_FLUSH)
+            .unwrap()
+            .with_range_lock(range_lock.clone())
+            .with_journal(journal.try_clone().unwrap());
+
+        // The copy waits for the locked sectors of the destination.
+        let guard = range_lock.lock_shared(150, 151);
+        let done = Arc::new(AtomicBool::new(false));
+        let handle = {
+            let done = done.clone();
+            std::thread::spawn(move || {
+                src.copy_to(&mut dst, None).unwrap();
+                done.store(true, Ordering::SeqCst);
+                dst
+            })
+        };
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        assert!(!done.load(Ordering::SeqCst));
+        drop(guard);
+        let mut dst = handle.join().unwrap();
+        assert_eq!(dst.sector_aligned_read(0, 200).unwrap(), data);
+
+        // Every chunk is logged, and stays uncommitted until the destination is flushed.
+        let mut records = vec![0u8; journal.metadata().unwrap().len() as usize];
+        journal.read_exact_at(&mut records, 0).unwrap();
         assert_eq!(
-            req_exec.execute(&mem, &discard_req).unwrap_err(),
-            Error::Unsupported(VIRTIO_BLK_T_DISCARD)
+            journal::uncommitted_entries(&records[..]).unwrap(),
+            [
+                JournalEntry {
+                    lsn: 0,
+                    sector: 0,
+                    data: JournalData::Write(data[..128 * 0x200].to_vec()),
+                },
+                JournalEntry {
+                    lsn: 1,
+                    sector: 128,
+                    data: JournalData::Write(data[128 * 0x200..].to_vec()),
+                },
+            ]
         );
-        req_exec.features = (1 << VIRTIO_BLK_F_DISCARD) | (1 << VIRTIO_BLK_F_WRITE_ZEROES);
+    }
 
-        // Test discard request with invalid data length.
-        let discard_req = Request::new(
-            RequestType::Discard,
+    #[test]
+    fn test_read_ahead() {
+        let mut f = TempFile::new().unwrap().into_file();
+        for sector in 0..16u8 {
+            f.write_all(&[sector + 1; SECTOR_SIZE as usize]).unwrap();
+        }
+        let mem = GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let backend = BlockingBackend {
+            file: f,
+            budget: 0x2200,
+        };
+        let mut req_exec = StdIoBackend::new(backend, 0)
+            .unwrap()
+            .with_read_ahead_sectors(4);
+        let in_req =
+            |sector, data| Request::new(RequestType::In, data, sector, GuestAddress(0x100));
+        let cached = |req_exec: &StdIoBackend<BlockingBackend>| {
+            let mut sectors = req_exec
+                .read_ahead_cache
+                .lock()
+                .unwrap()
+                .keys()
+                .copied()
+                .collect::<Vec<_>>();
+            sectors.sort_unstable();
+            sectors
+        };
+
+        // The first read isn't known to be sequential, so nothing is read ahead.
+        let first = in_req(0, vec![DataDescriptor::new(GuestAddress(0x1000), 0x200)]);
+        assert_eq!(req_exec.execute(&mem, &first).unwrap(), 0x200);
+        assert_eq!(req_exec.inner().budget, 0x2000);
+        assert!(cached(&req_exec).is_empty());
+
+        // The read that continues it misses the cache and reads the next 4 sectors ahead.
+        let second = in_req(
+            1,
             vec![
-                (GuestAddress(0x5000), DiscardWriteZeroes::LEN as u32 / 2),
-                (GuestAddress(0x1000), DiscardWriteZeroes::LEN as u32 / 2),
+                DataDescriptor::new(GuestAddress(0x2000), 0x300),
+                DataDescriptor::new(GuestAddress(0x3000), 0x100),
             ],
-            7,
-            GuestAddress(0x2000),
-        );
-        assert_eq!(
-            req_exec.execute(&mem, &discard_req).unwrap_err(),
-            Error::InvalidDataLength
         );
+        assert_eq!(req_exec.execute(&mem, &second).unwrap(), 0x400);
+        assert_eq!(req_exec.inner().budget, 0x1400);
+        assert_eq!(cached(&req_exec), [3, 4, 5, 6]);
+        let mut v = vec![0u8; 0x300];
+        mem.read_slice(&mut v, GuestAddress(0x2000)).unwrap();
+        assert_eq!(v[..0x200], [2; 0x200]);
+        assert_eq!(v[0x200..], [3; 0x100]);
+        mem.read_slice(&mut v[..0x100], GuestAddress(0x3000))
+            .unwrap();
+        assert_eq!(v[..0x100], [3; 0x100]);
+
+        // The sequential reads are served from the cache.
+        let third = in_req(3, vec![DataDescriptor::new(GuestAddress(0x4000), 0x400)]);
+        assert_eq!(req_exec.execute(&mem, &third).unwrap(), 0x400);
+        assert_eq!(req_exec.inner().budget, 0x1400);
+        let mut buf = vec![0u8; 0x800];
+        mem.read_slice(&mut buf[..0x400], GuestAddress(0x4000))
+            .unwrap();
+        assert_eq!(buf[..0x200], [4; 0x200]);
+        assert_eq!(buf[0x200..0x400], [5; 0x200]);
+
+        // A hit that reaches the end of the cached sectors starts the next window.
+        let fourth = in_req(5, vec![DataDescriptor::new(GuestAddress(0x4000), 0x400)]);
+        assert_eq!(req_exec.execute(&mem, &fourth).unwrap(), 0x400);
+        assert_eq!(req_exec.inner().budget, 0xC00);
+        assert_eq!(cached(&req_exec), [7, 8, 9, 10]);
+        let fifth = in_req(7, vec![DataDescriptor::new(GuestAddress(0x4000), 0x800)]);
+        assert_eq!(req_exec.execute(&mem, &fifth).unwrap(), 0x800);
+        assert_eq!(req_exec.inner().budget, 0x400);
+        assert_eq!(cached(&req_exec), [11, 12, 13, 14]);
+        mem.read_slice(&mut buf, GuestAddress(0x4000)).unwrap();
+        for (sector, data) in buf.chunks_exact(SECTOR_SIZE as usize).enumerate() {
+            assert!(data.iter().all(|&b| b == sector as u8 + 8));
+        }
 
-        let discard_req = Request::new(
-            RequestType::Discard,
-            vec![(GuestAddress(0x1000), DiscardWriteZeroes::LEN as u32 - 1)],
-            7,
-            GuestAddress(0x2000),
+        // Random reads need the backend, and don't trigger read-ahead.
+        let random = in_req(2, vec![DataDescriptor::new(GuestAddress(0x4000), 0x200)]);
+        assert_eq!(req_exec.execute(&mem, &random).unwrap(), 0x200);
+        assert_eq!(req_exec.inner().budget, 0x200);
+        assert_eq!(cached(&req_exec), [11, 12, 13, 14]);
+        req_exec.inner_mut().budget = 0;
+        assert!(req_exec.execute(&mem, &first).unwrap_err().is_retryable());
+
+        // Writes invalidate the cache.
+        req_exec.inner_mut().budget = 0x1000;
+        mem.write_slice(&[0xAA; 0x200], GuestAddress(0x5000))
+            .unwrap();
+        let out_req = Request::new(
+            RequestType::Out,
+            vec![DataDescriptor::new(GuestAddress(0x5000), 0x200)],
+            2,
+            GuestAddress(0x100),
         );
+        req_exec.execute(&mem, &out_req).unwrap();
+        req_exec.read_ahead(1);
+        assert!(!req_exec.read_ahead_cache.lock().unwrap().is_empty());
+        req_exec.execute(&mem, &out_req).unwrap();
+        assert!(req_exec.read_ahead_cache.lock().unwrap().is_empty());
+        let budget = req_exec.inner().budget;
+        assert_eq!(req_exec.execute(&mem, &second).unwrap(), 0x400);
+        mem.read_slice(&mut v[..0x100], GuestAddress(0x2200))
+            .unwrap();
+        assert_eq!(v[..0x100], [0xAA; 0x100]);
+        assert!(req_exec.inner().budget < budget);
+
+        // Sectors read ahead while a write is in progress are dropped once it completes.
+        req_exec.inner_mut().budget = 0x1000;
+        let written = req_exec.start_write();
+        req_exec.read_ahead(1);
+        assert!(!req_exec.read_ahead_cache.lock().unwrap().is_empty());
+        drop(written);
+        assert!(req_exec.read_ahead_cache.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_diff_from() {
+        use std::os::unix::fs::FileExt;
+
+        let mut data = vec![0u8; 300 * 0x200];
+        crate::rand::SplitMix64::new(5).fill_bytes(&mut data);
+        let mut f = TempFile::new().unwrap().into_file();
+        f.write_all(&data).unwrap();
+        let mut req_exec = StdIoBackend::new(f, 0).unwrap();
+        let mut copy_file = TempFile::new().unwrap().into_file();
+        copy_file.write_all(&data).unwrap();
+        let mut copy = StdIoBackend::new(copy_file, 0).unwrap();
+        assert!(req_exec.diff_from(&mut copy).unwrap().is_empty());
+
+        // Sectors in different chunks, with a single byte changed in one of them.
+        copy.write_same(0, 1, &[0xAA; 0x200]).unwrap();
+        copy.write_same(130, 2, &[0xBB; 0x200]).unwrap();
+        copy.inner()
+            .write_all_at(&[!data[299 * 0x200 + 7]], 299 * 0x200 + 7)
+            .unwrap();
+        assert_eq!(req_exec.diff_from(&mut copy).unwrap(), [0, 130, 131, 299]);
+        assert_eq!(copy.diff_from(&mut req_exec).unwrap(), [0, 130, 131, 299]);
+
+        let small_file = TempFile::new().unwrap().into_file();
+        small_file.set_len(0x200).unwrap();
+        let mut small = StdIoBackend::new(small_file, 0).unwrap();
         assert_eq!(
-            req_exec.execute(&mem, &discard_req).unwrap_err(),
-            Error::InvalidDataLength
+            req_exec.diff_from(&mut small).unwrap_err(),
+            Error::InvalidAccess
         );
 
-        // Test discard request with an invalid memory access that would cause an overflow.
-        let discard_req = Request::new(
-            RequestType::Discard,
-            vec![(
-                GuestAddress(u64::MAX - DiscardWriteZeroes::LEN),
-                2 * DiscardWriteZeroes::LEN as u32,
-            )],
-            7,
-            GuestAddress(0x2000),
-        );
-        assert_eq!(
-            req_exec.execute(&mem, &discard_req).unwrap_err(),
-            Error::Overflow
+        // A read error of either device stops the comparison.
+        let backend = BlockingBackend {
+            file: copy.inner().try_clone().unwrap(),
+            budget: 0x18000,
+        };
+        let mut failing = StdIoBackend::new(backend, 0).unwrap();
+        assert!(matches!(
+            req_exec.diff_from(&mut failing).unwrap_err(),
+            Error::Backend(VolatileMemoryError::IOError(_))
+        ));
+    }
+
+    #[test]
+    fn test_poll_timeout() {
+        let mem = GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let (backend, mut write_end) = PipeBackend::new();
+        let fd = backend.as_raw_fd();
+        let mut req_exec = StdIoBackend::new(backend, 0)
+            .unwrap()
+            .with_poll_timeout(Duration::from_millis(20));
+        let in_req = Request::new(
+            RequestType::In,
+            vec![DataDescriptor::new(GuestAddress(0x1000), 0x200)],
+            0,
+            GuestAddress(0x100),
         );
 
-        // Test discard request with invalid sectors.
-        let discard_req = DiscardWriteZeroes {
-            sector: 7,
-            num_sectors: 2,
-            flags: 0,
+        // The pipe is empty, so reading from it would block.
+        let err = req_exec.execute(&mem, &in_req).unwrap_err();
+        assert_eq!(err, Error::PollTimeout { fd });
+        assert!(err.is_retryable());
+        assert_eq!(req_exec.stats().snapshot().errors, 1);
+
+        write_end.write_all(&[0xAB; 0x200]).unwrap();
+        assert_eq!(req_exec.execute(&mem, &in_req).unwrap(), 0x200);
+        let mut v = vec![0u8; 0x200];
+        mem.read_slice(&mut v, GuestAddress(0x1000)).unwrap();
+        assert_eq!(v, vec![0xAB; 0x200]);
+    }
+
+    #[test]
+    fn test_write_audit_hook() {
+        use std::sync::atomic::AtomicUsize;
+
+        let f = TempFile::new().unwrap().into_file();
+        f.set_len(0x1000).unwrap();
+        let mem = GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let calls = Arc::new(AtomicUsize::new(0));
+        let hook_calls = calls.clone();
+        let mut req_exec = StdIoBackend::new(f, 0)
+            .unwrap()
+            .with_write_audit_hook(Box::new(move |request| {
+                assert!(request.is_write_modifying());
+                hook_calls.fetch_add(1, Ordering::SeqCst);
+            }));
+        let request = |request_type, sector| {
+            Request::new(
+                request_type,
+                vec![DataDescriptor::new(GuestAddress(0x1000), 0x200)],
+                sector,
+                GuestAddress(0x100),
+            )
         };
-        mem.write_obj::<DiscardWriteZeroes>(discard_req, GuestAddress(0x1000))
+
+        req_exec
+            .execute(&mem, &request(RequestType::In, 0))
+            .unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+        req_exec
+            .execute(&mem, &request(RequestType::Out, 0))
             .unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        // Failed attempts are reported as well.
+        req_exec
+            .execute(&mem, &request(RequestType::Out, 8))
+            .unwrap_err();
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        assert!(!request(RequestType::Flush, 0).is_write_modifying());
+        assert!(!request(RequestType::GetDeviceID, 0).is_write_modifying());
+        assert!(request(RequestType::Discard, 0).is_write_modifying());
+        assert!(request(RequestType::WriteZeroes, 0).is_write_modifying());
+    }
 
-        let discard_req = Request::new(
-            RequestType::Discard,
-            vec![(GuestAddress(0x1000), DiscardWriteZeroes::LEN as u32)],
-            7,
-            GuestAddress(0x2000),
+    #[test]
+    fn test_read_write_at() {
+        let f = TempFile::new().unwrap().into_file();
+        f.set_len(0x2000).unwrap();
+        let mut req_exec = StdIoBackend::new(f.try_clone().unwrap(), 0).unwrap();
+        let position = req_exec.inner_mut().stream_position().unwrap();
+        for sector in 0..16u8 {
+            assert_eq!(
+                req_exec
+                    .write_at(u64::from(sector), &[sector; 0x200])
+                    .unwrap(),
+                0x200
+            );
+        }
+        // The position of the backend is not changed.
+        assert_eq!(req_exec.inner_mut().stream_position().unwrap(), position);
+
+        // The two readers share the file position, which positional reads don't use.
+        let readers = (0..2u8).map(|i| {
+            let mut reader = StdIoBackend::new(f.try_clone().unwrap(), 0).unwrap();
+            thread::spawn(move || {
+                let mut buf = [0u8; 0x400];
+                for _ in 0..1000 {
+                    let sector = u64::from(i) * 8 + 2;
+                    assert_eq!(reader.read_at(sector, &mut buf).unwrap(), 0x400);
+                    assert_eq!(buf[..0x200], [i * 8 + 2; 0x200]);
+                    assert_eq!(buf[0x200..], [i * 8 + 3; 0x200]);
+                }
+            })
+        });
+        readers
+            .collect::<Vec<_>>()
+            .into_iter()
+            .for_each(|reader| reader.join().unwrap());
+
+        let mut buf = [0u8; 0x300];
+        assert_eq!(
+            req_exec.read_at(0, &mut buf).unwrap_err(),
+            Error::InvalidDataLength
         );
         assert_eq!(
-            req_exec.execute(&mem, &discard_req).unwrap_err(),
+            req_exec.write_at(16, &[0u8; 0x200]).unwrap_err(),
             Error::InvalidAccess
         );
+    }
 
-        // Test discard request with invalid flags (unmap bit set).
-        let discard_req = DiscardWriteZeroes {
-            sector: 3,
-            num_sectors: 2,
-            flags: 0x0001,
-        };
-        mem.write_obj::<DiscardWriteZeroes>(discard_req, GuestAddress(0x1000))
+    #[test]
+    fn test_integrity_scan() {
+        use crate::backend::{BackendLayer, DelegatingBackend};
+        use std::cell::RefCell;
+
+        // A layer that fails the reads of the given sector.
+        struct BadSector(u64);
+
+        impl<B: Backend> BackendLayer<B> for BadSector {
+            fn read_volatile<S: BitmapSlice>(
+                &mut self,
+                inner: &mut B,
+                buf: &mut VolatileSlice<S>,
+            ) -> result::Result<usize, VolatileMemoryError> {
+                let start = inner
+                    .stream_position()
+                    .map_err(VolatileMemoryError::IOError)?;
+                let bad = self.0 * SECTOR_SIZE;
+                if start <= bad && bad < start + buf.len() as u64 {
+                    return Err(VolatileMemoryError::IOError(io::Error::from_raw_os_error(
+                        libc::EIO,
+                    )));
+                }
+                inner.read_volatile(buf)
+            }
+        }
+
+        let f = TempFile::new().unwrap().into_file();
+        f.set_len(300 * SECTOR_SIZE).unwrap();
+        let mut req_exec = StdIoBackend::new(f.try_clone().unwrap(), 0).unwrap();
+        let progress = RefCell::new(Vec::new());
+        let report = req_exec
+            .integrity_scan(|scanned, total| progress.borrow_mut().push((scanned, total)))
             .unwrap();
+        assert!(report.is_clean());
+        assert!(report.io_errors.is_empty());
+        assert_eq!(progress.into_inner(), [(128, 300), (256, 300), (300, 300)]);
+
+        let mut req_exec = StdIoBackend::new(DelegatingBackend::new(f, BadSector(130)), 0).unwrap();
+        let report = req_exec.integrity_scan(|_, _| {}).unwrap();
+        assert!(!report.is_clean());
+        assert_eq!(report.bad_sectors, [130]);
+        assert_eq!(report.io_errors.len(), 1);
+        assert_eq!(report.io_errors[0].0, 130);
+        assert_eq!(report.io_errors[0].1.raw_os_error(), Some(libc::EIO));
+    }
 
-        let discard_req = Request::new(
-            RequestType::Discard,
-            vec![(GuestAddress(0x1000), DiscardWriteZeroes::LEN as u32)],
-            7,
-            GuestAddress(0x2000),
+    #[test]
+    fn test_prefault_backing_file() {
+        let mut f = TempFile::new().unwrap().into_file();
+        f.write_all(&[0xAB; 0x10000]).unwrap();
+        let req_exec = StdIoBackend::new(f, 0).unwrap();
+
+        req_exec.prefault_backing_file(None).unwrap();
+        req_exec.prefault_backing_file(Some(3..=9)).unwrap();
+        req_exec.prefault_backing_file(Some(127..=127)).unwrap();
+        #[allow(clippy::reversed_empty_ranges)]
+        req_exec.prefault_backing_file(Some(9..=3)).unwrap();
+        #[cfg(target_os = "linux")]
+        req_exec.populate_read(0x1100, 0x200).unwrap();
+
+        assert_eq!(
+            req_exec.prefault_backing_file(Some(100..=128)).unwrap_err(),
+            Error::InvalidAccess
         );
         assert_eq!(
-            req_exec.execute(&mem, &discard_req).unwrap_err(),
-            Error::InvalidFlags
+            req_exec
+                .prefault_backing_file(Some(0..=u64::MAX))
+                .unwrap_err(),
+            Error::InvalidAccess
         );
+    }
+
+    #[test]
+    fn test_last_error() {
+        let mut harness = BlockDeviceHarness::with_temp_file(8, 0);
+        let (mem, req_exec) = harness.split_mut();
+        assert_eq!(req_exec.last_error(), None);
+        let clone = req_exec.clone_for_queue(1).unwrap();
+
+        let request = |request_type, sector| {
+            Request::new(
+                request_type,
+                vec![DataDescriptor::new(GuestAddress(0x1000), 0x200)],
+                sector,
+                GuestAddress(0x100),
+            )
+        };
+        let err = req_exec
+            .execute(mem, &request(RequestType::In, 8))
+            .unwrap_err();
+        assert_eq!(req_exec.last_error(), Some(err.to_string()));
+
+        // Successful requests don't clear the error.
+        req_exec.execute(mem, &request(RequestType::In, 0)).unwrap();
+        assert_eq!(req_exec.last_error(), Some(err.to_string()));
+
+        let err = req_exec
+            .execute(mem, &request(RequestType::Unsupported(0xFF), 0))
+            .unwrap_err();
+        assert_eq!(err, Error::Unsupported(0xFF));
+        assert_eq!(req_exec.last_error(), Some(err.to_string()));
+
+        // The executor and its clones share the last error.
+        assert_eq!(clone.last_error(), Some(err.to_string()));
+        let mut clone = clone;
+        let err = clone
+            .execute(mem, &request(RequestType::Out, 8))
+            .unwrap_err();
+        assert_eq!(req_exec.last_error(), Some(err.to_string()));
+    }
 
-        // Test write zeroes request with invalid flags (reserved bit set).
-        let wr_zeroes_req = DiscardWriteZeroes {
-            sector: 3,
-            num_sectors: 2,
-            flags: 0xA000,
+    #[test]
+    fn test_flush_policy() {
+        let f = TempFile::new().unwrap().into_file();
+        f.set_len(0x1000).unwrap();
+        let mem = GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let request = |request_type| {
+            Request::new(
+                request_type,
+                vec![DataDescriptor::new(GuestAddress(0x1000), 0x200)],
+                0,
+                GuestAddress(0x100),
+            )
         };
-        mem.write_obj::<DiscardWriteZeroes>(wr_zeroes_req, GuestAddress(0x1000))
+        let new_backend = |policy| {
+            StdIoBackend::new(
+                CountingBackend::new(f.try_clone().unwrap()),
+                1 << VIRTIO_BLK_F_FLUSH,
+            )
+            .unwrap()
+            .with_flush_policy(policy)
+        };
+
+        let mut req_exec = new_backend(FlushPolicy::Never);
+        req_exec.execute(&mem, &request(RequestType::Out)).unwrap();
+        req_exec
+            .execute(&mem, &request(RequestType::Flush))
+            .unwrap();
+        req_exec.barrier().unwrap();
+        assert_eq!(req_exec.inner().fsyncs, 0);
+
+        let mut req_exec = new_backend(FlushPolicy::OnFeature);
+        req_exec.execute(&mem, &request(RequestType::Out)).unwrap();
+        assert_eq!(req_exec.inner().fsyncs, 0);
+        req_exec
+            .execute(&mem, &request(RequestType::Flush))
             .unwrap();
+        assert_eq!(req_exec.inner().fsyncs, 1);
+
+        let mut req_exec = new_backend(FlushPolicy::Always);
+        req_exec.execute(&mem, &request(RequestType::Out)).unwrap();
+        req_exec.execute(&mem, &request(RequestType::Out)).unwrap();
+        assert_eq!(req_exec.inner().fsyncs, 2);
+        // There were no writes since the last sync.
+        req_exec
+            .execute(&mem, &request(RequestType::Flush))
+            .unwrap();
+        assert_eq!(req_exec.inner().fsyncs, 2);
+        req_exec.features = 0;
+        req_exec.barrier().unwrap();
+        assert_eq!(req_exec.inner().fsyncs, 3);
+    }
 
-        let wr_zeroes_req = Request::new(
-            RequestType::WriteZeroes,
-            vec![(GuestAddress(0x1000), DiscardWriteZeroes::LEN as u32)],
-            7,
-            GuestAddress(0x2000),
+    #[test]
+    fn test_validate_features() {
+        type Exec = StdIoBackend<File>;
+
+        assert_eq!(Exec::validate_features(0), Ok(()));
+        assert_eq!(
+            Exec::validate_features(
+                (1 << VIRTIO_BLK_F_CONFIG_WCE)
+                    | (1 << VIRTIO_BLK_F_FLUSH)
+                    | (1 << VIRTIO_BLK_F_ZONED)
+            ),
+            Ok(())
         );
         assert_eq!(
-            req_exec.execute(&mem, &wr_zeroes_req).unwrap_err(),
-            Error::InvalidFlags
+            Exec::validate_features(1 << VIRTIO_BLK_F_CONFIG_WCE),
+            Err(FeatureError::MissingDependency {
+                required: VIRTIO_BLK_F_FLUSH,
+                depends_on: VIRTIO_BLK_F_CONFIG_WCE,
+            })
         );
-
-        // Invalid data address.
-        let wr_zeroes_req = Request::new(
-            RequestType::WriteZeroes,
-            vec![(GuestAddress(0x1100_0000), DiscardWriteZeroes::LEN as u32)],
-            7,
-            GuestAddress(0x2000),
+        let err = Exec::validate_features((1 << VIRTIO_BLK_F_ZONED) | (1 << VIRTIO_BLK_F_RO))
+            .unwrap_err();
+        assert_eq!(
+            err,
+            FeatureError::ConflictingFeatures {
+                a: VIRTIO_BLK_F_ZONED,
+                b: VIRTIO_BLK_F_RO,
+            }
         );
-
         assert_eq!(
-            req_exec.execute(&mem, &wr_zeroes_req).unwrap_err(),
-            Error::GuestMemory(InvalidGuestAddress(GuestAddress(0x1100_0000)))
+            err.to_string(),
+            "features 17 and 5 can't be negotiated together"
         );
     }
 
     #[test]
-    fn test_get_device_id() {
+    fn test_with_aligned_buffer() {
         let f = TempFile::new().unwrap().into_file();
         f.set_len(0x1000).unwrap();
-
-        let mem = GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x1000_0000)]).unwrap();
-        let dev_id = [
-            0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x00A, 0x0B, 0x0C, 0x0D, 0x0E,
-            0x0F, 0x10, 0x11, 0x12, 0x13, 0x14,
-        ];
         let mut req_exec = StdIoBackend::new(f, 0).unwrap();
 
-        let get_id_req = Request::new(
-            RequestType::GetDeviceID,
-            vec![(GuestAddress(0x100), VIRTIO_BLK_ID_BYTES)],
-            1,
-            GuestAddress(0x200),
-        );
-
-        // Device id was not set, so a GetDeviceID request is unsupported.
-        assert_eq!(
-            req_exec.execute(&mem, &get_id_req).unwrap_err(),
-            Error::Unsupported(VIRTIO_BLK_T_GET_ID)
-        );
+        let ptr = req_exec.with_aligned_buffer(|buf| {
+            assert_eq!(buf.len(), SECTOR_SIZE as usize);
+            assert_eq!(buf.as_ptr() as usize % 4096, 0);
+            assert!(buf.iter().all(|&b| b == 0));
+            buf.fill(0xAB);
+            buf.as_ptr()
+        });
+        // The same buffer is reused, and zeroed again.
+        let reused = req_exec.with_aligned_buffer(|buf| {
+            assert!(buf.iter().all(|&b| b == 0));
+            buf.fill(0xCD);
+            buf.as_ptr()
+        });
+        assert_eq!(ptr, reused);
+
+        // Nested calls get a different buffer.
+        req_exec.with_sector_buf(|req_exec, outer| {
+            outer.fill(0xEF);
+            req_exec.with_aligned_buffer(|inner| {
+                assert_ne!(inner.as_ptr(), outer.as_ptr());
+                assert_eq!(inner.as_ptr() as usize % 4096, 0);
+                assert!(inner.iter().all(|&b| b == 0));
+            });
+            assert!(outer.iter().all(|&b| b == 0xEF));
+        });
+    }
 
-        req_exec = req_exec.with_device_id(dev_id);
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "invalid features")]
+    fn test_assert_invariants() {
+        let mut harness = BlockDeviceHarness::new(8, 1 << VIRTIO_BLK_F_FLUSH);
+        let (mem, req_exec) = harness.split_mut();
+        let request = Request::new(RequestType::Flush, vec![], 0, GuestAddress(0x200));
+        req_exec.execute(mem, &request).unwrap();
+
+        // Fabricate a state that the constructors reject.
+        req_exec.features = (1 << VIRTIO_BLK_F_ZONED) | (1 << VIRTIO_BLK_F_RO);
+        let _ = req_exec.execute(mem, &request);
+    }
 
-        // Invalid get device ID request, data length should be VIRTIO_BLK_ID_BYTES.
-        let get_id_req = Request::new(
-            RequestType::GetDeviceID,
-            vec![(GuestAddress(0x100), VIRTIO_BLK_ID_BYTES - 1)],
-            1,
-            GuestAddress(0x200),
-        );
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "don't fit in the 4096 bytes backing object")]
+    fn test_assert_invariants_size() {
+        let mut harness = BlockDeviceHarness::new(8, 1 << VIRTIO_BLK_F_FLUSH);
+        let (mem, req_exec) = harness.split_mut();
+        let request = Request::new(RequestType::Flush, vec![], 0, GuestAddress(0x200));
+        req_exec.execute(mem, &request).unwrap();
+
+        // Fabricate a device larger than its backing object.
+        req_exec.num_sectors = 9;
+        let _ = req_exec.execute(mem, &request);
+    }
 
-        assert_eq!(
-            req_exec.execute(&mem, &get_id_req).unwrap_err(),
-            Error::InvalidDataLength
-        );
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "is past the end of the 4096 bytes backing object")]
+    fn test_assert_invariants_position() {
+        let mut harness = BlockDeviceHarness::new(8, 1 << VIRTIO_BLK_F_FLUSH);
+        let (mem, req_exec) = harness.split_mut();
+        let request = Request::new(RequestType::Flush, vec![], 0, GuestAddress(0x200));
+        req_exec.execute(mem, &request).unwrap();
+
+        // Fabricate a position that no request leaves behind.
+        req_exec.inner_mut().seek(SeekFrom::Start(0x2000)).unwrap();
+        let _ = req_exec.execute(mem, &request);
+    }
 
-        let get_id_req = Request::new(
-            RequestType::GetDeviceID,
-            vec![(GuestAddress(0x100), VIRTIO_BLK_ID_BYTES)],
-            1,
-            GuestAddress(0x200),
-        );
+    #[test]
+    fn test_assert_invariants_out_of_range() {
+        let mut harness = BlockDeviceHarness::new(8, 0);
+        let (mem, req_exec) = harness.split_mut();
 
-        // VIRTIO_BLK_ID_BYTES bytes should've been written in memory.
-        assert_eq!(
-            req_exec.execute(&mem, &get_id_req).unwrap(),
-            VIRTIO_BLK_ID_BYTES
+        // Requests past the end of the device fail without tripping the invariants in debug
+        // builds, and don't affect the next request.
+        let in_req = Request::new(
+            RequestType::In,
+            vec![DataDescriptor::new(GuestAddress(0x1000), 0x200)],
+            0x100,
+            GuestAddress(0x100),
         );
-        let mut buf = [0x00; VIRTIO_BLK_ID_BYTES as usize];
-        mem.read_slice(&mut buf, GuestAddress(0x100)).unwrap();
-        assert_eq!(buf, dev_id);
-
+        assert_eq!(req_exec.execute(mem, &in_req), Err(Error::InvalidAccess));
+        // The sector of the other requests isn't checked, nor used.
+        req_exec.set_device_id(*b"serial\0\0\0\0\0\0\0\0\0\0\0\0\0\0");
         let get_id_req = Request::new(
             RequestType::GetDeviceID,
-            vec![(GuestAddress(0x100), 0x08), (GuestAddress(0x200), 0x0C)],
-            1,
-            GuestAddress(0x200),
+            vec![DataDescriptor::new(
+                GuestAddress(0x1000),
+                VIRTIO_BLK_ID_BYTES,
+            )],
+            0x100,
+            GuestAddress(0x100),
         );
-
-        mem.write_slice(&[0; 20], GuestAddress(0x100)).unwrap();
-
-        // VIRTIO_BLK_ID_BYTES bytes should've been written in memory.
-        assert_eq!(
-            req_exec.execute(&mem, &get_id_req).unwrap(),
-            VIRTIO_BLK_ID_BYTES
+        assert_eq!(req_exec.execute(mem, &get_id_req), Ok(VIRTIO_BLK_ID_BYTES));
+        let in_req = Request::new(
+            RequestType::In,
+            vec![DataDescriptor::new(GuestAddress(0x1000), 0x200)],
+            7,
+            GuestAddress(0x100),
         );
-        let mut buf = [0x00; 8];
-        mem.read_slice(&mut buf, GuestAddress(0x100)).unwrap();
-        assert_eq!(buf, dev_id[0..8]);
-        let mut buf = [0x00; 12];
-        mem.read_slice(&mut buf, GuestAddress(0x200)).unwrap();
-        assert_eq!(buf, dev_id[8..VIRTIO_BLK_ID_BYTES as usize]);
+        assert_eq!(req_exec.execute(mem, &in_req), Ok(0x200));
     }
 
     #[test]
-    fn test_process_request() {
+    fn test_dma_page_size() {
+        let mem = GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        mem.write_slice(&[0xAB; 0x1000], GuestAddress(0x1800))
+            .unwrap();
         let f = TempFile::new().unwrap().into_file();
-        f.set_len(0x1000).unwrap();
+        f.set_len(0x4000).unwrap();
+        let mut req_exec = StdIoBackend::new(CountingBackend::new(f), 0)
+            .unwrap()
+            .with_dma_page_size(0x1000, false);
 
-        let mem = GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x1000_0000)]).unwrap();
-        let flush_req = Request::new(
-            RequestType::Flush,
-            vec![(GuestAddress(0x100), 0x400)],
+        // The descriptor crosses the boundary at 0x2000.
+        let out_req = Request::new(
+            RequestType::Out,
+            vec![DataDescriptor::new(GuestAddress(0x1800), 0x1000)],
             0,
-            GuestAddress(0x600),
-        );
-
-        // VIRTIO_BLK_F_FLUSH not negotiated.
-        let mut req_exec = StdIoBackend::new(f, 0).unwrap();
-        assert_eq!(req_exec.process_request(&mem, &flush_req).unwrap(), 1);
-        assert_eq!(
-            mem.read_obj::<u8>(GuestAddress(0x600)).unwrap(),
-            VIRTIO_BLK_S_UNSUPP as u8
+            GuestAddress(0x100),
         );
-
-        // VIRTIO_BLK_F_FLUSH negotiated.
-        req_exec.features = 1 << VIRTIO_BLK_F_FLUSH;
-        assert_eq!(req_exec.process_request(&mem, &flush_req).unwrap(), 1);
-        assert_eq!(
-            mem.read_obj::<u8>(GuestAddress(0x600)).unwrap(),
-            VIRTIO_BLK_S_OK as u8
-        );
-
-        // Ok In request.
+        let err = Error::DmaPageCrossing {
+            addr: 0x1800,
+            page_size: 0x1000,
+        };
+        assert_eq!(req_exec.execute(&mem, &out_req).unwrap_err(), err);
+        assert_eq!(req_exec.inner().writes, 0);
         let in_req = Request::new(
             RequestType::In,
-            vec![(GuestAddress(0x100), 0x400), (GuestAddress(0x600), 0x200)],
+            vec![DataDescriptor::new(GuestAddress(0x4800), 0x1000)],
             0,
-            GuestAddress(0x900),
+            GuestAddress(0x100),
         );
-        // 0x600 bytes should've been written in memory.
-        assert_eq!(req_exec.process_request(&mem, &in_req).unwrap(), 0x601);
         assert_eq!(
-            mem.read_obj::<u8>(GuestAddress(0x900)).unwrap(),
-            VIRTIO_BLK_S_OK as u8
+            req_exec.execute(&mem, &in_req).unwrap_err(),
+            Error::DmaPageCrossing {
+                addr: 0x4800,
+                page_size: 0x1000,
+            }
         );
 
-        // Invalid status address.
-        let in_req = Request::new(
-            RequestType::In,
-            vec![(GuestAddress(0x100), 0x400), (GuestAddress(0x600), 0x200)],
+        // With splitting, each page is transferred separately.
+        let mut req_exec = req_exec.with_dma_page_size(0x1000, true);
+        req_exec.execute(&mem, &out_req).unwrap();
+        assert_eq!(req_exec.inner().writes, 2);
+        assert_eq!(req_exec.execute(&mem, &in_req).unwrap(), 0x1000);
+        let mut data = vec![0u8; 0x1000];
+        mem.read_slice(&mut data, GuestAddress(0x4800)).unwrap();
+        assert_eq!(data, vec![0xAB; 0x1000]);
+
+        // Descriptors within a page are not split.
+        let out_req = Request::new(
+            RequestType::Out,
+            vec![DataDescriptor::new(GuestAddress(0x2000), 0x1000)],
             0,
-            GuestAddress(0x1000_0001),
+            GuestAddress(0x100),
         );
-        assert!(matches!(
-            req_exec.process_request(&mem, &in_req).unwrap_err(),
-            ProcessReqError::GuestMemory(InvalidGuestAddress(GuestAddress(0x1000_0001)))
-        ));
+        req_exec.execute(&mem, &out_req).unwrap();
+        assert_eq!(req_exec.inner().writes, 3);
 
-        req_exec.features = 1 << VIRTIO_BLK_F_DISCARD;
-        // Test discard request with invalid flags.
-        let discard_req = DiscardWriteZeroes {
-            sector: 3,
-            num_sectors: 2,
-            flags: 0x0001,
-        };
-        mem.write_obj::<DiscardWriteZeroes>(discard_req, GuestAddress(0x1000))
+        // The vectored path checks the pages as well, and splits the transfers the same way.
+        let out_req = Request::new(
+            RequestType::Out,
+            vec![
+                DataDescriptor::new(GuestAddress(0x1800), 0x1000),
+                DataDescriptor::new(GuestAddress(0x3000), 0x200),
+            ],
+            0,
+            GuestAddress(0x100),
+        );
+        let mut req_exec = req_exec.with_dma_page_size(0x1000, false);
+        assert_eq!(req_exec.execute_vectored(&mem, &out_req).unwrap_err(), err);
+        assert_eq!(req_exec.inner().writes, 3);
+        let mut req_exec = req_exec.with_dma_page_size(0x1000, true);
+        req_exec.execute_vectored(&mem, &out_req).unwrap();
+        assert_eq!(req_exec.inner().writes, 6);
+    }
+
+    #[test]
+    fn test_from_vec() {
+        let mem = GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        mem.write_slice(&[0xAB; 0x400], GuestAddress(0x1000))
             .unwrap();
-        let discard_req = Request::new(
-            RequestType::Discard,
-            vec![(
-                GuestAddress(0x1000),
-                mem::size_of::<DiscardWriteZeroes>() as u32,
-            )],
-            7,
-            GuestAddress(0x2000),
+        let mut req_exec = StdIoBackend::from_vec(vec![0x11; 0x1000], 0).unwrap();
+        assert_eq!(req_exec.capacity_bytes(), 0x1000);
+
+        let out_req = Request::new(
+            RequestType::Out,
+            vec![DataDescriptor::new(GuestAddress(0x1000), 0x400)],
+            2,
+            GuestAddress(0x100),
         );
-        assert_eq!(req_exec.process_request(&mem, &discard_req).unwrap(), 1);
-        assert_eq!(
-            mem.read_obj::<u8>(GuestAddress(0x2000)).unwrap(),
-            VIRTIO_BLK_S_UNSUPP as u8
+        assert_eq!(req_exec.execute(&mem, &out_req).unwrap(), 0);
+        let in_req = Request::new(
+            RequestType::In,
+            vec![DataDescriptor::new(GuestAddress(0x2000), 0x600)],
+            1,
+            GuestAddress(0x100),
         );
+        assert_eq!(req_exec.execute(&mem, &in_req).unwrap(), 0x600);
+        let mut data = vec![0u8; 0x600];
+        mem.read_slice(&mut data, GuestAddress(0x2000)).unwrap();
+        assert_eq!(data[..0x200], [0x11; 0x200]);
+        assert_eq!(data[0x200..], [0xAB; 0x400]);
 
-        // Invalid memory address for write operation.
+        // Accesses past the end of the data fail.
         let out_req = Request::new(
             RequestType::Out,
-            vec![(GuestAddress(0xFFF_FFF0), 0x200)],
+            vec![DataDescriptor::new(GuestAddress(0x1000), 0x400)],
             7,
-            GuestAddress(0x200),
+            GuestAddress(0x100),
         );
-        assert_eq!(req_exec.process_request(&mem, &out_req).unwrap(), 1);
         assert_eq!(
-            mem.read_obj::<u8>(GuestAddress(0x200)).unwrap(),
-            VIRTIO_BLK_S_IOERR as u8
+            req_exec.execute(&mem, &out_req).unwrap_err(),
+            Error::InvalidAccess
         );
 
-        // Invalid memory address for read operation.
+        let data = req_exec.into_inner().into_inner();
+        assert_eq!(data.len(), 0x1000);
+        assert_eq!(data[0x400..0x800], [0xAB; 0x400]);
+    }
+
+    #[test]
+    fn test_max_queue_depth() {
+        let mut harness = BlockDeviceHarness::with_temp_file(8, 0)
+            .configure(|backend| backend.with_max_queue_depth(2));
+        let (mem, req_exec) = harness.split_mut();
+        let mut clone = req_exec.clone_for_queue(1).unwrap();
         let in_req = Request::new(
             RequestType::In,
-            vec![(GuestAddress(0xFFF_FFF0), 0x200)],
-            7,
-            GuestAddress(0x200),
+            vec![DataDescriptor::new(GuestAddress(0x1000), 0x200)],
+            0,
+            GuestAddress(0x100),
         );
+
+        // The depth is back to 0 after every execution, even a failed one.
+        req_exec.execute(mem, &in_req).unwrap();
+        clone.execute(mem, &in_req).unwrap();
+        let flush_req = Request::new(RequestType::Flush, vec![], 0, GuestAddress(0x100));
+        req_exec.execute(mem, &flush_req).unwrap_err();
+        assert_eq!(req_exec.current_depth.load(Ordering::SeqCst), 0);
+
+        // Saturate the limit with requests executed by other executors of the device.
+        req_exec.current_depth.store(2, Ordering::SeqCst);
+        let err = Error::QueueFull { depth: 2, max: 2 };
+        assert_eq!(req_exec.execute(mem, &in_req).unwrap_err(), err);
+        assert_eq!(clone.execute(mem, &in_req).unwrap_err(), err);
+        assert!(err.is_retryable());
+        // The rejected requests are not recorded in the statistics.
+        assert_eq!(req_exec.stats().snapshot().reads, 1);
+
+        // Once one of them completes, requests are executed again.
+        req_exec.current_depth.fetch_sub(1, Ordering::SeqCst);
+        assert_eq!(clone.execute(mem, &in_req).unwrap(), 0x200);
+        assert_eq!(req_exec.current_depth.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_wipe_for_decommission() {
+        use crate::journal::{JournalData, JournalEntry, JournalRecord};
+        use std::os::unix::fs::FileExt;
+
+        let progress = Arc::new(AtomicU64::new(0));
+        for (standard, passes) in [
+            (WipeStandard::Zeros, 1),
+            (WipeStandard::NIST800_88, 1),
+            (WipeStandard::DoD5220_22M, 3),
+        ] {
+            // More sectors than fit in a chunk.
+            let mut req_exec = StdIoBackend::from_vec(vec![0xAB; 0x30000], 0).unwrap();
+            req_exec
+                .wipe_for_decommission(standard, progress.clone())
+                .unwrap();
+            assert_eq!(progress.load(Ordering::SeqCst), passes);
+            let data = req_exec.into_inner().into_inner();
+            assert_eq!(data.len(), 0x30000);
+            if standard == WipeStandard::DoD5220_22M {
+                // The last pass writes random data.
+                for sector in data.chunks_exact(SECTOR_SIZE as usize) {
+                    assert!(sector.iter().any(|&b| b != 0xAB));
+                    assert!(sector.iter().any(|&b| b != 0xFF));
+                }
+            } else {
+                assert!(data.iter().all(|&b| b == 0));
+            }
+        }
+
+        // The zeroes are written as data, not with the zeroing operation of the backend.
+        let f = TempFile::new().unwrap().into_file();
+        f.set_len(0x30000).unwrap();
+        let mut req_exec = StdIoBackend::new(CountingBackend::new(f), 0).unwrap();
+        req_exec
+            .wipe_for_decommission(WipeStandard::NIST800_88, progress)
+            .unwrap();
+        assert_eq!(req_exec.inner().writes, 0x30000 / 0x10000);
+
+        // The wipe waits for the locked sectors, and the writes logged before it, including the
+        // ones that were never committed, aren't replayed over the erased data.
+        let journal = TempFile::new().unwrap().into_file();
+        JournalRecord::Entry(JournalEntry {
+            lsn: 5,
+            sector: 3,
+            data: JournalData::Write(vec![0xCD; 0x200]),
+        })
+        .write_to(&mut &journal)
+        .unwrap();
+        let range_lock = Arc::new(SectorRangeLock::new());
+        let mut req_exec = StdIoBackend::from_vec(vec![0xAB; 0x2000], 1 << VIRTIO_BLK_F_FLUSH)
+            .unwrap()
+            .with_range_lock(range_lock.clone())
+            .with_journal(journal.try_clone().unwrap());
+        req_exec.write_same(1, 1, &[0xEF; 0x200]).unwrap();
+        let guard = range_lock.lock_shared(15, 16);
+        let done = Arc::new(AtomicBool::new(false));
+        let handle = {
+            let done = done.clone();
+            let progress = Arc::new(AtomicU64::new(0));
+            std::thread::spawn(move || {
+                req_exec
+                    .wipe_for_decommission(WipeStandard::Zeros, progress)
+                    .unwrap();
+                done.store(true, Ordering::SeqCst);
+                req_exec
+            })
+        };
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        assert!(!done.load(Ordering::SeqCst));
+        drop(guard);
+        let req_exec = handle.join().unwrap();
+
+        let mut records = vec![0u8; journal.metadata().unwrap().len() as usize];
+        journal.read_exact_at(&mut records, 0).unwrap();
+        let mut req_exec =
+            StdIoBackend::from_vec(req_exec.into_inner().into_inner(), 1 << VIRTIO_BLK_F_FLUSH)
+                .unwrap()
+                .with_journal(journal);
+        assert_eq!(req_exec.replay_journal(&records[..]).unwrap(), 0);
+        assert_eq!(req_exec.sector_aligned_read(0, 16).unwrap(), [0; 0x2000]);
+    }
+
+    #[test]
+    fn test_to_virtio_status() {
+        let io_err = || io::Error::from(io::ErrorKind::Other);
+        let mem_err = || InvalidGuestAddress(GuestAddress(0));
+        let errors = [
+            Error::AlignmentPaddingFailed(io_err()),
+            Error::Backend(VolatileMemoryError::OutOfBounds { addr: 0 }),
+            Error::DeadlineExceeded,
+            Error::DescriptorTooLarge { len: 2, max: 1 },
+            Error::DiscardWriteZeroes(io_err()),
+            Error::DmaPageCrossing {
+                addr: 0,
+                page_size: 0x1000,
+            },
+            Error::Flush(io_err()),
+            Error::GuestMemory(mem_err()),
+            Error::InvalidAccess,
+            Error::InvalidDataLength,
+            Error::NonZeroSector(1),
+            Error::Overflow,
+            Error::PollTimeout { fd: 3 },
+            Error::Read(mem_err(), 0),
+            Error::ProtectedRange { sector: 1 },
+            Error::QueueFull { depth: 1, max: 1 },
+            Error::ReadOnly,
+            Error::RequestTooLarge {
+                request_sectors: 2,
+                max_sectors: 1,
+            },
+            Error::Write(mem_err()),
+            Error::Seek(io_err()),
+            Error::TooManySegments { count: 2, max: 1 },
+            Error::WouldBlock(PendingRequest {
+                transferred_bytes: 0,
+                remaining_bytes: 0x200,
+            }),
+            Error::ZoneReport(io_err()),
+        ];
+        for err in errors.iter() {
+            let status = err.to_virtio_status();
+            assert_eq!(VirtioBlkReqStatus::try_from(status as u8), Ok(status));
+            assert_eq!(status, VirtioBlkReqStatus::IoError, "{}", err);
+        }
+        for err in [Error::InvalidFlags, Error::Unsupported(VIRTIO_BLK_T_GET_ID)] {
+            assert_eq!(err.to_virtio_status(), VirtioBlkReqStatus::Unsupported);
+            assert_eq!(err.to_virtio_status() as u8, VIRTIO_BLK_S_UNSUPP as u8);
+        }
+    }
+
+    #[test]
+    fn test_pipe_to() {
+        let mut data = vec![0u8; 300 * SECTOR_SIZE as usize];
+        crate::rand::SplitMix64::new(9).fill_bytes(&mut data);
+        let mut req_exec = StdIoBackend::from_vec(data.clone(), 0).unwrap();
+
+        let mut piped = Vec::new();
         assert_eq!(
-            req_exec.process_request(&mem, &in_req).unwrap(),
-            0x1000_0000 - 0xFFF_FFF0 + 1
+            req_exec.pipe_to(&mut piped, None).unwrap(),
+            300 * SECTOR_SIZE
         );
+        assert_eq!(piped, data);
+
+        // More sectors than fit in a chunk, starting in the middle of the device.
+        let mut piped = Vec::new();
         assert_eq!(
-            mem.read_obj::<u8>(GuestAddress(0x200)).unwrap(),
-            VIRTIO_BLK_S_IOERR as u8
+            req_exec.pipe_to(&mut piped, Some(10..=209)).unwrap(),
+            200 * SECTOR_SIZE
         );
+        assert_eq!(piped, req_exec.sector_aligned_read(10, 200).unwrap());
 
-        let dev_id = [
-            0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x00A, 0x0B, 0x0C, 0x0D, 0x0E,
-            0x0F, 0x10, 0x11, 0x12, 0x13, 0x14,
-        ];
-        req_exec = req_exec.with_device_id(dev_id);
-
-        // Invalid memory address for get device id operation.
-        let get_id_req = Request::new(
-            RequestType::GetDeviceID,
-            vec![(GuestAddress(0xFFF_FFFA), VIRTIO_BLK_ID_BYTES)],
-            7,
-            GuestAddress(0x200),
+        assert_eq!(
+            req_exec.pipe_to(Vec::new(), Some(299..=300)).unwrap_err(),
+            Error::InvalidAccess
         );
+        #[allow(clippy::reversed_empty_ranges)]
+        let empty = 5..=4;
         assert_eq!(
-            req_exec.process_request(&mem, &get_id_req).unwrap(),
-            0x1000_0000 - 0xFFF_FFFA + 1
+            req_exec.pipe_to(Vec::new(), Some(empty)).unwrap_err(),
+            Error::InvalidAccess
         );
+
+        // A full destination.
+        let mut small = [0u8; 0x100];
         assert_eq!(
-            mem.read_obj::<u8>(GuestAddress(0x200)).unwrap(),
-            VIRTIO_BLK_S_IOERR as u8
+            req_exec.pipe_to(&mut small[..], Some(0..=0)).unwrap_err(),
+            Error::Pipe(io::Error::from(io::ErrorKind::WriteZero))
         );
     }
 }
@@ -0,0 +1,1132 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Utilities used by unit tests for inspecting the operations performed on a block device
+//! backend.
+//!
+//! This module provides the following abstractions:
+//!
+//! - [`RecordingBackend`](struct.RecordingBackend.html) which wraps a backend and records every
+//! operation performed on it, with its arguments and result, as an
+//! [`IoRecord`](enum.IoRecord.html).
+//! - [`PlaybackBackend`](struct.PlaybackBackend.html) which replays a sequence of recorded
+//! operations without performing any real I/O, e.g. for reproducing an intermittent failure.
+//! - [`DeterministicBackend`](struct.DeterministicBackend.html) which reads seeded pseudo-random
+//! data and discards the writes, e.g. for reproducible benchmarks without file I/O.
+//! - [`BlockDeviceHarness`](struct.BlockDeviceHarness.html) which sets up a
+//! [`StdIoBackend`](../stdio_executor/struct.StdIoBackend.html) on top of an in-memory or
+//! temporary disk, together with the guest memory used by its requests.
+//! - [`BackendFuzzer`](struct.BackendFuzzer.html) which performs random sequences of valid
+//! operations on a backend and checks that it stays consistent.
+//! - [`GuestMemoryFuzz`](struct.GuestMemoryFuzz.html) which generates guest memories made of
+//! random non-contiguous regions, for checking that requests don't depend on the memory layout.
+
+use std::cmp;
+use std::collections::VecDeque;
+#[cfg(feature = "backend-stdio")]
+use std::fs::File;
+use std::io::{self, Seek, SeekFrom};
+use std::result;
+
+use vm_memory::bitmap::BitmapSlice;
+#[cfg(feature = "backend-stdio")]
+use vm_memory::{GuestAddress, GuestMemoryMmap};
+use vm_memory::{ReadVolatile, VolatileMemoryError, VolatileSlice, WriteVolatile};
+use vmm_sys_util::file_traits::FileSync;
+#[cfg(feature = "backend-stdio")]
+use vmm_sys_util::tempfile::TempFile;
+use vmm_sys_util::write_zeroes::{PunchHole, WriteZeroesAt};
+
+#[cfg(feature = "backend-stdio")]
+use crate::backend::VecBackend;
+use crate::defs::SECTOR_SIZE;
+use crate::rand::SplitMix64;
+#[cfg(feature = "backend-stdio")]
+use crate::request::Request;
+#[cfg(feature = "backend-stdio")]
+use crate::stdio_executor::{Backend, Result as ExecuteResult, StdIoBackend};
+
+/// The result of a recorded operation. Only the kind of the errors is recorded.
+pub type IoResult<T> = result::Result<T, io::ErrorKind>;
+
+/// An operation performed on a backend, together with its arguments and result.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum IoRecord {
+    /// A `read_volatile` call.
+    Read {
+        /// The length of the buffer.
+        len: usize,
+        /// The data that was read.
+        data: Vec<u8>,
+        /// The result of the call.
+        result: IoResult<usize>,
+    },
+    /// A `write_volatile` call.
+    Write {
+        /// The content of the buffer.
+        data: Vec<u8>,
+        /// The result of the call.
+        result: IoResult<usize>,
+    },
+    /// A `seek` call.
+    Seek {
+        /// The position to seek to.
+        pos: SeekFrom,
+        /// The result of the call.
+        result: IoResult<u64>,
+    },
+    /// A `punch_hole` call.
+    PunchHole {
+        /// The offset of the hole.
+        offset: u64,
+        /// The length of the hole.
+        length: u64,
+        /// The result of the call.
+        result: IoResult<()>,
+    },
+    /// A `write_zeroes_at` call, which is also used by `write_all_zeroes_at`.
+    WriteZeroesAt {
+        /// The offset of the range to zero out.
+        offset: u64,
+        /// The length of the range to zero out.
+        length: usize,
+        /// The result of the call.
+        result: IoResult<usize>,
+    },
+    /// A `fsync` call.
+    Fsync {
+        /// The result of the call.
+        result: IoResult<()>,
+    },
+}
+
+fn to_record<T>(result: &io::Result<T>) -> IoResult<T>
+where
+    T: Copy,
+{
+    result.as_ref().map(|v| *v).map_err(|e| e.kind())
+}
+
+fn volatile_to_record(result: &result::Result<usize, VolatileMemoryError>) -> IoResult<usize> {
+    match result {
+        Ok(v) => Ok(*v),
+        Err(VolatileMemoryError::IOError(e)) => Err(e.kind()),
+        Err(_) => Err(io::ErrorKind::Other),
+    }
+}
+
+/// Wraps a backend and records all the operations performed on it.
+///
+/// # Example
+///
+/// ```rust
+/// # use virtio_blk::stdio_executor::StdIoBackend;
+/// # use virtio_blk::testing::RecordingBackend;
+/// # use vmm_sys_util::tempfile::TempFile;
+/// let backend = RecordingBackend::new(TempFile::new().unwrap().into_file());
+/// let request_exec = StdIoBackend::new(backend, 0).unwrap();
+/// // Getting the size of the backend is recorded as well.
+/// let records = request_exec.into_inner().into_records();
+/// assert_eq!(records.len(), 1);
+/// ```
+#[derive(Debug)]
+pub struct RecordingBackend<B> {
+    inner: B,
+    records: Vec<IoRecord>,
+}
+
+impl<B> RecordingBackend<B> {
+    /// Creates a new `RecordingBackend` that wraps `inner`.
+    ///
+    /// # Arguments
+    /// * `inner` - The backend that performs the operations.
+    pub fn new(inner: B) -> Self {
+        RecordingBackend {
+            inner,
+            records: Vec::new(),
+        }
+    }
+
+    /// Returns the operations that were recorded so far.
+    pub fn records(&self) -> &[IoRecord] {
+        &self.records
+    }
+
+    /// Consumes the `RecordingBackend`, returning the recorded operations.
+    pub fn into_records(self) -> Vec<IoRecord> {
+        self.records
+    }
+
+    /// Creates a [`PlaybackBackend`] that replays the operations recorded so far.
+    pub fn playback(&self) -> PlaybackBackend {
+        PlaybackBackend::new(self.records.clone())
+    }
+}
+
+impl<B: ReadVolatile> ReadVolatile for RecordingBackend<B> {
+    fn read_volatile<S: BitmapSlice>(
+        &mut self,
+        buf: &mut VolatileSlice<S>,
+    ) -> result::Result<usize, VolatileMemoryError> {
+        let result = self.inner.read_volatile(buf);
+        let mut data = vec![0u8; *result.as_ref().unwrap_or(&0)];
+        buf.copy_to(&mut data);
+        self.records.push(IoRecord::Read {
+            len: buf.len(),
+            data,
+            result: volatile_to_record(&result),
+        });
+        result
+    }
+}
+
+impl<B: WriteVolatile> WriteVolatile for RecordingBackend<B> {
+    fn write_volatile<S: BitmapSlice>(
+        &mut self,
+        buf: &VolatileSlice<S>,
+    ) -> result::Result<usize, VolatileMemoryError> {
+        let mut data = vec![0u8; buf.len()];
+        buf.copy_to(&mut data);
+        let result = self.inner.write_volatile(buf);
+        self.records.push(IoRecord::Write {
+            data,
+            result: volatile_to_record(&result),
+        });
+        result
+    }
+}
+
+impl<B: Seek> Seek for RecordingBackend<B> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let result = self.inner.seek(pos);
+        self.records.push(IoRecord::Seek {
+            pos,
+            result: to_record(&result),
+        });
+        result
+    }
+}
+
+impl<B: FileSync> FileSync for RecordingBackend<B> {
+    fn fsync(&mut self) -> io::Result<()> {
+        let result = self.inner.fsync();
+        self.records.push(IoRecord::Fsync {
+            result: to_record(&result),
+        });
+        result
+    }
+}
+
+impl<B: PunchHole> PunchHole for RecordingBackend<B> {
+    fn punch_hole(&mut self, offset: u64, length: u64) -> io::Result<()> {
+        let result = self.inner.punch_hole(offset, length);
+        self.records.push(IoRecord::PunchHole {
+            offset,
+            length,
+            result: to_record(&result),
+        });
+        result
+    }
+}
+
+impl<B: WriteZeroesAt> WriteZeroesAt for RecordingBackend<B> {
+    fn write_zeroes_at(&mut self, offset: u64, length: usize) -> io::Result<usize> {
+        let result = self.inner.write_zeroes_at(offset, length);
+        self.records.push(IoRecord::WriteZeroesAt {
+            offset,
+            length,
+            result: to_record(&result),
+        });
+        result
+    }
+}
+
+/// Replays a sequence of operations recorded by a [`RecordingBackend`], without performing any
+/// real I/O.
+///
+/// Every operation has to match the next recorded one, i.e. it has to be of the same type and
+/// have the same arguments, and returns the recorded result. Otherwise, an error of kind
+/// `io::ErrorKind::InvalidInput` is returned.
+#[derive(Debug)]
+pub struct PlaybackBackend {
+    records: VecDeque<IoRecord>,
+}
+
+impl PlaybackBackend {
+    /// Creates a new `PlaybackBackend` that replays `records`.
+    ///
+    /// # Arguments
+    /// * `records` - The operations to replay, e.g. the ones returned by
+    ///               [`RecordingBackend::into_records`].
+    pub fn new(records: Vec<IoRecord>) -> Self {
+        PlaybackBackend {
+            records: records.into(),
+        }
+    }
+
+    /// Returns the number of recorded operations that were not replayed yet.
+    pub fn remaining(&self) -> usize {
+        self.records.len()
+    }
+
+    // Returns the next recorded operation if `matches` accepts it.
+    fn next_record<F: FnOnce(&IoRecord) -> bool>(&mut self, matches: F) -> io::Result<IoRecord> {
+        match self.records.front() {
+            Some(record) if matches(record) => Ok(self.records.pop_front().unwrap()),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the operation doesn't match the recorded one",
+            )),
+        }
+    }
+}
+
+impl ReadVolatile for PlaybackBackend {
+    fn read_volatile<S: BitmapSlice>(
+        &mut self,
+        buf: &mut VolatileSlice<S>,
+    ) -> result::Result<usize, VolatileMemoryError> {
+        let buf_len = buf.len();
+        let record = self
+            .next_record(|r| matches!(r, IoRecord::Read { len, .. } if *len == buf_len))
+            .map_err(VolatileMemoryError::IOError)?;
+        match record {
+            IoRecord::Read { data, result, .. } => {
+                buf.copy_from(&data);
+                result.map_err(|kind| VolatileMemoryError::IOError(kind.into()))
+            }
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl WriteVolatile for PlaybackBackend {
+    fn write_volatile<S: BitmapSlice>(
+        &mut self,
+        buf: &VolatileSlice<S>,
+    ) -> result::Result<usize, VolatileMemoryError> {
+        let mut buf_data = vec![0u8; buf.len()];
+        buf.copy_to(&mut buf_data);
+        let record = self
+            .next_record(|r| matches!(r, IoRecord::Write { data, .. } if *data == buf_data))
+            .map_err(VolatileMemoryError::IOError)?;
+        match record {
+            IoRecord::Write { result, .. } => {
+                result.map_err(|kind| VolatileMemoryError::IOError(kind.into()))
+            }
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl Seek for PlaybackBackend {
+    fn seek(&mut self, seek_pos: SeekFrom) -> io::Result<u64> {
+        match self.next_record(|r| matches!(r, IoRecord::Seek { pos, .. } if *pos == seek_pos))? {
+            IoRecord::Seek { result, .. } => result.map_err(io::Error::from),
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl FileSync for PlaybackBackend {
+    fn fsync(&mut self) -> io::Result<()> {
+        match self.next_record(|r| matches!(r, IoRecord::Fsync { .. }))? {
+            IoRecord::Fsync { result } => result.map_err(io::Error::from),
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl PunchHole for PlaybackBackend {
+    fn punch_hole(&mut self, hole_offset: u64, hole_length: u64) -> io::Result<()> {
+        match self.next_record(|r| {
+            matches!(r, IoRecord::PunchHole { offset, length, .. }
+                if *offset == hole_offset && *length == hole_length)
+        })? {
+            IoRecord::PunchHole { result, .. } => result.map_err(io::Error::from),
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl WriteZeroesAt for PlaybackBackend {
+    fn write_zeroes_at(&mut self, zeroes_offset: u64, zeroes_length: usize) -> io::Result<usize> {
+        match self.next_record(|r| {
+            matches!(r, IoRecord::WriteZeroesAt { offset, length, .. }
+                if *offset == zeroes_offset && *length == zeroes_length)
+        })? {
+            IoRecord::WriteZeroesAt { result, .. } => result.map_err(io::Error::from),
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// A backend with pseudo-random content that is derived from a seed, and discards the writes.
+///
+/// The content of every sector is generated from `seed ^ sector`, so reading the same sector of
+/// two backends created with the same seed always returns the same data.
+#[derive(Debug)]
+pub struct DeterministicBackend {
+    seed: u64,
+    size_sectors: u64,
+    position: u64,
+}
+
+impl DeterministicBackend {
+    /// Creates a new `DeterministicBackend`.
+    ///
+    /// # Arguments
+    /// * `seed` - The seed the content is derived from.
+    /// * `size_sectors` - The size of the backend, in 512-byte sectors.
+    pub fn new(seed: u64, size_sectors: u64) -> Self {
+        DeterministicBackend {
+            seed,
+            size_sectors,
+            position: 0,
+        }
+    }
+
+    /// Fills `buf` with the content of the backend starting at `sector`.
+    ///
+    /// The content past the end of the backend is generated as well.
+    ///
+    /// # Arguments
+    /// * `sector` - The first sector to read.
+    /// * `buf` - The buffer to fill.
+    pub fn read(&self, sector: u64, buf: &mut [u8]) {
+        self.read_at(sector * SECTOR_SIZE, buf);
+    }
+
+    // Fills `buf` with the content of the backend starting at byte `offset`.
+    fn read_at(&self, offset: u64, buf: &mut [u8]) {
+        let mut sector = offset / SECTOR_SIZE;
+        let mut sector_offset = (offset % SECTOR_SIZE) as usize;
+        let mut filled = 0;
+        while filled < buf.len() {
+            let mut data = [0u8; SECTOR_SIZE as usize];
+            SplitMix64::new(self.seed ^ sector).fill_bytes(&mut data);
+            let count = cmp::min(data.len() - sector_offset, buf.len() - filled);
+            buf[filled..filled + count]
+                .copy_from_slice(&data[sector_offset..sector_offset + count]);
+            filled += count;
+            sector += 1;
+            sector_offset = 0;
+        }
+    }
+
+    // Returns the size of the backend, in bytes.
+    fn size(&self) -> u64 {
+        self.size_sectors * SECTOR_SIZE
+    }
+}
+
+impl ReadVolatile for DeterministicBackend {
+    fn read_volatile<S: BitmapSlice>(
+        &mut self,
+        buf: &mut VolatileSlice<S>,
+    ) -> result::Result<usize, VolatileMemoryError> {
+        let count = cmp::min(buf.len() as u64, self.size().saturating_sub(self.position)) as usize;
+        let mut data = vec![0u8; count];
+        self.read_at(self.position, &mut data);
+        buf.copy_from(&data);
+        self.position += count as u64;
+        Ok(count)
+    }
+}
+
+impl WriteVolatile for DeterministicBackend {
+    fn write_volatile<S: BitmapSlice>(
+        &mut self,
+        buf: &VolatileSlice<S>,
+    ) -> result::Result<usize, VolatileMemoryError> {
+        self.position += buf.len() as u64;
+        Ok(buf.len())
+    }
+}
+
+impl Seek for DeterministicBackend {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.size().checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+        self.position = position.ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
+        Ok(self.position)
+    }
+}
+
+impl FileSync for DeterministicBackend {
+    fn fsync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl PunchHole for DeterministicBackend {
+    fn punch_hole(&mut self, _offset: u64, _length: u64) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl WriteZeroesAt for DeterministicBackend {
+    fn write_zeroes_at(&mut self, _offset: u64, length: usize) -> io::Result<usize> {
+        Ok(length)
+    }
+}
+
+/// A block device, together with the guest memory its requests transfer data from and to.
+///
+/// The disk is kept in memory by default, so the tests don't touch the filesystem. Tests that
+/// need a file descriptor, e.g. for cloning the backend, can use a temporary file instead with
+/// [`with_temp_file`](#method.with_temp_file), or any other backend with
+/// [`with_backend`](#method.with_backend).
+///
+/// # Example
+///
+/// ```rust
+/// # use virtio_blk::request::{DataDescriptor, Request, RequestType};
+/// # use virtio_blk::testing::BlockDeviceHarness;
+/// # use vm_memory::{Bytes, GuestAddress};
+/// let mut harness = BlockDeviceHarness::new(8, 0);
+/// harness
+///     .mem()
+///     .write_slice(&[0xAB; 0x200], GuestAddress(0x1000))
+///     .unwrap();
+/// let request = Request::new(
+///     RequestType::Out,
+///     vec![DataDescriptor::new(GuestAddress(0x1000), 0x200)],
+///     0,
+///     GuestAddress(0x100),
+/// );
+/// harness.send_request(request).unwrap();
+/// ```
+#[cfg(feature = "backend-stdio")]
+#[derive(Debug)]
+pub struct BlockDeviceHarness<B: Backend = VecBackend> {
+    mem: GuestMemoryMmap,
+    backend: StdIoBackend<B>,
+}
+
+#[cfg(feature = "backend-stdio")]
+impl BlockDeviceHarness {
+    /// Creates a new `BlockDeviceHarness` with a zeroed disk kept in memory.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the guest memory can't be created, or if the backend doesn't support the
+    /// `features`.
+    ///
+    /// # Arguments
+    /// * `disk_sectors` - The size of the disk, in 512-byte sectors.
+    /// * `features` - The features negotiated with the driver.
+    pub fn new(disk_sectors: u64, features: u64) -> Self {
+        let disk = VecBackend::new(vec![0; (disk_sectors * SECTOR_SIZE) as usize]);
+        Self::with_backend(disk, features)
+    }
+}
+
+#[cfg(feature = "backend-stdio")]
+impl BlockDeviceHarness<File> {
+    /// Creates a new `BlockDeviceHarness` with a zeroed disk backed by a temporary file.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the temporary disk or the guest memory can't be created, or if the backend
+    /// doesn't support the `features`.
+    ///
+    /// # Arguments
+    /// * `disk_sectors` - The size of the disk, in 512-byte sectors.
+    /// * `features` - The features negotiated with the driver.
+    pub fn with_temp_file(disk_sectors: u64, features: u64) -> Self {
+        let disk = TempFile::new().unwrap().into_file();
+        disk.set_len(disk_sectors * SECTOR_SIZE).unwrap();
+        Self::with_backend(disk, features)
+    }
+}
+
+#[cfg(feature = "backend-stdio")]
+impl<B: Backend> BlockDeviceHarness<B> {
+    /// Size of the guest memory, which starts at guest address 0.
+    pub const MEM_SIZE: usize = 0x10_0000;
+
+    /// Creates a new `BlockDeviceHarness` on top of `disk`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the guest memory can't be created, or if the backend doesn't support the
+    /// `features`.
+    ///
+    /// # Arguments
+    /// * `disk` - The backend of the disk.
+    /// * `features` - The features negotiated with the driver.
+    pub fn with_backend(disk: B, features: u64) -> Self {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), Self::MEM_SIZE)]).unwrap();
+        let backend = StdIoBackend::new(disk, features).unwrap();
+
+        BlockDeviceHarness { mem, backend }
+    }
+
+    /// Configures the backend with `f`, e.g. for setting the limits of the requests.
+    ///
+    /// # Arguments
+    /// * `f` - Called with the backend, returns the configured backend.
+    pub fn configure<F>(self, f: F) -> Self
+    where
+        F: FnOnce(StdIoBackend<B>) -> StdIoBackend<B>,
+    {
+        BlockDeviceHarness {
+            mem: self.mem,
+            backend: f(self.backend),
+        }
+    }
+
+    /// Obtains a reference to the guest memory.
+    pub fn mem(&self) -> &GuestMemoryMmap {
+        &self.mem
+    }
+
+    /// Obtains a mutable reference to the backend.
+    pub fn backend_mut(&mut self) -> &mut StdIoBackend<B> {
+        &mut self.backend
+    }
+
+    /// Obtains a reference to the guest memory together with a mutable reference to the
+    /// backend, e.g. for calling the methods of the backend that take the guest memory.
+    pub fn split_mut(&mut self) -> (&GuestMemoryMmap, &mut StdIoBackend<B>) {
+        (&self.mem, &mut self.backend)
+    }
+
+    /// Executes `request` on the backend, returning the number of bytes transferred to the
+    /// guest memory.
+    ///
+    /// # Arguments
+    /// * `request` - The request to execute.
+    pub fn send_request(&mut self, request: Request) -> ExecuteResult<u32> {
+        self.backend.execute(&self.mem, &request)
+    }
+}
+
+/// Performs seeded pseudo-random sequences of valid operations on a backend, and checks that the
+/// backend stays consistent after each one of them.
+///
+/// The operations are reads, writes, seeks, syncs, hole punches and zero writes. Their ranges are
+/// always within the size the backend had when the fuzzer was created. After every operation,
+/// the position of the backend has to be the one expected from the results of the operations,
+/// and within the bounds of the backend.
+#[cfg(feature = "backend-stdio")]
+#[derive(Debug)]
+pub struct BackendFuzzer<B> {
+    backend: B,
+    rng: SplitMix64,
+    size: u64,
+    position: u64,
+}
+
+#[cfg(feature = "backend-stdio")]
+impl<B: Backend> BackendFuzzer<B> {
+    /// The maximum length of the data of a single operation.
+    pub const MAX_IO_LEN: u64 = 0x2000;
+
+    /// Creates a new `BackendFuzzer` for `backend`, rewinding it.
+    ///
+    /// # Arguments
+    /// * `backend` - The backend to perform the operations on.
+    /// * `seed` - The seed the operations are derived from.
+    pub fn new(mut backend: B, seed: u64) -> io::Result<Self> {
+        let size = backend.seek(SeekFrom::End(0))?;
+        backend.seek(SeekFrom::Start(0))?;
+        Ok(BackendFuzzer {
+            backend,
+            rng: SplitMix64::new(seed),
+            size,
+            position: 0,
+        })
+    }
+
+    /// Obtains a reference to the backend.
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    /// Consumes the `BackendFuzzer`, returning the backend.
+    pub fn into_inner(self) -> B {
+        self.backend
+    }
+
+    /// Performs `operations` random operations, stopping at the first error or inconsistency.
+    ///
+    /// An inconsistency is reported as an error of kind `io::ErrorKind::InvalidData`.
+    ///
+    /// # Arguments
+    /// * `operations` - The number of operations to perform.
+    pub fn run(&mut self, operations: usize) -> io::Result<()> {
+        for _ in 0..operations {
+            self.step()?;
+        }
+        Ok(())
+    }
+
+    /// Performs a single random operation, and checks the state of the backend afterwards.
+    pub fn step(&mut self) -> io::Result<()> {
+        match self.rng.next_u64() % 6 {
+            0 => {
+                let len = self.random_len(self.size - self.position);
+                let mut data = vec![0u8; len as usize];
+                let count = self
+                    .backend
+                    .read_volatile(&mut VolatileSlice::from(data.as_mut_slice()))
+                    .map_err(Self::volatile_to_io_error)?;
+                self.advance(count, len)?;
+            }
+            1 => {
+                let len = self.random_len(self.size - self.position);
+                let mut data = vec![0u8; len as usize];
+                self.rng.fill_bytes(&mut data);
+                let count = self
+                    .backend
+                    .write_volatile(&VolatileSlice::from(data.as_mut_slice()))
+                    .map_err(Self::volatile_to_io_error)?;
+                self.advance(count, len)?;
+            }
+            2 => {
+                let target = self.rng.next_u64() % (self.size + 1);
+                // The offsets fit in an i64 since they are smaller than the size of the backend.
+                let pos = match self.rng.next_u64() % 3 {
+                    0 => SeekFrom::Start(target),
+                    1 => SeekFrom::Current(target as i64 - self.position as i64),
+                    _ => SeekFrom::End(target as i64 - self.size as i64),
+                };
+                let position = self.backend.seek(pos)?;
+                if position != target {
+                    return Err(Self::inconsistency(format!(
+                        "seek to {:?} returned {}, expected {}",
+                        pos, position, target
+                    )));
+                }
+                self.position = target;
+            }
+            3 => self.backend.fsync()?,
+            4 => {
+                if let Some((offset, len)) = self.random_range() {
+                    self.backend.punch_hole(offset, len)?;
+                }
+            }
+            _ => {
+                let Some((offset, len)) = self.random_range() else {
+                    return self.check_position();
+                };
+                let count = self.backend.write_zeroes_at(offset, len as usize)?;
+                if count as u64 > len {
+                    return Err(Self::inconsistency(format!(
+                        "zeroed {} bytes out of {}",
+                        count, len
+                    )));
+                }
+            }
+        }
+        self.check_position()
+    }
+
+    // Moves the expected position after transferring `count` bytes out of `len`.
+    fn advance(&mut self, count: usize, len: u64) -> io::Result<()> {
+        if count as u64 > len {
+            return Err(Self::inconsistency(format!(
+                "transferred {} bytes out of {}",
+                count, len
+            )));
+        }
+        self.position += count as u64;
+        Ok(())
+    }
+
+    // Checks that the position of the backend is the expected one, and within its bounds.
+    fn check_position(&mut self) -> io::Result<()> {
+        let position = self.backend.stream_position()?;
+        if position != self.position || position > self.size {
+            return Err(Self::inconsistency(format!(
+                "the position is {}, expected {} (size {})",
+                position, self.position, self.size
+            )));
+        }
+        Ok(())
+    }
+
+    // Returns a random length of at most `max` and `MAX_IO_LEN` bytes.
+    fn random_len(&mut self, max: u64) -> u64 {
+        self.rng.next_u64() % (cmp::min(max, Self::MAX_IO_LEN) + 1)
+    }
+
+    // Returns a random non-empty range of the backend, as an (offset, length) pair, unless the
+    // backend is empty.
+    fn random_range(&mut self) -> Option<(u64, u64)> {
+        if self.size == 0 {
+            return None;
+        }
+        let offset = self.rng.next_u64() % self.size;
+        let max_len = cmp::min(self.size - offset, Self::MAX_IO_LEN);
+        Some((offset, self.rng.next_u64() % max_len + 1))
+    }
+
+    fn inconsistency(msg: String) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, msg)
+    }
+
+    fn volatile_to_io_error(err: VolatileMemoryError) -> io::Error {
+        match err {
+            VolatileMemoryError::IOError(err) => err,
+            err => io::Error::other(err),
+        }
+    }
+}
+
+/// Generates seeded pseudo-random guest memory layouts.
+///
+/// Every layout has between 1 and `MAX_REGIONS` regions, each of them between 1 and
+/// `MAX_REGION_PAGES` pages long. The regions are separated by gaps of at least one page, so no
+/// two of them are contiguous, and the first one doesn't necessarily start at guest address 0.
+#[cfg(feature = "backend-stdio")]
+#[derive(Debug)]
+pub struct GuestMemoryFuzz {
+    rng: SplitMix64,
+}
+
+#[cfg(feature = "backend-stdio")]
+impl GuestMemoryFuzz {
+    /// The maximum number of regions of a layout.
+    pub const MAX_REGIONS: u64 = 8;
+    /// The maximum length of a region, and of the gap before it, in pages.
+    pub const MAX_REGION_PAGES: u64 = 16;
+    /// The size of the pages the regions and gaps are made of.
+    pub const PAGE_SIZE: u64 = 0x1000;
+
+    /// Creates a new `GuestMemoryFuzz`.
+    ///
+    /// # Arguments
+    /// * `seed` - The seed the layouts are derived from.
+    pub fn new(seed: u64) -> Self {
+        GuestMemoryFuzz {
+            rng: SplitMix64::new(seed),
+        }
+    }
+
+    /// Returns the (start, length) pairs of the regions of the next layout, sorted by address.
+    pub fn next_ranges(&mut self) -> Vec<(GuestAddress, usize)> {
+        let num_regions = self.rng.next_u64() % Self::MAX_REGIONS + 1;
+        let mut next_start = (self.rng.next_u64() % Self::MAX_REGION_PAGES) * Self::PAGE_SIZE;
+        (0..num_regions)
+            .map(|_| {
+                let pages = self.rng.next_u64() % Self::MAX_REGION_PAGES + 1;
+                let gap = self.rng.next_u64() % Self::MAX_REGION_PAGES + 1;
+                let range = (GuestAddress(next_start), (pages * Self::PAGE_SIZE) as usize);
+                next_start += (pages + gap) * Self::PAGE_SIZE;
+                range
+            })
+            .collect()
+    }
+
+    /// Creates a guest memory with the next layout.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the guest memory can't be created.
+    pub fn next_layout(&mut self) -> GuestMemoryMmap {
+        GuestMemoryMmap::from_ranges(&self.next_ranges()).unwrap()
+    }
+}
+
+#[cfg(all(test, feature = "backend-stdio"))]
+mod tests {
+    use super::*;
+
+    use virtio_bindings::bindings::virtio_blk::VIRTIO_BLK_F_FLUSH;
+    use vm_memory::{Address, Bytes, GuestAddress, GuestMemoryError, GuestMemoryMmap};
+    use vmm_sys_util::tempfile::TempFile;
+
+    use crate::request::{DataDescriptor, Request, RequestType};
+    use crate::stdio_executor::{Error, StdIoBackend};
+
+    // Executes the same sequence of requests on `backend`, returning their results and the
+    // content of the guest memory afterwards.
+    fn run_session<B: Backend>(backend: B) -> (B, Vec<String>, Vec<u8>) {
+        let mem = GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        mem.write_slice(&[0xAB; 0x400], GuestAddress(0x1000))
+            .unwrap();
+        let mut req_exec = StdIoBackend::new(backend, 1 << VIRTIO_BLK_F_FLUSH).unwrap();
+
+        let requests = [
+            Request::new(
+                RequestType::Out,
+                vec![
+                    DataDescriptor::new(GuestAddress(0x1000), 0x200),
+                    DataDescriptor::new(GuestAddress(0x1200), 0x200),
+                ],
+                1,
+                GuestAddress(0x100),
+            ),
+            Request::new(
+                RequestType::In,
+                vec![DataDescriptor::new(GuestAddress(0x2000), 0x600)],
+                0,
+                GuestAddress(0x100),
+            ),
+            Request::new(RequestType::Flush, vec![], 0, GuestAddress(0x100)),
+            // Out of bounds.
+            Request::new(
+                RequestType::In,
+                vec![DataDescriptor::new(GuestAddress(0x2000), 0x200)],
+                8,
+                GuestAddress(0x100),
+            ),
+        ];
+        let results = requests
+            .iter()
+            .map(|request| format!("{:?}", req_exec.execute(&mem, request)))
+            .collect();
+
+        let mut content = vec![0u8; 0x600];
+        mem.read_slice(&mut content, GuestAddress(0x2000)).unwrap();
+        (req_exec.into_inner(), results, content)
+    }
+
+    #[test]
+    fn test_record_playback() {
+        let f = TempFile::new().unwrap().into_file();
+        f.set_len(0x1000).unwrap();
+
+        let (recorder, results, content) = run_session(RecordingBackend::new(f));
+        assert_eq!(
+            results,
+            vec!["Ok(0)", "Ok(1536)", "Ok(0)", "Err(InvalidAccess)"]
+        );
+        assert_eq!(&content[..0x200], &[0u8; 0x200][..]);
+        assert_eq!(&content[0x200..], &[0xAB; 0x400][..]);
+
+        let records = recorder.records().to_vec();
+        assert_eq!(
+            records[0],
+            IoRecord::Seek {
+                pos: SeekFrom::End(0),
+                result: Ok(0x1000)
+            }
+        );
+        assert!(records.contains(&IoRecord::Fsync { result: Ok(()) }));
+
+        let (player, replayed_results, replayed_content) = run_session(recorder.playback());
+        assert_eq!(replayed_results, results);
+        assert_eq!(replayed_content, content);
+        assert_eq!(player.remaining(), 0);
+
+        // Operations that diverge from the recorded ones fail.
+        let mut player = PlaybackBackend::new(records);
+        assert_eq!(
+            player.fsync().unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+        assert!(player.seek(SeekFrom::End(0)).is_ok());
+    }
+
+    #[test]
+    fn test_harness() {
+        let mut harness = BlockDeviceHarness::new(8, 0);
+        assert_eq!(harness.backend_mut().num_sectors(), 8);
+
+        harness
+            .mem()
+            .write_slice(&[0xAB; 0x200], GuestAddress(0x1000))
+            .unwrap();
+        let out_req = Request::new(
+            RequestType::Out,
+            vec![DataDescriptor::new(GuestAddress(0x1000), 0x200)],
+            7,
+            GuestAddress(0x100),
+        );
+        assert_eq!(harness.send_request(out_req).unwrap(), 0);
+        let in_req = Request::new(
+            RequestType::In,
+            vec![DataDescriptor::new(GuestAddress(0x2000), 0x400)],
+            6,
+            GuestAddress(0x100),
+        );
+        assert_eq!(harness.send_request(in_req).unwrap(), 0x400);
+
+        let mut v = vec![0u8; 0x400];
+        harness
+            .mem()
+            .read_slice(&mut v, GuestAddress(0x2000))
+            .unwrap();
+        assert_eq!(v[..0x200], [0u8; 0x200]);
+        assert_eq!(v[0x200..], [0xAB; 0x200]);
+
+        // Past the end of the disk.
+        let in_req = Request::new(
+            RequestType::In,
+            vec![DataDescriptor::new(GuestAddress(0x2000), 0x200)],
+            8,
+            GuestAddress(0x100),
+        );
+        assert!(harness.send_request(in_req).is_err());
+    }
+
+    #[test]
+    fn test_deterministic_backend() {
+        let backend = DeterministicBackend::new(0x1234, 8);
+        let mut first = vec![0u8; 0x400];
+        let mut second = vec![0u8; 0x400];
+        backend.read(3, &mut first);
+        DeterministicBackend::new(0x1234, 8).read(3, &mut second);
+        assert_eq!(first, second);
+        // The data varies between sectors and seeds.
+        assert_ne!(first[..0x200], first[0x200..]);
+        DeterministicBackend::new(0x4321, 8).read(3, &mut second);
+        assert_ne!(first, second);
+
+        // Requests read the same data, and the writes are discarded.
+        let mem = GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let mut req_exec = StdIoBackend::new(backend, 0).unwrap();
+        assert_eq!(req_exec.num_sectors(), 8);
+        let out_req = Request::new(
+            RequestType::Out,
+            vec![DataDescriptor::new(GuestAddress(0x1000), 0x200)],
+            3,
+            GuestAddress(0x100),
+        );
+        req_exec.execute(&mem, &out_req).unwrap();
+        let in_req = Request::new(
+            RequestType::In,
+            vec![
+                DataDescriptor::new(GuestAddress(0x2000), 0x100),
+                DataDescriptor::new(GuestAddress(0x3000), 0x300),
+            ],
+            3,
+            GuestAddress(0x100),
+        );
+        assert_eq!(req_exec.execute(&mem, &in_req).unwrap(), 0x400);
+        let mut v = vec![0u8; 0x300];
+        mem.read_slice(&mut v, GuestAddress(0x3000)).unwrap();
+        assert_eq!(v, first[0x100..]);
+    }
+
+    #[test]
+    fn test_backend_fuzzer() {
+        let f = TempFile::new().unwrap().into_file();
+        f.set_len(64 * SECTOR_SIZE).unwrap();
+        let mut fuzzer = BackendFuzzer::new(f, 0x1234).unwrap();
+        fuzzer.run(10000).unwrap();
+        // The operations never change the size of the backend.
+        let f = fuzzer.into_inner();
+        assert_eq!(f.metadata().unwrap().len(), 64 * SECTOR_SIZE);
+
+        let mut fuzzer = BackendFuzzer::new(DeterministicBackend::new(1, 64), 0x1234).unwrap();
+        fuzzer.run(10000).unwrap();
+
+        let backend = VecBackend::new(vec![0; 64 * SECTOR_SIZE as usize]);
+        let mut fuzzer = BackendFuzzer::new(backend, 0x1234).unwrap();
+        fuzzer.run(10000).unwrap();
+        assert_eq!(fuzzer.backend().data().len() as u64, 64 * SECTOR_SIZE);
+    }
+
+    #[test]
+    fn test_guest_memory_fuzz() {
+        let mut fuzz = GuestMemoryFuzz::new(0x1234);
+        for _ in 0..50 {
+            let ranges = fuzz.next_ranges();
+            for pair in ranges.windows(2) {
+                let (start, len) = pair[0];
+                assert!(start.0 + (len as u64) < pair[1].0 .0);
+            }
+            // Two adjacent regions after the layout, separated from it by an unmapped page.
+            let (last_start, last_len) = ranges[ranges.len() - 1];
+            let gap = last_start.unchecked_add(last_len as u64);
+            let boundary = gap.unchecked_add(2 * GuestMemoryFuzz::PAGE_SIZE);
+            let adjacent = [
+                (
+                    gap.unchecked_add(GuestMemoryFuzz::PAGE_SIZE),
+                    GuestMemoryFuzz::PAGE_SIZE as usize,
+                ),
+                (boundary, GuestMemoryFuzz::PAGE_SIZE as usize),
+            ];
+            let mem =
+                GuestMemoryMmap::<()>::from_ranges(&[&ranges[..], &adjacent[..]].concat()).unwrap();
+            let disk = TempFile::new().unwrap().into_file();
+            disk.set_len(16 * SECTOR_SIZE).unwrap();
+            let mut req_exec = StdIoBackend::new(disk, 1 << VIRTIO_BLK_F_FLUSH).unwrap();
+
+            // Write one sector from each region, and read them back into other addresses of the
+            // same regions.
+            let mut out_descs = Vec::new();
+            let mut in_descs = Vec::new();
+            for (i, &(start, _)) in ranges.iter().enumerate() {
+                mem.write_slice(&[i as u8 + 1; SECTOR_SIZE as usize], start)
+                    .unwrap();
+                out_descs.push(DataDescriptor::new(start, SECTOR_SIZE as u32));
+                in_descs.push(DataDescriptor::new(
+                    start.unchecked_add(0x800),
+                    SECTOR_SIZE as u32,
+                ));
+            }
+            let status_addr = ranges[0].0.unchecked_add(0x400);
+            let requests = [
+                Request::new(RequestType::Out, out_descs, 1, status_addr),
+                Request::new(RequestType::Flush, vec![], 0, status_addr),
+                Request::new(RequestType::In, in_descs, 1, status_addr),
+            ];
+            for request in requests.iter() {
+                req_exec.execute(&mem, request).unwrap();
+            }
+            for (i, &(start, _)) in ranges.iter().enumerate() {
+                let mut data = [0u8; SECTOR_SIZE as usize];
+                mem.read_slice(&mut data, start.unchecked_add(0x800))
+                    .unwrap();
+                assert_eq!(data, [i as u8 + 1; SECTOR_SIZE as usize]);
+            }
+
+            // A sector written from a chain of descriptors in the same region is read back into
+            // a buffer split across the adjacent regions.
+            let chain_start = ranges[0].0.unchecked_add(0xA00);
+            let mut chain = Vec::new();
+            for i in 0..4u8 {
+                let addr = chain_start.unchecked_add(u64::from(i) * 0x80);
+                mem.write_slice(&[0x10 + i; 0x80], addr).unwrap();
+                chain.push(DataDescriptor::new(addr, 0x80));
+            }
+            let split = boundary.unchecked_sub(0x100);
+            let out_req = Request::new(RequestType::Out, chain, 2, status_addr);
+            let in_req = Request::new(
+                RequestType::In,
+                vec![DataDescriptor::new(split, SECTOR_SIZE as u32)],
+                2,
+                status_addr,
+            );
+            assert_eq!(req_exec.execute(&mem, &out_req).unwrap(), 0);
+            assert_eq!(req_exec.execute(&mem, &in_req).unwrap(), SECTOR_SIZE as u32);
+            let mut data = [0u8; SECTOR_SIZE as usize];
+            mem.read_slice(&mut data, split).unwrap();
+            for (i, chunk) in data.chunks_exact(0x80).enumerate() {
+                assert_eq!(chunk, [0x10 + i as u8; 0x80]);
+            }
+
+            // Buffers in an unmapped gap can't be accessed.
+            let gap_request = |request_type| {
+                Request::new(
+                    request_type,
+                    vec![DataDescriptor::new(gap, SECTOR_SIZE as u32)],
+                    2,
+                    status_addr,
+                )
+            };
+            assert_eq!(
+                req_exec
+                    .execute(&mem, &gap_request(RequestType::In))
+                    .unwrap_err(),
+                Error::Read(GuestMemoryError::InvalidGuestAddress(gap), 0)
+            );
+            assert_eq!(
+                req_exec
+                    .execute(&mem, &gap_request(RequestType::Out))
+                    .unwrap_err(),
+                Error::Write(GuestMemoryError::InvalidGuestAddress(gap))
+            );
+        }
+    }
+}
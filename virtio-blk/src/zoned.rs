@@ -0,0 +1,173 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Zoned block device definitions.
+//!
+//! This module provides the following abstractions for the zone reports returned by the
+//! `VIRTIO_BLK_T_ZONE_REPORT` requests:
+//!
+//! - [`ZoneReportHeader`](struct.ZoneReportHeader.html) which is the `virtio_blk_zone_report`
+//! header of the report.
+//! - [`ZoneDescriptor`](struct.ZoneDescriptor.html) which is a `virtio_blk_zone_descriptor`
+//! entry of the report, describing one zone.
+
+use std::mem;
+
+use vm_memory::ByteValued;
+
+/// The header of a zone report, followed by `nr_zones` [`ZoneDescriptor`] entries.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(C)]
+pub struct ZoneReportHeader {
+    nr_zones: u64,
+    reserved: [u8; 56],
+}
+
+impl ZoneReportHeader {
+    /// Size of the `ZoneReportHeader` struct.
+    pub const LEN: usize = mem::size_of::<ZoneReportHeader>();
+
+    /// Creates a new `ZoneReportHeader` for a report with `nr_zones` zones.
+    ///
+    /// # Arguments
+    /// * `nr_zones` - The number of zones in the report.
+    pub fn new(nr_zones: u64) -> Self {
+        ZoneReportHeader {
+            nr_zones: nr_zones.to_le(),
+            reserved: [0; 56],
+        }
+    }
+
+    /// Returns the number of zones in the report.
+    pub fn nr_zones(&self) -> u64 {
+        u64::from_le(self.nr_zones)
+    }
+}
+
+impl Default for ZoneReportHeader {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+// SAFETY: Safe because ZoneReportHeader contains only plain data.
+unsafe impl ByteValued for ZoneReportHeader {}
+
+/// The description of a zone, as reported to the driver.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(C)]
+pub struct ZoneDescriptor {
+    z_cap: u64,
+    z_start: u64,
+    z_wp: u64,
+    z_type: u8,
+    z_state: u8,
+    reserved: [u8; 38],
+}
+
+impl ZoneDescriptor {
+    /// Size of the `ZoneDescriptor` struct.
+    pub const LEN: usize = mem::size_of::<ZoneDescriptor>();
+
+    /// Creates a new `ZoneDescriptor`.
+    ///
+    /// # Arguments
+    /// * `zone_type` - The type of the zone, one of the `VIRTIO_BLK_ZT_*` values.
+    /// * `state` - The state of the zone, one of the `VIRTIO_BLK_ZS_*` values.
+    /// * `start` - The first sector of the zone.
+    /// * `capacity` - The number of sectors that can be written in the zone.
+    /// * `write_pointer` - The sector where the next write in the zone has to start.
+    pub fn new(zone_type: u8, state: u8, start: u64, capacity: u64, write_pointer: u64) -> Self {
+        ZoneDescriptor {
+            z_cap: capacity.to_le(),
+            z_start: start.to_le(),
+            z_wp: write_pointer.to_le(),
+            z_type: zone_type,
+            z_state: state,
+            reserved: [0; 38],
+        }
+    }
+
+    /// Returns the type of the zone, one of the `VIRTIO_BLK_ZT_*` values.
+    pub fn zone_type(&self) -> u8 {
+        self.z_type
+    }
+
+    /// Returns the state of the zone, one of the `VIRTIO_BLK_ZS_*` values.
+    pub fn state(&self) -> u8 {
+        self.z_state
+    }
+
+    /// Returns the first sector of the zone.
+    pub fn start(&self) -> u64 {
+        u64::from_le(self.z_start)
+    }
+
+    /// Returns the number of sectors that can be written in the zone.
+    pub fn capacity(&self) -> u64 {
+        u64::from_le(self.z_cap)
+    }
+
+    /// Returns the sector where the next write in the zone has to start.
+    pub fn write_pointer(&self) -> u64 {
+        u64::from_le(self.z_wp)
+    }
+}
+
+impl Default for ZoneDescriptor {
+    fn default() -> Self {
+        Self::new(0, 0, 0, 0, 0)
+    }
+}
+
+// SAFETY: Safe because ZoneDescriptor contains only plain data.
+unsafe impl ByteValued for ZoneDescriptor {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use virtio_bindings::bindings::virtio_blk::{
+        virtio_blk_zone_descriptor, virtio_blk_zone_report, VIRTIO_BLK_ZS_IOPEN, VIRTIO_BLK_ZT_SWR,
+    };
+
+    #[test]
+    fn test_layout() {
+        assert_eq!(
+            ZoneReportHeader::LEN,
+            mem::size_of::<virtio_blk_zone_report>()
+        );
+        assert_eq!(
+            ZoneDescriptor::LEN,
+            mem::size_of::<virtio_blk_zone_descriptor>()
+        );
+
+        let header = ZoneReportHeader::new(3);
+        assert_eq!(header.nr_zones(), 3);
+        assert_eq!(header.as_slice()[..8], 3u64.to_le_bytes());
+        assert_eq!(header.as_slice()[8..], [0u8; 56]);
+
+        let zone = ZoneDescriptor::new(
+            VIRTIO_BLK_ZT_SWR as u8,
+            VIRTIO_BLK_ZS_IOPEN as u8,
+            0x100,
+            0x80,
+            0x110,
+        );
+        assert_eq!(zone.zone_type(), VIRTIO_BLK_ZT_SWR as u8);
+        assert_eq!(zone.state(), VIRTIO_BLK_ZS_IOPEN as u8);
+        assert_eq!(zone.start(), 0x100);
+        assert_eq!(zone.capacity(), 0x80);
+        assert_eq!(zone.write_pointer(), 0x110);
+
+        // The fields are at the offsets of `virtio_blk_zone_descriptor`.
+        let bytes = zone.as_slice();
+        assert_eq!(bytes[0..8], 0x80u64.to_le_bytes());
+        assert_eq!(bytes[8..16], 0x100u64.to_le_bytes());
+        assert_eq!(bytes[16..24], 0x110u64.to_le_bytes());
+        assert_eq!(bytes[24], VIRTIO_BLK_ZT_SWR as u8);
+        assert_eq!(bytes[25], VIRTIO_BLK_ZS_IOPEN as u8);
+        assert_eq!(bytes[26..], [0u8; 38]);
+    }
+}